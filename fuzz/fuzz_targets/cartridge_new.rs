@@ -0,0 +1,15 @@
+// cargo fuzz run cartridge_new corpus/cartridge_new
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use zephyrnes::cartridge::{CartridgeData, ParseOptions};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(cartridge) = CartridgeData::new(data.to_vec()) {
+        // Anything that loads has to survive being written back out
+        let _ = CartridgeData::new(cartridge.to_nes2_bytes());
+        let _ = cartridge.to_ines_bytes();
+        let _ = cartridge.expected_file_len();
+    }
+    let _ = CartridgeData::new_with_options(data, ParseOptions::strict());
+});
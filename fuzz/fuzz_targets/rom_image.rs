@@ -0,0 +1,9 @@
+// cargo fuzz run rom_image corpus/rom_image
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use zephyrnes::cartridge::RomImage;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = RomImage::from_bytes(data);
+});
@@ -0,0 +1,240 @@
+// The delta modulation channel at $4010-$4013, which plays 1 bit deltas
+// fetched from CPU memory, taking the bus from the CPU for each byte
+// https://www.nesdev.org/wiki/APU_DMC
+
+use crate::state::{StateError, StateReader, StateWriter};
+
+// In CPU cycles, NTSC
+const RATES: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+pub(super) struct Dmc {
+    irq_enabled: bool,
+    looping: bool,
+    rate: u16,
+    timer: u16,
+    // 7 bits, moved up or down by 2 for each bit played
+    level: u8,
+    sample_address: u16,
+    sample_length: u16,
+    // The memory reader
+    current_address: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+    // The output unit, which plays a byte taken from the buffer
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+    interrupt: bool,
+}
+
+impl Default for Dmc {
+    fn default() -> Self {
+        Dmc {
+            irq_enabled: false,
+            looping: false,
+            rate: RATES[0],
+            timer: 0,
+            level: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+            interrupt: false,
+        }
+    }
+}
+
+impl Dmc {
+    // register is the address's offset into $4010-$4013
+    pub fn write_register(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.irq_enabled = value & 0x80 != 0;
+                if !self.irq_enabled {
+                    self.interrupt = false;
+                }
+                self.looping = value & 0x40 != 0;
+                self.rate = RATES[(value & 0x0F) as usize];
+            }
+            1 => self.level = value & 0x7F,
+            // Samples start at $C000 + A * 64 and are L * 16 + 1 bytes long
+            2 => self.sample_address = 0xC000 | ((value as u16) << 6),
+            _ => self.sample_length = ((value as u16) << 4) + 1,
+        }
+    }
+
+    // Through $4015. Enabling only restarts a sample that has finished, and
+    // either way the interrupt is acknowledged.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.interrupt = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    pub fn bytes_remaining(&self) -> u16 {
+        self.bytes_remaining
+    }
+
+    pub fn interrupt(&self) -> bool {
+        self.interrupt
+    }
+
+    // The address the memory reader wants next, if the buffer has room
+    pub fn pending_read(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    // The byte at pending_read's address. The address wraps from $FFFF to
+    // $8000, and the last byte of a sample either starts it over or raises
+    // the interrupt.
+    pub fn fill_buffer(&mut self, value: u8) {
+        self.sample_buffer = Some(value);
+        self.current_address = self.current_address.checked_add(1).unwrap_or(0x8000);
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.interrupt = true;
+            }
+        }
+    }
+
+    // Once every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.rate - 1;
+        if !self.silence {
+            if self.shift_register & 1 != 0 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(byte) => {
+                    self.silence = false;
+                    self.shift_register = byte;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    // 0-127
+    pub fn output(&self) -> u8 {
+        self.level
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.irq_enabled);
+        state.bool(self.looping);
+        state.u16(self.rate);
+        state.u16(self.timer);
+        state.u8(self.level);
+        state.u16(self.sample_address);
+        state.u16(self.sample_length);
+        state.u16(self.current_address);
+        state.u16(self.bytes_remaining);
+        state.option_u8(self.sample_buffer);
+        state.u8(self.shift_register);
+        state.u8(self.bits_remaining);
+        state.bool(self.silence);
+        state.bool(self.interrupt);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.irq_enabled = state.bool()?;
+        self.looping = state.bool()?;
+        self.rate = state.u16()?;
+        if !RATES.contains(&self.rate) {
+            return Err(StateError::InvalidValue("DMC rate"));
+        }
+        self.timer = state.u16()?;
+        self.level = state.u8_below(128, "DMC level")?;
+        self.sample_address = state.u16()?;
+        self.sample_length = state.u16()?;
+        self.current_address = state.u16()?;
+        self.bytes_remaining = state.u16()?;
+        self.sample_buffer = state.option_u8()?;
+        self.shift_register = state.u8()?;
+        self.bits_remaining = state.u8_below(9, "DMC bits remaining")?;
+        if self.bits_remaining == 0 {
+            return Err(StateError::InvalidValue("DMC bits remaining"));
+        }
+        self.silence = state.bool()?;
+        self.interrupt = state.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_address_wraps_to_8000() {
+        let mut dmc = Dmc::default();
+        dmc.write_register(0, 0x80);
+        // $FFC0, 65 bytes
+        dmc.write_register(2, 0xFF);
+        dmc.write_register(3, 0x04);
+        dmc.set_enabled(true);
+
+        let mut addresses = Vec::new();
+        while let Some(address) = dmc.pending_read() {
+            addresses.push(address);
+            dmc.fill_buffer(0);
+            // As if the output unit had taken it
+            dmc.sample_buffer = None;
+        }
+        assert_eq!(addresses.len(), 65);
+        assert_eq!(addresses[0], 0xFFC0);
+        assert_eq!(addresses[63], 0xFFFF);
+        assert_eq!(addresses[64], 0x8000);
+        assert!(dmc.interrupt());
+    }
+
+    #[test]
+    fn looping_samples_start_over() {
+        let mut dmc = Dmc::default();
+        dmc.write_register(0, 0xC0);
+        // $C040, 1 byte
+        dmc.write_register(2, 0x01);
+        dmc.write_register(3, 0x00);
+        dmc.set_enabled(true);
+        for _ in 0..3 {
+            assert_eq!(dmc.pending_read(), Some(0xC040));
+            dmc.fill_buffer(0);
+            dmc.sample_buffer = None;
+        }
+        assert!(!dmc.interrupt());
+    }
+}
@@ -0,0 +1,77 @@
+// Volume for the pulse and noise channels, either constant or a sawtooth that
+// decays from 15 once every period+1 quarter frames
+// https://www.nesdev.org/wiki/APU_Envelope
+
+use crate::state::{StateError, StateReader, StateWriter};
+
+#[derive(Default)]
+pub(super) struct Envelope {
+    start: bool,
+    // Also the length counter halt flag
+    looping: bool,
+    constant_volume: bool,
+    // Constant volume, or the decay period
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    // The low six bits of $4000, $4004 and $400C
+    pub fn write_control(&mut self, value: u8) {
+        self.looping = value & 0b00100000 != 0;
+        self.constant_volume = value & 0b00010000 != 0;
+        self.volume = value & 0x0F;
+    }
+
+    // Writing the channel's length counter restarts the decay
+    pub fn restart(&mut self) {
+        self.start = true;
+    }
+
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+            return;
+        }
+        if self.divider > 0 {
+            self.divider -= 1;
+            return;
+        }
+        self.divider = self.volume;
+        if self.decay > 0 {
+            self.decay -= 1;
+        } else if self.looping {
+            self.decay = 15;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.constant_volume {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.start);
+        state.bool(self.looping);
+        state.bool(self.constant_volume);
+        state.u8(self.volume);
+        state.u8(self.divider);
+        state.u8(self.decay);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.start = state.bool()?;
+        self.looping = state.bool()?;
+        self.constant_volume = state.bool()?;
+        self.volume = state.u8_below(16, "envelope volume")?;
+        self.divider = state.u8()?;
+        self.decay = state.u8_below(16, "envelope decay")?;
+        Ok(())
+    }
+}
@@ -0,0 +1,200 @@
+// The frame sequencer at $4017, which clocks the envelopes, sweeps and
+// counters at roughly 240 Hz and can raise an IRQ at the end of each sequence
+// https://www.nesdev.org/wiki/APU_Frame_Counter
+
+use crate::state::{StateError, StateReader, StateWriter};
+
+// Cycles into each sequence at which a step happens, NTSC. The steps are on
+// half APU cycles, so these are in CPU cycles.
+const STEP_1: u16 = 7457;
+const STEP_2: u16 = 14913;
+const STEP_3: u16 = 22371;
+const FOUR_STEP_END: u16 = 29829;
+const FIVE_STEP_END: u16 = 37281;
+
+// What a cycle of the sequencer clocks. Half frames clock everything quarter
+// frames do, as well as the length counters and sweeps.
+#[derive(Clone, Copy, PartialEq)]
+pub(super) enum FrameStep {
+    Quarter,
+    Half,
+}
+
+#[derive(Default)]
+pub(super) struct FrameCounter {
+    five_step: bool,
+    irq_inhibit: bool,
+    interrupt: bool,
+    cycle: u16,
+    // Cycles until a $4017 write restarts the sequence
+    reset_delay: u8,
+}
+
+impl FrameCounter {
+    // MI-- ----, 5-step mode and IRQ inhibit. The sequence restarts 3 or 4
+    // cycles after the write, depending on where it lands in an APU cycle.
+    pub fn write(&mut self, value: u8, odd_cycle: bool) {
+        self.five_step = value & 0x80 != 0;
+        self.irq_inhibit = value & 0x40 != 0;
+        if self.irq_inhibit {
+            self.interrupt = false;
+        }
+        self.reset_delay = if odd_cycle { 4 } else { 3 };
+    }
+
+    pub fn interrupt(&self) -> bool {
+        self.interrupt
+    }
+
+    // Reading $4015 acknowledges it
+    pub fn clear_interrupt(&mut self) {
+        self.interrupt = false;
+    }
+
+    // Once every CPU cycle
+    pub fn tick(&mut self) -> Option<FrameStep> {
+        if self.reset_delay > 0 {
+            self.reset_delay -= 1;
+            if self.reset_delay == 0 {
+                self.cycle = 0;
+                // Restarting in 5-step mode clocks everything straight away
+                if self.five_step {
+                    return Some(FrameStep::Half);
+                }
+                return None;
+            }
+        }
+
+        self.cycle += 1;
+        // The 4-step sequence holds the IRQ for the last three cycles
+        if !self.five_step
+            && !self.irq_inhibit
+            && (FOUR_STEP_END - 1..=FOUR_STEP_END + 1).contains(&self.cycle)
+        {
+            self.interrupt = true;
+        }
+        match (self.cycle, self.five_step) {
+            (STEP_1 | STEP_3, _) => Some(FrameStep::Quarter),
+            (STEP_2, _) | (FOUR_STEP_END, false) | (FIVE_STEP_END, true) => Some(FrameStep::Half),
+            (c, false) if c == FOUR_STEP_END + 1 => {
+                self.cycle = 0;
+                None
+            }
+            (c, true) if c == FIVE_STEP_END + 1 => {
+                self.cycle = 0;
+                None
+            }
+            _ => None,
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.five_step);
+        state.bool(self.irq_inhibit);
+        state.bool(self.interrupt);
+        state.u16(self.cycle);
+        state.u8(self.reset_delay);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.five_step = state.bool()?;
+        self.irq_inhibit = state.bool()?;
+        self.interrupt = state.bool()?;
+        // The sequence wraps to 0 on the cycle after its last step
+        let sequence_end = if self.five_step {
+            FIVE_STEP_END
+        } else {
+            FOUR_STEP_END
+        };
+        self.cycle = state.u16_below(sequence_end + 1, "frame counter cycle")?;
+        self.reset_delay = state.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The cycles each step lands on over one sequence, and the first cycle
+    // with the interrupt raised
+    fn run_sequence(frame_counter: &mut FrameCounter, len: u16) -> (Vec<u16>, Option<u16>) {
+        let mut steps = Vec::new();
+        let mut first_interrupt = None;
+        for cycle in 1..=len {
+            if frame_counter.tick().is_some() {
+                steps.push(cycle);
+            }
+            if frame_counter.interrupt() && first_interrupt.is_none() {
+                first_interrupt = Some(cycle);
+            }
+        }
+        (steps, first_interrupt)
+    }
+
+    #[test]
+    fn four_step_mode_raises_the_irq() {
+        let mut frame_counter = FrameCounter::default();
+        let (steps, interrupt) = run_sequence(&mut frame_counter, FOUR_STEP_END + 1);
+        assert_eq!(steps, [STEP_1, STEP_2, STEP_3, FOUR_STEP_END]);
+        assert_eq!(interrupt, Some(FOUR_STEP_END - 1));
+
+        // It stays up until acknowledged, and comes back next sequence
+        assert!(frame_counter.interrupt());
+        frame_counter.clear_interrupt();
+        let (_, interrupt) = run_sequence(&mut frame_counter, FOUR_STEP_END + 1);
+        assert_eq!(interrupt, Some(FOUR_STEP_END - 1));
+    }
+
+    #[test]
+    fn five_step_mode_never_does() {
+        let mut frame_counter = FrameCounter::default();
+        frame_counter.write(0x80, false);
+        // The restart after the write clocks a half frame
+        let (steps, _) = run_sequence(&mut frame_counter, 3);
+        assert_eq!(steps, [3]);
+        for _ in 0..3 {
+            let (steps, interrupt) = run_sequence(&mut frame_counter, FIVE_STEP_END + 1);
+            assert_eq!(steps, [STEP_1, STEP_2, STEP_3, FIVE_STEP_END]);
+            assert_eq!(interrupt, None);
+        }
+    }
+
+    #[test]
+    fn irq_inhibit_clears_and_blocks_it() {
+        let mut frame_counter = FrameCounter::default();
+        run_sequence(&mut frame_counter, FOUR_STEP_END + 1);
+        assert!(frame_counter.interrupt());
+        frame_counter.write(0x40, true);
+        assert!(!frame_counter.interrupt());
+        let (_, interrupt) = run_sequence(&mut frame_counter, 2 * (FOUR_STEP_END + 1));
+        assert_eq!(interrupt, None);
+    }
+
+    #[test]
+    fn cycle_is_checked_on_load() {
+        let mut frame_counter = FrameCounter::default();
+        frame_counter.write(0x80, false);
+        run_sequence(&mut frame_counter, FOUR_STEP_END + 10);
+        let mut state = StateWriter::new();
+        frame_counter.save_state(&mut state);
+        let mut bytes = state.into_bytes();
+        assert!(frame_counter
+            .load_state(&mut StateReader::new(&bytes))
+            .is_ok());
+
+        // Past the end of the 4-step sequence, which would never wrap
+        bytes[0] = 0;
+        assert_eq!(
+            frame_counter.load_state(&mut StateReader::new(&bytes)),
+            Err(StateError::InvalidValue("frame counter cycle"))
+        );
+        // The cycle follows the three flags
+        bytes[0] = 1;
+        bytes[3..5].copy_from_slice(&(FIVE_STEP_END + 1).to_le_bytes());
+        assert_eq!(
+            frame_counter.load_state(&mut StateReader::new(&bytes)),
+            Err(StateError::InvalidValue("frame counter cycle"))
+        );
+    }
+}
@@ -0,0 +1,61 @@
+// Silences a channel once a number of half frames pass, unless halted
+// https://www.nesdev.org/wiki/APU_Length_Counter
+
+use crate::state::{StateError, StateReader, StateWriter};
+
+// Indexed by the top five bits of the channel's length register
+const LENGTHS: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+#[derive(Default)]
+pub(super) struct LengthCounter {
+    counter: u8,
+    halted: bool,
+    // Cleared through $4015, which also holds the counter at 0
+    enabled: bool,
+}
+
+impl LengthCounter {
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    pub fn set_halted(&mut self, halted: bool) {
+        self.halted = halted;
+    }
+
+    // Takes the whole register, the table index is in the top five bits
+    pub fn load(&mut self, value: u8) {
+        if self.enabled {
+            self.counter = LENGTHS[(value >> 3) as usize];
+        }
+    }
+
+    pub fn clock(&mut self) {
+        if !self.halted && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.counter > 0
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.counter);
+        state.bool(self.halted);
+        state.bool(self.enabled);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.counter = state.u8()?;
+        self.halted = state.bool()?;
+        self.enabled = state.bool()?;
+        Ok(())
+    }
+}
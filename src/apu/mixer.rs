@@ -0,0 +1,93 @@
+// Combines the channel outputs into one level the way the resistor network on
+// the console does. The pulses share one nonlinear DAC, and the triangle,
+// noise and DMC another.
+// https://www.nesdev.org/wiki/APU_Mixer#Lookup_Table
+
+pub(super) struct Mixer {
+    // Indexed by pulse 1 + pulse 2
+    pulse_table: [f32; 31],
+    // Indexed by 3 * triangle + 2 * noise + DMC
+    tnd_table: [f32; 203],
+}
+
+impl Mixer {
+    pub fn new() -> Mixer {
+        let mut pulse_table = [0.0; 31];
+        for (n, level) in pulse_table.iter_mut().enumerate().skip(1) {
+            *level = 95.52 / (8128.0 / n as f32 + 100.0);
+        }
+        let mut tnd_table = [0.0; 203];
+        for (n, level) in tnd_table.iter_mut().enumerate().skip(1) {
+            *level = 163.67 / (24329.0 / n as f32 + 100.0);
+        }
+        Mixer {
+            pulse_table,
+            tnd_table,
+        }
+    }
+
+    // Roughly 0.0-1.0, though it tops out a little short
+    pub fn mix(&self, pulse_1: u8, pulse_2: u8, triangle: u8, noise: u8, dmc: u8) -> f32 {
+        let pulse = self.pulse_table[(pulse_1 + pulse_2) as usize];
+        let tnd = self.tnd_table[3 * triangle as usize + 2 * noise as usize + dmc as usize];
+        pulse + tnd
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The mixer's formula without the lookup table's approximation, which
+    // is up to about 2% off at the extremes
+    fn reference(pulse_1: u8, pulse_2: u8, triangle: u8, noise: u8, dmc: u8) -> f32 {
+        let pulse = pulse_1 as f32 + pulse_2 as f32;
+        let pulse = if pulse == 0.0 {
+            0.0
+        } else {
+            95.88 / (8128.0 / pulse + 100.0)
+        };
+        let tnd = triangle as f32 / 8227.0 + noise as f32 / 12241.0 + dmc as f32 / 22638.0;
+        let tnd = if tnd == 0.0 {
+            0.0
+        } else {
+            159.79 / (1.0 / tnd + 100.0)
+        };
+        pulse + tnd
+    }
+
+    #[test]
+    fn matches_the_hardware_formula() {
+        let mixer = Mixer::new();
+        assert_eq!(mixer.mix(0, 0, 0, 0, 0), 0.0);
+        for levels in [
+            (15, 0, 0, 0, 0),
+            (15, 15, 0, 0, 0),
+            (0, 0, 15, 0, 0),
+            (0, 0, 0, 15, 0),
+            (0, 0, 0, 0, 127),
+            (8, 4, 12, 6, 64),
+            (15, 15, 15, 15, 127),
+        ] {
+            let (pulse_1, pulse_2, triangle, noise, dmc) = levels;
+            let mixed = mixer.mix(pulse_1, pulse_2, triangle, noise, dmc);
+            let expected = reference(pulse_1, pulse_2, triangle, noise, dmc);
+            assert!(
+                (mixed - expected).abs() < 0.02,
+                "{levels:?} mixed to {mixed}, expected {expected}"
+            );
+        }
+        // Full scale stays just under 1
+        let full = mixer.mix(15, 15, 15, 15, 127);
+        assert!(full > 0.95 && full < 1.0, "{full}");
+    }
+
+    #[test]
+    fn louder_channels_mix_louder() {
+        let mixer = Mixer::new();
+        let pulse: Vec<f32> = (0..=15).map(|level| mixer.mix(level, 0, 0, 0, 0)).collect();
+        assert!(pulse.windows(2).all(|pair| pair[0] < pair[1]));
+        // The DAC saturates, so two pulses aren't twice as loud as one
+        assert!(mixer.mix(15, 15, 0, 0, 0) < 2.0 * mixer.mix(15, 0, 0, 0, 0));
+    }
+}
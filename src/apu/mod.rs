@@ -0,0 +1,219 @@
+// The 2A03's audio processing unit, at $4000-$4017
+// https://www.nesdev.org/wiki/APU
+// https://www.nesdev.org/wiki/APU_registers
+
+mod dmc;
+mod envelope;
+mod frame_counter;
+mod length_counter;
+mod mixer;
+mod noise;
+mod pulse;
+mod resampler;
+mod triangle;
+
+use dmc::Dmc;
+use frame_counter::{FrameCounter, FrameStep};
+use mixer::Mixer;
+use noise::Noise;
+use pulse::{Pulse, PulseChannel};
+use resampler::Resampler;
+use triangle::Triangle;
+
+use crate::state::{StateError, StateReader, StateWriter};
+
+pub use resampler::CPU_CLOCK_RATE;
+
+// $4015
+const STATUS_PULSE_1: u8 = 0b00000001;
+const STATUS_PULSE_2: u8 = 0b00000010;
+const STATUS_TRIANGLE: u8 = 0b00000100;
+const STATUS_NOISE: u8 = 0b00001000;
+const STATUS_DMC: u8 = 0b00010000;
+const STATUS_FRAME_INTERRUPT: u8 = 0b01000000;
+const STATUS_DMC_INTERRUPT: u8 = 0b10000000;
+
+pub struct Apu {
+    pulse_1: Pulse,
+    pulse_2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    frame_counter: FrameCounter,
+    mixer: Mixer,
+    // Every cycle's output, waiting to be drained at the host's rate
+    resampler: Resampler,
+    // The pulse timers run at half the CPU clock
+    odd_cycle: bool,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Apu {
+    pub fn new() -> Apu {
+        Apu {
+            pulse_1: Pulse::new(PulseChannel::One),
+            pulse_2: Pulse::new(PulseChannel::Two),
+            triangle: Triangle::default(),
+            noise: Noise::default(),
+            dmc: Dmc::default(),
+            frame_counter: FrameCounter::default(),
+            mixer: Mixer::new(),
+            resampler: Resampler::new(),
+            odd_cycle: false,
+        }
+    }
+
+    // Advances by one CPU cycle
+    pub fn tick(&mut self) {
+        match self.frame_counter.tick() {
+            Some(FrameStep::Quarter) => self.clock_quarter_frame(),
+            Some(FrameStep::Half) => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            None => {}
+        }
+        if self.odd_cycle {
+            self.pulse_1.clock_timer();
+            self.pulse_2.clock_timer();
+        }
+        self.odd_cycle = !self.odd_cycle;
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        self.resampler.push(self.output());
+    }
+
+    // Fills out with audio at sample_rate from what's been generated since
+    // the last call, filtered the way the console's output stage filters it.
+    // The high-passes take out the DC offset, so samples are centred on 0.
+    // Returns how many samples were written, which falls short of out's
+    // length once the buffered audio runs out.
+    pub fn drain_samples(&mut self, out: &mut [f32], sample_rate: u32) -> usize {
+        self.resampler.drain(out, sample_rate)
+    }
+
+    // The address the DMC wants to fetch a sample byte from. Whoever owns
+    // the bus reads it, stalls the CPU, and hands the byte to
+    // load_dmc_sample.
+    pub fn pending_dmc_read(&self) -> Option<u16> {
+        self.dmc.pending_read()
+    }
+
+    pub fn load_dmc_sample(&mut self, value: u8) {
+        self.dmc.fill_buffer(value);
+    }
+
+    // Whether the APU is holding the CPU's IRQ line low
+    pub fn irq_pending(&self) -> bool {
+        self.frame_counter.interrupt() || self.dmc.interrupt()
+    }
+
+    // Envelopes and the linear counter, four times a frame
+    fn clock_quarter_frame(&mut self) {
+        self.pulse_1.clock_quarter_frame();
+        self.pulse_2.clock_quarter_frame();
+        self.triangle.clock_quarter_frame();
+        self.noise.clock_quarter_frame();
+    }
+
+    // Length counters and sweeps, twice a frame
+    fn clock_half_frame(&mut self) {
+        self.pulse_1.clock_half_frame();
+        self.pulse_2.clock_half_frame();
+        self.triangle.clock_half_frame();
+        self.noise.clock_half_frame();
+    }
+
+    // address is in $4000-$4017. The bus keeps $4014 and $4016 for itself.
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x4000..=0x4003 => self.pulse_1.write_register(address - 0x4000, value),
+            0x4004..=0x4007 => self.pulse_2.write_register(address - 0x4004, value),
+            0x4008..=0x400B => self.triangle.write_register(address - 0x4008, value),
+            0x400C..=0x400F => self.noise.write_register(address - 0x400C, value),
+            0x4010..=0x4013 => self.dmc.write_register(address - 0x4010, value),
+            // Length counter enables, and starting or stopping the DMC
+            0x4015 => {
+                self.pulse_1.set_enabled(value & STATUS_PULSE_1 != 0);
+                self.pulse_2.set_enabled(value & STATUS_PULSE_2 != 0);
+                self.triangle.set_enabled(value & STATUS_TRIANGLE != 0);
+                self.noise.set_enabled(value & STATUS_NOISE != 0);
+                self.dmc.set_enabled(value & STATUS_DMC != 0);
+            }
+            0x4017 => self.frame_counter.write(value, self.odd_cycle),
+            _ => {}
+        }
+    }
+
+    // $4015, the only readable register. Each bit says whether that channel's
+    // length counter is still running, or for the DMC whether it has bytes
+    // left to fetch. Reading it acknowledges the frame interrupt.
+    pub fn read_status(&mut self) -> u8 {
+        let mut status = 0;
+        if self.pulse_1.length_active() {
+            status |= STATUS_PULSE_1;
+        }
+        if self.pulse_2.length_active() {
+            status |= STATUS_PULSE_2;
+        }
+        if self.triangle.length_active() {
+            status |= STATUS_TRIANGLE;
+        }
+        if self.noise.length_active() {
+            status |= STATUS_NOISE;
+        }
+        if self.dmc.bytes_remaining() > 0 {
+            status |= STATUS_DMC;
+        }
+        if self.frame_counter.interrupt() {
+            status |= STATUS_FRAME_INTERRUPT;
+        }
+        if self.dmc.interrupt() {
+            status |= STATUS_DMC_INTERRUPT;
+        }
+        self.frame_counter.clear_interrupt();
+        status
+    }
+
+    // The current amplitude, 0.0-1.0
+    pub fn output(&self) -> f32 {
+        self.mixer.mix(
+            self.pulse_1.output(),
+            self.pulse_2.output(),
+            self.triangle.output(),
+            self.noise.output(),
+            self.dmc.output(),
+        )
+    }
+
+    pub fn sample(&mut self) -> f32 {
+        self.output()
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        self.pulse_1.save_state(state);
+        self.pulse_2.save_state(state);
+        self.triangle.save_state(state);
+        self.noise.save_state(state);
+        self.dmc.save_state(state);
+        self.frame_counter.save_state(state);
+        state.bool(self.odd_cycle);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.pulse_1.load_state(state)?;
+        self.pulse_2.load_state(state)?;
+        self.triangle.load_state(state)?;
+        self.noise.load_state(state)?;
+        self.dmc.load_state(state)?;
+        self.frame_counter.load_state(state)?;
+        self.odd_cycle = state.bool()?;
+        Ok(())
+    }
+}
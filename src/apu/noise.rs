@@ -0,0 +1,161 @@
+// The noise channel at $400C-$400F, a 15 bit linear feedback shift register
+// stepped at one of 16 rates
+// https://www.nesdev.org/wiki/APU_Noise
+
+use crate::state::{StateError, StateReader, StateWriter};
+
+use super::envelope::Envelope;
+use super::length_counter::LengthCounter;
+
+// In CPU cycles, NTSC
+const PERIODS: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+pub(super) struct Noise {
+    // Short mode feeds back from bit 6 instead of bit 1, which repeats after
+    // 93 or 31 steps instead of 32767
+    short_mode: bool,
+    period: u16,
+    timer: u16,
+    shift_register: u16,
+    envelope: Envelope,
+    length: LengthCounter,
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Noise {
+            short_mode: false,
+            period: PERIODS[0],
+            timer: 0,
+            // Loaded with 1 at power-on
+            shift_register: 1,
+            envelope: Envelope::default(),
+            length: LengthCounter::default(),
+        }
+    }
+}
+
+impl Noise {
+    // register is the address's offset into $400C-$400F
+    pub fn write_register(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.length.set_halted(value & 0b00100000 != 0);
+                self.envelope.write_control(value);
+            }
+            // $400D isn't connected
+            1 => {}
+            2 => {
+                self.short_mode = value & 0x80 != 0;
+                self.period = PERIODS[(value & 0x0F) as usize];
+            }
+            _ => {
+                self.length.load(value);
+                self.envelope.restart();
+            }
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.length.set_enabled(enabled);
+    }
+
+    pub fn length_active(&self) -> bool {
+        self.length.is_active()
+    }
+
+    // Once every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period - 1;
+        let tap = if self.short_mode { 6 } else { 1 };
+        let feedback = (self.shift_register ^ (self.shift_register >> tap)) & 1;
+        self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+    }
+
+    // 0-15, silent while bit 0 of the shift register is set
+    pub fn output(&self) -> u8 {
+        if !self.length.is_active() || self.shift_register & 1 != 0 {
+            return 0;
+        }
+        self.envelope.output()
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.short_mode);
+        state.u16(self.period);
+        state.u16(self.timer);
+        state.u16(self.shift_register);
+        self.envelope.save_state(state);
+        self.length.save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.short_mode = state.bool()?;
+        self.period = state.u16()?;
+        if !PERIODS.contains(&self.period) {
+            return Err(StateError::InvalidValue("noise period"));
+        }
+        self.timer = state.u16()?;
+        self.shift_register = state.u16()?;
+        self.envelope.load_state(state)?;
+        self.length.load_state(state)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Steps until the shift register comes back around to its starting value
+    fn sequence_length(short_mode: bool) -> u32 {
+        let mut noise = Noise::default();
+        // The fastest rate steps the register every 4 cycles
+        noise.write_register(2, if short_mode { 0x80 } else { 0x00 });
+        let start = noise.shift_register;
+        let mut steps = 0;
+        loop {
+            for _ in 0..PERIODS[0] {
+                noise.clock_timer();
+            }
+            steps += 1;
+            if noise.shift_register == start {
+                return steps;
+            }
+        }
+    }
+
+    #[test]
+    fn lfsr_sequence_lengths() {
+        assert_eq!(sequence_length(false), 32767);
+        // Starting from 1, short mode lands in the longer of its two loops
+        assert_eq!(sequence_length(true), 93);
+    }
+
+    #[test]
+    fn period_is_checked_on_load() {
+        let mut state = StateWriter::new();
+        Noise::default().save_state(&mut state);
+        let mut bytes = state.into_bytes();
+        // The period follows the mode flag
+        bytes[1..3].copy_from_slice(&0u16.to_le_bytes());
+        assert_eq!(
+            Noise::default().load_state(&mut StateReader::new(&bytes)),
+            Err(StateError::InvalidValue("noise period"))
+        );
+    }
+}
@@ -0,0 +1,270 @@
+// The two square wave channels at $4000-$4003 and $4004-$4007
+// https://www.nesdev.org/wiki/APU_Pulse
+// https://www.nesdev.org/wiki/APU_Sweep
+
+use crate::state::{StateError, StateReader, StateWriter};
+
+use super::envelope::Envelope;
+use super::length_counter::LengthCounter;
+
+// The sequencer steps down through these, starting from 0 when the length is
+// written, so it reads 0, 7, 6, ... 1
+const DUTY_CYCLES: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+// The two channels only differ in how the sweep negates. Pulse 1 adds the
+// ones' complement of the change, so it ends up one lower than pulse 2.
+#[derive(Clone, Copy, PartialEq)]
+pub(super) enum PulseChannel {
+    One,
+    Two,
+}
+
+pub(super) struct Pulse {
+    channel: PulseChannel,
+    duty: u8,
+    step: u8,
+    // 11 bits, the timer counts down from it every APU cycle
+    period: u16,
+    timer: u16,
+    envelope: Envelope,
+    length: LengthCounter,
+    sweep: Sweep,
+}
+
+#[derive(Default)]
+struct Sweep {
+    enabled: bool,
+    period: u8,
+    negate: bool,
+    shift: u8,
+    divider: u8,
+    reload: bool,
+}
+
+impl Pulse {
+    pub fn new(channel: PulseChannel) -> Pulse {
+        Pulse {
+            channel,
+            duty: 0,
+            step: 0,
+            period: 0,
+            timer: 0,
+            envelope: Envelope::default(),
+            length: LengthCounter::default(),
+            sweep: Sweep::default(),
+        }
+    }
+
+    // register is the address's offset into the channel's four
+    pub fn write_register(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.duty = value >> 6;
+                self.length.set_halted(value & 0b00100000 != 0);
+                self.envelope.write_control(value);
+            }
+            1 => {
+                self.sweep.enabled = value & 0b10000000 != 0;
+                self.sweep.period = (value >> 4) & 0b111;
+                self.sweep.negate = value & 0b00001000 != 0;
+                self.sweep.shift = value & 0b111;
+                self.sweep.reload = true;
+            }
+            2 => self.period = (self.period & 0x0700) | value as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((value as u16 & 0b111) << 8);
+                self.length.load(value);
+                self.step = 0;
+                self.envelope.restart();
+            }
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.length.set_enabled(enabled);
+    }
+
+    pub fn length_active(&self) -> bool {
+        self.length.is_active()
+    }
+
+    // Once every APU cycle, every other CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = self.step.wrapping_sub(1) & 0b111;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+        self.clock_sweep();
+    }
+
+    fn clock_sweep(&mut self) {
+        if self.sweep.divider == 0 && self.sweep.enabled && self.sweep.shift > 0 && !self.muted() {
+            self.period = self.target_period();
+        }
+        if self.sweep.divider == 0 || self.sweep.reload {
+            self.sweep.divider = self.sweep.period;
+            self.sweep.reload = false;
+        } else {
+            self.sweep.divider -= 1;
+        }
+    }
+
+    // Worked out continuously, whether the sweep is enabled or not
+    fn target_period(&self) -> u16 {
+        let change = self.period >> self.sweep.shift;
+        if !self.sweep.negate {
+            self.period + change
+        } else if self.channel == PulseChannel::One {
+            self.period.saturating_sub(change + 1)
+        } else {
+            self.period.saturating_sub(change)
+        }
+    }
+
+    // The sweep silences the channel if the period is too short to be
+    // audible or the target would overflow 11 bits, even when it's disabled
+    pub fn muted(&self) -> bool {
+        self.period < 8 || self.target_period() > 0x07FF
+    }
+
+    // 0-15
+    pub fn output(&self) -> u8 {
+        if !self.length.is_active()
+            || self.muted()
+            || DUTY_CYCLES[self.duty as usize][self.step as usize] == 0
+        {
+            return 0;
+        }
+        self.envelope.output()
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.duty);
+        state.u8(self.step);
+        state.u16(self.period);
+        state.u16(self.timer);
+        self.envelope.save_state(state);
+        self.length.save_state(state);
+        state.bool(self.sweep.enabled);
+        state.u8(self.sweep.period);
+        state.bool(self.sweep.negate);
+        state.u8(self.sweep.shift);
+        state.u8(self.sweep.divider);
+        state.bool(self.sweep.reload);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.duty = state.u8_below(4, "pulse duty")?;
+        self.step = state.u8_below(8, "pulse step")?;
+        self.period = state.u16_below(0x800, "pulse period")?;
+        self.timer = state.u16()?;
+        self.envelope.load_state(state)?;
+        self.length.load_state(state)?;
+        self.sweep.enabled = state.bool()?;
+        self.sweep.period = state.u8_below(8, "sweep period")?;
+        self.sweep.negate = state.bool()?;
+        self.sweep.shift = state.u8_below(8, "sweep shift")?;
+        self.sweep.divider = state.u8()?;
+        self.sweep.reload = state.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Constant volume 15 on the duty that starts high, so step 0 outputs
+    fn pulse(channel: PulseChannel, sweep: u8, period: u16) -> Pulse {
+        let mut pulse = Pulse::new(channel);
+        pulse.set_enabled(true);
+        pulse.write_register(0, 0xFF);
+        pulse.write_register(1, sweep);
+        pulse.write_register(2, period as u8);
+        pulse.write_register(3, (period >> 8) as u8 | 0x08);
+        pulse
+    }
+
+    #[test]
+    fn target_over_7ff_mutes() {
+        // $500 + $280 is still in range
+        let pulse_in_range = pulse(PulseChannel::One, 0x01, 0x500);
+        assert!(!pulse_in_range.muted());
+        assert_eq!(pulse_in_range.output(), 15);
+
+        // $600 + $300 isn't, and the sweep being disabled doesn't matter
+        let mut overflowing = pulse(PulseChannel::One, 0x01, 0x600);
+        assert!(overflowing.muted());
+        assert_eq!(overflowing.output(), 0);
+        // An enabled sweep leaves the period alone rather than overflowing
+        overflowing.write_register(1, 0x81);
+        for _ in 0..4 {
+            overflowing.clock_half_frame();
+        }
+        assert_eq!(overflowing.period, 0x600);
+        assert_eq!(overflowing.output(), 0);
+
+        // Negating never overflows
+        assert!(!pulse(PulseChannel::One, 0x09, 0x600).muted());
+    }
+
+    #[test]
+    fn short_periods_mute() {
+        assert!(pulse(PulseChannel::Two, 0x00, 7).muted());
+        assert_eq!(pulse(PulseChannel::Two, 0x00, 7).output(), 0);
+        assert!(!pulse(PulseChannel::Two, 0x00, 8).muted());
+    }
+
+    #[test]
+    fn sweep_negates_differently_per_channel() {
+        // Shift 2 of $100 is $40, less one more on pulse 1
+        let mut one = pulse(PulseChannel::One, 0x8A, 0x100);
+        let mut two = pulse(PulseChannel::Two, 0x8A, 0x100);
+        // With a divider period of 0 the sweep adjusts every half frame
+        one.clock_half_frame();
+        two.clock_half_frame();
+        assert_eq!((one.period, two.period), (0x0BF, 0x0C0));
+        one.clock_half_frame();
+        two.clock_half_frame();
+        assert_eq!((one.period, two.period), (0x08F, 0x090));
+    }
+
+    #[test]
+    fn sweep_is_checked_on_load() {
+        let mut state = StateWriter::new();
+        pulse(PulseChannel::One, 0x87, 0x100).save_state(&mut state);
+        let bytes = state.into_bytes();
+        // Duty, step, period and timer, then 6 bytes of envelope and 3 of
+        // length counter ahead of the sweep's enable, period, negate and shift
+        let sweep = 15;
+        assert_eq!(bytes[sweep + 3], 7);
+
+        let mut shift = bytes.clone();
+        shift[sweep + 3] = 16;
+        assert_eq!(
+            Pulse::new(PulseChannel::One).load_state(&mut StateReader::new(&shift)),
+            Err(StateError::InvalidValue("sweep shift"))
+        );
+        let mut period = bytes.clone();
+        period[sweep + 1] = 8;
+        assert_eq!(
+            Pulse::new(PulseChannel::One).load_state(&mut StateReader::new(&period)),
+            Err(StateError::InvalidValue("sweep period"))
+        );
+    }
+}
@@ -0,0 +1,264 @@
+// Takes the APU output at the CPU clock rate down to whatever the host plays
+// at. The console's own filters come first, then a low-pass to keep what's
+// left above the host's Nyquist frequency from aliasing, then each output
+// sample averages the input samples that fall into it.
+// https://www.nesdev.org/wiki/APU_Mixer#Emulation
+
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+// NTSC, 21.477272 MHz / 12
+pub const CPU_CLOCK_RATE: f64 = 1_789_772.727;
+// About 100 ms of output. Past this, the oldest samples are dropped.
+const BUFFER_CAPACITY: usize = CPU_CLOCK_RATE as usize / 10;
+
+pub(super) struct Resampler {
+    buffer: VecDeque<f32>,
+    // Two high-passes at 90 Hz and 440 Hz and a low-pass at 14 kHz
+    high_pass_90: HighPass,
+    high_pass_440: HighPass,
+    low_pass_14k: LowPass,
+    // Fourth order Butterworth, redesigned when the host rate changes
+    sample_rate: u32,
+    anti_alias: [Biquad; 2],
+    // Input samples into the current output sample
+    phase: f64,
+    sum: f64,
+    count: u32,
+}
+
+impl Resampler {
+    pub fn new() -> Resampler {
+        Resampler {
+            buffer: VecDeque::with_capacity(BUFFER_CAPACITY),
+            high_pass_90: HighPass::new(90.0),
+            high_pass_440: HighPass::new(440.0),
+            low_pass_14k: LowPass::new(14_000.0),
+            sample_rate: 0,
+            anti_alias: [Biquad::default(), Biquad::default()],
+            phase: 0.0,
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    pub fn push(&mut self, sample: f32) {
+        if self.buffer.len() == BUFFER_CAPACITY {
+            self.buffer.pop_front();
+        }
+        self.buffer.push_back(sample);
+    }
+
+    // Fills as much of out as the buffered input covers and returns how many
+    // samples that was
+    pub fn drain(&mut self, out: &mut [f32], sample_rate: u32) -> usize {
+        if sample_rate == 0 {
+            return 0;
+        }
+        if sample_rate != self.sample_rate {
+            self.set_sample_rate(sample_rate);
+        }
+        let ratio = CPU_CLOCK_RATE / sample_rate as f64;
+        let mut written = 0;
+        while written < out.len() {
+            let Some(sample) = self.buffer.pop_front() else {
+                break;
+            };
+            let mut x = sample as f64;
+            x = self.high_pass_90.process(x);
+            x = self.high_pass_440.process(x);
+            x = self.low_pass_14k.process(x);
+            for biquad in &mut self.anti_alias {
+                x = biquad.process(x);
+            }
+            self.sum += x;
+            self.count += 1;
+            self.phase += 1.0;
+            if self.phase >= ratio {
+                self.phase -= ratio;
+                out[written] = (self.sum / self.count as f64) as f32;
+                written += 1;
+                self.sum = 0.0;
+                self.count = 0;
+            }
+        }
+        written
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        let cutoff = (sample_rate as f64 * 0.45).min(20_000.0);
+        // Q for each half of a fourth order Butterworth
+        self.anti_alias = [
+            Biquad::low_pass(cutoff, 0.541_196_1),
+            Biquad::low_pass(cutoff, 1.306_563),
+        ];
+    }
+}
+
+// First order filters, as the console has
+// https://en.wikipedia.org/wiki/High-pass_filter#Discrete-time_realization
+struct HighPass {
+    alpha: f64,
+    previous_in: f64,
+    previous_out: f64,
+}
+
+impl HighPass {
+    fn new(cutoff: f64) -> HighPass {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        HighPass {
+            alpha: rc / (rc + 1.0 / CPU_CLOCK_RATE),
+            previous_in: 0.0,
+            previous_out: 0.0,
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        self.previous_out = self.alpha * (self.previous_out + x - self.previous_in);
+        self.previous_in = x;
+        self.previous_out
+    }
+}
+
+struct LowPass {
+    alpha: f64,
+    previous_out: f64,
+}
+
+impl LowPass {
+    fn new(cutoff: f64) -> LowPass {
+        LowPass {
+            alpha: 1.0 - (-2.0 * PI * cutoff / CPU_CLOCK_RATE).exp(),
+            previous_out: 0.0,
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        self.previous_out += self.alpha * (x - self.previous_out);
+        self.previous_out
+    }
+}
+
+// https://www.w3.org/TR/audio-eq-cookbook/
+#[derive(Default)]
+struct Biquad {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+    z1: f64,
+    z2: f64,
+}
+
+impl Biquad {
+    fn low_pass(cutoff: f64, q: f64) -> Biquad {
+        let w0 = 2.0 * PI * cutoff / CPU_CLOCK_RATE;
+        let alpha = w0.sin() / (2.0 * q);
+        let cos = w0.cos();
+        let a0 = 1.0 + alpha;
+        Biquad {
+            b0: (1.0 - cos) / 2.0 / a0,
+            b1: (1.0 - cos) / a0,
+            b2: (1.0 - cos) / 2.0 / a0,
+            a1: -2.0 * cos / a0,
+            a2: (1.0 - alpha) / a0,
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+
+    // Transposed direct form II
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b0 * x + self.z1;
+        self.z1 = self.b1 * x - self.a1 * y + self.z2;
+        self.z2 = self.b2 * x - self.a2 * y;
+        y
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 44_100;
+    // 100 ms, so the bins are 10 Hz apart and the harmonics of 1 kHz are
+    // every hundredth. Harmonics past Nyquist fold back onto multiples of
+    // 100 Hz in between.
+    const WINDOW: usize = 4410;
+
+    // A 1 kHz square wave at the CPU clock rate, drained as it goes so none
+    // of it is dropped, with the first 100 ms left for the filters to settle
+    fn resampled_square() -> Vec<f32> {
+        let mut resampler = Resampler::new();
+        let mut out = Vec::new();
+        let mut chunk = [0.0; 1024];
+        let mut cycle = 0u64;
+        while out.len() < SAMPLE_RATE as usize / 10 + WINDOW {
+            for _ in 0..10_000 {
+                let phase = (cycle as f64 * 1000.0 / CPU_CLOCK_RATE).fract();
+                resampler.push(if phase < 0.5 { 0.5 } else { 0.0 });
+                cycle += 1;
+            }
+            loop {
+                let written = resampler.drain(&mut chunk, SAMPLE_RATE);
+                out.extend_from_slice(&chunk[..written]);
+                if written < chunk.len() {
+                    break;
+                }
+            }
+        }
+        out.split_off(SAMPLE_RATE as usize / 10)[..WINDOW].to_vec()
+    }
+
+    // Power in each DFT bin up to Nyquist
+    fn spectrum(samples: &[f32]) -> Vec<f64> {
+        let n = samples.len();
+        let (cos, sin): (Vec<f64>, Vec<f64>) = (0..n)
+            .map(|i| {
+                let angle = 2.0 * PI * i as f64 / n as f64;
+                (angle.cos(), angle.sin())
+            })
+            .unzip();
+        (0..n / 2)
+            .map(|bin| {
+                let (mut re, mut im) = (0.0, 0.0);
+                for (i, &sample) in samples.iter().enumerate() {
+                    re += sample as f64 * cos[bin * i % n];
+                    im -= sample as f64 * sin[bin * i % n];
+                }
+                re * re + im * im
+            })
+            .collect()
+    }
+
+    #[test]
+    fn square_wave_keeps_its_frequency() {
+        let samples = resampled_square();
+        let crossings = samples
+            .windows(2)
+            .filter(|pair| (pair[0] < 0.0) != (pair[1] < 0.0))
+            .count();
+        assert!((198..=202).contains(&crossings), "{crossings} crossings");
+
+        let spectrum = spectrum(&samples);
+        let peak = (1..spectrum.len())
+            .max_by(|&a, &b| spectrum[a].total_cmp(&spectrum[b]))
+            .unwrap();
+        assert_eq!(peak, 100);
+
+        // Anything folded back from above Nyquist would land between the
+        // harmonics. Without the anti-aliasing filter it's about 0.05%.
+        let total: f64 = spectrum[1..].iter().sum();
+        let between: f64 = (1..spectrum.len())
+            .filter(|bin| bin % 100 != 0)
+            .map(|bin| spectrum[bin])
+            .sum();
+        assert!(
+            between / total < 0.0002,
+            "{} of the power aliased",
+            between / total
+        );
+    }
+}
@@ -0,0 +1,111 @@
+// The triangle channel at $4008-$400B, which has a linear counter for finer
+// control over note length on top of the usual length counter, and no volume
+// https://www.nesdev.org/wiki/APU_Triangle
+
+use crate::state::{StateError, StateReader, StateWriter};
+
+use super::length_counter::LengthCounter;
+
+const SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+    13, 14, 15,
+];
+
+#[derive(Default)]
+pub(super) struct Triangle {
+    step: u8,
+    // 11 bits, the timer counts down from it every CPU cycle
+    period: u16,
+    timer: u16,
+    length: LengthCounter,
+    // Also the length counter halt flag
+    control: bool,
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+}
+
+impl Triangle {
+    // register is the address's offset into $4008-$400B
+    pub fn write_register(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.control = value & 0x80 != 0;
+                self.length.set_halted(self.control);
+                self.linear_reload_value = value & 0x7F;
+            }
+            // $4009 isn't connected
+            1 => {}
+            2 => self.period = (self.period & 0x0700) | value as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((value as u16 & 0b111) << 8);
+                self.length.load(value);
+                self.linear_reload = true;
+            }
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.length.set_enabled(enabled);
+    }
+
+    pub fn length_active(&self) -> bool {
+        self.length.is_active()
+    }
+
+    // The sequencer only moves while both counters are running, so a
+    // silenced triangle holds its last level rather than dropping to 0
+    pub fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period;
+        if self.linear_counter > 0 && self.length.is_active() {
+            self.step = (self.step + 1) % 32;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+    }
+
+    // 0-15
+    pub fn output(&self) -> u8 {
+        SEQUENCE[self.step as usize]
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.step);
+        state.u16(self.period);
+        state.u16(self.timer);
+        self.length.save_state(state);
+        state.bool(self.control);
+        state.u8(self.linear_reload_value);
+        state.u8(self.linear_counter);
+        state.bool(self.linear_reload);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.step = state.u8_below(32, "triangle step")?;
+        self.period = state.u16()?;
+        self.timer = state.u16()?;
+        self.length.load_state(state)?;
+        self.control = state.bool()?;
+        self.linear_reload_value = state.u8()?;
+        self.linear_counter = state.u8()?;
+        self.linear_reload = state.bool()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,350 @@
+// What the CPU sees of the rest of the console. Reads take &mut self since
+// some registers change when read, like the PPU status and controller ports.
+// https://www.nesdev.org/wiki/CPU_memory_map
+
+use crate::apu::Apu;
+use crate::input::{Controller, InputDevice, Zapper};
+use crate::mapper::Mapper;
+use crate::ppu::Ppu;
+use crate::state::{StateError, StateReader, StateWriter};
+
+pub trait Bus {
+    fn read(&mut self, address: u16) -> u8;
+    fn write(&mut self, address: u16, value: u8);
+
+    // A read for debuggers and traces, which shouldn't set off whatever a
+    // real read would. Buses where reads have no side effects can leave it.
+    fn peek(&mut self, address: u16) -> u8 {
+        self.read(address)
+    }
+
+    // The PPU's scanline and dot, for traces. Buses without one stay at 0, 0.
+    fn ppu_position(&self) -> (u16, u16) {
+        (0, 0)
+    }
+
+    // Page written to $4014 since the last call, if any. The CPU copies the
+    // page into OAM through $2004 and stalls while it does.
+    fn take_oam_dma(&mut self) -> Option<u8> {
+        None
+    }
+
+    // The CPU takes an NMI whenever this goes from low to high
+    fn nmi_line(&self) -> bool {
+        false
+    }
+
+    // Whether anything is holding IRQ low. The CPU takes an IRQ for as long
+    // as it is, unless interrupts are disabled.
+    fn irq_line(&self) -> bool {
+        false
+    }
+
+    // Cycles stolen from the CPU since the last call by DMA it didn't start,
+    // like the DMC's sample fetches
+    fn take_stall_cycles(&mut self) -> u16 {
+        0
+    }
+}
+
+pub struct NesBus {
+    work_memory: [u8; 2048],
+    ppu: Ppu,
+    oam_dma_page: Option<u8>,
+    apu: Apu,
+    dmc_stall_cycles: u16,
+    // $4016 and $4017
+    ports: [InputDevice; 2],
+    // What's left of $4000-$401F reads back the last value written
+    io_registers: [u8; 32],
+    mapper: Box<dyn Mapper>,
+}
+
+impl NesBus {
+    pub fn new(mapper: Box<dyn Mapper>) -> NesBus {
+        NesBus {
+            work_memory: [0; 2048],
+            ppu: Ppu::new(),
+            oam_dma_page: None,
+            apu: Apu::new(),
+            dmc_stall_cycles: 0,
+            ports: [InputDevice::default(), InputDevice::default()],
+            io_registers: [0; 32],
+            mapper,
+        }
+    }
+
+    pub fn ppu(&self) -> &Ppu {
+        &self.ppu
+    }
+
+    pub fn ppu_mut(&mut self) -> &mut Ppu {
+        &mut self.ppu
+    }
+
+    // The reset button only reaches the CPU, the PPU and the cartridge. The
+    // APU is silenced, as if $4015 had been written with 0.
+    pub fn reset(&mut self) {
+        self.ppu.reset();
+        self.apu.write_register(0x4015, 0);
+        self.mapper.reset();
+    }
+
+    // Runs the PPU for one dot
+    pub fn tick_ppu(&mut self) {
+        self.ppu.tick(self.mapper.as_mut());
+    }
+
+    pub fn apu(&self) -> &Apu {
+        &self.apu
+    }
+
+    pub fn apu_mut(&mut self) -> &mut Apu {
+        &mut self.apu
+    }
+
+    // Runs the APU for one CPU cycle. Each DMC fetch halts the CPU for 4
+    // cycles, which the CPU spends repeating the read it was about to do once
+    // it gets to one.
+    // https://www.nesdev.org/wiki/DMA#DMC_DMA
+    pub fn tick_apu(&mut self) {
+        self.apu.tick();
+        if let Some(address) = self.apu.pending_dmc_read() {
+            let value = self.read(address);
+            self.apu.load_dmc_sample(value);
+            self.dmc_stall_cycles += 4;
+        }
+    }
+
+    // Port 0 is $4016 and port 1 is $4017. Both start with a standard pad.
+    pub fn connect(&mut self, port: usize, device: InputDevice) {
+        self.ports[port] = device;
+    }
+
+    pub fn port(&self, port: usize) -> &InputDevice {
+        &self.ports[port]
+    }
+
+    pub fn port_mut(&mut self, port: usize) -> &mut InputDevice {
+        &mut self.ports[port]
+    }
+
+    // None if something else is plugged in
+    pub fn controller_mut(&mut self, port: usize) -> Option<&mut Controller> {
+        match &mut self.ports[port] {
+            InputDevice::Controller(controller) => Some(controller),
+            _ => None,
+        }
+    }
+
+    pub fn zapper_mut(&mut self, port: usize) -> Option<&mut Zapper> {
+        match &mut self.ports[port] {
+            InputDevice::Zapper(zapper) => Some(zapper),
+            _ => None,
+        }
+    }
+
+    // Runs the cartridge for one CPU cycle
+    pub fn tick_mapper(&mut self) {
+        self.mapper.cpu_clock();
+    }
+
+    pub fn mapper(&self) -> &dyn Mapper {
+        self.mapper.as_ref()
+    }
+
+    pub fn mapper_mut(&mut self) -> &mut dyn Mapper {
+        self.mapper.as_mut()
+    }
+
+    // Everything on the bus, including the PPU, APU and cartridge
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.work_memory);
+        state.option_u8(self.oam_dma_page);
+        state.u16(self.dmc_stall_cycles);
+        state.bytes(&self.io_registers);
+        self.ppu.save_state(state);
+        for device in &self.ports {
+            device.save_state(state);
+        }
+        self.apu.save_state(state);
+        self.mapper.save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.fill(&mut self.work_memory)?;
+        self.oam_dma_page = state.option_u8()?;
+        self.dmc_stall_cycles = state.u16()?;
+        state.fill(&mut self.io_registers)?;
+        self.ppu.load_state(state)?;
+        // After the PPU, since the Zapper's trigger is timed in its frames
+        for device in &mut self.ports {
+            device.load_state(state, self.ppu.frame())?;
+        }
+        self.apu.load_state(state)?;
+        self.mapper.load_state(state)
+    }
+}
+
+impl Bus for NesBus {
+    #[inline]
+    fn read(&mut self, address: u16) -> u8 {
+        match address {
+            // Work Memory & Mirrors
+            0x0000..=0x1FFF => self.work_memory[(address % 2048) as usize],
+            // PPU Ctrl Registers & Mirrors
+            0x2000..=0x3FFF => self.ppu.read_register(address, self.mapper.as_mut()),
+            // APU status
+            0x4015 => self.apu.read_status(),
+            // Controller ports. Only the low bits are driven, and the rest
+            // keep the $40 left on the bus by the address.
+            0x4016 => 0x40 | self.ports[0].read(&self.ppu),
+            0x4017 => 0x40 | self.ports[1].read(&self.ppu),
+            //APU and IO registers, and the disabled CPU test mode registers
+            0x4000..=0x401F => self.io_registers[(address - 0x4000) as usize],
+            //Cartridge Read
+            0x4020..=0xFFFF => self.mapper.cpu_read(address),
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            // Work Memory & Mirrorsw
+            0x0000..=0x1FFF => self.work_memory[(address % 2048) as usize] = value,
+            // PPU Ctrl Registers & Mirrors
+            0x2000..=0x3FFF => {
+                self.ppu
+                    .write_register(address, value, self.mapper.as_mut());
+                self.mapper.notify_ppu_register_write(address, value);
+            }
+            // OAMDMA
+            0x4014 => self.oam_dma_page = Some(value),
+            // Strobe for both ports
+            0x4016 => {
+                for device in &mut self.ports {
+                    device.write_strobe(value);
+                }
+            }
+            // Sound channels, the APU status and the frame counter
+            0x4000..=0x4013 | 0x4015 | 0x4017 => self.apu.write_register(address, value),
+            //APU and IO registers, and the disabled CPU test mode registers
+            0x4000..=0x401F => self.io_registers[(address - 0x4000) as usize] = value,
+            //Cartridge Write
+            0x4020..=0xFFFF => self.mapper.cpu_write(address, value),
+        }
+    }
+
+    // Registers that react to reads show open bus instead, and so does the
+    // cartridge below $6000, where some boards keep theirs
+    fn peek(&mut self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x1FFF => self.work_memory[(address % 2048) as usize],
+            0x6000..=0xFFFF => self.mapper.cpu_read(address),
+            _ => (address >> 8) as u8,
+        }
+    }
+
+    fn ppu_position(&self) -> (u16, u16) {
+        (self.ppu.scanline(), self.ppu.dot())
+    }
+
+    fn take_oam_dma(&mut self) -> Option<u8> {
+        self.oam_dma_page.take()
+    }
+
+    fn nmi_line(&self) -> bool {
+        self.ppu.nmi_line()
+    }
+
+    fn irq_line(&self) -> bool {
+        self.apu.irq_pending() || self.mapper.irq_pending()
+    }
+
+    fn take_stall_cycles(&mut self) -> u16 {
+        std::mem::take(&mut self.dmc_stall_cycles)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cartridge::CartridgeBuilder;
+    use crate::input::{Button, Buttons};
+    use crate::mapper::Nrom;
+    use crate::mos6502::Mos6502;
+
+    use super::*;
+
+    // NROM with the program at $8000 and the reset vector pointing to it
+    fn nes_bus_with_program(program: &[u8]) -> NesBus {
+        let mut prg_rom = vec![0xEA; 0x8000];
+        prg_rom[..program.len()].copy_from_slice(program);
+        prg_rom[0x7FFC..0x7FFE].copy_from_slice(&[0x00, 0x80]);
+        let cartridge = CartridgeBuilder::new().prg_rom(prg_rom).build().unwrap();
+        NesBus::new(Box::new(Nrom::new(cartridge)))
+    }
+
+    fn nes_bus() -> NesBus {
+        nes_bus_with_program(&[])
+    }
+
+    #[test]
+    fn work_memory_is_mirrored() {
+        let mut bus = nes_bus();
+        bus.write(0x0123, 0x42);
+        for mirror in [0x0800, 0x1000, 0x1800] {
+            assert_eq!(bus.read(mirror + 0x0123), 0x42);
+        }
+        bus.write(0x1FFF, 0x99);
+        assert_eq!(bus.read(0x07FF), 0x99);
+        assert_eq!(bus.peek(0x0FFF), 0x99);
+    }
+
+    // Runs the two instructions that start the DMA and returns the cycles
+    // the CPU then spends stalled
+    fn oam_dma_stall(program: &[u8]) -> u64 {
+        let mut bus = nes_bus_with_program(program);
+        for offset in 0..=0xFF {
+            bus.write(0x0200 + offset, offset as u8 ^ 0xA5);
+        }
+        bus.write(0x0000, 0x02);
+        let mut cpu = Mos6502::new();
+        cpu.reset();
+        cpu.step(&mut bus);
+        cpu.step(&mut bus);
+        let stored = cpu.total_cycles();
+        // The NOP after the store takes 2 cycles
+        cpu.step(&mut bus);
+        let expected: Vec<u8> = (0..=0xFF).map(|offset: u8| offset ^ 0xA5).collect();
+        assert_eq!(bus.ppu().oam()[..], expected[..]);
+        cpu.total_cycles() - stored - 2
+    }
+
+    #[test]
+    fn oam_dma_copies_a_page() {
+        // LDA #$02, STA $4014 ends on an odd cycle, counting the 7 of reset
+        assert_eq!(oam_dma_stall(&[0xA9, 0x02, 0x8D, 0x14, 0x40]), 514);
+        // LDA $00 takes a cycle longer, so no alignment cycle is needed
+        assert_eq!(oam_dma_stall(&[0xA5, 0x00, 0x8D, 0x14, 0x40]), 513);
+    }
+
+    #[test]
+    fn both_controller_ports() {
+        let mut bus = nes_bus();
+        bus.controller_mut(0)
+            .unwrap()
+            .set_buttons(Buttons::new(&[Button::Up, Button::A]).0);
+        bus.controller_mut(1).unwrap().set_pressed(Button::B, true);
+        bus.write(0x4016, 1);
+        bus.write(0x4016, 0);
+        let port_0: Vec<u8> = (0..9).map(|_| bus.read(0x4016)).collect();
+        let port_1: Vec<u8> = (0..9).map(|_| bus.read(0x4017)).collect();
+        assert_eq!(
+            port_0,
+            [0x41, 0x40, 0x40, 0x40, 0x41, 0x40, 0x40, 0x40, 0x41]
+        );
+        assert_eq!(
+            port_1,
+            [0x40, 0x41, 0x40, 0x40, 0x40, 0x40, 0x40, 0x40, 0x41]
+        );
+    }
+}
@@ -0,0 +1,153 @@
+// Loading from .zip archives, which is how most ROM sets are distributed
+
+use std::io::{Cursor, Read, Seek};
+use std::path::Path;
+
+use zip::ZipArchive;
+
+use super::{
+    CartridgeData, ParseOptions, RomReadError, HEADER_SIZE, INST_ROM_SIZE, PROM_SIZE, TRAINER_SIZE,
+};
+
+// Miscellaneous ROMs have no size in the header, so allow this much for them
+const MISC_ROM_ALLOWANCE: usize = 1024 * 1024;
+
+impl CartridgeData {
+    // Loads the only .nes file in the archive
+    pub fn from_zip_bytes(bytes: &[u8]) -> Result<CartridgeData, RomReadError> {
+        from_zip(Cursor::new(bytes), None)
+    }
+
+    // Loads the entry called `name`, for archives holding more than one ROM
+    pub fn from_zip_bytes_entry(bytes: &[u8], name: &str) -> Result<CartridgeData, RomReadError> {
+        from_zip(Cursor::new(bytes), Some(name))
+    }
+
+    pub fn from_zip_file(
+        path: impl AsRef<Path>,
+        name: Option<&str>,
+    ) -> Result<CartridgeData, RomReadError> {
+        let file = std::fs::File::open(path).map_err(RomReadError::Io)?;
+        from_zip(file, name)
+    }
+}
+
+fn from_zip(reader: impl Read + Seek, name: Option<&str>) -> Result<CartridgeData, RomReadError> {
+    let mut archive = ZipArchive::new(reader).map_err(RomReadError::Zip)?;
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => {
+            let mut names: Vec<String> = archive
+                .file_names()
+                .filter(|name| name.to_ascii_lowercase().ends_with(".nes"))
+                .map(String::from)
+                .collect();
+            match names.len() {
+                0 => return Err(RomReadError::NoRomInArchive),
+                1 => names.remove(0),
+                _ => {
+                    names.sort();
+                    return Err(RomReadError::MultipleRomsInArchive { names });
+                }
+            }
+        }
+    };
+
+    // Only the chosen entry gets decompressed, and never past the largest
+    // file the parser would take. The size in the archive is checked first
+    // but can't be trusted, so the read is limited too.
+    let limit = max_entry_size();
+    let entry = archive.by_name(&name).map_err(RomReadError::Zip)?;
+    if entry.size() > limit as u64 {
+        return Err(RomReadError::ArchiveEntryTooLarge { limit });
+    }
+    let mut filebytes = Vec::new();
+    entry
+        .take(limit as u64 + 1)
+        .read_to_end(&mut filebytes)
+        .map_err(RomReadError::Io)?;
+    if filebytes.len() > limit {
+        return Err(RomReadError::ArchiveEntryTooLarge { limit });
+    }
+    CartridgeData::new(filebytes)
+}
+
+fn max_entry_size() -> usize {
+    let options = ParseOptions::default();
+    [
+        HEADER_SIZE,
+        TRAINER_SIZE,
+        options.max_prg_rom_size,
+        options.max_chr_rom_size,
+        INST_ROM_SIZE,
+        PROM_SIZE,
+        MISC_ROM_ALLOWANCE,
+    ]
+    .iter()
+    .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use zip::write::{FileOptions, ZipWriter};
+
+    use super::super::tests::{header, rom_file};
+    use super::*;
+
+    fn zip(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, bytes) in entries {
+            writer.start_file(*name, FileOptions::default()).unwrap();
+            writer.write_all(bytes).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn archive_without_a_rom() {
+        assert!(matches!(
+            CartridgeData::from_zip_bytes(&zip(&[])),
+            Err(RomReadError::NoRomInArchive)
+        ));
+        assert!(matches!(
+            CartridgeData::from_zip_bytes(&zip(&[("readme.txt", b"hi")])),
+            Err(RomReadError::NoRomInArchive)
+        ));
+    }
+
+    #[test]
+    fn archive_with_one_rom() {
+        let rom = rom_file(header(1, 1));
+        let archive = zip(&[("readme.txt", b"hi"), ("Game (U).NES", &rom)]);
+        let cartridge = CartridgeData::from_zip_bytes(&archive).unwrap();
+        assert_eq!(cartridge.prg_rom(), &rom[HEADER_SIZE..][..0x4000]);
+        // Picked out by magic number too
+        assert!(CartridgeData::from_bytes_auto(&archive).is_ok());
+    }
+
+    #[test]
+    fn archive_with_two_roms() {
+        let (one, two) = (rom_file(header(1, 1)), rom_file(header(2, 1)));
+        let archive = zip(&[("b.nes", &two), ("a.nes", &one)]);
+        let Err(RomReadError::MultipleRomsInArchive { names }) =
+            CartridgeData::from_zip_bytes(&archive)
+        else {
+            panic!("expected both ROMs to be listed");
+        };
+        assert_eq!(names, ["a.nes", "b.nes"]);
+        let cartridge = CartridgeData::from_zip_bytes_entry(&archive, "b.nes").unwrap();
+        assert_eq!(cartridge.prg_rom_banks(), 2);
+    }
+
+    #[test]
+    fn oversized_entry_is_refused() {
+        let limit = max_entry_size();
+        let archive = zip(&[("huge.nes", &vec![0; limit + 1])]);
+        assert!(matches!(
+            CartridgeData::from_zip_bytes(&archive),
+            Err(RomReadError::ArchiveEntryTooLarge { .. })
+        ));
+    }
+}
@@ -0,0 +1,269 @@
+// Puts a header on raw PRG and CHR banks, for tests and homebrew builds
+
+use super::{
+    CartridgeData, Mirroring, Region, RomReadError, CHR_BANK_SIZE, HEADER_SIZE, PRG_BANK_SIZE,
+    TRAINER_SIZE,
+};
+
+#[derive(Debug)]
+pub enum BuilderError {
+    // PRG ROM has to be a non-zero number of 16 KB banks
+    PrgRomSize { len: usize },
+    // CHR ROM has to be a whole number of 8 KB banks, or empty for CHR RAM
+    ChrRomSize { len: usize },
+    TrainerSize { len: usize },
+    Rom(RomReadError),
+}
+
+/// Builds cartridges and ROM files from their parts.
+///
+/// ```
+/// use zephyrnes::cartridge::{CartridgeBuilder, Mirroring};
+///
+/// let cartridge = CartridgeBuilder::new()
+///     .prg_rom(vec![0xEA; 0x8000])
+///     .chr_rom(vec![0; 0x2000])
+///     .mirroring(Mirroring::Vertical)
+///     .build()
+///     .unwrap();
+/// assert_eq!(cartridge.prg_rom_banks(), 2);
+/// ```
+#[derive(Clone)]
+pub struct CartridgeBuilder {
+    prg_rom: Vec<u8>,
+    chr_rom: Vec<u8>,
+    mapper: u16,
+    submapper: u8,
+    mirroring: Mirroring,
+    battery: bool,
+    trainer: Option<Vec<u8>>,
+    region: Region,
+}
+
+impl Default for CartridgeBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CartridgeBuilder {
+    pub fn new() -> CartridgeBuilder {
+        CartridgeBuilder {
+            prg_rom: Vec::new(),
+            chr_rom: Vec::new(),
+            mapper: 0,
+            submapper: 0,
+            mirroring: Mirroring::Horizontal,
+            battery: false,
+            trainer: None,
+            region: Region::Ntsc,
+        }
+    }
+
+    pub fn prg_rom(mut self, prg_rom: impl Into<Vec<u8>>) -> Self {
+        self.prg_rom = prg_rom.into();
+        self
+    }
+
+    pub fn chr_rom(mut self, chr_rom: impl Into<Vec<u8>>) -> Self {
+        self.chr_rom = chr_rom.into();
+        self
+    }
+
+    pub fn mapper(mut self, mapper: u16) -> Self {
+        self.mapper = mapper;
+        self
+    }
+
+    pub fn submapper(mut self, submapper: u8) -> Self {
+        self.submapper = submapper;
+        self
+    }
+
+    // Only horizontal, vertical and four-screen can be written to a header
+    pub fn mirroring(mut self, mirroring: Mirroring) -> Self {
+        self.mirroring = mirroring;
+        self
+    }
+
+    pub fn battery(mut self, battery: bool) -> Self {
+        self.battery = battery;
+        self
+    }
+
+    pub fn trainer(mut self, trainer: impl Into<Vec<u8>>) -> Self {
+        self.trainer = Some(trainer.into());
+        self
+    }
+
+    pub fn region(mut self, region: Region) -> Self {
+        self.region = region;
+        self
+    }
+
+    pub fn build(&self) -> Result<CartridgeData, BuilderError> {
+        CartridgeData::new(self.build_nes2_bytes()?).map_err(BuilderError::Rom)
+    }
+
+    // An iNES 1.0 file, unless the mapper is above 255, there's a submapper,
+    // there are more than 255 PRG or CHR banks, or the region is one iNES
+    // can't describe, in which case it has to be NES 2.0
+    pub fn build_ines_bytes(&self) -> Result<Vec<u8>, BuilderError> {
+        if self.mapper > 0xFF
+            || self.submapper != 0
+            || self.prg_rom.len() / PRG_BANK_SIZE > 0xFF
+            || self.chr_rom.len() / CHR_BANK_SIZE > 0xFF
+            || !matches!(self.region, Region::Ntsc | Region::Pal)
+        {
+            return self.build_nes2_bytes();
+        }
+        self.validate()?;
+        let mut header = self.common_header();
+        header[7] = self.mapper as u8 & 0xF0;
+        header[9] = (self.region == Region::Pal) as u8;
+        Ok(self.with_sections(header))
+    }
+
+    pub fn build_nes2_bytes(&self) -> Result<Vec<u8>, BuilderError> {
+        self.validate()?;
+        let prg_rom_banks = self.prg_rom.len() / PRG_BANK_SIZE;
+        let chr_rom_banks = self.chr_rom.len() / CHR_BANK_SIZE;
+        let mut header = self.common_header();
+        header[7] = (self.mapper as u8 & 0xF0) | 0b00001000;
+        header[8] = (self.submapper << 4) | ((self.mapper >> 8) as u8 & 0x0F);
+        header[9] =
+            (((chr_rom_banks >> 8) as u8 & 0x0F) << 4) | ((prg_rom_banks >> 8) as u8 & 0x0F);
+        // 8 KB of PRG RAM (64 << 7), battery-backed or not, and 8 KB of CHR
+        // RAM if there's no CHR ROM, the same as an iNES 1.0 file implies
+        header[10] = if self.battery { 0x70 } else { 0x07 };
+        header[11] = if self.chr_rom.is_empty() { 0x07 } else { 0 };
+        header[12] = match self.region {
+            Region::Ntsc => 0,
+            Region::Pal => 1,
+            Region::MultiRegion => 2,
+            Region::Dendy => 3,
+        };
+        Ok(self.with_sections(header))
+    }
+
+    fn validate(&self) -> Result<(), BuilderError> {
+        let len = self.prg_rom.len();
+        if len == 0 || !len.is_multiple_of(PRG_BANK_SIZE) || len / PRG_BANK_SIZE > 0xEFF {
+            return Err(BuilderError::PrgRomSize { len });
+        }
+        let len = self.chr_rom.len();
+        if !len.is_multiple_of(CHR_BANK_SIZE) || len / CHR_BANK_SIZE > 0xEFF {
+            return Err(BuilderError::ChrRomSize { len });
+        }
+        if let Some(trainer) = &self.trainer {
+            if trainer.len() != TRAINER_SIZE {
+                return Err(BuilderError::TrainerSize { len: trainer.len() });
+            }
+        }
+        Ok(())
+    }
+
+    // Bytes 0-6, which both formats share. Bank counts above 255 only fit
+    // with NES 2.0's byte 9.
+    fn common_header(&self) -> [u8; HEADER_SIZE] {
+        let mut header = [0; HEADER_SIZE];
+        header[0..4].copy_from_slice(b"NES\x1A");
+        header[4] = (self.prg_rom.len() / PRG_BANK_SIZE) as u8;
+        header[5] = (self.chr_rom.len() / CHR_BANK_SIZE) as u8;
+        let mirroring = match self.mirroring {
+            Mirroring::Vertical => 0b00000001,
+            Mirroring::FourScreen => 0b00001000,
+            _ => 0,
+        };
+        header[6] = mirroring
+            | ((self.battery as u8) << 1)
+            | ((self.trainer.is_some() as u8) << 2)
+            | ((self.mapper as u8 & 0x0F) << 4);
+        header
+    }
+
+    fn with_sections(&self, header: [u8; HEADER_SIZE]) -> Vec<u8> {
+        let mut filebytes = header.to_vec();
+        if let Some(trainer) = &self.trainer {
+            filebytes.extend_from_slice(trainer);
+        }
+        filebytes.extend_from_slice(&self.prg_rom);
+        filebytes.extend_from_slice(&self.chr_rom);
+        filebytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{HeaderVersion, RomFormat};
+    use super::*;
+
+    fn banks() -> CartridgeBuilder {
+        CartridgeBuilder::new()
+            .prg_rom((0..2 * PRG_BANK_SIZE).map(|i| i as u8).collect::<Vec<_>>())
+            .chr_rom(vec![0x5A; CHR_BANK_SIZE])
+    }
+
+    #[test]
+    fn sizes_are_validated() {
+        let error = CartridgeBuilder::new().build_ines_bytes().unwrap_err();
+        assert!(matches!(error, BuilderError::PrgRomSize { len: 0 }));
+        let error = banks()
+            .prg_rom(vec![0; 100])
+            .build_ines_bytes()
+            .unwrap_err();
+        assert!(matches!(error, BuilderError::PrgRomSize { len: 100 }));
+        let error = banks()
+            .chr_rom(vec![0; 0x1000])
+            .build_ines_bytes()
+            .unwrap_err();
+        assert!(matches!(error, BuilderError::ChrRomSize { len: 0x1000 }));
+        let error = banks().trainer(vec![0; 16]).build_ines_bytes().unwrap_err();
+        assert!(matches!(error, BuilderError::TrainerSize { len: 16 }));
+        // No CHR ROM is fine, that's CHR RAM
+        assert!(banks().chr_rom(Vec::new()).build().is_ok());
+    }
+
+    #[test]
+    fn ines_round_trip() {
+        let builder = banks()
+            .mapper(4)
+            .mirroring(Mirroring::Vertical)
+            .battery(true)
+            .trainer(vec![0xEE; TRAINER_SIZE])
+            .region(Region::Pal);
+        let filebytes = builder.build_ines_bytes().unwrap();
+        let cartridge = CartridgeData::new(filebytes).unwrap();
+        assert_eq!(cartridge.header_version(), HeaderVersion::INes1);
+        assert_eq!(cartridge.prg_rom(), builder.prg_rom);
+        assert_eq!(cartridge.chr_rom(), builder.chr_rom);
+        assert_eq!(cartridge.mapper_number(), 4);
+        assert_eq!(cartridge.mirroring(), Mirroring::Vertical);
+        assert!(cartridge.is_battery_backed());
+        assert_eq!(cartridge.trainer(), Some(&[0xEE; TRAINER_SIZE]));
+        assert_eq!(cartridge.region(), Region::Pal);
+    }
+
+    #[test]
+    fn some_fields_force_nes2() {
+        let builder = banks().mapper(0x123).submapper(2).region(Region::Dendy);
+        let cartridge = CartridgeData::new(builder.build_ines_bytes().unwrap()).unwrap();
+        assert_eq!(cartridge.format(), RomFormat::Nes2);
+        assert_eq!(cartridge.mapper_number(), 0x123);
+        assert_eq!(cartridge.submapper(), 2);
+        assert_eq!(cartridge.region(), Region::Dendy);
+
+        let builder = banks().prg_rom(vec![0; 0x100 * PRG_BANK_SIZE]);
+        let cartridge = CartridgeData::new(builder.build_ines_bytes().unwrap()).unwrap();
+        assert_eq!(cartridge.format(), RomFormat::Nes2);
+        assert_eq!(cartridge.prg_rom_banks(), 0x100);
+    }
+
+    #[test]
+    fn build_matches_the_nes2_file() {
+        let cartridge = banks().mapper(1).build().unwrap();
+        let filebytes = banks().mapper(1).build_nes2_bytes().unwrap();
+        assert_eq!(cartridge.raw_header()[..], filebytes[..HEADER_SIZE]);
+        assert_eq!(cartridge.prg_ram_size(), 8192);
+    }
+}
@@ -0,0 +1,273 @@
+// Console type from flags 7 bits 0-1, with the details in NES 2.0 byte 13
+// https://www.nesdev.org/wiki/NES_2.0#Vs._System_Type
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ConsoleType {
+    Nes,
+    VsSystem {
+        ppu: VsPpuType,
+        hardware: VsHardwareType,
+    },
+    PlayChoice10,
+    // Extended console type from the low nibble of byte 13, e.g. 3 for a
+    // Famiclone with a decimal mode CPU or 5-9 for the V.R. Technology VTxx
+    Extended(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum VsPpuType {
+    // RP2C03B, or any RP2C03/RC2C03 variant. These and the other 2C03s
+    // share the regular 2C02 palette.
+    Rp2c03,
+    Rp2c03g,
+    // RP2C04-0001 to RP2C04-0004, each with a scrambled palette
+    Rp2c04(u8),
+    Rc2c03b,
+    Rc2c03c,
+    // RC2C05-01 to RC2C05-05, which swap $2000 and $2001
+    Rc2c05(u8),
+    Reserved(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum VsHardwareType {
+    Unisystem,
+    UnisystemRbiBaseball,
+    UnisystemTkoBoxing,
+    UnisystemSuperXevious,
+    UnisystemIceClimber,
+    DualSystem,
+    DualSystemRaidOnBungelingBay,
+    Reserved(u8),
+}
+
+impl ConsoleType {
+    pub(super) fn from_header(header: &[u8], nes2: bool) -> ConsoleType {
+        // iNES 1.0 only has the Vs. and PlayChoice flags, with no further detail
+        if !nes2 {
+            return if header[7] & 0b00000001 != 0 {
+                ConsoleType::VsSystem {
+                    ppu: VsPpuType::Rp2c03,
+                    hardware: VsHardwareType::Unisystem,
+                }
+            } else if header[7] & 0b00000010 != 0 {
+                ConsoleType::PlayChoice10
+            } else {
+                ConsoleType::Nes
+            };
+        }
+        match header[7] & 0b00000011 {
+            0 => ConsoleType::Nes,
+            1 => ConsoleType::VsSystem {
+                ppu: VsPpuType::from_nibble(header[13] & 0x0F),
+                hardware: VsHardwareType::from_nibble(header[13] >> 4),
+            },
+            2 => ConsoleType::PlayChoice10,
+            _ => ConsoleType::Extended(header[13] & 0x0F),
+        }
+    }
+
+    // Extended console types $D-$F are reserved
+    pub fn is_known(self) -> bool {
+        !matches!(self, ConsoleType::Extended(0x0D..=0x0F))
+    }
+
+    // Flags 7 bits 0-1 and byte 13, as NES 2.0 lays them out
+    pub(super) fn to_header_bits(self) -> (u8, u8) {
+        match self {
+            ConsoleType::Nes => (0, 0),
+            ConsoleType::VsSystem { ppu, hardware } => {
+                (1, (hardware.to_nibble() << 4) | ppu.to_nibble())
+            }
+            ConsoleType::PlayChoice10 => (2, 0),
+            ConsoleType::Extended(console) => (3, console & 0x0F),
+        }
+    }
+}
+
+impl VsPpuType {
+    fn from_nibble(nibble: u8) -> VsPpuType {
+        match nibble {
+            0x0 => VsPpuType::Rp2c03,
+            0x1 => VsPpuType::Rp2c03g,
+            0x2..=0x5 => VsPpuType::Rp2c04(nibble - 1),
+            0x6 => VsPpuType::Rc2c03b,
+            0x7 => VsPpuType::Rc2c03c,
+            0x8..=0xC => VsPpuType::Rc2c05(nibble - 7),
+            _ => VsPpuType::Reserved(nibble),
+        }
+    }
+
+    fn to_nibble(self) -> u8 {
+        match self {
+            VsPpuType::Rp2c03 => 0x0,
+            VsPpuType::Rp2c03g => 0x1,
+            VsPpuType::Rp2c04(variant) => variant + 1,
+            VsPpuType::Rc2c03b => 0x6,
+            VsPpuType::Rc2c03c => 0x7,
+            VsPpuType::Rc2c05(variant) => variant + 7,
+            VsPpuType::Reserved(nibble) => nibble,
+        }
+    }
+}
+
+impl VsHardwareType {
+    fn from_nibble(nibble: u8) -> VsHardwareType {
+        match nibble {
+            0 => VsHardwareType::Unisystem,
+            1 => VsHardwareType::UnisystemRbiBaseball,
+            2 => VsHardwareType::UnisystemTkoBoxing,
+            3 => VsHardwareType::UnisystemSuperXevious,
+            4 => VsHardwareType::UnisystemIceClimber,
+            5 => VsHardwareType::DualSystem,
+            6 => VsHardwareType::DualSystemRaidOnBungelingBay,
+            _ => VsHardwareType::Reserved(nibble),
+        }
+    }
+
+    fn to_nibble(self) -> u8 {
+        match self {
+            VsHardwareType::Unisystem => 0,
+            VsHardwareType::UnisystemRbiBaseball => 1,
+            VsHardwareType::UnisystemTkoBoxing => 2,
+            VsHardwareType::UnisystemSuperXevious => 3,
+            VsHardwareType::UnisystemIceClimber => 4,
+            VsHardwareType::DualSystem => 5,
+            VsHardwareType::DualSystemRaidOnBungelingBay => 6,
+            VsHardwareType::Reserved(nibble) => nibble,
+        }
+    }
+}
+
+// Input device the game expects, from NES 2.0 byte 15
+// https://www.nesdev.org/wiki/NES_2.0#Default_Expansion_Device
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DefaultExpansionDevice {
+    Unspecified,
+    StandardController,
+    FourScore,
+    FamicomFourPlayersAdapter,
+    Zapper,
+    TwoZappers,
+    PowerPadSideA,
+    PowerPadSideB,
+    ArkanoidPaddle,
+    FamilyKeyboard,
+    Other(u8),
+}
+
+impl DefaultExpansionDevice {
+    pub(super) fn from_byte(byte: u8) -> DefaultExpansionDevice {
+        match byte & 0b00111111 {
+            0x00 => DefaultExpansionDevice::Unspecified,
+            0x01 => DefaultExpansionDevice::StandardController,
+            0x02 => DefaultExpansionDevice::FourScore,
+            0x03 => DefaultExpansionDevice::FamicomFourPlayersAdapter,
+            0x08 => DefaultExpansionDevice::Zapper,
+            0x09 => DefaultExpansionDevice::TwoZappers,
+            0x0B => DefaultExpansionDevice::PowerPadSideA,
+            0x0C => DefaultExpansionDevice::PowerPadSideB,
+            0x0F => DefaultExpansionDevice::ArkanoidPaddle,
+            0x23 => DefaultExpansionDevice::FamilyKeyboard,
+            device => DefaultExpansionDevice::Other(device),
+        }
+    }
+
+    pub(super) fn to_byte(self) -> u8 {
+        match self {
+            DefaultExpansionDevice::Unspecified => 0x00,
+            DefaultExpansionDevice::StandardController => 0x01,
+            DefaultExpansionDevice::FourScore => 0x02,
+            DefaultExpansionDevice::FamicomFourPlayersAdapter => 0x03,
+            DefaultExpansionDevice::Zapper => 0x08,
+            DefaultExpansionDevice::TwoZappers => 0x09,
+            DefaultExpansionDevice::PowerPadSideA => 0x0B,
+            DefaultExpansionDevice::PowerPadSideB => 0x0C,
+            DefaultExpansionDevice::ArkanoidPaddle => 0x0F,
+            DefaultExpansionDevice::FamilyKeyboard => 0x23,
+            DefaultExpansionDevice::Other(device) => device & 0b00111111,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{header, nes2_header, rom_file};
+    use super::super::{CartridgeData, ParseOptions, ParseWarning, RomReadError};
+    use super::*;
+
+    fn console_type(flags_7: u8, byte_13: u8) -> ConsoleType {
+        let mut header = nes2_header(1, 1);
+        header[7] |= flags_7;
+        header[13] = byte_13;
+        ConsoleType::from_header(&header, true)
+    }
+
+    #[test]
+    fn each_console_type() {
+        assert_eq!(console_type(0, 0), ConsoleType::Nes);
+        assert_eq!(
+            console_type(1, 0x52),
+            ConsoleType::VsSystem {
+                ppu: VsPpuType::Rp2c04(1),
+                hardware: VsHardwareType::DualSystem,
+            }
+        );
+        assert_eq!(console_type(2, 0), ConsoleType::PlayChoice10);
+        assert_eq!(console_type(3, 0x03), ConsoleType::Extended(3));
+    }
+
+    #[test]
+    fn vs_ppu_types() {
+        let ppu = |nibble| VsPpuType::from_nibble(nibble);
+        assert_eq!(ppu(0x0), VsPpuType::Rp2c03);
+        assert_eq!(ppu(0x1), VsPpuType::Rp2c03g);
+        assert_eq!(ppu(0x5), VsPpuType::Rp2c04(4));
+        assert_eq!(ppu(0x6), VsPpuType::Rc2c03b);
+        assert_eq!(ppu(0x7), VsPpuType::Rc2c03c);
+        assert_eq!(ppu(0x8), VsPpuType::Rc2c05(1));
+        assert_eq!(ppu(0xC), VsPpuType::Rc2c05(5));
+        assert_eq!(ppu(0xD), VsPpuType::Reserved(0xD));
+        for nibble in 0..=0xF {
+            assert_eq!(ppu(nibble).to_nibble(), nibble);
+            assert_eq!(VsHardwareType::from_nibble(nibble).to_nibble(), nibble);
+        }
+    }
+
+    #[test]
+    fn reserved_extended_types_are_flagged() {
+        assert!(ConsoleType::Extended(0x0C).is_known());
+        assert!(!ConsoleType::Extended(0x0D).is_known());
+        assert!(!ConsoleType::Extended(0x0F).is_known());
+
+        let mut header = nes2_header(1, 1);
+        header[7] |= 3;
+        header[13] = 0x0E;
+        let cartridge = CartridgeData::new(rom_file(header)).unwrap();
+        assert_eq!(
+            cartridge.warnings(),
+            [ParseWarning::UnknownConsoleType { console_type: 0x0E }]
+        );
+        assert!(matches!(
+            CartridgeData::new_with_options(&rom_file(header), ParseOptions::strict()),
+            Err(RomReadError::UnknownConsoleType { console_type: 0x0E })
+        ));
+    }
+
+    #[test]
+    fn ines_vs_system_flag() {
+        let mut header = header(1, 1);
+        header[7] = 0b00000001;
+        assert_eq!(
+            CartridgeData::new(rom_file(header)).unwrap().console_type(),
+            ConsoleType::VsSystem {
+                ppu: VsPpuType::Rp2c03,
+                hardware: VsHardwareType::Unisystem,
+            }
+        );
+    }
+}
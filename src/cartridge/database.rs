@@ -0,0 +1,246 @@
+// Header corrections keyed by ROM hash, for iNES 1.0 dumps whose headers are
+// wrong or incomplete. The database is a JSON array of entries in the spirit of
+// nes20db, where every field other than the hashes is optional:
+//
+//     [{ "crc32": "3FE272FB", "sha1": "...", "mapper": 1, "mirroring": "vertical" }]
+
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use super::{CartridgeData, Mirroring, Region};
+
+#[derive(Debug)]
+pub enum DatabaseError {
+    Json(serde_json::Error),
+    InvalidCrc32(String),
+    InvalidMirroring(String),
+    InvalidRegion(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DatabaseMatch {
+    // Found, and the header already agreed with it
+    Matched,
+    // Found, and at least one field was fixed up
+    Corrected,
+    NotFound,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DatabaseEntry {
+    pub crc32: Option<u32>,
+    pub sha1: Option<String>,
+    pub mapper_number: Option<u16>,
+    pub submapper: Option<u8>,
+    pub mirroring: Option<Mirroring>,
+    pub region: Option<Region>,
+    pub prg_ram_size: Option<usize>,
+    pub prg_nvram_size: Option<usize>,
+    pub chr_ram_size: Option<usize>,
+    pub chr_nvram_size: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct RawEntry {
+    crc32: Option<String>,
+    sha1: Option<String>,
+    mapper: Option<u16>,
+    submapper: Option<u8>,
+    mirroring: Option<String>,
+    region: Option<String>,
+    prg_ram_size: Option<usize>,
+    prg_nvram_size: Option<usize>,
+    chr_ram_size: Option<usize>,
+    chr_nvram_size: Option<usize>,
+}
+
+#[derive(Default)]
+pub struct RomDatabase {
+    entries: Vec<DatabaseEntry>,
+    by_crc32: HashMap<u32, usize>,
+    by_sha1: HashMap<String, usize>,
+}
+
+impl RomDatabase {
+    pub fn from_json(json: &str) -> Result<RomDatabase, DatabaseError> {
+        let raw_entries: Vec<RawEntry> = serde_json::from_str(json).map_err(DatabaseError::Json)?;
+        let mut database = RomDatabase::default();
+        for raw in raw_entries {
+            database.insert(DatabaseEntry::from_raw(raw)?);
+        }
+        Ok(database)
+    }
+
+    pub fn insert(&mut self, entry: DatabaseEntry) {
+        let index = self.entries.len();
+        if let Some(crc32) = entry.crc32 {
+            self.by_crc32.insert(crc32, index);
+        }
+        if let Some(sha1) = &entry.sha1 {
+            self.by_sha1.insert(sha1.to_ascii_lowercase(), index);
+        }
+        self.entries.push(entry);
+    }
+
+    // SHA-1 is preferred, CRC32 collisions aren't unheard of in full sets
+    pub fn lookup(&self, cartridge: &CartridgeData) -> Option<&DatabaseEntry> {
+        self.by_sha1
+            .get(&cartridge.sha1_hex())
+            .or_else(|| self.by_crc32.get(&cartridge.rom_crc32()))
+            .map(|&index| &self.entries[index])
+    }
+}
+
+impl DatabaseEntry {
+    fn from_raw(raw: RawEntry) -> Result<DatabaseEntry, DatabaseError> {
+        let crc32 = match raw.crc32 {
+            Some(crc32) => Some(
+                u32::from_str_radix(crc32.trim_start_matches("0x"), 16)
+                    .map_err(|_| DatabaseError::InvalidCrc32(crc32))?,
+            ),
+            None => None,
+        };
+        let mirroring = match raw.mirroring.as_deref() {
+            None => None,
+            Some("horizontal") => Some(Mirroring::Horizontal),
+            Some("vertical") => Some(Mirroring::Vertical),
+            Some("four-screen") => Some(Mirroring::FourScreen),
+            Some(other) => return Err(DatabaseError::InvalidMirroring(other.to_string())),
+        };
+        let region = match raw.region.as_deref() {
+            None => None,
+            Some("ntsc") => Some(Region::Ntsc),
+            Some("pal") => Some(Region::Pal),
+            Some("multi") => Some(Region::MultiRegion),
+            Some("dendy") => Some(Region::Dendy),
+            Some(other) => return Err(DatabaseError::InvalidRegion(other.to_string())),
+        };
+        Ok(DatabaseEntry {
+            crc32,
+            sha1: raw.sha1,
+            mapper_number: raw.mapper,
+            submapper: raw.submapper,
+            mirroring,
+            region,
+            prg_ram_size: raw.prg_ram_size,
+            prg_nvram_size: raw.prg_nvram_size,
+            chr_ram_size: raw.chr_ram_size,
+            chr_nvram_size: raw.chr_nvram_size,
+        })
+    }
+}
+
+impl CartridgeData {
+    pub fn apply_database(&mut self, database: &RomDatabase) -> DatabaseMatch {
+        let Some(entry) = database.lookup(self) else {
+            return DatabaseMatch::NotFound;
+        };
+
+        let mut corrected = false;
+        correct(&mut self.mapper_number, entry.mapper_number, &mut corrected);
+        correct(&mut self.submapper, entry.submapper, &mut corrected);
+        correct(&mut self.mirroring, entry.mirroring, &mut corrected);
+        correct(&mut self.region, entry.region, &mut corrected);
+
+        // RAM buffers are reallocated to the corrected sizes
+        if correct(&mut self.prg_ram_size, entry.prg_ram_size, &mut corrected) {
+            self.prg_ram = vec![0; self.prg_ram_size];
+        }
+        if correct(
+            &mut self.prg_nvram_size,
+            entry.prg_nvram_size,
+            &mut corrected,
+        ) {
+            self.prg_nvram = vec![0; self.prg_nvram_size];
+            self.battery_backed = self.prg_nvram_size > 0;
+        }
+        let chr_ram_changed = correct(&mut self.chr_ram_size, entry.chr_ram_size, &mut corrected);
+        if correct(
+            &mut self.chr_nvram_size,
+            entry.chr_nvram_size,
+            &mut corrected,
+        ) || chr_ram_changed
+        {
+            self.chr_ram = vec![0; self.chr_ram_size + self.chr_nvram_size];
+        }
+
+        if corrected {
+            DatabaseMatch::Corrected
+        } else {
+            DatabaseMatch::Matched
+        }
+    }
+}
+
+// Overwrites the field if the database knows better, returning whether it did
+fn correct<T: PartialEq>(field: &mut T, value: Option<T>, corrected: &mut bool) -> bool {
+    match value {
+        Some(value) if *field != value => {
+            *field = value;
+            *corrected = true;
+            true
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{header, rom_file};
+    use super::*;
+
+    // One entry for each of the cartridges the tests build from two banks
+    // and from one, looked up by CRC32 and by SHA-1
+    const DATABASE: &str = r#"[
+        { "crc32": "C94F8E32", "mapper": 1, "mirroring": "vertical", "prg_nvram_size": 8192 },
+        { "sha1": "F5914A87A6E452109EDF1C0247E6FFB19C768DFF", "mapper": 4, "region": "pal" }
+    ]"#;
+
+    #[test]
+    fn corrects_a_bad_header() {
+        let database = RomDatabase::from_json(DATABASE).unwrap();
+        let mut cartridge = CartridgeData::new(rom_file(header(2, 1))).unwrap();
+        assert_eq!(cartridge.mapper_number(), 0);
+        assert_eq!(
+            cartridge.apply_database(&database),
+            DatabaseMatch::Corrected
+        );
+        assert_eq!(cartridge.mapper_number(), 1);
+        assert_eq!(cartridge.mirroring(), Mirroring::Vertical);
+        assert!(cartridge.is_battery_backed());
+        assert_eq!(cartridge.prg_nvram().len(), 8192);
+        // Nothing left to fix
+        assert_eq!(cartridge.apply_database(&database), DatabaseMatch::Matched);
+    }
+
+    #[test]
+    fn sha1_lookup_ignores_case() {
+        let database = RomDatabase::from_json(DATABASE).unwrap();
+        let mut cartridge = CartridgeData::new(rom_file(header(1, 1))).unwrap();
+        assert_eq!(
+            cartridge.apply_database(&database),
+            DatabaseMatch::Corrected
+        );
+        assert_eq!(cartridge.mapper_number(), 4);
+        assert_eq!(cartridge.region(), Region::Pal);
+
+        let mut cartridge = CartridgeData::new(rom_file(header(1, 0))).unwrap();
+        assert_eq!(cartridge.apply_database(&database), DatabaseMatch::NotFound);
+    }
+    #[test]
+    fn bad_fields_are_errors() {
+        assert!(matches!(
+            RomDatabase::from_json(r#"[{ "crc32": "xyz" }]"#),
+            Err(DatabaseError::InvalidCrc32(_))
+        ));
+        assert!(matches!(
+            RomDatabase::from_json(r#"[{ "mirroring": "diagonal" }]"#),
+            Err(DatabaseError::InvalidMirroring(_))
+        ));
+        assert!(matches!(
+            RomDatabase::from_json("{"),
+            Err(DatabaseError::Json(_))
+        ));
+    }
+}
@@ -0,0 +1,234 @@
+// Famicom Disk System images
+// https://www.nesdev.org/wiki/FDS_file_format
+// https://www.nesdev.org/wiki/FDS_disk_format
+
+use super::RomReadError;
+
+const FDS_HEADER_SIZE: usize = 16;
+// Each side is stored without the gaps and CRCs the drive sees
+pub const DISK_SIDE_SIZE: usize = 65500;
+const DISK_INFO_BLOCK_SIZE: usize = 56;
+const FILE_AMOUNT_BLOCK_SIZE: usize = 2;
+const FILE_HEADER_BLOCK_SIZE: usize = 16;
+
+// One side of a disk, exactly as it appeared in the image
+#[derive(Clone)]
+pub struct DiskSide {
+    bytes: Box<[u8; DISK_SIDE_SIZE]>,
+    file_count: u8,
+}
+
+impl DiskSide {
+    pub fn bytes(&self) -> &[u8; DISK_SIDE_SIZE] {
+        &self.bytes
+    }
+
+    // For the disk drive to write to
+    pub fn bytes_mut(&mut self) -> &mut [u8; DISK_SIDE_SIZE] {
+        &mut self.bytes
+    }
+
+    // Three letter code from the disk info block
+    pub fn game_name(&self) -> String {
+        String::from_utf8_lossy(&self.bytes[16..19]).into_owned()
+    }
+
+    // 0 for side A, 1 for side B
+    pub fn side_number(&self) -> u8 {
+        self.bytes[21]
+    }
+
+    pub fn disk_number(&self) -> u8 {
+        self.bytes[22]
+    }
+
+    // As declared in the file amount block. Some games hide files past it.
+    pub fn file_count(&self) -> u8 {
+        self.file_count
+    }
+}
+
+#[derive(Clone)]
+pub struct FdsImage {
+    // fwNES header, kept so the image can be written back the way it came
+    header: Option<[u8; FDS_HEADER_SIZE]>,
+    sides: Vec<DiskSide>,
+}
+
+impl FdsImage {
+    pub fn new(filebytes: &[u8]) -> Result<FdsImage, RomReadError> {
+        let (header, body) = if filebytes.starts_with(b"FDS\x1A") {
+            if filebytes.len() < FDS_HEADER_SIZE {
+                return Err(RomReadError::TooShort);
+            }
+            let mut header = [0; FDS_HEADER_SIZE];
+            header.copy_from_slice(&filebytes[..FDS_HEADER_SIZE]);
+            (Some(header), &filebytes[FDS_HEADER_SIZE..])
+        } else {
+            (None, filebytes)
+        };
+        if body.is_empty() {
+            return Err(RomReadError::TooShort);
+        }
+        if !body.len().is_multiple_of(DISK_SIDE_SIZE) {
+            return Err(RomReadError::InvalidDiskSize { len: body.len() });
+        }
+
+        let sides = body
+            .chunks_exact(DISK_SIDE_SIZE)
+            .enumerate()
+            .map(|(side, bytes)| {
+                let file_count = check_blocks(side, bytes)?;
+                let mut side_bytes = Box::new([0; DISK_SIDE_SIZE]);
+                side_bytes.copy_from_slice(bytes);
+                Ok(DiskSide {
+                    bytes: side_bytes,
+                    file_count,
+                })
+            })
+            .collect::<Result<Vec<_>, RomReadError>>()?;
+        Ok(FdsImage { header, sides })
+    }
+
+    pub fn has_header(&self) -> bool {
+        self.header.is_some()
+    }
+
+    pub fn sides(&self) -> &[DiskSide] {
+        &self.sides
+    }
+
+    pub fn sides_mut(&mut self) -> &mut [DiskSide] {
+        &mut self.sides
+    }
+
+    pub fn side_count(&self) -> usize {
+        self.sides.len()
+    }
+
+    pub fn game_name(&self) -> String {
+        self.sides[0].game_name()
+    }
+
+    // The image as it was loaded, with any writes made to the sides since
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut filebytes = Vec::with_capacity(FDS_HEADER_SIZE + self.sides.len() * DISK_SIDE_SIZE);
+        if let Some(header) = &self.header {
+            filebytes.extend_from_slice(header);
+        }
+        for side in &self.sides {
+            filebytes.extend_from_slice(side.bytes.as_slice());
+        }
+        filebytes
+    }
+}
+
+// Walks the disk info block, the file amount block, then a file header and
+// file data block for every file. Returns the number of files.
+fn check_blocks(side: usize, bytes: &[u8]) -> Result<u8, RomReadError> {
+    let expect_block = |offset: usize, block: u8| {
+        if bytes.get(offset) != Some(&block) {
+            return Err(RomReadError::InvalidDiskBlock {
+                side,
+                offset,
+                expected: block,
+            });
+        }
+        Ok(())
+    };
+
+    expect_block(0, 1)?;
+    if &bytes[1..15] != b"*NINTENDO-HVC*" {
+        return Err(RomReadError::InvalidDiskBlock {
+            side,
+            offset: 1,
+            expected: 1,
+        });
+    }
+    let mut offset = DISK_INFO_BLOCK_SIZE;
+    expect_block(offset, 2)?;
+    let file_count = bytes[offset + 1];
+    offset += FILE_AMOUNT_BLOCK_SIZE;
+
+    for _ in 0..file_count {
+        expect_block(offset, 3)?;
+        if offset + FILE_HEADER_BLOCK_SIZE > bytes.len() {
+            return Err(RomReadError::InvalidDiskBlock {
+                side,
+                offset,
+                expected: 3,
+            });
+        }
+        let file_size = u16::from_le_bytes([bytes[offset + 13], bytes[offset + 14]]) as usize;
+        offset += FILE_HEADER_BLOCK_SIZE;
+        expect_block(offset, 4)?;
+        offset += 1 + file_size;
+    }
+    if offset > bytes.len() {
+        return Err(RomReadError::InvalidDiskBlock {
+            side,
+            offset,
+            expected: 4,
+        });
+    }
+    Ok(file_count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A side holding one 4 byte file
+    fn side(side_number: u8) -> Vec<u8> {
+        let mut side = vec![0; DISK_SIDE_SIZE];
+        side[0] = 1;
+        side[1..15].copy_from_slice(b"*NINTENDO-HVC*");
+        side[16..19].copy_from_slice(b"ZEP");
+        side[21] = side_number;
+        let mut offset = DISK_INFO_BLOCK_SIZE;
+        side[offset..offset + 2].copy_from_slice(&[2, 1]);
+        offset += FILE_AMOUNT_BLOCK_SIZE;
+        side[offset] = 3;
+        side[offset + 13] = 4;
+        offset += FILE_HEADER_BLOCK_SIZE;
+        side[offset..offset + 5].copy_from_slice(&[4, 0xDE, 0xAD, 0xBE, 0xEF]);
+        side
+    }
+
+    #[test]
+    fn loads_sides_with_and_without_a_header() {
+        let body = [side(0), side(1)].concat();
+        let image = FdsImage::new(&body).unwrap();
+        assert!(!image.has_header());
+        assert_eq!(image.side_count(), 2);
+        assert_eq!(image.game_name(), "ZEP");
+        assert_eq!(image.sides()[1].side_number(), 1);
+        assert_eq!(image.sides()[0].file_count(), 1);
+        assert_eq!(image.to_bytes(), body);
+
+        let mut file = b"FDS\x1A\x02".to_vec();
+        file.resize(FDS_HEADER_SIZE, 0);
+        file.extend(&body);
+        let image = FdsImage::new(&file).unwrap();
+        assert!(image.has_header());
+        assert_eq!(image.to_bytes(), file);
+    }
+
+    #[test]
+    fn rejects_broken_images() {
+        assert!(matches!(
+            FdsImage::new(&[0; 100]),
+            Err(RomReadError::InvalidDiskSize { len: 100 })
+        ));
+        let mut side = side(0);
+        side[DISK_INFO_BLOCK_SIZE + FILE_AMOUNT_BLOCK_SIZE + FILE_HEADER_BLOCK_SIZE] = 3;
+        assert!(matches!(
+            FdsImage::new(&side),
+            Err(RomReadError::InvalidDiskBlock {
+                side: 0,
+                expected: 4,
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,246 @@
+// Checksums used by ROM databases to identify a dump. These are computed over
+// PRG ROM followed by CHR ROM, leaving out the header, and only over what was
+// in the file: padding added while parsing would change the hash of exactly
+// the dumps with odd sizes that need looking up.
+
+use std::sync::OnceLock;
+
+use super::CartridgeData;
+
+// Filled in the first time each hash is asked for
+#[derive(Clone, Default)]
+pub(super) struct HashCache {
+    prg_crc32: OnceLock<u32>,
+    chr_crc32: OnceLock<u32>,
+    rom_crc32: OnceLock<u32>,
+    rom_sha1: OnceLock<[u8; 20]>,
+}
+
+impl CartridgeData {
+    pub fn prg_crc32(&self) -> u32 {
+        *self
+            .hashes
+            .prg_crc32
+            .get_or_init(|| crc32(self.dumped_prg_rom()))
+    }
+
+    pub fn chr_crc32(&self) -> u32 {
+        *self
+            .hashes
+            .chr_crc32
+            .get_or_init(|| crc32(self.dumped_chr_rom()))
+    }
+
+    // PRG ROM and CHR ROM together
+    pub fn rom_crc32(&self) -> u32 {
+        *self
+            .hashes
+            .rom_crc32
+            .get_or_init(|| crc32_parts(&[self.dumped_prg_rom(), self.dumped_chr_rom()]))
+    }
+
+    // The old name, from before PRG and CHR ROM were hashed separately
+    #[deprecated(note = "use rom_crc32")]
+    pub fn prg_chr_crc32(&self) -> u32 {
+        self.rom_crc32()
+    }
+
+    pub fn rom_sha1(&self) -> [u8; 20] {
+        *self
+            .hashes
+            .rom_sha1
+            .get_or_init(|| sha1(&[self.dumped_prg_rom(), self.dumped_chr_rom()]))
+    }
+
+    pub fn sha1_hex(&self) -> String {
+        self.rom_sha1()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    fn dumped_prg_rom(&self) -> &[u8] {
+        &self.prg_rom()[..self.prg_rom_file_len]
+    }
+
+    fn dumped_chr_rom(&self) -> &[u8] {
+        &self.chr_rom()[..self.chr_rom_file_len]
+    }
+}
+
+// CRC-32 as used by zip, PNG and the ROM databases (reflected, polynomial $EDB88320)
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    // For loops not allowed in a const expression
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+pub fn crc32(bytes: &[u8]) -> u32 {
+    crc32_parts(&[bytes])
+}
+
+// The CRC-32 of the parts one after another, without joining them first
+fn crc32_parts(parts: &[&[u8]]) -> u32 {
+    !parts.iter().copied().flatten().fold(!0, |crc, &byte| {
+        CRC32_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+// FIPS 180-4 SHA-1 of the parts one after another, fed through a block at a
+// time without joining them first
+fn sha1(parts: &[&[u8]]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut block = [0; 64];
+    let mut filled = 0;
+    let mut len: u64 = 0;
+    for &byte in parts.iter().copied().flatten() {
+        block[filled] = byte;
+        filled += 1;
+        len += 1;
+        if filled == 64 {
+            sha1_block(&mut state, &block);
+            filled = 0;
+        }
+    }
+
+    // Pad with a 1 bit, zeros, then the message length in bits, spilling
+    // into another block if the length doesn't fit
+    block[filled] = 0x80;
+    block[filled + 1..].fill(0);
+    if filled >= 56 {
+        sha1_block(&mut state, &block);
+        block.fill(0);
+    }
+    block[56..].copy_from_slice(&len.wrapping_mul(8).to_be_bytes());
+    sha1_block(&mut state, &block);
+
+    let mut digest = [0; 20];
+    for (chunk, value) in digest.chunks_exact_mut(4).zip(state) {
+        chunk.copy_from_slice(&value.to_be_bytes());
+    }
+    digest
+}
+
+fn sha1_block(state: &mut [u32; 5], block: &[u8; 64]) {
+    let mut w = [0u32; 80];
+    for (i, word) in block.chunks_exact(4).enumerate() {
+        w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+    }
+    for i in 16..80 {
+        w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e] = *state;
+    for (i, word) in w.iter().enumerate() {
+        let (f, k) = match i {
+            0..=19 => ((b & c) | (!b & d), 0x5A827999),
+            20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+            40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+            _ => (b ^ c ^ d, 0xCA62C1D6),
+        };
+        let temp = a
+            .rotate_left(5)
+            .wrapping_add(f)
+            .wrapping_add(e)
+            .wrapping_add(k)
+            .wrapping_add(*word);
+        e = d;
+        d = c;
+        c = b.rotate_left(30);
+        b = a;
+        a = temp;
+    }
+    for (value, new) in state.iter_mut().zip([a, b, c, d, e]) {
+        *value = value.wrapping_add(new);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{header, nes2_header, rom_file};
+    use super::*;
+
+    #[test]
+    fn known_digests() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        assert_eq!(crc32_parts(&[b"1234", b"", b"56789"]), 0xCBF43926);
+        let hex = |digest: [u8; 20]| -> String {
+            digest.iter().map(|byte| format!("{byte:02x}")).collect()
+        };
+        assert_eq!(
+            hex(sha1(&[b""])),
+            "da39a3ee5e6b4b0d3255bfef95601890afd80709"
+        );
+        assert_eq!(
+            hex(sha1(&[b"abc"])),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        // Two blocks once padded
+        assert_eq!(
+            hex(sha1(&[
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            ])),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+        // Split across parts and across a block boundary
+        let message = [0x5A; 130];
+        assert_eq!(
+            sha1(&[&message[..3], &message[3..70], &[], &message[70..]]),
+            sha1(&[&message])
+        );
+        assert_eq!(
+            hex(sha1(&[&message])),
+            "0b29cd200592f0fecff097b09f1ac31679d8ad39"
+        );
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn cartridge_hashes() {
+        let cartridge = CartridgeData::new(rom_file(header(2, 1))).unwrap();
+        assert_eq!(cartridge.prg_chr_crc32(), 0xC94F8E32);
+        assert_eq!(cartridge.prg_crc32(), 0xC748E322);
+        assert_eq!(cartridge.chr_crc32(), 0x25DDEDF0);
+        assert_eq!(cartridge.rom_crc32(), 0xC94F8E32);
+        assert_eq!(
+            cartridge.sha1_hex(),
+            "e845cbbfa3623985c8714392d5d28e7a986c3f13"
+        );
+    }
+
+    #[test]
+    fn hashes_leave_out_padding() {
+        // 2^0 * 3 bytes of PRG ROM, in exponent-multiplier notation (E=0, MM=1)
+        let mut header = nes2_header(0b00000001, 0);
+        header[9] = 0x0F;
+        let mut file = header.to_vec();
+        file.extend(b"abc");
+        let cartridge = CartridgeData::new(file).unwrap();
+        assert_eq!(cartridge.prg_rom().len(), 0x4000);
+        assert_eq!(cartridge.hashes.prg_crc32.get(), None);
+        assert_eq!(cartridge.prg_crc32(), 0x352441C2);
+        assert_eq!(cartridge.rom_crc32(), 0x352441C2);
+        assert_eq!(cartridge.rom_sha1(), sha1(&[b"abc"]));
+        // Cached by the first call
+        assert_eq!(cartridge.hashes.prg_crc32.get(), Some(&0x352441C2));
+        assert_eq!(cartridge.hashes.rom_crc32.get(), Some(&0x352441C2));
+        assert_eq!(cartridge.hashes.rom_sha1.get(), Some(&sha1(&[b"abc"])));
+        assert_eq!(cartridge.hashes.chr_crc32.get(), None);
+    }
+}
@@ -0,0 +1,81 @@
+use super::fds::FdsImage;
+use super::nsf::NsfFile;
+use super::{CartridgeData, RomReadError};
+
+// Anything the emulator can be started with
+pub enum RomImage {
+    Cartridge(Box<CartridgeData>),
+    Disk(FdsImage),
+    Music(NsfFile),
+}
+
+impl RomImage {
+    // Picks the format from the file's magic number. Headerless FDS images
+    // start with the disk info block instead.
+    pub fn from_bytes(filebytes: &[u8]) -> Result<RomImage, RomReadError> {
+        if filebytes.starts_with(b"FDS\x1A") || filebytes.starts_with(b"\x01*NINTENDO-HVC*") {
+            return FdsImage::new(filebytes).map(RomImage::Disk);
+        }
+        if filebytes.starts_with(b"NESM\x1A") {
+            return NsfFile::new(filebytes).map(RomImage::Music);
+        }
+        CartridgeData::from_bytes_auto(filebytes)
+            .map(|cartridge| RomImage::Cartridge(Box::new(cartridge)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::super::ParseOptions;
+    use super::*;
+
+    // Every loader, which may fail but mustn't panic
+    fn load_everywhere(filebytes: &[u8]) {
+        let _ = CartridgeData::try_from(filebytes);
+        let lenient = ParseOptions {
+            strict_size_check: false,
+            ..ParseOptions::default()
+        };
+        let _ = CartridgeData::new_with_options(filebytes, lenient);
+        let _ = RomImage::from_bytes(filebytes);
+    }
+
+    #[test]
+    fn fuzz_corpus_loads_without_panicking() {
+        let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/corpus");
+        for target in ["cartridge_new", "rom_image"] {
+            for entry in fs::read_dir(corpus.join(target)).unwrap() {
+                load_everywhere(&fs::read(entry.unwrap().path()).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn garbage_headers_load_without_panicking() {
+        // xorshift, so failures can be reproduced
+        let mut state = 0x2545F4914F6CDD1Du64;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let magic: [&[u8]; 4] = [b"NES\x1A", b"UNIF", b"FDS\x1A", b"NESM\x1A"];
+        for _ in 0..2000 {
+            let mut filebytes = magic[random() as usize % magic.len()].to_vec();
+            filebytes.extend((0..0x80).map(|_| random() as u8));
+            // Sometimes enough data for a small cartridge, more often not
+            let len = random() as usize % 0x6000;
+            filebytes.extend((0..len).map(|_| random() as u8));
+            load_everywhere(&filebytes);
+        }
+        // Every header cut short, with all its bits set
+        let all_set: Vec<u8> = b"NES\x1A".iter().copied().chain([0xFF; 28]).collect();
+        for len in 0..all_set.len() {
+            load_everywhere(&all_set[..len]);
+        }
+    }
+}
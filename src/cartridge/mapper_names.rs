@@ -0,0 +1,151 @@
+// Board names for display, by mapper number and submapper
+// https://www.nesdev.org/wiki/Mapper
+
+// None matches any submapper, and is what a submapper without its own entry
+// falls back to
+const MAPPER_NAMES: &[(u16, Option<u8>, &str)] = &[
+    (0, None, "NROM"),
+    (1, None, "MMC1 (SxROM)"),
+    (1, Some(5), "MMC1 (SEROM/SHROM/SH1ROM)"),
+    (2, None, "UxROM"),
+    (3, None, "CNROM"),
+    (4, None, "MMC3 (TxROM)"),
+    (4, Some(1), "MMC6 (HKROM)"),
+    (5, None, "MMC5 (ExROM)"),
+    (7, None, "AxROM"),
+    (9, None, "MMC2 (PxROM)"),
+    (10, None, "MMC4 (FxROM)"),
+    (11, None, "Color Dreams"),
+    (13, None, "CPROM"),
+    (15, None, "K-1029 multicart"),
+    (16, None, "Bandai FCG"),
+    (18, None, "Jaleco SS88006"),
+    (19, None, "Namco 163"),
+    (21, None, "VRC4a/VRC4c"),
+    (22, None, "VRC2a"),
+    (23, None, "VRC2b/VRC4e"),
+    (24, None, "VRC6a"),
+    (25, None, "VRC4b/VRC4d"),
+    (26, None, "VRC6b"),
+    (28, None, "Action 53"),
+    (30, None, "UNROM 512"),
+    (32, None, "Irem G-101"),
+    (33, None, "Taito TC0190"),
+    (34, None, "BNROM/NINA-001"),
+    (34, Some(1), "NINA-001"),
+    (34, Some(2), "BNROM"),
+    (36, None, "TXC 01-22000-400"),
+    (38, None, "Bit Corp PCI556"),
+    (41, None, "Caltron 6-in-1"),
+    (46, None, "Rumble Station"),
+    (48, None, "Taito TC0690"),
+    (64, None, "Tengen RAMBO-1"),
+    (65, None, "Irem H3001"),
+    (66, None, "GxROM"),
+    (67, None, "Sunsoft-3"),
+    (68, None, "Sunsoft-4"),
+    (69, None, "Sunsoft FME-7"),
+    (70, None, "Bandai 74161/32"),
+    (71, None, "Camerica/Codemasters"),
+    (71, Some(1), "Camerica (Fire Hawk)"),
+    (72, None, "Jaleco JF-17"),
+    (73, None, "VRC3"),
+    (74, None, "Waixing MMC3 clone"),
+    (75, None, "VRC1"),
+    (76, None, "Namco 109 (NAMCOT-3446)"),
+    (77, None, "Irem LROG017"),
+    (78, None, "Irem/Jaleco 74HC161/32"),
+    (79, None, "NINA-03/NINA-06"),
+    (80, None, "Taito X1-005"),
+    (82, None, "Taito X1-017"),
+    (85, None, "VRC7"),
+    (86, None, "Jaleco JF-13"),
+    (87, None, "Jaleco/Konami J87"),
+    (88, None, "Namco 118 (NAMCOT-3443)"),
+    (89, None, "Sunsoft-2 (Tenka no Goikenban)"),
+    (92, None, "Jaleco JF-19"),
+    (93, None, "Sunsoft-2 (Fantasy Zone)"),
+    (94, None, "UN1ROM"),
+    (95, None, "Namco 118 (NAMCOT-3425)"),
+    (97, None, "Irem TAM-S1"),
+    (99, None, "Vs. System"),
+    (105, None, "NES-EVENT"),
+    (113, None, "NINA-03/NINA-06 multicart"),
+    (118, None, "MMC3 (TxSROM)"),
+    (119, None, "MMC3 (TQROM)"),
+    (140, None, "Jaleco JF-11/JF-14"),
+    (152, None, "Bandai 74161/32 (single screen)"),
+    (154, None, "Namco 129 (NAMCOT-3453)"),
+    (159, None, "Bandai FCG (24C01)"),
+    (180, None, "UNROM (Crazy Climber)"),
+    (184, None, "Sunsoft-1"),
+    (185, None, "CNROM with copy protection"),
+    (206, None, "Namco 108 (DxROM)"),
+    (210, None, "Namco 175/340"),
+    (228, None, "Action 52"),
+    (232, None, "Camerica BF9096 (Quattro)"),
+];
+
+pub fn mapper_name(mapper: u16, submapper: u8) -> Option<&'static str> {
+    let specific = MAPPER_NAMES
+        .iter()
+        .find(|&&(number, sub, _)| number == mapper && sub == Some(submapper));
+    specific
+        .or_else(|| {
+            MAPPER_NAMES
+                .iter()
+                .find(|&&(number, sub, _)| number == mapper && sub.is_none())
+        })
+        .map(|&(_, _, name)| name)
+}
+
+impl super::CartridgeData {
+    pub fn mapper_name(&self) -> Option<&'static str> {
+        mapper_name(self.mapper_number, self.submapper)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{header, rom_file};
+    use super::super::CartridgeData;
+    use super::*;
+
+    #[test]
+    fn well_known_mappers() {
+        for (mapper, name) in [
+            (0, "NROM"),
+            (1, "MMC1 (SxROM)"),
+            (2, "UxROM"),
+            (3, "CNROM"),
+            (4, "MMC3 (TxROM)"),
+            (5, "MMC5 (ExROM)"),
+            (7, "AxROM"),
+            (9, "MMC2 (PxROM)"),
+            (10, "MMC4 (FxROM)"),
+            (19, "Namco 163"),
+            (24, "VRC6a"),
+            (66, "GxROM"),
+            (69, "Sunsoft FME-7"),
+        ] {
+            assert_eq!(mapper_name(mapper, 0), Some(name));
+        }
+        assert_eq!(mapper_name(4095, 0), None);
+    }
+
+    #[test]
+    fn submapper_names() {
+        assert_eq!(mapper_name(1, 5), Some("MMC1 (SEROM/SHROM/SH1ROM)"));
+        assert_eq!(mapper_name(4, 1), Some("MMC6 (HKROM)"));
+        // Submappers without a name of their own fall back
+        assert_eq!(mapper_name(1, 3), Some("MMC1 (SxROM)"));
+    }
+
+    #[test]
+    fn cartridge_mapper_name() {
+        let mut header = header(1, 1);
+        header[6] = 0x40;
+        let cartridge = CartridgeData::new(rom_file(header)).unwrap();
+        assert_eq!(cartridge.mapper_name(), Some("MMC3 (TxROM)"));
+    }
+}
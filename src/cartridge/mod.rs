@@ -0,0 +1,1737 @@
+// iNES / NES 2.0 file parsing
+// https://www.nesdev.org/wiki/INES
+// https://www.nesdev.org/wiki/NES_2.0
+
+#[cfg(feature = "zip")]
+mod archive;
+mod builder;
+mod console;
+#[cfg(feature = "database")]
+pub mod database;
+mod fds;
+mod hash;
+mod loader;
+mod mapper_names;
+mod nsf;
+mod snapshot;
+mod split;
+mod summary;
+mod unif;
+mod writer;
+
+pub use builder::{BuilderError, CartridgeBuilder};
+pub use console::{ConsoleType, DefaultExpansionDevice, VsHardwareType, VsPpuType};
+pub use fds::{DiskSide, FdsImage, DISK_SIDE_SIZE};
+pub use loader::RomImage;
+pub use mapper_names::mapper_name;
+pub use nsf::NsfFile;
+pub use snapshot::{CartridgeSnapshot, SnapshotError};
+pub use summary::HeaderSummary;
+pub use unif::mapper_for_board;
+
+use std::borrow::Cow;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
+// Where the trainer goes in PRG RAM, which starts at $6000
+const TRAINER_OFFSET: usize = 0x1000;
+const PRG_BANK_SIZE: usize = 16384;
+const CHR_BANK_SIZE: usize = 8192;
+// PlayChoice-10 data appended after CHR ROM
+const INST_ROM_SIZE: usize = 8192;
+const PROM_SIZE: usize = 32;
+// iNES 1.0 can't describe PRG RAM, so assume the usual 8 KB at $6000-$7FFF
+const DEFAULT_PRG_RAM_SIZE: usize = 8192;
+// iNES 1.0 boards without CHR ROM have 8 KB of CHR RAM instead
+const DEFAULT_CHR_RAM_SIZE: usize = 8192;
+// CNROM, MMC2, MMC4, Color Dreams, GxROM, and the Jaleco and Jaleco/Konami
+// discrete boards, none of which can switch CHR RAM
+const MAPPERS_WITH_CHR_ROM: [u16; 7] = [3, 9, 10, 11, 66, 87, 140];
+// The Front Fareast copier modes
+const MAPPERS_WITH_TRAINERS: [u16; 3] = [6, 8, 17];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RomSection {
+    Trainer,
+    PrgRom,
+    ChrRom,
+    InstRom,
+    Prom,
+    // Not stored in the file, but their sizes come from the header
+    PrgRam,
+    ChrRam,
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum RomReadError {
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "snapshot::serde_with::display")
+    )]
+    Io(std::io::Error),
+    #[cfg(feature = "zip")]
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "snapshot::serde_with::display")
+    )]
+    Zip(zip::result::ZipError),
+    // The archive has no .nes entries, or more than one and none was picked
+    #[cfg(feature = "zip")]
+    NoRomInArchive,
+    #[cfg(feature = "zip")]
+    MultipleRomsInArchive {
+        names: Vec<String>,
+    },
+    // The entry is bigger than any ROM file the parser would accept
+    #[cfg(feature = "zip")]
+    ArchiveEntryTooLarge {
+        limit: usize,
+    },
+    TooShort,
+    InvalidHeader {
+        index: usize,
+    },
+    // The file ended before the end of a section declared in the header
+    TruncatedData {
+        section: RomSection,
+        expected: usize,
+        got: usize,
+    },
+    // The declared size of a section doesn't fit in a usize
+    SizeOverflow {
+        section: RomSection,
+    },
+    // The declared size of a section is over the limit set in ParseOptions
+    SizeTooLarge {
+        section: RomSection,
+        declared: usize,
+        limit: usize,
+    },
+    // An iNES 1.0 header with garbage in its unused bits, from new_strict
+    DirtyHeader {
+        byte: usize,
+    },
+    // Data after the last section, when ParseOptions doesn't allow it
+    TrailingData {
+        count: usize,
+    },
+    // The trainer flag is set but the file is exactly 512 bytes too short
+    // to hold one, when ParseOptions doesn't allow it
+    MissingTrainer,
+    UnknownConsoleType {
+        console_type: u8,
+    },
+    // A UNIF chunk runs past the end of the file
+    MalformedUnifChunk {
+        offset: usize,
+    },
+    MissingUnifChunk {
+        id: &'static str,
+    },
+    // A UNIF board name with no known iNES mapper number
+    UnknownUnifBoard {
+        board: String,
+    },
+    // An FDS image that isn't a whole number of 65500 byte sides
+    InvalidDiskSize {
+        len: usize,
+    },
+    // A disk side whose blocks don't follow the expected order
+    InvalidDiskBlock {
+        side: usize,
+        offset: usize,
+        expected: u8,
+    },
+    InvalidNsfVersion {
+        version: u8,
+    },
+    // Tunes that don't bankswitch have to load into $8000-$FFFF
+    InvalidLoadAddress {
+        address: u16,
+    },
+    // More NSF data than the CPU can address
+    NsfDataTooLarge {
+        len: usize,
+    },
+}
+
+// The PRG RAM buffer can't reach $71FF
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PrgRamTooSmall {
+    pub len: usize,
+}
+
+// Problems that didn't stop the file from loading
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ParseWarning {
+    // The file ended partway through the section. PRG and CHR ROM keep only
+    // the bytes that were there, the fixed size sections are zero padded.
+    TruncatedSection {
+        section: RomSection,
+        expected: usize,
+        got: usize,
+    },
+    TrailingBytes {
+        count: usize,
+    },
+    // Loaded as if the trainer flag was clear
+    MissingTrainer,
+    UnknownConsoleType {
+        console_type: u8,
+    },
+    // Bytes 7-15 held junk and were ignored
+    ArchaicHeader,
+    // An iNES 1.0 header with something other than zero in bytes 10-15
+    DirtyPadding {
+        byte: usize,
+    },
+    // The mapper only ever shipped with CHR ROM but the header declares none
+    MissingChrRom {
+        mapper: u16,
+    },
+    // Trainers came from copier hardware, which only a few mappers emulate
+    UnexpectedTrainer {
+        mapper: u16,
+    },
+}
+
+// Nametable arrangement. The header can only describe the first three;
+// the single-screen layouts are selected at runtime by mappers like AxROM and MMC1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    FourScreen,
+    SingleScreenLower,
+    SingleScreenUpper,
+}
+
+// Archaic headers are iNES files with junk in bytes 7-15, which are ignored
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum HeaderVersion {
+    Archaic,
+    INes1,
+    Nes2,
+}
+
+// Which header layout the fields were read with. Archaic headers count as
+// iNES. HeaderVersion tells them apart.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RomFormat {
+    INes,
+    Nes2,
+}
+
+// CPU/PPU timing the game expects
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Region {
+    Ntsc,
+    Pal,
+    MultiRegion,
+    Dendy,
+}
+
+// Limits and leniency applied while parsing. The defaults refuse files cut
+// short, since there's no knowing what the missing data was, but load other
+// oddities recording them as a ParseWarning; a validator would rather turn
+// all the leniency off. The size caps are well above any licensed cartridge
+// but keep a hostile header from describing gigabytes.
+#[derive(Debug, Clone, Copy)]
+pub struct ParseOptions {
+    pub max_prg_rom_size: usize,
+    pub max_chr_rom_size: usize,
+    // Fail on sections cut short by the end of the file. Turning this off
+    // loads them with whatever bytes are there instead.
+    pub strict_size_check: bool,
+    // Warn about bytes after the last section instead of failing
+    pub allow_trailing_data: bool,
+    // Warn about a trainer flag with no trainer in the file instead of failing
+    pub allow_missing_trainer: bool,
+    pub treat_unknown_console_type_as_error: bool,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        ParseOptions {
+            max_prg_rom_size: 4 * 1024 * 1024,
+            max_chr_rom_size: 4 * 1024 * 1024,
+            strict_size_check: true,
+            allow_trailing_data: true,
+            allow_missing_trainer: true,
+            treat_unknown_console_type_as_error: false,
+        }
+    }
+}
+
+impl ParseOptions {
+    // Every oddity is an error
+    pub fn strict() -> ParseOptions {
+        ParseOptions {
+            allow_trailing_data: false,
+            allow_missing_trainer: false,
+            treat_unknown_console_type_as_error: true,
+            ..ParseOptions::default()
+        }
+    }
+}
+
+/// A parsed iNES / NES 2.0 ROM image. Cloning is cheap, the ROM data is shared.
+///
+/// ```no_run
+/// use zephyrnes::cartridge::CartridgeData;
+///
+/// let bytes = std::fs::read("game.nes").unwrap();
+/// let cartridge = CartridgeData::new(bytes).unwrap();
+/// println!(
+///     "mapper {}, {} PRG banks, {} CHR banks",
+///     cartridge.mapper_number(),
+///     cartridge.prg_rom_banks(),
+///     cartridge.chr_rom_banks()
+/// );
+/// ```
+#[derive(Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "snapshot::serde_with::CartridgeFields")
+)]
+pub struct CartridgeData {
+    // Exactly as it appeared in the file, before archaic bytes were discarded
+    header: [u8; HEADER_SIZE],
+    header_version: HeaderVersion,
+    console_type: ConsoleType,
+    // PRG ROM followed by CHR ROM, shared between clones of the cartridge
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "snapshot::serde_with::shared_bytes")
+    )]
+    rom: Arc<[u8]>,
+    prg_rom_len: usize,
+    // How much of each the file held, before either was padded to whole
+    // banks. The hashes only cover these bytes.
+    prg_rom_file_len: usize,
+    chr_rom_file_len: usize,
+    // PRG ROM and CHR ROM as the header declares them
+    declared_prg_rom_len: usize,
+    declared_chr_rom_len: usize,
+    mapper_number: u16,
+    submapper: u8,
+    mirroring: Mirroring,
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "snapshot::serde_with::optional_bytes")
+    )]
+    trainer: Option<[u8; TRAINER_SIZE]>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "snapshot::serde_with::optional_bytes")
+    )]
+    inst_rom: Option<Box<[u8; INST_ROM_SIZE]>>,
+    prom: Option<[u8; PROM_SIZE]>,
+    misc_rom_count: u8,
+    misc_rom: Vec<u8>,
+    default_expansion_device: DefaultExpansionDevice,
+    battery_backed: bool,
+    prg_ram_size: usize,
+    prg_nvram_size: usize,
+    prg_ram: Vec<u8>,
+    prg_nvram: Vec<u8>,
+    chr_ram_size: usize,
+    chr_nvram_size: usize,
+    chr_ram: Vec<u8>,
+    region: Region,
+    warnings: Vec<ParseWarning>,
+    // Worked out again when needed
+    #[cfg_attr(feature = "serde", serde(skip))]
+    hashes: hash::HashCache,
+}
+
+impl CartridgeData {
+    pub fn new(filebytes: Vec<u8>) -> Result<CartridgeData, RomReadError> {
+        CartridgeData::try_from(filebytes.as_slice())
+    }
+
+    // Picks the parser from the file's magic number. See RomImage for files
+    // that may not be cartridges at all.
+    pub fn from_bytes_auto(filebytes: &[u8]) -> Result<CartridgeData, RomReadError> {
+        match filebytes.get(0..4) {
+            Some(b"UNIF") => CartridgeData::from_unif_bytes(filebytes),
+            #[cfg(feature = "zip")]
+            Some(b"PK\x03\x04") => CartridgeData::from_zip_bytes(filebytes),
+            _ => CartridgeData::try_from(filebytes),
+        }
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<CartridgeData, RomReadError> {
+        let filebytes = std::fs::read(path).map_err(RomReadError::Io)?;
+        CartridgeData::new(filebytes)
+    }
+
+    pub fn from_reader(mut reader: impl Read) -> Result<CartridgeData, RomReadError> {
+        let mut filebytes = Vec::new();
+        reader
+            .read_to_end(&mut filebytes)
+            .map_err(RomReadError::Io)?;
+        CartridgeData::new(filebytes)
+    }
+
+    // Like new, but refuses iNES 1.0 headers with anything set that the
+    // format leaves unused: bits 2-3 of byte 7, bits 1-7 of byte 9 and bytes
+    // 10-15. Dumps like that often have junk in the high nibble of byte 7 as
+    // well. new guesses around it, taking the mapper from byte 6 alone when
+    // bytes 12-15 are dirty; this reports the first dirty byte instead.
+    pub fn new_strict(filebytes: Vec<u8>) -> Result<CartridgeData, RomReadError> {
+        if let Some(header) = filebytes.get(..HEADER_SIZE) {
+            let nes2 = header[7] & 0b00001100 == 0b00001000;
+            let dirty_byte = if nes2 {
+                None
+            } else if header[7] & 0b00001100 != 0 {
+                Some(7)
+            } else if header[9] & 0b11111110 != 0 {
+                Some(9)
+            } else {
+                (10..HEADER_SIZE).find(|&i| header[i] != 0)
+            };
+            if let Some(byte) = dirty_byte {
+                return Err(RomReadError::DirtyHeader { byte });
+            }
+        }
+        CartridgeData::new(filebytes)
+    }
+
+    pub fn new_with_options(
+        filebytes: &[u8],
+        options: ParseOptions,
+    ) -> Result<CartridgeData, RomReadError> {
+        let mut header: [u8; HEADER_SIZE] = filebytes
+            .get(..HEADER_SIZE)
+            .and_then(|header| header.try_into().ok())
+            .ok_or(RomReadError::TooShort)?;
+        let raw_header = header;
+
+        // Constant $4E $45 $53 $1A ("NES" followed by MS-DOS end-of-file)
+        for (index, byte) in b"NES\x1A".iter().enumerate() {
+            if header[index] != *byte {
+                return Err(RomReadError::InvalidHeader { index });
+            }
+        }
+
+        // Old dumping tools wrote their signature (e.g. "DiskDude!") over bytes
+        // 7-15, which would be misread as the mapper high nibble. iNES 1.0 never
+        // uses bytes 12-15, so junk there means none of bytes 7-15 can be trusted.
+        let nes2 = header[7] & 0b00001100 == 0b00001000;
+        let header_version = if nes2 {
+            HeaderVersion::Nes2
+        } else if &header[7..HEADER_SIZE] == b"DiskDude!"
+            || header[12..HEADER_SIZE].iter().any(|&byte| byte != 0)
+        {
+            header[7..HEADER_SIZE].fill(0);
+            HeaderVersion::Archaic
+        } else {
+            HeaderVersion::INes1
+        };
+
+        // Size of PRG ROM in 16 KB units, and CHR ROM in 8 KB units.
+        // NES 2.0 keeps the most significant bits in byte 9.
+        let (prg_rom_msb, chr_rom_msb) = if nes2 {
+            (header[9] & 0x0F, header[9] >> 4)
+        } else {
+            (0, 0)
+        };
+        let prg_rom_len_bytes = rom_size_in_bytes(header[4], prg_rom_msb, PRG_BANK_SIZE).ok_or(
+            RomReadError::SizeOverflow {
+                section: RomSection::PrgRom,
+            },
+        )?;
+        let chr_rom_len_bytes = rom_size_in_bytes(header[5], chr_rom_msb, CHR_BANK_SIZE).ok_or(
+            RomReadError::SizeOverflow {
+                section: RomSection::ChrRom,
+            },
+        )?;
+        check_size_limit(
+            RomSection::PrgRom,
+            prg_rom_len_bytes,
+            options.max_prg_rom_size,
+        )?;
+        check_size_limit(
+            RomSection::ChrRom,
+            chr_rom_len_bytes,
+            options.max_chr_rom_size,
+        )?;
+
+        // Flags 6
+        // Four-screen VRAM overrides the mirroring bit
+        let mirroring = if header[6] & 0b00001000 != 0 {
+            Mirroring::FourScreen
+        } else if header[6] & 0b00000001 != 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        };
+        // NES 2.0 byte 12, otherwise the rarely-set TV system bit of iNES flags 9
+        let region = if nes2 {
+            match header[12] & 0b11 {
+                0 => Region::Ntsc,
+                1 => Region::Pal,
+                2 => Region::MultiRegion,
+                _ => Region::Dendy,
+            }
+        } else if header[9] & 0b00000001 != 0 {
+            Region::Pal
+        } else {
+            Region::Ntsc
+        };
+
+        let mut warnings = Vec::new();
+        let console_type = ConsoleType::from_header(&header, nes2);
+        if let ConsoleType::Extended(extended) = console_type {
+            if !console_type.is_known() {
+                if options.treat_unknown_console_type_as_error {
+                    return Err(RomReadError::UnknownConsoleType {
+                        console_type: extended,
+                    });
+                }
+                warnings.push(ParseWarning::UnknownConsoleType {
+                    console_type: extended,
+                });
+            }
+        }
+
+        let battery_backed = header[6] & 0b00000010 != 0;
+        let has_trainer = header[6] & 0b00000100 != 0;
+
+        // Mapper number is split across the high nibbles of flags 6 and 7,
+        // with NES 2.0 adding another nibble in the low bits of byte 8.
+        // The high nibble of byte 8 is the submapper.
+        let mut mapper_number = ((header[6] >> 4) | (header[7] & 0xF0)) as u16;
+        let mut submapper = 0;
+        if nes2 {
+            mapper_number |= ((header[8] & 0x0F) as u16) << 8;
+            submapper = (header[8] & 0xF0) >> 4;
+        }
+
+        match header_version {
+            HeaderVersion::Archaic => warnings.push(ParseWarning::ArchaicHeader),
+            HeaderVersion::INes1 => {
+                if let Some(byte) = (10..HEADER_SIZE).find(|&i| header[i] != 0) {
+                    warnings.push(ParseWarning::DirtyPadding { byte });
+                }
+            }
+            HeaderVersion::Nes2 => (),
+        }
+        if chr_rom_len_bytes == 0 && MAPPERS_WITH_CHR_ROM.contains(&mapper_number) {
+            warnings.push(ParseWarning::MissingChrRom {
+                mapper: mapper_number,
+            });
+        }
+        if has_trainer && !MAPPERS_WITH_TRAINERS.contains(&mapper_number) {
+            warnings.push(ParseWarning::UnexpectedTrainer {
+                mapper: mapper_number,
+            });
+        }
+
+        let playchoice_len = if console_type == ConsoleType::PlayChoice10 {
+            INST_ROM_SIZE + PROM_SIZE
+        } else {
+            0
+        };
+        // A common bad dump: the flag is set but the trainer was never included.
+        // The size limits in ParseOptions can be raised far enough for the sum
+        // to overflow, in which case no file could be that long anyway.
+        let len_without_trainer = [prg_rom_len_bytes, chr_rom_len_bytes, playchoice_len]
+            .into_iter()
+            .try_fold(HEADER_SIZE, usize::checked_add);
+        let has_trainer = if has_trainer && len_without_trainer == Some(filebytes.len()) {
+            if !options.allow_missing_trainer {
+                return Err(RomReadError::MissingTrainer);
+            }
+            warnings.push(ParseWarning::MissingTrainer);
+            false
+        } else {
+            has_trainer
+        };
+
+        // Sections follow the header in order: trainer, PRG ROM, CHR ROM
+        let mut section_reader = SectionReader {
+            filebytes,
+            offset: HEADER_SIZE,
+            options: &options,
+            warnings: &mut warnings,
+        };
+        let trainer = if has_trainer {
+            Some(section_reader.read_array(RomSection::Trainer)?)
+        } else {
+            None
+        };
+        let prg_rom = section_reader.read(prg_rom_len_bytes, RomSection::PrgRom)?;
+        let chr_rom = section_reader.read(chr_rom_len_bytes, RomSection::ChrRom)?;
+        let (prg_rom_file_len, chr_rom_file_len) = (prg_rom.len(), chr_rom.len());
+        // NES 2.0 can describe sizes that aren't whole banks
+        let prg_rom = pad_to_bank(prg_rom, PRG_BANK_SIZE);
+        let chr_rom = pad_to_bank(chr_rom, CHR_BANK_SIZE);
+        let prg_rom_len = prg_rom.len();
+        // Both are copied straight into the shared buffer, which is
+        // allocated once at its full size
+        let rom: Arc<[u8]> = prg_rom.iter().chain(chr_rom.iter()).copied().collect();
+
+        // PlayChoice-10 INST-ROM, then the PROM data and CounterOut bytes
+        let (inst_rom, prom) = if console_type == ConsoleType::PlayChoice10 {
+            let inst_rom = Box::new(section_reader.read_array(RomSection::InstRom)?);
+            let prom = section_reader.read_array(RomSection::Prom)?;
+            (Some(inst_rom), Some(prom))
+        } else {
+            (None, None)
+        };
+        let offset = section_reader.offset;
+
+        // NES 2.0 bytes 14-15. The header only gives the number of miscellaneous
+        // ROMs, so whatever follows the other sections is kept together.
+        let (misc_rom_count, default_expansion_device) = if nes2 {
+            (
+                header[14] & 0b00000011,
+                DefaultExpansionDevice::from_byte(header[15]),
+            )
+        } else {
+            (0, DefaultExpansionDevice::Unspecified)
+        };
+        let remaining = filebytes.get(offset..).unwrap_or_default();
+        let misc_rom = if misc_rom_count > 0 {
+            remaining.to_vec()
+        } else {
+            if !remaining.is_empty() {
+                let count = remaining.len();
+                if !options.allow_trailing_data {
+                    return Err(RomReadError::TrailingData { count });
+                }
+                warnings.push(ParseWarning::TrailingBytes { count });
+            }
+            Vec::new()
+        };
+
+        // NES 2.0 byte 10 holds shift counts for volatile (low nibble) and
+        // battery-backed (high nibble) PRG RAM, both mapped at $6000
+        let (prg_ram_size, prg_nvram_size) = if nes2 {
+            (
+                shift_count_size(header[10] & 0x0F, RomSection::PrgRam)?,
+                shift_count_size(header[10] >> 4, RomSection::PrgRam)?,
+            )
+        } else if battery_backed {
+            (0, DEFAULT_PRG_RAM_SIZE)
+        } else {
+            (DEFAULT_PRG_RAM_SIZE, 0)
+        };
+        // Byte 11 is laid out the same way for CHR RAM
+        let (chr_ram_size, chr_nvram_size) = if nes2 {
+            (
+                shift_count_size(header[11] & 0x0F, RomSection::ChrRam)?,
+                shift_count_size(header[11] >> 4, RomSection::ChrRam)?,
+            )
+        } else if chr_rom_len_bytes == 0 {
+            (DEFAULT_CHR_RAM_SIZE, 0)
+        } else {
+            (0, 0)
+        };
+
+        Ok(CartridgeData {
+            header: raw_header,
+            header_version,
+            console_type,
+            rom,
+            prg_rom_len,
+            prg_rom_file_len,
+            chr_rom_file_len,
+            declared_prg_rom_len: prg_rom_len_bytes,
+            declared_chr_rom_len: chr_rom_len_bytes,
+            mapper_number,
+            submapper,
+            mirroring,
+            trainer,
+            inst_rom,
+            prom,
+            misc_rom_count,
+            misc_rom,
+            default_expansion_device,
+            battery_backed,
+            prg_ram_size,
+            prg_nvram_size,
+            prg_ram: vec![0; prg_ram_size],
+            prg_nvram: vec![0; prg_nvram_size],
+            chr_ram_size,
+            chr_nvram_size,
+            chr_ram: vec![0; chr_ram_size + chr_nvram_size],
+            region,
+            warnings,
+            hashes: hash::HashCache::default(),
+        })
+    }
+
+    pub fn raw_header(&self) -> &[u8; HEADER_SIZE] {
+        &self.header
+    }
+
+    pub fn header_version(&self) -> HeaderVersion {
+        self.header_version
+    }
+
+    pub fn format(&self) -> RomFormat {
+        match self.header_version {
+            HeaderVersion::Nes2 => RomFormat::Nes2,
+            HeaderVersion::Archaic | HeaderVersion::INes1 => RomFormat::INes,
+        }
+    }
+
+    // Whether bytes 7-15 of the header were ignored as junk
+    pub fn is_archaic(&self) -> bool {
+        self.header_version == HeaderVersion::Archaic
+    }
+
+    // Whether the game is meant for something other than a plain NES is left
+    // for the caller to decide on
+    pub fn console_type(&self) -> ConsoleType {
+        self.console_type
+    }
+
+    pub fn prg_rom(&self) -> &[u8] {
+        &self.rom[..self.prg_rom_len]
+    }
+
+    pub fn chr_rom(&self) -> &[u8] {
+        &self.rom[self.prg_rom_len..]
+    }
+
+    // Length of a well-formed file with this header: the header, trainer, PRG
+    // ROM, CHR ROM, PlayChoice-10 data and any miscellaneous ROM. A file that
+    // was loaded with truncated sections is shorter than this.
+    pub fn expected_file_len(&self) -> usize {
+        HEADER_SIZE
+            + self.trainer.map_or(0, |trainer| trainer.len())
+            + self.declared_prg_rom_len
+            + self.declared_chr_rom_len
+            + self.inst_rom.as_ref().map_or(0, |inst_rom| inst_rom.len())
+            + self.prom.map_or(0, |prom| prom.len())
+            + self.misc_rom.len()
+    }
+
+    // Number of 16 KB PRG ROM banks
+    pub fn prg_rom_banks(&self) -> usize {
+        self.prg_rom().len() / PRG_BANK_SIZE
+    }
+
+    // Number of 8 KB CHR ROM banks
+    pub fn chr_rom_banks(&self) -> usize {
+        self.chr_rom().len() / CHR_BANK_SIZE
+    }
+
+    // Banks as mappers switch them. Bank numbers past the end wrap around the
+    // way unconnected address lines mirror the ROM. PRG ROM and CHR ROM are
+    // always whole 16 KB and 8 KB banks, since odd sizes are padded when the
+    // file is parsed. Without CHR ROM the CHR banks are empty.
+    pub fn prg_bank_16k(&self, bank: usize) -> &[u8] {
+        rom_bank(self.prg_rom(), 0x4000, bank)
+    }
+
+    pub fn prg_bank_8k(&self, bank: usize) -> &[u8] {
+        rom_bank(self.prg_rom(), 0x2000, bank)
+    }
+
+    pub fn chr_bank_8k(&self, bank: usize) -> &[u8] {
+        rom_bank(self.chr_rom(), 0x2000, bank)
+    }
+
+    pub fn chr_bank_4k(&self, bank: usize) -> &[u8] {
+        rom_bank(self.chr_rom(), 0x1000, bank)
+    }
+
+    pub fn chr_bank_1k(&self, bank: usize) -> &[u8] {
+        rom_bank(self.chr_rom(), 0x0400, bank)
+    }
+
+    pub fn prg_bank_count_16k(&self) -> usize {
+        self.prg_rom().len() / 0x4000
+    }
+
+    pub fn prg_bank_count_8k(&self) -> usize {
+        self.prg_rom().len() / 0x2000
+    }
+
+    pub fn chr_bank_count_8k(&self) -> usize {
+        self.chr_rom().len() / 0x2000
+    }
+
+    pub fn chr_bank_count_4k(&self) -> usize {
+        self.chr_rom().len() / 0x1000
+    }
+
+    pub fn chr_bank_count_1k(&self) -> usize {
+        self.chr_rom().len() / 0x0400
+    }
+
+    pub fn mapper_number(&self) -> u16 {
+        self.mapper_number
+    }
+
+    // Always 0 for iNES 1.0 files
+    pub fn submapper(&self) -> u8 {
+        self.submapper
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    pub fn has_trainer(&self) -> bool {
+        self.trainer.is_some()
+    }
+
+    // 512 bytes the game expects at $7000-$71FF when it starts
+    pub fn trainer(&self) -> Option<&[u8; TRAINER_SIZE]> {
+        self.trainer.as_ref()
+    }
+
+    // Copies the trainer to $7000 in a PRG RAM buffer that starts at $6000.
+    // Does nothing without a trainer.
+    pub fn load_trainer_into(&self, prg_ram: &mut [u8]) -> Result<(), PrgRamTooSmall> {
+        match &self.trainer {
+            Some(trainer) => copy_trainer(trainer, prg_ram),
+            None => Ok(()),
+        }
+    }
+
+    // Puts the trainer in the cartridge's own PRG RAM, the battery-backed
+    // RAM if it has some, which is what happens at power-on
+    pub(crate) fn load_trainer(&mut self) -> Result<(), PrgRamTooSmall> {
+        let Some(trainer) = &self.trainer else {
+            return Ok(());
+        };
+        if self.prg_nvram.is_empty() {
+            copy_trainer(trainer, &mut self.prg_ram)
+        } else {
+            copy_trainer(trainer, &mut self.prg_nvram)
+        }
+    }
+
+    // PlayChoice-10 hint screen ROM
+    pub fn inst_rom(&self) -> Option<&[u8; INST_ROM_SIZE]> {
+        self.inst_rom.as_deref()
+    }
+
+    // PlayChoice-10 decryption PROM, 16 bytes of data followed by 16 CounterOut bytes
+    pub fn prom(&self) -> Option<&[u8; PROM_SIZE]> {
+        self.prom.as_ref()
+    }
+
+    pub fn misc_rom_count(&self) -> u8 {
+        self.misc_rom_count
+    }
+
+    // All miscellaneous ROM data, in file order
+    pub fn misc_rom(&self) -> &[u8] {
+        &self.misc_rom
+    }
+
+    pub fn default_expansion_device(&self) -> DefaultExpansionDevice {
+        self.default_expansion_device
+    }
+
+    // Whether PRG RAM should be persisted between sessions
+    pub fn is_battery_backed(&self) -> bool {
+        self.battery_backed
+    }
+
+    // The same flag, under the name frontends deciding what to save look for
+    pub fn has_battery(&self) -> bool {
+        self.is_battery_backed()
+    }
+
+    // Size in bytes of volatile PRG RAM
+    pub fn prg_ram_size(&self) -> usize {
+        self.prg_ram_size
+    }
+
+    // Size in bytes of battery-backed PRG RAM
+    pub fn prg_nvram_size(&self) -> usize {
+        self.prg_nvram_size
+    }
+
+    // The RAM at $6000. On a cartridge whose only PRG RAM is battery-backed,
+    // which is how iNES 1.0 describes every battery cartridge, that's the
+    // same buffer as prg_nvram.
+    pub fn prg_ram(&self) -> &[u8] {
+        if self.prg_ram.is_empty() {
+            &self.prg_nvram
+        } else {
+            &self.prg_ram
+        }
+    }
+
+    pub fn prg_ram_mut(&mut self) -> &mut [u8] {
+        if self.prg_ram.is_empty() {
+            &mut self.prg_nvram
+        } else {
+            &mut self.prg_ram
+        }
+    }
+
+    // Battery-backed PRG RAM, kept apart from prg_ram since it's what gets saved
+    pub fn prg_nvram(&self) -> &[u8] {
+        &self.prg_nvram
+    }
+
+    pub fn prg_nvram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_nvram
+    }
+
+    // Size in bytes of volatile CHR RAM
+    pub fn chr_ram_size(&self) -> usize {
+        self.chr_ram_size
+    }
+
+    // Size in bytes of battery-backed CHR RAM
+    pub fn chr_nvram_size(&self) -> usize {
+        self.chr_nvram_size
+    }
+
+    pub fn has_chr_ram(&self) -> bool {
+        !self.chr_ram.is_empty()
+    }
+
+    pub fn chr_ram(&self) -> &[u8] {
+        &self.chr_ram
+    }
+
+    pub fn chr_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.chr_ram
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    // Everything odd about the file that ParseOptions let through
+    pub fn warnings(&self) -> &[ParseWarning] {
+        &self.warnings
+    }
+}
+
+impl TryFrom<&[u8]> for CartridgeData {
+    type Error = RomReadError;
+
+    fn try_from(filebytes: &[u8]) -> Result<CartridgeData, RomReadError> {
+        CartridgeData::new_with_options(filebytes, ParseOptions::default())
+    }
+}
+
+fn copy_trainer(trainer: &[u8; TRAINER_SIZE], prg_ram: &mut [u8]) -> Result<(), PrgRamTooSmall> {
+    let len = prg_ram.len();
+    prg_ram
+        .get_mut(TRAINER_OFFSET..TRAINER_OFFSET + TRAINER_SIZE)
+        .ok_or(PrgRamTooSmall { len })?
+        .copy_from_slice(trainer);
+    Ok(())
+}
+
+// With an MSB nibble of $F, NES 2.0 switches the LSB byte to exponent-multiplier
+// notation: EEEEEEMM describes 2^E * (MM * 2 + 1) bytes. Otherwise the size is
+// a count of units. Returns None if the size can't be represented.
+fn rom_size_in_bytes(lsb: u8, msb: u8, unit: usize) -> Option<usize> {
+    if msb == 0x0F {
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0b11) as usize * 2 + 1;
+        1usize.checked_shl(exponent)?.checked_mul(multiplier)
+    } else {
+        (((msb as usize) << 8) | lsb as usize).checked_mul(unit)
+    }
+}
+
+// NES 2.0 RAM sizes are stored as 64 << shift, where a shift of 0 means none.
+// The largest is 2 MB, which only overflows a 16-bit usize.
+fn shift_count_size(shift: u8, section: RomSection) -> Result<usize, RomReadError> {
+    if shift == 0 {
+        return Ok(0);
+    }
+    1usize
+        .checked_shl(shift as u32 + 6)
+        .ok_or(RomReadError::SizeOverflow { section })
+}
+
+fn check_size_limit(
+    section: RomSection,
+    declared: usize,
+    limit: usize,
+) -> Result<(), RomReadError> {
+    if declared > limit {
+        return Err(RomReadError::SizeTooLarge {
+            section,
+            declared,
+            limit,
+        });
+    }
+    Ok(())
+}
+
+// Sizes that evenly divide a bank are repeated to fill it, as a smaller chip
+// would be mirrored on the board. Anything else is zero padded.
+fn pad_to_bank(rom: Cow<[u8]>, bank_size: usize) -> Cow<[u8]> {
+    if rom.len().is_multiple_of(bank_size) {
+        return rom;
+    }
+    let padded_len = rom.len().next_multiple_of(bank_size);
+    if bank_size.is_multiple_of(rom.len()) {
+        return Cow::Owned(rom.repeat(bank_size / rom.len()));
+    }
+    let mut padded = rom.into_owned();
+    padded.resize(padded_len, 0);
+    Cow::Owned(padded)
+}
+
+fn rom_bank(rom: &[u8], bank_size: usize, bank: usize) -> &[u8] {
+    let bank_count = rom.len() / bank_size;
+    if bank_count == 0 {
+        return &[];
+    }
+    let start = (bank % bank_count) * bank_size;
+    &rom[start..start + bank_size]
+}
+
+// Reads the sections after the header one after another
+struct SectionReader<'a, 'w> {
+    filebytes: &'a [u8],
+    offset: usize,
+    options: &'w ParseOptions,
+    warnings: &'w mut Vec<ParseWarning>,
+}
+
+impl<'a> SectionReader<'a, '_> {
+    fn read(&mut self, len: usize, section: RomSection) -> Result<Cow<'a, [u8]>, RomReadError> {
+        let start = self.offset.min(self.filebytes.len());
+        let end = self.offset.saturating_add(len).min(self.filebytes.len());
+        self.offset = self.offset.saturating_add(len);
+        let bytes = &self.filebytes[start..end];
+        if bytes.len() == len {
+            return Ok(Cow::Borrowed(bytes));
+        }
+
+        // Nothing is allocated for the missing part, which the header could
+        // make as large as the size limits
+        let got = bytes.len();
+        if self.options.strict_size_check {
+            return Err(RomReadError::TruncatedData {
+                section,
+                expected: len,
+                got,
+            });
+        }
+        self.warnings.push(ParseWarning::TruncatedSection {
+            section,
+            expected: len,
+            got,
+        });
+        Ok(Cow::Borrowed(bytes))
+    }
+
+    // For the fixed size sections, which are zero padded if cut short
+    fn read_array<const N: usize>(&mut self, section: RomSection) -> Result<[u8; N], RomReadError> {
+        let bytes = self.read(N, section)?;
+        let mut array = [0; N];
+        array[..bytes.len()].copy_from_slice(&bytes);
+        Ok(array)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub(super) fn header(prg_banks: u8, chr_banks: u8) -> [u8; HEADER_SIZE] {
+        let mut header = [0; HEADER_SIZE];
+        header[..4].copy_from_slice(b"NES\x1A");
+        header[4] = prg_banks;
+        header[5] = chr_banks;
+        header
+    }
+
+    // The header and the iNES 1.0 sized ROM it declares, each PRG bank
+    // filled with its number and each CHR bank with $80 plus its number
+    pub(super) fn rom_file(header: [u8; HEADER_SIZE]) -> Vec<u8> {
+        let mut file = header.to_vec();
+        for bank in 0..header[4] {
+            file.extend([bank; PRG_BANK_SIZE]);
+        }
+        for bank in 0..header[5] {
+            file.extend([0x80 | bank; CHR_BANK_SIZE]);
+        }
+        file
+    }
+
+    #[test]
+    fn loads_prg_and_chr_rom() {
+        let cartridge = CartridgeData::new(rom_file(header(2, 1))).unwrap();
+        assert_eq!(cartridge.prg_rom().len(), 2 * PRG_BANK_SIZE);
+        assert_eq!(cartridge.chr_rom().len(), CHR_BANK_SIZE);
+        assert!(cartridge.prg_rom()[..PRG_BANK_SIZE]
+            .iter()
+            .all(|&byte| byte == 0));
+        assert!(cartridge.prg_rom()[PRG_BANK_SIZE..]
+            .iter()
+            .all(|&byte| byte == 1));
+        assert!(cartridge.chr_rom().iter().all(|&byte| byte == 0x80));
+    }
+
+    #[test]
+    fn loads_one_bank_of_each() {
+        let cartridge = CartridgeData::new(rom_file(header(1, 1))).unwrap();
+        assert_eq!(cartridge.prg_rom(), [0; PRG_BANK_SIZE]);
+        assert_eq!(cartridge.chr_rom(), [0x80; CHR_BANK_SIZE]);
+    }
+
+    #[test]
+    fn prg_rom_follows_the_trainer() {
+        let mut header = header(1, 1);
+        header[6] = 0b00000100;
+        let mut file = rom_file(header);
+        file.splice(HEADER_SIZE..HEADER_SIZE, [0xEE; TRAINER_SIZE]);
+        let cartridge = CartridgeData::new(file).unwrap();
+        assert_eq!(cartridge.trainer(), Some(&[0xEE; TRAINER_SIZE]));
+        assert_eq!(cartridge.prg_rom(), [0; PRG_BANK_SIZE]);
+        assert_eq!(cartridge.chr_rom(), [0x80; CHR_BANK_SIZE]);
+    }
+
+    #[test]
+    fn truncated_file_is_an_error() {
+        let mut file = rom_file(header(1, 1));
+        file.truncate(HEADER_SIZE + PRG_BANK_SIZE + 100);
+        assert!(matches!(
+            CartridgeData::new(file),
+            Err(RomReadError::TruncatedData {
+                section: RomSection::ChrRom,
+                expected: CHR_BANK_SIZE,
+                got: 100,
+            })
+        ));
+    }
+
+    fn mirroring(flags_6: u8) -> Mirroring {
+        let mut header = header(1, 1);
+        header[6] = flags_6;
+        CartridgeData::new(rom_file(header)).unwrap().mirroring()
+    }
+
+    #[test]
+    fn mirroring_from_flags_6() {
+        assert_eq!(mirroring(0b0000), Mirroring::Horizontal);
+        assert_eq!(mirroring(0b0001), Mirroring::Vertical);
+        assert_eq!(mirroring(0b1000), Mirroring::FourScreen);
+    }
+
+    pub(super) fn nes2_header(prg_banks: u8, chr_banks: u8) -> [u8; HEADER_SIZE] {
+        let mut header = header(prg_banks, chr_banks);
+        header[7] = 0b00001000;
+        header
+    }
+
+    #[test]
+    fn submapper_and_mapper_msb_share_byte_8() {
+        let mut header = nes2_header(1, 1);
+        header[8] = 0x5A;
+        let cartridge = CartridgeData::new(rom_file(header)).unwrap();
+        assert_eq!(cartridge.submapper(), 5);
+        assert_eq!(cartridge.mapper_number() >> 8, 0xA);
+    }
+
+    #[test]
+    fn four_screen_overrides_the_mirroring_bit() {
+        assert_eq!(mirroring(0b1001), Mirroring::FourScreen);
+        // The other flags don't get in the way
+        assert_eq!(mirroring(0b0011), Mirroring::Vertical);
+    }
+
+    #[test]
+    fn battery_cartridge_has_prg_ram() {
+        let mut header = header(1, 1);
+        header[6] = 0b00000010;
+        let cartridge = CartridgeData::new(rom_file(header)).unwrap();
+        assert!(cartridge.is_battery_backed());
+        assert_eq!(cartridge.prg_ram().len(), DEFAULT_PRG_RAM_SIZE);
+    }
+
+    fn nes2_with_ram(byte_10: u8, byte_11: u8) -> CartridgeData {
+        let mut header = nes2_header(1, 1);
+        header[10] = byte_10;
+        header[11] = byte_11;
+        CartridgeData::new(rom_file(header)).unwrap()
+    }
+
+    #[test]
+    fn prg_ram_shift_counts() {
+        assert_eq!(nes2_with_ram(0x00, 0).prg_ram_size(), 0);
+        assert_eq!(nes2_with_ram(0x07, 0).prg_ram_size(), 8192);
+        assert_eq!(nes2_with_ram(0x0F, 0).prg_ram_size(), 2 * 1024 * 1024);
+        assert_eq!(nes2_with_ram(0x0F, 0).prg_ram().len(), 2 * 1024 * 1024);
+    }
+
+    #[test]
+    fn prg_ram_and_nvram_sizes() {
+        let cartridge = nes2_with_ram(0x75, 0);
+        assert_eq!(cartridge.prg_ram_size(), 2048);
+        assert_eq!(cartridge.prg_nvram_size(), 8192);
+        assert_eq!(cartridge.prg_ram().len(), 2048);
+        assert_eq!(cartridge.prg_nvram().len(), 8192);
+
+        // With only battery-backed RAM, that's what's at $6000
+        let mut cartridge = nes2_with_ram(0x70, 0);
+        assert_eq!(cartridge.prg_ram_size(), 0);
+        cartridge.prg_ram_mut()[0] = 0x42;
+        assert_eq!(cartridge.prg_nvram()[0], 0x42);
+    }
+
+    #[test]
+    fn ines_battery_ram_is_nvram() {
+        let mut header = header(1, 1);
+        header[6] = 0b00000010;
+        let cartridge = CartridgeData::new(rom_file(header)).unwrap();
+        assert_eq!(cartridge.prg_ram_size(), 0);
+        assert_eq!(cartridge.prg_nvram_size(), DEFAULT_PRG_RAM_SIZE);
+
+        let cartridge = CartridgeData::new(rom_file(self::header(1, 1))).unwrap();
+        assert_eq!(cartridge.prg_ram_size(), DEFAULT_PRG_RAM_SIZE);
+        assert_eq!(cartridge.prg_nvram_size(), 0);
+    }
+
+    #[test]
+    fn chr_ram_sizes() {
+        let cartridge = CartridgeData::new(rom_file(header(1, 0))).unwrap();
+        assert_eq!(cartridge.chr_ram_size(), DEFAULT_CHR_RAM_SIZE);
+        assert_eq!(cartridge.chr_ram().len(), DEFAULT_CHR_RAM_SIZE);
+
+        let cartridge = CartridgeData::new(rom_file(nes2_header(1, 0))).unwrap();
+        assert_eq!(cartridge.chr_ram_size(), 0);
+        assert!(!cartridge.has_chr_ram());
+
+        let mut header = nes2_header(1, 0);
+        header[11] = 0x57;
+        let cartridge = CartridgeData::new(rom_file(header)).unwrap();
+        assert_eq!(cartridge.chr_ram_size(), 8192);
+        assert_eq!(cartridge.chr_nvram_size(), 2048);
+        assert_eq!(cartridge.chr_ram().len(), 8192 + 2048);
+    }
+
+    #[test]
+    fn nes2_mapper_number_spans_three_nibbles() {
+        let mut header = nes2_header(1, 1);
+        header[6] = 0x40;
+        header[7] |= 0x10;
+        header[8] = 0x5A;
+        let cartridge = CartridgeData::new(rom_file(header)).unwrap();
+        assert_eq!(cartridge.mapper_number(), 0xA14);
+        assert_eq!(cartridge.submapper(), 5);
+
+        // iNES 1.0 has no byte 8 to read
+        let mut header = self::header(1, 1);
+        header[8] = 0x5A;
+        let cartridge = CartridgeData::new(rom_file(header)).unwrap();
+        assert_eq!(cartridge.mapper_number(), 0);
+        assert_eq!(cartridge.submapper(), 0);
+    }
+
+    #[test]
+    fn battery_flag() {
+        let mut header = header(1, 1);
+        let cartridge = CartridgeData::new(rom_file(header)).unwrap();
+        assert!(!cartridge.is_battery_backed() && !cartridge.has_battery());
+        header[6] = 0b00000010;
+        let cartridge = CartridgeData::new(rom_file(header)).unwrap();
+        assert!(cartridge.is_battery_backed() && cartridge.has_battery());
+    }
+
+    #[test]
+    fn format_of_each_header_layout() {
+        let cartridge = CartridgeData::new(rom_file(header(1, 1))).unwrap();
+        assert_eq!(cartridge.header_version(), HeaderVersion::INes1);
+        assert_eq!(cartridge.format(), RomFormat::INes);
+
+        let cartridge = CartridgeData::new(rom_file(nes2_header(1, 1))).unwrap();
+        assert_eq!(cartridge.header_version(), HeaderVersion::Nes2);
+        assert_eq!(cartridge.format(), RomFormat::Nes2);
+    }
+
+    #[test]
+    fn region_from_the_header() {
+        let region =
+            |header: [u8; HEADER_SIZE]| CartridgeData::new(rom_file(header)).unwrap().region();
+        let mut header = nes2_header(1, 1);
+        for (timing, expected) in [
+            (0, Region::Ntsc),
+            (1, Region::Pal),
+            (2, Region::MultiRegion),
+            (3, Region::Dendy),
+        ] {
+            header[12] = timing;
+            assert_eq!(region(header), expected);
+        }
+
+        let mut header = self::header(1, 1);
+        assert_eq!(region(header), Region::Ntsc);
+        header[9] = 0b00000001;
+        assert_eq!(region(header), Region::Pal);
+    }
+
+    #[test]
+    fn new_strict_refuses_dirty_headers() {
+        let dirty_byte = |index: usize, value: u8| {
+            let mut header = header(1, 1);
+            header[index] = value;
+            match CartridgeData::new_strict(rom_file(header)) {
+                Err(RomReadError::DirtyHeader { byte }) => Some(byte),
+                _ => None,
+            }
+        };
+        assert_eq!(dirty_byte(7, 0b00000100), Some(7));
+        assert_eq!(dirty_byte(9, 0b00000010), Some(9));
+        assert_eq!(dirty_byte(10, 1), Some(10));
+        assert_eq!(dirty_byte(15, 1), Some(15));
+        // Flags that iNES 1.0 does use
+        assert_eq!(dirty_byte(7, 0x10), None);
+        assert_eq!(dirty_byte(9, 0b00000001), None);
+        assert!(CartridgeData::new_strict(rom_file(nes2_header(1, 1))).is_ok());
+    }
+
+    #[test]
+    fn dirty_padding_masks_the_mapper() {
+        let mut header = header(1, 1);
+        header[6] = 0x10;
+        header[7] = 0x40;
+        header[13] = b'x';
+        let cartridge = CartridgeData::new(rom_file(header)).unwrap();
+        assert_eq!(cartridge.mapper_number(), 1);
+        assert!(matches!(
+            CartridgeData::new_strict(rom_file(header)),
+            Err(RomReadError::DirtyHeader { byte: 13 })
+        ));
+    }
+
+    #[test]
+    fn exponent_multiplier_rom_size() {
+        // E=20, MM=1: 2^20 * 3
+        assert_eq!(
+            rom_size_in_bytes(20 << 2 | 1, 0x0F, PRG_BANK_SIZE),
+            Some(3 * 1024 * 1024)
+        );
+        assert_eq!(rom_size_in_bytes(0, 0x0F, PRG_BANK_SIZE), Some(1));
+        assert_eq!(
+            rom_size_in_bytes(0x02, 0x01, PRG_BANK_SIZE),
+            Some(0x102 * PRG_BANK_SIZE)
+        );
+        // E=63, MM=3: 2^63 * 7
+        assert_eq!(rom_size_in_bytes(0xFF, 0x0F, PRG_BANK_SIZE), None);
+
+        let mut header = nes2_header(0xFF, 0);
+        header[9] = 0x0F;
+        assert!(matches!(
+            CartridgeData::new(header.to_vec()),
+            Err(RomReadError::SizeOverflow {
+                section: RomSection::PrgRom
+            })
+        ));
+    }
+
+    #[test]
+    fn try_from_a_slice() {
+        let file = rom_file(header(1, 1));
+        let cartridge = CartridgeData::try_from(file.as_slice()).unwrap();
+        assert_eq!(cartridge.prg_rom_banks(), 1);
+        assert!(matches!(
+            CartridgeData::try_from(&file[..8]),
+            Err(RomReadError::TooShort)
+        ));
+        assert!(matches!(
+            CartridgeData::try_from(&file[1..]),
+            Err(RomReadError::InvalidHeader { index: 0 })
+        ));
+    }
+
+    #[test]
+    fn absurd_sizes_are_refused_from_the_header_alone() {
+        let mut header = nes2_header(0xFF, 0);
+        header[9] = 0x01;
+        let Err(RomReadError::SizeTooLarge {
+            section: RomSection::PrgRom,
+            declared,
+            limit,
+        }) = CartridgeData::new(header.to_vec())
+        else {
+            panic!("the PRG ROM size wasn't refused");
+        };
+        assert_eq!(declared, 0x1FF * PRG_BANK_SIZE);
+        assert_eq!(limit, ParseOptions::default().max_prg_rom_size);
+    }
+
+    #[test]
+    fn from_reader_and_from_file() {
+        let file = rom_file(header(1, 1));
+        let cartridge = CartridgeData::from_reader(std::io::Cursor::new(&file)).unwrap();
+        assert_eq!(cartridge.prg_rom(), &file[HEADER_SIZE..][..PRG_BANK_SIZE]);
+        assert!(matches!(
+            CartridgeData::from_file("no/such/game.nes"),
+            Err(RomReadError::Io(_))
+        ));
+    }
+
+    fn diskdude_header(flags_6: u8) -> [u8; HEADER_SIZE] {
+        let mut header = header(1, 1);
+        header[6] = flags_6;
+        header[7..].copy_from_slice(b"DiskDude!");
+        header
+    }
+
+    #[test]
+    fn diskdude_mapper_comes_from_byte_6() {
+        let cartridge = CartridgeData::new(rom_file(diskdude_header(0x31))).unwrap();
+        assert_eq!(cartridge.mapper_number(), 3);
+        assert_eq!(cartridge.mirroring(), Mirroring::Vertical);
+        assert_eq!(cartridge.raw_header()[7..], *b"DiskDude!");
+    }
+
+    #[test]
+    fn clones_share_the_rom() {
+        let cartridge = CartridgeData::new(rom_file(header(2, 1))).unwrap();
+        let clone = cartridge.clone();
+        assert_eq!(cartridge.prg_rom().as_ptr(), clone.prg_rom().as_ptr());
+        assert_eq!(cartridge.chr_rom().as_ptr(), clone.chr_rom().as_ptr());
+        // CHR ROM follows PRG ROM in the same buffer
+        let prg_rom_end = cartridge.prg_rom().as_ptr_range().end;
+        assert_eq!(prg_rom_end, cartridge.chr_rom().as_ptr());
+    }
+
+    #[test]
+    fn diskdude_mmc1_game_is_mapper_1() {
+        let cartridge = CartridgeData::new(rom_file(diskdude_header(0x10))).unwrap();
+        assert_eq!(cartridge.mapper_number(), 1);
+        assert_eq!(cartridge.header_version(), HeaderVersion::Archaic);
+        assert!(cartridge.is_archaic());
+        assert_eq!(cartridge.format(), RomFormat::INes);
+        assert_eq!(cartridge.warnings(), [ParseWarning::ArchaicHeader]);
+    }
+
+    #[test]
+    fn playchoice_sections_follow_chr_rom() {
+        let mut header = header(1, 1);
+        header[7] = 0b00000010;
+        let mut file = rom_file(header);
+        file.extend([0x11; INST_ROM_SIZE]);
+        file.extend([0x22; PROM_SIZE]);
+        let cartridge = CartridgeData::new(file).unwrap();
+        assert_eq!(cartridge.console_type(), ConsoleType::PlayChoice10);
+        assert_eq!(cartridge.inst_rom(), Some(&[0x11; INST_ROM_SIZE]));
+        assert_eq!(cartridge.prom(), Some(&[0x22; PROM_SIZE]));
+        assert_eq!(cartridge.chr_rom(), [0x80; CHR_BANK_SIZE]);
+        assert!(cartridge.warnings().is_empty());
+
+        let cartridge = CartridgeData::new(rom_file(self::header(1, 1))).unwrap();
+        assert_eq!(cartridge.inst_rom(), None);
+        assert_eq!(cartridge.prom(), None);
+    }
+
+    #[test]
+    fn misc_rom_and_expansion_device() {
+        let mut header = nes2_header(1, 1);
+        header[14] = 1;
+        header[15] = 0x08;
+        let mut file = rom_file(header);
+        file.extend(b"misc rom");
+        let cartridge = CartridgeData::new(file).unwrap();
+        assert_eq!(cartridge.misc_rom_count(), 1);
+        assert_eq!(cartridge.misc_rom(), b"misc rom");
+        assert_eq!(
+            cartridge.default_expansion_device(),
+            DefaultExpansionDevice::Zapper
+        );
+        assert!(cartridge.warnings().is_empty());
+
+        // Without the count the same bytes are just trailing data
+        let mut file = rom_file(nes2_header(1, 1));
+        file.extend(b"misc rom");
+        let cartridge = CartridgeData::new(file).unwrap();
+        assert!(cartridge.misc_rom().is_empty());
+        assert_eq!(
+            cartridge.warnings(),
+            [ParseWarning::TrailingBytes { count: 8 }]
+        );
+    }
+
+    fn lenient() -> ParseOptions {
+        ParseOptions {
+            strict_size_check: false,
+            ..ParseOptions::default()
+        }
+    }
+
+    #[test]
+    fn strict_size_check_in_both_modes() {
+        let mut file = rom_file(header(1, 1));
+        file.truncate(file.len() - 1);
+        assert!(matches!(
+            CartridgeData::new_with_options(&file, ParseOptions::default()),
+            Err(RomReadError::TruncatedData {
+                section: RomSection::ChrRom,
+                ..
+            })
+        ));
+        let cartridge = CartridgeData::new_with_options(&file, lenient()).unwrap();
+        assert_eq!(
+            cartridge.warnings(),
+            [ParseWarning::TruncatedSection {
+                section: RomSection::ChrRom,
+                expected: CHR_BANK_SIZE,
+                got: CHR_BANK_SIZE - 1,
+            }]
+        );
+    }
+
+    #[test]
+    fn allow_trailing_data_in_both_modes() {
+        let mut file = rom_file(header(1, 1));
+        file.extend([0; 4]);
+        let cartridge = CartridgeData::new_with_options(&file, ParseOptions::default()).unwrap();
+        assert_eq!(
+            cartridge.warnings(),
+            [ParseWarning::TrailingBytes { count: 4 }]
+        );
+        let options = ParseOptions {
+            allow_trailing_data: false,
+            ..ParseOptions::default()
+        };
+        assert!(matches!(
+            CartridgeData::new_with_options(&file, options),
+            Err(RomReadError::TrailingData { count: 4 })
+        ));
+    }
+
+    #[test]
+    fn allow_missing_trainer_in_both_modes() {
+        let mut header = header(1, 1);
+        header[6] = 0b00000100;
+        let file = rom_file(header);
+        let cartridge = CartridgeData::new_with_options(&file, ParseOptions::default()).unwrap();
+        assert!(cartridge.trainer().is_none());
+        assert!(cartridge.warnings().contains(&ParseWarning::MissingTrainer));
+        assert_eq!(cartridge.prg_rom()[0], 0);
+        let options = ParseOptions {
+            allow_missing_trainer: false,
+            ..ParseOptions::default()
+        };
+        assert!(matches!(
+            CartridgeData::new_with_options(&file, options),
+            Err(RomReadError::MissingTrainer)
+        ));
+    }
+
+    #[test]
+    fn treat_unknown_console_type_as_error_in_both_modes() {
+        let mut header = nes2_header(1, 1);
+        header[7] |= 0b11;
+        header[13] = 0x0E;
+        let file = rom_file(header);
+        let cartridge = CartridgeData::new_with_options(&file, ParseOptions::default()).unwrap();
+        assert_eq!(
+            cartridge.warnings(),
+            [ParseWarning::UnknownConsoleType { console_type: 0x0E }]
+        );
+        let options = ParseOptions {
+            treat_unknown_console_type_as_error: true,
+            ..ParseOptions::default()
+        };
+        assert!(matches!(
+            CartridgeData::new_with_options(&file, options),
+            Err(RomReadError::UnknownConsoleType { console_type: 0x0E })
+        ));
+    }
+
+    #[test]
+    fn size_limits() {
+        let file = rom_file(header(2, 1));
+        let options = ParseOptions {
+            max_prg_rom_size: PRG_BANK_SIZE,
+            ..ParseOptions::default()
+        };
+        assert!(matches!(
+            CartridgeData::new_with_options(&file, options),
+            Err(RomReadError::SizeTooLarge {
+                section: RomSection::PrgRom,
+                declared,
+                limit: PRG_BANK_SIZE,
+            }) if declared == 2 * PRG_BANK_SIZE
+        ));
+        let options = ParseOptions {
+            max_prg_rom_size: 2 * PRG_BANK_SIZE,
+            ..ParseOptions::default()
+        };
+        assert!(CartridgeData::new_with_options(&file, options).is_ok());
+    }
+
+    #[test]
+    fn strict_options_refuse_everything() {
+        let mut file = rom_file(header(1, 1));
+        file.push(0);
+        assert!(CartridgeData::new_with_options(&file, ParseOptions::strict()).is_err());
+        file.pop();
+        assert!(CartridgeData::new_with_options(&file, ParseOptions::strict()).is_ok());
+    }
+
+    fn warnings(header: [u8; HEADER_SIZE], extra: &[u8]) -> Vec<ParseWarning> {
+        let mut file = rom_file(header);
+        file.extend(extra);
+        let cartridge = CartridgeData::new_with_options(&file, lenient()).unwrap();
+        cartridge.warnings().to_vec()
+    }
+
+    #[test]
+    fn each_warning_kind() {
+        assert!(warnings(header(1, 1), &[]).is_empty());
+        assert_eq!(
+            warnings(header(1, 1), &[0; 100]),
+            [ParseWarning::TrailingBytes { count: 100 }]
+        );
+
+        let mut dirty = header(1, 1);
+        dirty[10] = 0x01;
+        assert_eq!(
+            warnings(dirty, &[]),
+            [ParseWarning::DirtyPadding { byte: 10 }]
+        );
+        dirty[15] = 0x01;
+        assert_eq!(warnings(dirty, &[]), [ParseWarning::ArchaicHeader]);
+
+        let mut cnrom = header(1, 0);
+        cnrom[6] = 0x30;
+        assert_eq!(
+            warnings(cnrom, &[]),
+            [ParseWarning::MissingChrRom { mapper: 3 }]
+        );
+
+        // A trainer that really is there, on a mapper with no use for it
+        let mut trainer = header(1, 1);
+        trainer[6] = 0b00000100;
+        let mut file = trainer.to_vec();
+        file.extend([0xEE; TRAINER_SIZE]);
+        file.extend(&rom_file(trainer)[HEADER_SIZE..]);
+        let cartridge = CartridgeData::new(file).unwrap();
+        assert_eq!(
+            cartridge.warnings(),
+            [ParseWarning::UnexpectedTrainer { mapper: 0 }]
+        );
+        // Mapper 6 is one of the copier modes the trainer was for
+        trainer[6] |= 0x60;
+        assert_eq!(warnings(trainer, &[]), [ParseWarning::MissingTrainer]);
+
+        let mut short = rom_file(header(1, 2));
+        short.truncate(short.len() - CHR_BANK_SIZE);
+        let cartridge = CartridgeData::new_with_options(&short, lenient()).unwrap();
+        assert_eq!(
+            cartridge.warnings(),
+            [ParseWarning::TruncatedSection {
+                section: RomSection::ChrRom,
+                expected: 2 * CHR_BANK_SIZE,
+                got: CHR_BANK_SIZE,
+            }]
+        );
+    }
+
+    #[test]
+    fn file_length_against_the_header() {
+        let exact = rom_file(header(2, 1));
+        let cartridge = CartridgeData::new(exact.clone()).unwrap();
+        assert_eq!(cartridge.expected_file_len(), exact.len());
+        assert!(cartridge.warnings().is_empty());
+
+        let short = &exact[..exact.len() - 1];
+        assert!(matches!(
+            CartridgeData::new(short.to_vec()),
+            Err(RomReadError::TruncatedData {
+                section: RomSection::ChrRom,
+                expected: CHR_BANK_SIZE,
+                got,
+            }) if got == CHR_BANK_SIZE - 1
+        ));
+        // Loaded anyway, it still knows how long the file should have been
+        let cartridge = CartridgeData::new_with_options(short, lenient()).unwrap();
+        assert_eq!(cartridge.expected_file_len() - short.len(), 1);
+
+        let mut long = exact;
+        long.extend([0xFF; 100]);
+        let cartridge = CartridgeData::new(long.clone()).unwrap();
+        assert_eq!(
+            cartridge.warnings(),
+            [ParseWarning::TrailingBytes { count: 100 }]
+        );
+        assert_eq!(cartridge.expected_file_len() + 100, long.len());
+    }
+
+    #[test]
+    fn bank_numbers_wrap() {
+        let cartridge = CartridgeData::new(rom_file(header(2, 1))).unwrap();
+        assert_eq!(cartridge.prg_bank_count_16k(), 2);
+        assert_eq!(cartridge.prg_bank_16k(5), [1; PRG_BANK_SIZE]);
+        assert_eq!(cartridge.prg_bank_count_8k(), 4);
+        assert_eq!(cartridge.prg_bank_8k(5), [0; 0x2000]);
+        assert_eq!(cartridge.prg_bank_8k(6), [1; 0x2000]);
+        assert_eq!(cartridge.chr_bank_count_1k(), 8);
+        assert_eq!(cartridge.chr_bank_4k(3), [0x80; 0x1000]);
+        assert_eq!(cartridge.chr_bank_1k(9).len(), 0x400);
+
+        let cartridge = CartridgeData::new(rom_file(header(1, 0))).unwrap();
+        assert_eq!(cartridge.chr_bank_count_8k(), 0);
+        assert!(cartridge.chr_bank_8k(0).is_empty());
+    }
+
+    fn with_trainer() -> CartridgeData {
+        let mut header = header(1, 1);
+        header[6] = 0b00000100;
+        let mut file = header.to_vec();
+        file.extend((0..TRAINER_SIZE).map(|i| i as u8));
+        file.extend(&rom_file(header)[HEADER_SIZE..]);
+        CartridgeData::new(file).unwrap()
+    }
+
+    #[test]
+    fn trainer_goes_to_7000() {
+        let cartridge = with_trainer();
+        let trainer = cartridge.trainer().unwrap();
+        assert_eq!(trainer[0x1FF], 0xFF);
+        let mut prg_ram = vec![0; 0x2000];
+        cartridge.load_trainer_into(&mut prg_ram).unwrap();
+        assert_eq!(prg_ram[0x1000..0x1200], trainer[..]);
+        assert!(prg_ram[..0x1000].iter().all(|&byte| byte == 0));
+        assert!(prg_ram[0x1200..].iter().all(|&byte| byte == 0));
+
+        // At power-on it goes in the cartridge's own RAM
+        let mut cartridge = with_trainer();
+        cartridge.load_trainer().unwrap();
+        assert_eq!(cartridge.prg_ram()[0x1000..0x1200], trainer[..]);
+    }
+
+    #[test]
+    fn trainer_needs_room_up_to_71ff() {
+        let cartridge = with_trainer();
+        let mut prg_ram = vec![0; 0x11FF];
+        assert_eq!(
+            cartridge.load_trainer_into(&mut prg_ram),
+            Err(PrgRamTooSmall { len: 0x11FF })
+        );
+        assert!(prg_ram.iter().all(|&byte| byte == 0));
+        assert!(cartridge.load_trainer_into(&mut [0; 0x1200]).is_ok());
+
+        // Nothing to do without a trainer
+        let cartridge = CartridgeData::new(rom_file(header(1, 1))).unwrap();
+        assert!(cartridge.load_trainer_into(&mut []).is_ok());
+    }
+}
@@ -0,0 +1,257 @@
+// NES Sound Format, ripped music with the game code that plays it
+// https://www.nesdev.org/wiki/NSF
+
+use super::{Region, RomReadError};
+
+const NSF_HEADER_SIZE: usize = 128;
+const NSF_BANK_SIZE: usize = 4096;
+
+#[derive(Clone)]
+pub struct NsfFile {
+    version: u8,
+    song_count: u8,
+    // 1-based, like the header
+    starting_song: u8,
+    load_address: u16,
+    init_address: u16,
+    play_address: u16,
+    song_name: String,
+    artist: String,
+    copyright: String,
+    // Microseconds between calls to the play routine
+    ntsc_speed: u16,
+    pal_speed: u16,
+    // All zero when the tune doesn't bankswitch
+    bankswitch_init: [u8; 8],
+    region: Region,
+    // Bit 0 VRC6, 1 VRC7, 2 FDS, 3 MMC5, 4 Namco 163, 5 Sunsoft 5B
+    expansion_audio: u8,
+    data: Vec<u8>,
+}
+
+impl NsfFile {
+    pub fn new(filebytes: &[u8]) -> Result<NsfFile, RomReadError> {
+        if filebytes.len() < NSF_HEADER_SIZE {
+            return Err(RomReadError::TooShort);
+        }
+        for (index, byte) in b"NESM\x1A".iter().enumerate() {
+            if filebytes[index] != *byte {
+                return Err(RomReadError::InvalidHeader { index });
+            }
+        }
+        let header = &filebytes[..NSF_HEADER_SIZE];
+        let word = |index: usize| u16::from_le_bytes([header[index], header[index + 1]]);
+        // Padded with zeros, though not always terminated
+        let text = |range: std::ops::Range<usize>| {
+            let field = &header[range];
+            let end = field
+                .iter()
+                .position(|&byte| byte == 0)
+                .unwrap_or(field.len());
+            String::from_utf8_lossy(&field[..end]).into_owned()
+        };
+
+        // Version 2 (NSF2) is a superset of version 1
+        let version = header[5];
+        if !(1..=2).contains(&version) {
+            return Err(RomReadError::InvalidNsfVersion { version });
+        }
+
+        let load_address = word(0x08);
+        let mut bankswitch_init = [0; 8];
+        bankswitch_init.copy_from_slice(&header[0x70..0x78]);
+        let bankswitched = bankswitch_init.iter().any(|&bank| bank != 0);
+
+        // NSF2 can give the data length, leaving room for metadata after it
+        let remaining = filebytes.len() - NSF_HEADER_SIZE;
+        let declared_len = u32::from_le_bytes([header[0x7D], header[0x7E], header[0x7F], 0]);
+        let data_len = if version == 2 && declared_len != 0 {
+            (declared_len as usize).min(remaining)
+        } else {
+            remaining
+        };
+        let data = filebytes[NSF_HEADER_SIZE..NSF_HEADER_SIZE + data_len].to_vec();
+
+        // Without bankswitching the data is copied straight to $8000-$FFFF.
+        // With it, the data is split into 4 KB banks after padding it so the
+        // load address falls at the same place within its bank.
+        if bankswitched {
+            let padded_len = (load_address as usize & 0x0FFF) + data.len();
+            if padded_len > 256 * NSF_BANK_SIZE {
+                return Err(RomReadError::NsfDataTooLarge { len: data.len() });
+            }
+        } else {
+            if load_address < 0x8000 {
+                return Err(RomReadError::InvalidLoadAddress {
+                    address: load_address,
+                });
+            }
+            if load_address as usize + data.len() > 0x10000 {
+                return Err(RomReadError::NsfDataTooLarge { len: data.len() });
+            }
+        }
+
+        let region = match header[0x7A] & 0b11 {
+            0 => Region::Ntsc,
+            1 => Region::Pal,
+            _ => Region::MultiRegion,
+        };
+
+        Ok(NsfFile {
+            version,
+            song_count: header[0x06],
+            starting_song: header[0x07],
+            load_address,
+            init_address: word(0x0A),
+            play_address: word(0x0C),
+            song_name: text(0x0E..0x2E),
+            artist: text(0x2E..0x4E),
+            copyright: text(0x4E..0x6E),
+            ntsc_speed: word(0x6E),
+            pal_speed: word(0x78),
+            bankswitch_init,
+            region,
+            expansion_audio: header[0x7B],
+            data,
+        })
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn song_count(&self) -> u8 {
+        self.song_count
+    }
+
+    pub fn starting_song(&self) -> u8 {
+        self.starting_song
+    }
+
+    pub fn load_address(&self) -> u16 {
+        self.load_address
+    }
+
+    // Called with the song number (0-based) in A and the region in X
+    pub fn init_address(&self) -> u16 {
+        self.init_address
+    }
+
+    pub fn play_address(&self) -> u16 {
+        self.play_address
+    }
+
+    pub fn song_name(&self) -> &str {
+        &self.song_name
+    }
+
+    pub fn artist(&self) -> &str {
+        &self.artist
+    }
+
+    pub fn copyright(&self) -> &str {
+        &self.copyright
+    }
+
+    pub fn ntsc_speed(&self) -> u16 {
+        self.ntsc_speed
+    }
+
+    pub fn pal_speed(&self) -> u16 {
+        self.pal_speed
+    }
+
+    pub fn is_bankswitched(&self) -> bool {
+        self.bankswitch_init.iter().any(|&bank| bank != 0)
+    }
+
+    // Banks loaded into $8000-$8FFF through $F000-$FFFF when a song starts
+    pub fn bankswitch_init(&self) -> [u8; 8] {
+        self.bankswitch_init
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn expansion_audio(&self) -> u8 {
+        self.expansion_audio
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two songs loading at $8000, with the init and play routines after a
+    // few bytes of data
+    fn nsf() -> Vec<u8> {
+        let mut file = vec![0; NSF_HEADER_SIZE];
+        file[..5].copy_from_slice(b"NESM\x1A");
+        file[5] = 1;
+        file[6] = 2;
+        file[7] = 1;
+        file[0x08..0x0E].copy_from_slice(&[0x00, 0x80, 0x03, 0x80, 0x04, 0x80]);
+        file[0x0E..0x13].copy_from_slice(b"Title");
+        file[0x2E..0x33].copy_from_slice(b"Si\xc3\xa2n");
+        file[0x4E..0x6E].fill(b'c');
+        file[0x6E..0x70].copy_from_slice(&16639u16.to_le_bytes());
+        file.extend([0xEA, 0xEA, 0xEA, 0x60, 0x60]);
+        file
+    }
+
+    #[test]
+    fn parses_the_header() {
+        let nsf = NsfFile::new(&nsf()).unwrap();
+        assert_eq!(nsf.version(), 1);
+        assert_eq!(nsf.song_count(), 2);
+        assert_eq!(nsf.starting_song(), 1);
+        assert_eq!(nsf.load_address(), 0x8000);
+        assert_eq!(nsf.init_address(), 0x8003);
+        assert_eq!(nsf.play_address(), 0x8004);
+        assert_eq!(nsf.song_name(), "Title");
+        assert_eq!(nsf.artist(), "Siân");
+        // Unterminated
+        assert_eq!(nsf.copyright(), "c".repeat(32));
+        assert_eq!(nsf.ntsc_speed(), 16639);
+        assert!(!nsf.is_bankswitched());
+        assert_eq!(nsf.region(), Region::Ntsc);
+        assert_eq!(nsf.data(), [0xEA, 0xEA, 0xEA, 0x60, 0x60]);
+    }
+
+    #[test]
+    fn nsf2_data_length() {
+        let mut file = nsf();
+        file[5] = 2;
+        file[0x7D] = 3;
+        let nsf = NsfFile::new(&file).unwrap();
+        assert_eq!(nsf.data(), [0xEA, 0xEA, 0xEA]);
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let mut file = nsf();
+        file[5] = 3;
+        assert!(matches!(
+            NsfFile::new(&file),
+            Err(RomReadError::InvalidNsfVersion { version: 3 })
+        ));
+        let mut file = nsf();
+        file[0x09] = 0x60;
+        assert!(matches!(
+            NsfFile::new(&file),
+            Err(RomReadError::InvalidLoadAddress { address: 0x6000 })
+        ));
+        // Bankswitched tunes can load anywhere
+        file[0x70] = 1;
+        assert!(NsfFile::new(&file).unwrap().is_bankswitched());
+        assert!(matches!(
+            NsfFile::new(&nsf()[..100]),
+            Err(RomReadError::TooShort)
+        ));
+    }
+}
@@ -0,0 +1,380 @@
+// The parts of a cartridge that change while a game runs. A snapshot leaves
+// the ROM out and keeps its hash instead, so it can only be restored onto the
+// same dump.
+
+use super::CartridgeData;
+use crate::state::{StateError, StateReader, StateWriter};
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CartridgeSnapshot {
+    rom_sha1: [u8; 20],
+    prg_ram: Vec<u8>,
+    prg_nvram: Vec<u8>,
+    chr_ram: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SnapshotError {
+    // The snapshot was taken from a different ROM
+    RomMismatch,
+    // A RAM section isn't the size the cartridge has
+    RamSizeMismatch {
+        section: &'static str,
+        expected: usize,
+        got: usize,
+    },
+}
+
+impl CartridgeSnapshot {
+    pub fn rom_sha1(&self) -> [u8; 20] {
+        self.rom_sha1
+    }
+
+    pub fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    pub fn prg_nvram(&self) -> &[u8] {
+        &self.prg_nvram
+    }
+
+    pub fn chr_ram(&self) -> &[u8] {
+        &self.chr_ram
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.rom_sha1);
+        state.vec(&self.prg_ram);
+        state.vec(&self.prg_nvram);
+        state.vec(&self.chr_ram);
+    }
+
+    pub fn load_state(state: &mut StateReader) -> Result<CartridgeSnapshot, StateError> {
+        let mut rom_sha1 = [0; 20];
+        state.fill(&mut rom_sha1)?;
+        Ok(CartridgeSnapshot {
+            rom_sha1,
+            prg_ram: state.vec()?,
+            prg_nvram: state.vec()?,
+            chr_ram: state.vec()?,
+        })
+    }
+}
+
+impl CartridgeData {
+    pub fn snapshot(&self) -> CartridgeSnapshot {
+        CartridgeSnapshot {
+            rom_sha1: self.rom_sha1(),
+            prg_ram: self.prg_ram.clone(),
+            prg_nvram: self.prg_nvram.clone(),
+            chr_ram: self.chr_ram.clone(),
+        }
+    }
+
+    // Leaves the cartridge untouched if the snapshot doesn't fit it
+    pub fn restore_snapshot(&mut self, snapshot: &CartridgeSnapshot) -> Result<(), SnapshotError> {
+        if snapshot.rom_sha1 != self.rom_sha1() {
+            return Err(SnapshotError::RomMismatch);
+        }
+        for (section, expected, got) in [
+            ("PRG RAM", self.prg_ram.len(), snapshot.prg_ram.len()),
+            ("PRG NVRAM", self.prg_nvram.len(), snapshot.prg_nvram.len()),
+            ("CHR RAM", self.chr_ram.len(), snapshot.chr_ram.len()),
+        ] {
+            if expected != got {
+                return Err(SnapshotError::RamSizeMismatch {
+                    section,
+                    expected,
+                    got,
+                });
+            }
+        }
+        self.prg_ram.copy_from_slice(&snapshot.prg_ram);
+        self.prg_nvram.copy_from_slice(&snapshot.prg_nvram);
+        self.chr_ram.copy_from_slice(&snapshot.chr_ram);
+        Ok(())
+    }
+}
+
+// Field adapters for the types serde can't derive Serialize for on its own:
+// shared and oversized byte arrays, and errors that are only worth keeping as
+// text. CartridgeData deserializes through CartridgeFields, which turns away
+// fields that disagree with each other before a mapper indexes with them.
+#[cfg(feature = "serde")]
+pub(super) mod serde_with {
+    use std::fmt;
+    use std::sync::Arc;
+
+    use serde::{Deserialize, Serialize, Serializer};
+
+    use super::super::{
+        hash, CartridgeData, ConsoleType, DefaultExpansionDevice, HeaderVersion, Mirroring,
+        ParseWarning, Region, HEADER_SIZE, INST_ROM_SIZE, PROM_SIZE, TRAINER_SIZE,
+    };
+
+    pub fn display<S: Serializer>(
+        value: &impl fmt::Display,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(value)
+    }
+
+    struct Bytes<'a>(&'a [u8]);
+
+    impl Serialize for Bytes<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_bytes(self.0)
+        }
+    }
+
+    pub fn shared_bytes<S: Serializer>(
+        value: &Arc<[u8]>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        Bytes(value).serialize(serializer)
+    }
+
+    pub trait ByteArray {
+        fn bytes(&self) -> &[u8];
+    }
+
+    impl<const N: usize> ByteArray for [u8; N] {
+        fn bytes(&self) -> &[u8] {
+            self
+        }
+    }
+
+    impl<const N: usize> ByteArray for Box<[u8; N]> {
+        fn bytes(&self) -> &[u8] {
+            self.as_slice()
+        }
+    }
+
+    // For Option<[u8; N]> and Option<Box<[u8; N]>>
+    pub fn optional_bytes<S, T>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        T: ByteArray,
+    {
+        value
+            .as_ref()
+            .map(|bytes| Bytes(bytes.bytes()))
+            .serialize(serializer)
+    }
+
+    // CartridgeData's fields in the order they're serialized, with the
+    // byte arrays as plain vectors
+    #[derive(Deserialize)]
+    pub struct CartridgeFields {
+        header: [u8; HEADER_SIZE],
+        header_version: HeaderVersion,
+        console_type: ConsoleType,
+        rom: Vec<u8>,
+        prg_rom_len: usize,
+        prg_rom_file_len: usize,
+        chr_rom_file_len: usize,
+        declared_prg_rom_len: usize,
+        declared_chr_rom_len: usize,
+        mapper_number: u16,
+        submapper: u8,
+        mirroring: Mirroring,
+        trainer: Option<Vec<u8>>,
+        inst_rom: Option<Vec<u8>>,
+        prom: Option<[u8; PROM_SIZE]>,
+        misc_rom_count: u8,
+        misc_rom: Vec<u8>,
+        default_expansion_device: DefaultExpansionDevice,
+        battery_backed: bool,
+        prg_ram_size: usize,
+        prg_nvram_size: usize,
+        prg_ram: Vec<u8>,
+        prg_nvram: Vec<u8>,
+        chr_ram_size: usize,
+        chr_nvram_size: usize,
+        chr_ram: Vec<u8>,
+        region: Region,
+        warnings: Vec<ParseWarning>,
+    }
+
+    impl TryFrom<CartridgeFields> for CartridgeData {
+        type Error = &'static str;
+
+        fn try_from(fields: CartridgeFields) -> Result<CartridgeData, &'static str> {
+            let chr_rom_len = fields
+                .rom
+                .len()
+                .checked_sub(fields.prg_rom_len)
+                .ok_or("PRG ROM is longer than the ROM data")?;
+            if fields.prg_rom_file_len > fields.prg_rom_len || fields.chr_rom_file_len > chr_rom_len
+            {
+                return Err("the file lengths are longer than the ROM data");
+            }
+            if fields.prg_ram.len() != fields.prg_ram_size
+                || fields.prg_nvram.len() != fields.prg_nvram_size
+                || Some(fields.chr_ram.len())
+                    != fields.chr_ram_size.checked_add(fields.chr_nvram_size)
+            {
+                return Err("a RAM section isn't the size the cartridge declares");
+            }
+            let trainer = match fields.trainer {
+                Some(trainer) => Some(
+                    <[u8; TRAINER_SIZE]>::try_from(trainer)
+                        .map_err(|_| "the trainer isn't 512 bytes")?,
+                ),
+                None => None,
+            };
+            let inst_rom = match fields.inst_rom {
+                Some(inst_rom) => Some(
+                    Box::<[u8; INST_ROM_SIZE]>::try_from(inst_rom.into_boxed_slice())
+                        .map_err(|_| "the INST-ROM isn't 8 KB")?,
+                ),
+                None => None,
+            };
+            Ok(CartridgeData {
+                header: fields.header,
+                header_version: fields.header_version,
+                console_type: fields.console_type,
+                rom: fields.rom.into(),
+                prg_rom_len: fields.prg_rom_len,
+                prg_rom_file_len: fields.prg_rom_file_len,
+                chr_rom_file_len: fields.chr_rom_file_len,
+                declared_prg_rom_len: fields.declared_prg_rom_len,
+                declared_chr_rom_len: fields.declared_chr_rom_len,
+                mapper_number: fields.mapper_number,
+                submapper: fields.submapper,
+                mirroring: fields.mirroring,
+                trainer,
+                inst_rom,
+                prom: fields.prom,
+                misc_rom_count: fields.misc_rom_count,
+                misc_rom: fields.misc_rom,
+                default_expansion_device: fields.default_expansion_device,
+                battery_backed: fields.battery_backed,
+                prg_ram_size: fields.prg_ram_size,
+                prg_nvram_size: fields.prg_nvram_size,
+                prg_ram: fields.prg_ram,
+                prg_nvram: fields.prg_nvram,
+                chr_ram_size: fields.chr_ram_size,
+                chr_nvram_size: fields.chr_nvram_size,
+                chr_ram: fields.chr_ram,
+                region: fields.region,
+                warnings: fields.warnings,
+                hashes: hash::HashCache::default(),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{header, rom_file};
+    use super::*;
+
+    fn cartridge() -> CartridgeData {
+        // Battery-backed, with CHR RAM
+        let mut header = header(1, 0);
+        header[6] = 0b00000010;
+        CartridgeData::new(rom_file(header)).unwrap()
+    }
+
+    fn played() -> CartridgeData {
+        let mut cartridge = cartridge();
+        cartridge.prg_ram_mut()[0x10] = 0x42;
+        cartridge.chr_ram_mut()[0x20] = 0x99;
+        cartridge
+    }
+
+    #[test]
+    fn state_format_round_trip() {
+        let snapshot = played().snapshot();
+        let mut state = StateWriter::new();
+        snapshot.save_state(&mut state);
+        let bytes = state.into_bytes();
+        let mut reader = StateReader::new(&bytes);
+        assert_eq!(
+            CartridgeSnapshot::load_state(&mut reader).unwrap(),
+            snapshot
+        );
+        reader.finish().unwrap();
+
+        let mut restored = cartridge();
+        restored.restore_snapshot(&snapshot).unwrap();
+        assert_eq!(restored.prg_nvram()[0x10], 0x42);
+        assert_eq!(restored.chr_ram()[0x20], 0x99);
+    }
+
+    #[test]
+    fn snapshots_only_fit_their_rom() {
+        let snapshot = played().snapshot();
+        let mut other = CartridgeData::new(rom_file(header(2, 0))).unwrap();
+        assert_eq!(
+            other.restore_snapshot(&snapshot),
+            Err(SnapshotError::RomMismatch)
+        );
+        assert_eq!(other.prg_ram()[0x10], 0);
+
+        let mut snapshot = snapshot;
+        snapshot.chr_ram.pop();
+        assert!(matches!(
+            cartridge().restore_snapshot(&snapshot),
+            Err(SnapshotError::RamSizeMismatch {
+                section: "CHR RAM",
+                ..
+            })
+        ));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_json_round_trip() {
+        let snapshot = played().snapshot();
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let deserialized: CartridgeSnapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(deserialized, snapshot);
+        let mut restored = cartridge();
+        restored.restore_snapshot(&deserialized).unwrap();
+        assert_eq!(restored.prg_nvram()[0x10], 0x42);
+
+        // A snapshot edited to claim another ROM is caught on restore
+        let mut tampered = deserialized;
+        tampered.rom_sha1[0] ^= 0xFF;
+        assert_eq!(
+            restored.restore_snapshot(&tampered),
+            Err(SnapshotError::RomMismatch)
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn bincode_round_trip() {
+        let snapshot = played().snapshot();
+        let bytes = bincode::serialize(&snapshot).unwrap();
+        let deserialized: CartridgeSnapshot = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(deserialized, snapshot);
+        assert!(bincode::deserialize::<CartridgeSnapshot>(&bytes[..bytes.len() - 1]).is_err());
+
+        let bytes = bincode::serialize(&played()).unwrap();
+        let cartridge: CartridgeData = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(bincode::serialize(&cartridge).unwrap(), bytes);
+        assert_eq!(cartridge.rom_sha1(), played().rom_sha1());
+        assert_eq!(cartridge.prg_nvram()[0x10], 0x42);
+        assert_eq!(cartridge.chr_ram()[0x20], 0x99);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn cartridges_serialize() {
+        let json = serde_json::to_value(cartridge()).unwrap();
+        assert_eq!(json["mapper_number"], 0);
+        assert_eq!(json["mirroring"], "Horizontal");
+        assert_eq!(json["battery_backed"], true);
+
+        let cartridge: CartridgeData = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&cartridge).unwrap(), json);
+        // Fields that disagree are turned away
+        let mut json = json;
+        json["prg_nvram_size"] = 0x1000.into();
+        assert!(serde_json::from_value::<CartridgeData>(json).is_err());
+    }
+}
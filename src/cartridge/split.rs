@@ -0,0 +1,101 @@
+// Raw PRG ROM and CHR ROM dumps, as used by graphics editors and assemblers
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use super::{hash, CartridgeData, RomReadError};
+
+impl CartridgeData {
+    pub fn write_prg(&self, mut w: impl Write) -> io::Result<()> {
+        w.write_all(self.prg_rom())
+    }
+
+    pub fn write_chr(&self, mut w: impl Write) -> io::Result<()> {
+        w.write_all(self.chr_rom())
+    }
+
+    // Writes stem.prg and stem.chr into dir. stem.chr is left out for boards
+    // with only CHR RAM.
+    pub fn split_to_files(&self, dir: &Path, stem: &str) -> io::Result<()> {
+        let mut prg_file = BufWriter::new(File::create(dir.join(format!("{stem}.prg")))?);
+        self.write_prg(&mut prg_file)?;
+        prg_file.flush()?;
+        if !self.chr_rom().is_empty() {
+            let mut chr_file = BufWriter::new(File::create(dir.join(format!("{stem}.chr")))?);
+            self.write_chr(&mut chr_file)?;
+            chr_file.flush()?;
+        }
+        Ok(())
+    }
+
+    // Reassembles a cartridge from PRG ROM and CHR ROM dumps, taking every
+    // other header field from template. The result is reparsed so sizes that
+    // aren't whole banks get padded and CHR RAM is worked out as usual.
+    pub fn from_parts(
+        prg_rom: Vec<u8>,
+        chr_rom: Vec<u8>,
+        template: &CartridgeData,
+    ) -> Result<CartridgeData, RomReadError> {
+        let mut cartridge = template.clone();
+        cartridge.prg_rom_len = prg_rom.len();
+        cartridge.prg_rom_file_len = prg_rom.len();
+        cartridge.chr_rom_file_len = chr_rom.len();
+        cartridge.declared_prg_rom_len = prg_rom.len();
+        cartridge.declared_chr_rom_len = chr_rom.len();
+        cartridge.rom = Arc::from([prg_rom, chr_rom].concat());
+        cartridge.hashes = hash::HashCache::default();
+        CartridgeData::try_from(cartridge.to_nes2_bytes().as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::super::tests::{header, rom_file};
+    use super::*;
+
+    #[test]
+    fn split_and_reassemble() {
+        let mut header = header(2, 1);
+        header[6] = 0b00010011;
+        let original = CartridgeData::new(rom_file(header)).unwrap();
+        let dir = std::env::temp_dir().join(format!("zephyrnes-split-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        original.split_to_files(&dir, "game").unwrap();
+        let prg_rom = fs::read(dir.join("game.prg")).unwrap();
+        let chr_rom = fs::read(dir.join("game.chr")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(prg_rom, original.prg_rom());
+        assert_eq!(chr_rom, original.chr_rom());
+
+        let reassembled = CartridgeData::from_parts(prg_rom, chr_rom.clone(), &original).unwrap();
+        assert_eq!(reassembled.prg_rom(), original.prg_rom());
+        assert_eq!(reassembled.chr_rom(), original.chr_rom());
+        assert_eq!(reassembled.mapper_number(), 1);
+        assert_eq!(reassembled.mirroring(), original.mirroring());
+        assert!(reassembled.is_battery_backed());
+
+        // With edited graphics
+        let mut edited = chr_rom;
+        edited[0] = 0xFF;
+        let reassembled =
+            CartridgeData::from_parts(original.prg_rom().to_vec(), edited, &original).unwrap();
+        assert_eq!(reassembled.chr_rom()[0], 0xFF);
+        assert_eq!(reassembled.chr_rom()[1..], original.chr_rom()[1..]);
+        assert_ne!(reassembled.chr_crc32(), original.chr_crc32());
+    }
+
+    #[test]
+    fn chr_ram_boards_have_no_chr_file() {
+        let cartridge = CartridgeData::new(rom_file(header(1, 0))).unwrap();
+        let mut chr = Vec::new();
+        cartridge.write_chr(&mut chr).unwrap();
+        assert!(chr.is_empty());
+        let mut prg = Vec::new();
+        cartridge.write_prg(&mut prg).unwrap();
+        assert_eq!(prg, cartridge.prg_rom());
+    }
+}
@@ -0,0 +1,68 @@
+use std::fmt;
+
+use super::{CartridgeData, ConsoleType, HeaderVersion, Mirroring, Region};
+
+/// Everything decoded from the header, with sizes in bytes.
+///
+/// ```no_run
+/// use zephyrnes::cartridge::CartridgeData;
+///
+/// let cartridge = CartridgeData::from_file("game.nes").unwrap();
+/// println!("{}", cartridge.header_summary());
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeaderSummary {
+    pub header_version: HeaderVersion,
+    pub console_type: ConsoleType,
+    pub mapper_number: u16,
+    pub submapper: u8,
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub mirroring: Mirroring,
+    pub region: Region,
+    pub battery_backed: bool,
+    pub has_trainer: bool,
+}
+
+impl CartridgeData {
+    pub fn header_summary(&self) -> HeaderSummary {
+        HeaderSummary {
+            header_version: self.header_version,
+            console_type: self.console_type,
+            mapper_number: self.mapper_number,
+            submapper: self.submapper,
+            prg_rom_size: self.prg_rom().len(),
+            chr_rom_size: self.chr_rom().len(),
+            prg_ram_size: self.prg_ram_size,
+            prg_nvram_size: self.prg_nvram_size,
+            chr_ram_size: self.chr_ram_size,
+            chr_nvram_size: self.chr_nvram_size,
+            mirroring: self.mirroring,
+            region: self.region,
+            battery_backed: self.battery_backed,
+            has_trainer: self.has_trainer(),
+        }
+    }
+}
+
+impl fmt::Display for HeaderSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Header:     {:?}", self.header_version)?;
+        writeln!(f, "Console:    {:?}", self.console_type)?;
+        writeln!(f, "Mapper:     {}.{}", self.mapper_number, self.submapper)?;
+        writeln!(f, "PRG ROM:    {} bytes", self.prg_rom_size)?;
+        writeln!(f, "CHR ROM:    {} bytes", self.chr_rom_size)?;
+        writeln!(f, "PRG RAM:    {} bytes", self.prg_ram_size)?;
+        writeln!(f, "PRG NVRAM:  {} bytes", self.prg_nvram_size)?;
+        writeln!(f, "CHR RAM:    {} bytes", self.chr_ram_size)?;
+        writeln!(f, "CHR NVRAM:  {} bytes", self.chr_nvram_size)?;
+        writeln!(f, "Mirroring:  {:?}", self.mirroring)?;
+        writeln!(f, "Region:     {:?}", self.region)?;
+        writeln!(f, "Battery:    {}", self.battery_backed)?;
+        write!(f, "Trainer:    {}", self.has_trainer)
+    }
+}
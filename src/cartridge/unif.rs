@@ -0,0 +1,328 @@
+// UNIF file parsing, used by many unlicensed and multicart dumps
+// https://www.nesdev.org/wiki/UNIF
+
+use std::borrow::Cow;
+use std::sync::Arc;
+
+use super::{
+    check_size_limit, hash, pad_to_bank, CartridgeData, ConsoleType, DefaultExpansionDevice,
+    HeaderVersion, Mirroring, ParseOptions, Region, RomReadError, RomSection, CHR_BANK_SIZE,
+    DEFAULT_CHR_RAM_SIZE, DEFAULT_PRG_RAM_SIZE, PRG_BANK_SIZE,
+};
+
+// "UNIF", a little-endian revision number, then zero padding
+const UNIF_HEADER_SIZE: usize = 32;
+
+// Board names with their vendor prefix ("NES-", "UNL-", ...) removed
+const BOARDS: &[(&str, u16)] = &[
+    ("NROM", 0),
+    ("NROM-128", 0),
+    ("NROM-256", 0),
+    ("RROM", 0),
+    ("RROM-128", 0),
+    ("SAROM", 1),
+    ("SBROM", 1),
+    ("SCROM", 1),
+    ("SEROM", 1),
+    ("SGROM", 1),
+    ("SKROM", 1),
+    ("SLROM", 1),
+    ("SL1ROM", 1),
+    ("SNROM", 1),
+    ("SOROM", 1),
+    ("SUROM", 1),
+    ("SXROM", 1),
+    ("UNROM", 2),
+    ("UOROM", 2),
+    ("CNROM", 3),
+    ("HKROM", 4),
+    ("TBROM", 4),
+    ("TEROM", 4),
+    ("TFROM", 4),
+    ("TGROM", 4),
+    ("TKROM", 4),
+    ("TLROM", 4),
+    ("TR1ROM", 4),
+    ("TSROM", 4),
+    ("TVROM", 4),
+    ("EKROM", 5),
+    ("ELROM", 5),
+    ("ETROM", 5),
+    ("EWROM", 5),
+    ("AMROM", 7),
+    ("ANROM", 7),
+    ("AN1ROM", 7),
+    ("AOROM", 7),
+    ("PEEOROM", 9),
+    ("PNROM", 9),
+    ("CPROM", 13),
+    ("H2288", 123),
+    ("Sachen-8259D", 137),
+    ("Sachen-8259B", 138),
+    ("Sachen-8259C", 139),
+    ("Sachen-8259A", 141),
+    ("KS7032", 142),
+    ("SA-NROM", 143),
+    ("SA-72007", 145),
+    ("TC-U01-1.5M", 147),
+    ("FK23C", 176),
+    ("Super24in1SC03", 176),
+    ("NovelDiamond9999999in1", 201),
+    ("8237", 215),
+    ("Ghostbusters63in1", 226),
+    ("70in1", 236),
+    ("GS-2004", 283),
+    ("GS-2013", 283),
+];
+
+pub fn mapper_for_board(board: &str) -> Option<u16> {
+    let name = ["NES-", "HVC-", "UNL-", "BMC-", "BTL-"]
+        .iter()
+        .find_map(|prefix| board.strip_prefix(prefix))
+        .unwrap_or(board);
+    BOARDS
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(name))
+        .map(|&(_, mapper_number)| mapper_number)
+}
+
+impl CartridgeData {
+    pub fn from_unif_bytes(filebytes: &[u8]) -> Result<CartridgeData, RomReadError> {
+        if filebytes.len() < UNIF_HEADER_SIZE {
+            return Err(RomReadError::TooShort);
+        }
+        for (index, byte) in b"UNIF".iter().enumerate() {
+            if filebytes[index] != *byte {
+                return Err(RomReadError::InvalidHeader { index });
+            }
+        }
+
+        // PRGn and CHRn chunks are numbered 0-F and concatenated in that order
+        // no matter where they appear in the file
+        let mut prg_chunks: [Option<&[u8]>; 16] = [None; 16];
+        let mut chr_chunks: [Option<&[u8]>; 16] = [None; 16];
+        let mut board = None;
+        let mut mirroring = Mirroring::Horizontal;
+        let mut battery_backed = false;
+        let mut region = Region::Ntsc;
+
+        // Each chunk is a 4 byte ID, a little-endian length, then the data
+        let mut offset = UNIF_HEADER_SIZE;
+        while offset < filebytes.len() {
+            if filebytes.len() - offset < 8 {
+                return Err(RomReadError::MalformedUnifChunk { offset });
+            }
+            let id = &filebytes[offset..offset + 4];
+            let len = u32::from_le_bytes([
+                filebytes[offset + 4],
+                filebytes[offset + 5],
+                filebytes[offset + 6],
+                filebytes[offset + 7],
+            ]) as usize;
+            let data = filebytes
+                .get(offset + 8..)
+                .and_then(|rest| rest.get(..len))
+                .ok_or(RomReadError::MalformedUnifChunk { offset })?;
+
+            match id {
+                b"MAPR" => {
+                    // Null terminated
+                    let end = data.iter().position(|&byte| byte == 0).unwrap_or(len);
+                    board = Some(String::from_utf8_lossy(&data[..end]).into_owned());
+                }
+                [b'P', b'R', b'G', n] | [b'C', b'H', b'R', n] if n.is_ascii_hexdigit() => {
+                    let n = (*n as char).to_digit(16).unwrap() as usize;
+                    if id[0] == b'P' {
+                        prg_chunks[n] = Some(data);
+                    } else {
+                        chr_chunks[n] = Some(data);
+                    }
+                }
+                b"MIRR" => {
+                    mirroring = match data.first() {
+                        Some(1) => Mirroring::Vertical,
+                        Some(2) => Mirroring::SingleScreenLower,
+                        Some(3) => Mirroring::SingleScreenUpper,
+                        Some(4) => Mirroring::FourScreen,
+                        // 5 means the mapper controls it
+                        _ => Mirroring::Horizontal,
+                    }
+                }
+                b"BATR" => battery_backed = data.first() != Some(&0),
+                b"TVCI" => {
+                    region = match data.first() {
+                        Some(1) => Region::Pal,
+                        Some(2) => Region::MultiRegion,
+                        _ => Region::Ntsc,
+                    }
+                }
+                // Names, checksums, dumper info and the like
+                _ => (),
+            }
+            offset += 8 + len;
+        }
+
+        let board = board.ok_or(RomReadError::MissingUnifChunk { id: "MAPR" })?;
+        let mapper_number =
+            mapper_for_board(&board).ok_or(RomReadError::UnknownUnifBoard { board })?;
+        if prg_chunks[0].is_none() {
+            return Err(RomReadError::MissingUnifChunk { id: "PRG0" });
+        }
+
+        let prg_rom: Vec<u8> = prg_chunks
+            .iter()
+            .flatten()
+            .copied()
+            .flatten()
+            .copied()
+            .collect();
+        let chr_rom: Vec<u8> = chr_chunks
+            .iter()
+            .flatten()
+            .copied()
+            .flatten()
+            .copied()
+            .collect();
+        let options = ParseOptions::default();
+        check_size_limit(RomSection::PrgRom, prg_rom.len(), options.max_prg_rom_size)?;
+        check_size_limit(RomSection::ChrRom, chr_rom.len(), options.max_chr_rom_size)?;
+
+        // UNIF doesn't describe RAM, so assume the same as an iNES 1.0 header would
+        let (prg_ram_size, prg_nvram_size) = if battery_backed {
+            (0, DEFAULT_PRG_RAM_SIZE)
+        } else {
+            (DEFAULT_PRG_RAM_SIZE, 0)
+        };
+        let chr_ram_size = if chr_rom.is_empty() {
+            DEFAULT_CHR_RAM_SIZE
+        } else {
+            0
+        };
+
+        let (prg_rom_file_len, chr_rom_file_len) = (prg_rom.len(), chr_rom.len());
+        let prg_rom = pad_to_bank(Cow::Owned(prg_rom), PRG_BANK_SIZE);
+        let chr_rom = pad_to_bank(Cow::Owned(chr_rom), CHR_BANK_SIZE);
+        let prg_rom_len = prg_rom.len();
+        let rom = [prg_rom, chr_rom].concat();
+        let mut cartridge = CartridgeData {
+            header: [0; super::HEADER_SIZE],
+            header_version: HeaderVersion::Nes2,
+            console_type: ConsoleType::Nes,
+            rom: Arc::from(rom),
+            prg_rom_len,
+            prg_rom_file_len,
+            chr_rom_file_len,
+            declared_prg_rom_len: prg_rom_file_len,
+            declared_chr_rom_len: chr_rom_file_len,
+            mapper_number,
+            submapper: 0,
+            mirroring,
+            trainer: None,
+            inst_rom: None,
+            prom: None,
+            misc_rom_count: 0,
+            misc_rom: Vec::new(),
+            default_expansion_device: DefaultExpansionDevice::Unspecified,
+            battery_backed,
+            prg_ram_size,
+            prg_nvram_size,
+            prg_ram: vec![0; prg_ram_size],
+            prg_nvram: vec![0; prg_nvram_size],
+            chr_ram_size,
+            chr_nvram_size: 0,
+            chr_ram: vec![0; chr_ram_size],
+            region,
+            warnings: Vec::new(),
+            hashes: hash::HashCache::default(),
+        };
+        // There's no header in the file, so describe the cartridge with the
+        // NES 2.0 header it would have been given
+        cartridge.header = cartridge.nes2_header();
+        Ok(cartridge)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend((data.len() as u32).to_le_bytes());
+        chunk.extend(data);
+        chunk
+    }
+
+    fn unif(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut file = b"UNIF".to_vec();
+        file.extend(7u32.to_le_bytes());
+        file.resize(UNIF_HEADER_SIZE, 0);
+        file.extend(chunks.concat());
+        file
+    }
+
+    #[test]
+    fn minimal_unif_image() {
+        let file = unif(&[
+            chunk(b"MAPR", b"NES-NROM-128\0"),
+            chunk(b"PRG0", &[0xEA; PRG_BANK_SIZE]),
+            chunk(b"MIRR", &[1]),
+        ]);
+        let cartridge = CartridgeData::from_unif_bytes(&file).unwrap();
+        assert_eq!(cartridge.mapper_number(), 0);
+        assert_eq!(cartridge.mirroring(), Mirroring::Vertical);
+        assert_eq!(cartridge.prg_rom(), [0xEA; PRG_BANK_SIZE]);
+        assert!(cartridge.chr_rom().is_empty());
+        assert_eq!(cartridge.chr_ram().len(), DEFAULT_CHR_RAM_SIZE);
+        // Picked out by magic number too
+        assert_eq!(
+            CartridgeData::from_bytes_auto(&file).unwrap().mirroring(),
+            Mirroring::Vertical
+        );
+    }
+
+    #[test]
+    fn chunks_go_in_number_order() {
+        let file = unif(&[
+            chunk(b"PRG1", &[1; PRG_BANK_SIZE]),
+            chunk(b"MAPR", b"UNL-UNROM"),
+            chunk(b"PRG0", &[0; PRG_BANK_SIZE]),
+        ]);
+        let cartridge = CartridgeData::from_unif_bytes(&file).unwrap();
+        assert_eq!(cartridge.mapper_number(), 2);
+        assert_eq!(cartridge.prg_bank_16k(0)[0], 0);
+        assert_eq!(cartridge.prg_bank_16k(1)[0], 1);
+    }
+
+    #[test]
+    fn broken_unif_images() {
+        assert!(matches!(
+            CartridgeData::from_unif_bytes(&unif(&[chunk(b"PRG0", &[0; 16])])),
+            Err(RomReadError::MissingUnifChunk { id: "MAPR" })
+        ));
+        assert!(matches!(
+            CartridgeData::from_unif_bytes(&unif(&[chunk(b"MAPR", b"NES-NROM")])),
+            Err(RomReadError::MissingUnifChunk { id: "PRG0" })
+        ));
+        let mut file = unif(&[chunk(b"PRG0", &[0; 16])]);
+        file.truncate(file.len() - 1);
+        assert!(matches!(
+            CartridgeData::from_unif_bytes(&file),
+            Err(RomReadError::MalformedUnifChunk {
+                offset: UNIF_HEADER_SIZE
+            })
+        ));
+        assert!(matches!(
+            CartridgeData::from_unif_bytes(&unif(&[chunk(b"MAPR", b"NES-XYZROM")])),
+            Err(RomReadError::UnknownUnifBoard { .. })
+        ));
+    }
+
+    #[test]
+    fn board_names() {
+        assert_eq!(mapper_for_board("NES-SLROM"), Some(1));
+        assert_eq!(mapper_for_board("tlrom"), Some(4));
+        assert_eq!(mapper_for_board("BMC-70in1"), Some(236));
+        assert_eq!(mapper_for_board("NES-XYZROM"), None);
+    }
+}
@@ -0,0 +1,309 @@
+use std::io::{self, Write};
+
+use super::{
+    CartridgeData, ConsoleType, Mirroring, Region, CHR_BANK_SIZE, HEADER_SIZE, PRG_BANK_SIZE,
+};
+
+impl CartridgeData {
+    // Rebuilds an iNES 1.0 file. Anything the format can't express is dropped:
+    // mapper bits above 255, the submapper, RAM sizes and partial ROM banks.
+    pub fn to_ines_bytes(&self) -> Vec<u8> {
+        let prg_rom_banks = self.prg_rom_banks().min(255);
+        let chr_rom_banks = self.chr_rom_banks().min(255);
+
+        let mut header = [0; HEADER_SIZE];
+        header[0..4].copy_from_slice(b"NES\x1A");
+        header[4] = prg_rom_banks as u8;
+        header[5] = chr_rom_banks as u8;
+        header[6] = self.flags_6();
+        header[7] = (self.mapper_number as u8 & 0xF0)
+            | match self.console_type {
+                ConsoleType::VsSystem { .. } => 0b00000001,
+                ConsoleType::PlayChoice10 => 0b00000010,
+                _ => 0,
+            };
+        header[9] = (self.region == Region::Pal) as u8;
+
+        self.with_sections(
+            &header,
+            &self.prg_rom()[..prg_rom_banks * PRG_BANK_SIZE],
+            &self.chr_rom()[..chr_rom_banks * CHR_BANK_SIZE],
+        )
+    }
+
+    // Rebuilds a NES 2.0 file, which can describe everything CartridgeData models
+    pub fn to_nes2_bytes(&self) -> Vec<u8> {
+        let (_, _, prg_rom_len) = encode_rom_size(self.declared_prg_rom_len, PRG_BANK_SIZE);
+        let (_, _, chr_rom_len) = encode_rom_size(self.declared_chr_rom_len, CHR_BANK_SIZE);
+
+        // The bank padding added when parsing is left off again. Sizes that
+        // had to be rounded up to whole units, and sections the file was
+        // missing part of, are zero padded.
+        let mut prg_rom = self.prg_rom()[..self.prg_rom_file_len].to_vec();
+        prg_rom.resize(prg_rom_len, 0);
+        let mut chr_rom = self.chr_rom()[..self.chr_rom_file_len].to_vec();
+        chr_rom.resize(chr_rom_len, 0);
+        let mut filebytes = self.with_sections(&self.nes2_header(), &prg_rom, &chr_rom);
+        filebytes.extend_from_slice(&self.misc_rom);
+        filebytes
+    }
+
+    /// Writes the cartridge out as a NES 2.0 file. Parsing the output gives
+    /// back the same cartridge, mapper numbers above 255 included.
+    ///
+    /// ```
+    /// use zephyrnes::cartridge::{CartridgeBuilder, CartridgeData};
+    ///
+    /// let cartridge = CartridgeBuilder::new()
+    ///     .prg_rom(vec![0xEA; 0x4000])
+    ///     .mapper(268)
+    ///     .submapper(1)
+    ///     .trainer(vec![0x60; 512])
+    ///     .build()
+    ///     .unwrap();
+    /// let mut filebytes = Vec::new();
+    /// cartridge.write_to(&mut filebytes).unwrap();
+    /// let reparsed = CartridgeData::try_from(&filebytes[..]).unwrap();
+    /// assert_eq!(reparsed.mapper_number(), 268);
+    /// assert_eq!(reparsed.to_nes2_bytes(), filebytes);
+    /// ```
+    pub fn write_to(&self, mut w: impl Write) -> io::Result<()> {
+        w.write_all(&self.to_nes2_bytes())
+    }
+
+    pub(super) fn nes2_header(&self) -> [u8; HEADER_SIZE] {
+        let (prg_rom_lsb, prg_rom_msb, _) =
+            encode_rom_size(self.declared_prg_rom_len, PRG_BANK_SIZE);
+        let (chr_rom_lsb, chr_rom_msb, _) =
+            encode_rom_size(self.declared_chr_rom_len, CHR_BANK_SIZE);
+
+        let mut header = [0; HEADER_SIZE];
+        header[0..4].copy_from_slice(b"NES\x1A");
+        header[4] = prg_rom_lsb;
+        header[5] = chr_rom_lsb;
+        header[6] = self.flags_6();
+        // Console type in bits 0-1, NES 2.0 identifier in bits 2-3
+        let (console_type, console_details) = self.console_type.to_header_bits();
+        header[7] = (self.mapper_number as u8 & 0xF0) | 0b00001000 | console_type;
+        header[8] = (self.submapper << 4) | ((self.mapper_number >> 8) as u8 & 0x0F);
+        header[9] = (chr_rom_msb << 4) | prg_rom_msb;
+        header[10] =
+            (encode_shift_count(self.prg_nvram_size) << 4) | encode_shift_count(self.prg_ram_size);
+        header[11] =
+            (encode_shift_count(self.chr_nvram_size) << 4) | encode_shift_count(self.chr_ram_size);
+        header[12] = match self.region {
+            Region::Ntsc => 0,
+            Region::Pal => 1,
+            Region::MultiRegion => 2,
+            Region::Dendy => 3,
+        };
+        header[13] = console_details;
+        header[14] = self.misc_rom_count & 0b00000011;
+        header[15] = self.default_expansion_device.to_byte();
+        header
+    }
+
+    // Mirroring, battery, trainer and the low nibble of the mapper number
+    // sit in the same place in both header formats
+    fn flags_6(&self) -> u8 {
+        let mirroring = match self.mirroring {
+            Mirroring::Vertical => 0b00000001,
+            Mirroring::FourScreen => 0b00001000,
+            _ => 0,
+        };
+        mirroring
+            | ((self.battery_backed as u8) << 1)
+            | ((self.trainer.is_some() as u8) << 2)
+            | ((self.mapper_number as u8 & 0x0F) << 4)
+    }
+
+    fn with_sections(&self, header: &[u8], prg_rom: &[u8], chr_rom: &[u8]) -> Vec<u8> {
+        let mut filebytes = header.to_vec();
+        if let Some(trainer) = &self.trainer {
+            filebytes.extend_from_slice(trainer);
+        }
+        filebytes.extend_from_slice(prg_rom);
+        filebytes.extend_from_slice(chr_rom);
+        if let (Some(inst_rom), Some(prom)) = (&self.inst_rom, &self.prom) {
+            filebytes.extend_from_slice(inst_rom.as_slice());
+            filebytes.extend_from_slice(prom);
+        }
+        filebytes
+    }
+}
+
+// Returns the LSB byte, MSB nibble and the size actually described. Sizes are
+// written in units when possible, then in exponent-multiplier notation
+// (2^E * (MM * 2 + 1) bytes), and otherwise rounded up to the next unit.
+fn encode_rom_size(len: usize, unit: usize) -> (u8, u8, usize) {
+    let units = len.div_ceil(unit);
+    if len.is_multiple_of(unit) && units <= 0xEFF {
+        return (units as u8, (units >> 8) as u8, len);
+    }
+    let exponent = len.trailing_zeros();
+    let multiplier = len >> exponent;
+    if exponent <= 0b111111 && multiplier <= 7 {
+        let lsb = ((exponent as u8) << 2) | ((multiplier as u8 - 1) / 2);
+        return (lsb, 0x0F, len);
+    }
+    let units = units.min(0xEFF);
+    (units as u8, (units >> 8) as u8, units * unit)
+}
+
+// Inverse of shift_count_size, rounding up to the next power of two
+fn encode_shift_count(size: usize) -> u8 {
+    if size == 0 {
+        return 0;
+    }
+    let mut shift = 1;
+    while shift < 15 && 64 << shift < size {
+        shift += 1;
+    }
+    shift
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{header, nes2_header, rom_file};
+    use super::super::{INST_ROM_SIZE, PROM_SIZE, TRAINER_SIZE};
+    use super::*;
+
+    // Everything iNES 1.0 can describe
+    fn assert_same_ines_fields(a: &CartridgeData, b: &CartridgeData) {
+        assert_eq!(a.prg_rom(), b.prg_rom());
+        assert_eq!(a.chr_rom(), b.chr_rom());
+        assert_eq!(a.trainer(), b.trainer());
+        assert_eq!(a.mapper_number(), b.mapper_number());
+        assert_eq!(a.mirroring(), b.mirroring());
+        assert_eq!(a.is_battery_backed(), b.is_battery_backed());
+        assert_eq!(a.console_type(), b.console_type());
+        assert_eq!(a.region(), b.region());
+        assert_eq!(a.prg_ram_size(), b.prg_ram_size());
+        assert_eq!(a.prg_nvram_size(), b.prg_nvram_size());
+        assert_eq!(a.chr_ram_size(), b.chr_ram_size());
+    }
+
+    #[test]
+    fn ines_round_trip() {
+        let mut header = header(2, 1);
+        header[6] = 0b01000011;
+        header[7] = 0x10;
+        header[9] = 0b00000001;
+        let cartridge = CartridgeData::new(rom_file(header)).unwrap();
+        let filebytes = cartridge.to_ines_bytes();
+        assert_eq!(filebytes, rom_file(header));
+        let reparsed = CartridgeData::new(filebytes).unwrap();
+        assert_same_ines_fields(&cartridge, &reparsed);
+        assert_eq!(reparsed.mapper_number(), 0x14);
+    }
+
+    #[test]
+    fn nes2_round_trip() {
+        let mut header = nes2_header(1, 2);
+        header[6] = 0b00100010;
+        header[8] = 0x31;
+        header[10] = 0x70;
+        header[11] = 0x07;
+        header[12] = 3;
+        let filebytes = rom_file(header);
+        let cartridge = CartridgeData::new(filebytes.clone()).unwrap();
+        assert_eq!(cartridge.to_nes2_bytes(), filebytes);
+        let reparsed = CartridgeData::new(cartridge.to_nes2_bytes()).unwrap();
+        assert_same_ines_fields(&cartridge, &reparsed);
+        assert_eq!(reparsed.mapper_number(), 0x102);
+        assert_eq!(reparsed.submapper(), 3);
+        assert_eq!(reparsed.region(), Region::Dendy);
+    }
+
+    #[test]
+    fn rom_sizes_in_both_notations() {
+        assert_eq!(
+            encode_rom_size(3 * PRG_BANK_SIZE, PRG_BANK_SIZE),
+            (3, 0, 3 * PRG_BANK_SIZE)
+        );
+        // 2^12 * 3, not a whole number of banks
+        assert_eq!(
+            encode_rom_size(3 << 12, PRG_BANK_SIZE),
+            (12 << 2 | 1, 0x0F, 3 << 12)
+        );
+        // Rounded up to a bank
+        assert_eq!(
+            encode_rom_size(9 << 12 | 1, CHR_BANK_SIZE),
+            (5, 0, 5 * CHR_BANK_SIZE)
+        );
+        assert_eq!(encode_shift_count(0), 0);
+        assert_eq!(encode_shift_count(8192), 7);
+        assert_eq!(encode_shift_count(8000), 7);
+    }
+
+    #[test]
+    fn parse_write_parse_is_a_fixed_point() {
+        let mut files = Vec::new();
+        files.push(rom_file(header(2, 1)));
+        // Mapper 1 with a battery and a trainer
+        let mut trainer = header(1, 0);
+        trainer[6] = 0b00010110;
+        let mut file = trainer.to_vec();
+        file.extend([0x60; TRAINER_SIZE]);
+        file.extend([0xEA; PRG_BANK_SIZE]);
+        files.push(file);
+        // Mapper 0x1FF submapper 5, PAL, with CHR RAM sizes of its own
+        let mut big_mapper = nes2_header(2, 0);
+        big_mapper[6] = 0xF1;
+        big_mapper[7] |= 0xF0;
+        big_mapper[8] = 0x51;
+        big_mapper[10] = 0x07;
+        big_mapper[11] = 0x09;
+        big_mapper[12] = 1;
+        files.push(rom_file(big_mapper));
+        // A Vs. System game and a PlayChoice-10 one
+        let mut vs = nes2_header(1, 1);
+        vs[7] |= 0b01;
+        vs[13] = 0x13;
+        files.push(rom_file(vs));
+        let mut playchoice = header(1, 1);
+        playchoice[7] = 0b10;
+        let mut file = rom_file(playchoice);
+        file.extend([0x11; INST_ROM_SIZE + PROM_SIZE]);
+        files.push(file);
+        // Miscellaneous ROM and an expansion device
+        let mut misc = nes2_header(1, 1);
+        misc[14] = 1;
+        misc[15] = 0x08;
+        let mut file = rom_file(misc);
+        file.extend(b"misc rom");
+        files.push(file);
+        // 12 KB of PRG ROM, which only exponent notation describes
+        let mut odd = nes2_header(0, 1);
+        odd[4] = 12 << 2 | 1;
+        odd[9] = 0x0F;
+        let mut file = odd.to_vec();
+        file.extend([0x42; 3 << 12]);
+        file.extend([0x80; CHR_BANK_SIZE]);
+        let cartridge = CartridgeData::new(file.clone()).unwrap();
+        let written = cartridge.to_nes2_bytes();
+        assert_eq!(written[4], 12 << 2 | 1);
+        assert_eq!(written[9], 0x0F);
+        assert_eq!(written.len(), file.len());
+        let reparsed = CartridgeData::new(written).unwrap();
+        assert_eq!(reparsed.prg_crc32(), cartridge.prg_crc32());
+        files.push(file);
+
+        for file in files {
+            let cartridge = CartridgeData::new(file).unwrap();
+            let written = cartridge.to_nes2_bytes();
+            let reparsed = CartridgeData::new(written.clone()).unwrap();
+            assert_same_ines_fields(&cartridge, &reparsed);
+            assert_eq!(reparsed.submapper(), cartridge.submapper());
+            assert_eq!(reparsed.chr_nvram_size(), cartridge.chr_nvram_size());
+            assert_eq!(reparsed.misc_rom(), cartridge.misc_rom());
+            assert_eq!(reparsed.inst_rom(), cartridge.inst_rom());
+            assert_eq!(reparsed.prom(), cartridge.prom());
+            assert_eq!(
+                reparsed.default_expansion_device(),
+                cartridge.default_expansion_device()
+            );
+            assert_eq!(reparsed.to_nes2_bytes(), written);
+        }
+    }
+}
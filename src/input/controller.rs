@@ -0,0 +1,138 @@
+// The standard pad, a shift register loaded from the buttons while $4016 bit 0
+// is held high
+// https://www.nesdev.org/wiki/Standard_controller
+
+use crate::state::{StateError, StateReader, StateWriter};
+
+// In the order they're read out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    A,
+    B,
+    Select,
+    Start,
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+// A whole pad's buttons at once, A in bit 0 through Right in bit 7
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Buttons(pub u8);
+
+impl Buttons {
+    pub fn new(pressed: &[Button]) -> Buttons {
+        Buttons(
+            pressed
+                .iter()
+                .fold(0, |bits, &button| bits | 1 << button as u8),
+        )
+    }
+
+    pub fn is_pressed(self, button: Button) -> bool {
+        self.0 & (1 << button as u8) != 0
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Controller {
+    // Bit n for each button, in the order of Button
+    buttons: u8,
+    shift_register: u8,
+    strobe: bool,
+}
+
+impl Controller {
+    pub fn new() -> Controller {
+        Controller::default()
+    }
+
+    pub fn set_pressed(&mut self, button: Button, pressed: bool) {
+        let bit = 1 << button as u8;
+        if pressed {
+            self.buttons |= bit;
+        } else {
+            self.buttons &= !bit;
+        }
+        if self.strobe {
+            self.shift_register = self.buttons;
+        }
+    }
+
+    pub fn is_pressed(&self, button: Button) -> bool {
+        self.buttons & (1 << button as u8) != 0
+    }
+
+    // All eight at once, A in bit 0 through Right in bit 7
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.buttons = buttons;
+        if self.strobe {
+            self.shift_register = buttons;
+        }
+    }
+
+    pub fn buttons(&self) -> u8 {
+        self.buttons
+    }
+
+    // Bit 0 of a $4016 write. The buttons are latched continuously while
+    // it's high, so reads keep returning A until it goes low again.
+    pub fn write_strobe(&mut self, value: u8) {
+        self.strobe = value & 1 != 0;
+        if self.strobe {
+            self.shift_register = self.buttons;
+        }
+    }
+
+    // The next button in bit 0. Official pads shift in 1s, so every read past
+    // the eighth returns 1.
+    pub fn read(&mut self) -> u8 {
+        if self.strobe {
+            return self.buttons & 1;
+        }
+        let bit = self.shift_register & 1;
+        self.shift_register = (self.shift_register >> 1) | 0x80;
+        bit
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.buttons);
+        state.u8(self.shift_register);
+        state.bool(self.strobe);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.buttons = state.u8()?;
+        self.shift_register = state.u8()?;
+        self.strobe = state.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn up_and_a_then_1s() {
+        let mut controller = Controller::new();
+        controller.set_pressed(Button::Up, true);
+        controller.set_pressed(Button::A, true);
+        controller.write_strobe(1);
+        controller.write_strobe(0);
+        let bits: Vec<u8> = (0..12).map(|_| controller.read()).collect();
+        assert_eq!(bits, [1, 0, 0, 0, 1, 0, 0, 0, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn strobe_held_high_keeps_reading_a() {
+        let mut controller = Controller::new();
+        controller.write_strobe(1);
+        assert_eq!(controller.read(), 0);
+        controller.set_pressed(Button::A, true);
+        assert_eq!(controller.read(), 1);
+        assert_eq!(controller.read(), 1);
+        assert_eq!(Buttons::new(&[Button::A, Button::Right]), Buttons(0x81));
+    }
+}
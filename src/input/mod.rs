@@ -0,0 +1,75 @@
+// Devices plugged into the controller ports, read one bit at a time through
+// $4016 and $4017
+// https://www.nesdev.org/wiki/Input_devices
+
+mod controller;
+mod zapper;
+
+pub use controller::{Button, Buttons, Controller};
+pub use zapper::Zapper;
+
+use crate::ppu::Ppu;
+use crate::state::{StateError, StateReader, StateWriter};
+
+// What's plugged into a port
+#[derive(Debug, Clone)]
+pub enum InputDevice {
+    Controller(Controller),
+    Zapper(Zapper),
+}
+
+impl Default for InputDevice {
+    fn default() -> Self {
+        InputDevice::Controller(Controller::new())
+    }
+}
+
+impl InputDevice {
+    // A $4016 write goes to both ports. The Zapper has nothing to latch.
+    pub fn write_strobe(&mut self, value: u8) {
+        if let InputDevice::Controller(controller) = self {
+            controller.write_strobe(value);
+        }
+    }
+
+    // The port's data bits. The light gun needs to know what the PPU is
+    // drawing.
+    pub fn read(&mut self, ppu: &Ppu) -> u8 {
+        match self {
+            InputDevice::Controller(controller) => controller.read(),
+            InputDevice::Zapper(zapper) => zapper.read(ppu),
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        match self {
+            InputDevice::Controller(controller) => {
+                state.u8(0);
+                controller.save_state(state);
+            }
+            InputDevice::Zapper(zapper) => {
+                state.u8(1);
+                zapper.save_state(state);
+            }
+        }
+    }
+
+    // Plugs in whatever the state was saved with if it's something else.
+    // frame is the PPU's, already restored.
+    pub fn load_state(&mut self, state: &mut StateReader, frame: u64) -> Result<(), StateError> {
+        match state.u8()? {
+            0 if !matches!(self, InputDevice::Controller(_)) => {
+                *self = InputDevice::Controller(Controller::new())
+            }
+            1 if !matches!(self, InputDevice::Zapper(_)) => {
+                *self = InputDevice::Zapper(Zapper::new())
+            }
+            0 | 1 => {}
+            _ => return Err(StateError::InvalidValue("input device")),
+        }
+        match self {
+            InputDevice::Controller(controller) => controller.load_state(state),
+            InputDevice::Zapper(zapper) => zapper.load_state(state, frame),
+        }
+    }
+}
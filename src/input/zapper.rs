@@ -0,0 +1,184 @@
+// The light gun. It reads the brightness of whatever spot on the screen it's
+// pointed at as the beam passes, rather than anything the console sends it.
+// https://www.nesdev.org/wiki/Zapper
+
+use crate::ppu::{Ppu, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::state::{StateError, StateReader, StateWriter};
+
+// Bit 3 is low while light is seen, bit 4 is high while the trigger is pulled
+const LIGHT_NOT_DETECTED: u8 = 0b00001000;
+const TRIGGER_PULLED: u8 = 0b00010000;
+
+// The photodiode keeps responding for a while after the beam has passed
+const LIGHT_SCANLINES: u16 = 20;
+// Luma, 0-255, that counts as bright. White and the lighter greys and pastels
+// are over it, black and the dark colours aren't.
+const LIGHT_THRESHOLD: u16 = 0x80;
+// The trigger springs back on its own about 100 ms after being pulled
+const TRIGGER_FRAMES: u64 = 6;
+
+#[derive(Debug, Default, Clone)]
+pub struct Zapper {
+    // None when pointed away from the screen
+    aim: Option<(u16, u16)>,
+    trigger_pending: bool,
+    // Frame the current pull was first seen in
+    trigger_frame: Option<u64>,
+}
+
+impl Zapper {
+    pub fn new() -> Zapper {
+        Zapper::default()
+    }
+
+    // In screen pixels. Anything off the 256x240 picture sees no light.
+    pub fn aim(&mut self, x: u16, y: u16) {
+        self.aim = if (x as usize) < SCREEN_WIDTH && (y as usize) < SCREEN_HEIGHT {
+            Some((x, y))
+        } else {
+            None
+        };
+    }
+
+    pub fn aim_off_screen(&mut self) {
+        self.aim = None;
+    }
+
+    // Registers for TRIGGER_FRAMES frames from the next read
+    pub fn pull_trigger(&mut self) {
+        self.trigger_pending = true;
+    }
+
+    // Whether the spot it's aimed at was lit up by the beam recently
+    pub fn light_detected(&self, ppu: &Ppu) -> bool {
+        let Some((x, y)) = self.aim else {
+            return false;
+        };
+        let (scanline, dot) = (ppu.scanline(), ppu.dot());
+        // Pixel x goes out at dot x + 1
+        let passed = scanline > y || (scanline == y && dot > x + 1);
+        if !passed || scanline >= y + LIGHT_SCANLINES {
+            return false;
+        }
+        let offset = (y as usize * SCREEN_WIDTH + x as usize) * 3;
+        let rgb = &ppu.framebuffer()[offset..offset + 3];
+        (rgb[0] as u16 * 77 + rgb[1] as u16 * 150 + rgb[2] as u16 * 29) >> 8 >= LIGHT_THRESHOLD
+    }
+
+    // $4017 bits 3 and 4. The others are left for the bus to fill in.
+    pub fn read(&mut self, ppu: &Ppu) -> u8 {
+        if self.trigger_pending {
+            self.trigger_pending = false;
+            self.trigger_frame = Some(ppu.frame());
+        }
+        let mut value = 0;
+        if !self.light_detected(ppu) {
+            value |= LIGHT_NOT_DETECTED;
+        }
+        match self.trigger_frame {
+            Some(frame) if ppu.frame() - frame < TRIGGER_FRAMES => value |= TRIGGER_PULLED,
+            _ => self.trigger_frame = None,
+        }
+        value
+    }
+
+    // The aim comes from the frontend, so only the trigger is kept
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.trigger_pending);
+        state.bool(self.trigger_frame.is_some());
+        state.u64(self.trigger_frame.unwrap_or(0));
+    }
+
+    // A pull can't have been seen after the frame the PPU is on
+    pub fn load_state(&mut self, state: &mut StateReader, frame: u64) -> Result<(), StateError> {
+        self.trigger_pending = state.bool()?;
+        let pulled = state.bool()?;
+        let trigger_frame = state.u64()?;
+        if pulled && trigger_frame > frame {
+            return Err(StateError::InvalidValue("Zapper trigger frame"));
+        }
+        self.trigger_frame = pulled.then_some(trigger_frame);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cartridge::CartridgeBuilder;
+    use crate::mapper::Nrom;
+
+    use super::*;
+
+    // With rendering off the whole picture is the backdrop colour. Stops
+    // with the beam at (200, 100).
+    fn ppu_showing(colour: u8) -> Ppu {
+        let cartridge = CartridgeBuilder::new()
+            .prg_rom(vec![0; 0x4000])
+            .build()
+            .unwrap();
+        let mut mapper = Nrom::new(cartridge);
+        let mut ppu = Ppu::new();
+        ppu.write_register(0x2006, 0x3F, &mut mapper);
+        ppu.write_register(0x2006, 0x00, &mut mapper);
+        ppu.write_register(0x2007, colour, &mut mapper);
+        while (ppu.scanline(), ppu.dot()) != (100, 200) {
+            ppu.tick(&mut mapper);
+        }
+        ppu
+    }
+
+    #[test]
+    fn bright_and_dark_pixels() {
+        let mut zapper = Zapper::new();
+        zapper.aim(50, 90);
+        // White
+        let bright = ppu_showing(0x30);
+        assert!(zapper.light_detected(&bright));
+        assert_eq!(zapper.read(&bright), 0);
+        // Black
+        let dark = ppu_showing(0x0F);
+        assert!(!zapper.light_detected(&dark));
+        assert_eq!(zapper.read(&dark), LIGHT_NOT_DETECTED);
+
+        // Nothing's been drawn there yet this frame, or it was too long ago
+        zapper.aim(50, 150);
+        assert!(!zapper.light_detected(&bright));
+        zapper.aim(50, 60);
+        assert!(!zapper.light_detected(&bright));
+        zapper.aim(300, 90);
+        assert!(!zapper.light_detected(&bright));
+    }
+
+    #[test]
+    fn trigger_pull() {
+        let ppu = ppu_showing(0x0F);
+        let mut zapper = Zapper::new();
+        assert_eq!(zapper.read(&ppu) & TRIGGER_PULLED, 0);
+        zapper.pull_trigger();
+        assert_eq!(zapper.read(&ppu) & TRIGGER_PULLED, TRIGGER_PULLED);
+        assert_eq!(zapper.read(&ppu) & TRIGGER_PULLED, TRIGGER_PULLED);
+    }
+
+    #[test]
+    fn trigger_frame_is_checked_on_load() {
+        let mut zapper = Zapper::new();
+        zapper.pull_trigger();
+        zapper.read(&ppu_showing(0x0F));
+        let mut state = StateWriter::new();
+        zapper.save_state(&mut state);
+        let state = state.into_bytes();
+
+        // Saved on frame 0, which a PPU on frame 0 is fine with
+        let mut loaded = Zapper::new();
+        loaded.load_state(&mut StateReader::new(&state), 0).unwrap();
+        let mut future = state.clone();
+        future[2..10].copy_from_slice(&7u64.to_le_bytes());
+        assert_eq!(
+            loaded.load_state(&mut StateReader::new(&future), 6),
+            Err(StateError::InvalidValue("Zapper trigger frame"))
+        );
+        loaded
+            .load_state(&mut StateReader::new(&future), 7)
+            .unwrap();
+    }
+}
@@ -0,0 +1,9 @@
+pub mod apu;
+pub mod bus;
+pub mod cartridge;
+pub mod input;
+pub mod mapper;
+pub mod mos6502;
+pub mod nes;
+pub mod ppu;
+pub mod state;
@@ -1,5 +1,3 @@
-mod cpu_memory;
-mod mos6502;
 use ggez::event::{self, EventHandler};
 use ggez::graphics::{self, Canvas, Color, DrawParam};
 use ggez::input::keyboard;
@@ -0,0 +1,152 @@
+use crate::cartridge::{CartridgeData, Mirroring};
+use crate::state::{StateError, StateReader, StateWriter};
+
+use super::{load_cartridge, read_chr, read_prg_rom, save_cartridge, write_chr, Mapper};
+
+// Mapper 7: https://www.nesdev.org/wiki/AxROM
+// Writes to $8000-$FFFF pick a 32 KB PRG bank with bits 0-2 and which
+// nametable is shown on all four screens with bit 4. CHR is 8 KB of RAM.
+// AMROM boards have bus conflicts and ANROM and AOROM don't, which NES 2.0
+// tells apart with submappers 2 and 1. Unlike the other discrete boards, an
+// unspecified submapper goes without, since most AxROM games are on boards
+// without conflicts and don't avoid them.
+pub struct Axrom {
+    cartridge: CartridgeData,
+    // xxxM xPPP
+    bank: u8,
+    bus_conflicts: bool,
+}
+
+impl Axrom {
+    pub fn new(cartridge: CartridgeData) -> Axrom {
+        let bus_conflicts = cartridge.submapper() == 2;
+        Axrom {
+            cartridge,
+            bank: 0,
+            bus_conflicts,
+        }
+    }
+
+    // Only as many of the three bank bits as the ROM needs are wired up
+    fn prg_bank(&self) -> usize {
+        let banks = self.cartridge.prg_rom().len() / 0x8000;
+        (self.bank & 0b111) as usize % banks.max(1)
+    }
+}
+
+impl Mapper for Axrom {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        match address {
+            0x8000..=0xFFFF => read_prg_rom(
+                &self.cartridge,
+                self.prg_bank() * 0x8000 + (address & 0x7FFF) as usize,
+            ),
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        if address >= 0x8000 {
+            self.bank = if self.bus_conflicts {
+                value & self.cpu_read(address)
+            } else {
+                value
+            };
+        }
+    }
+
+    fn ppu_read(&mut self, address: u16) -> u8 {
+        read_chr(&self.cartridge, (address & 0x1FFF) as usize)
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        write_chr(&mut self.cartridge, (address & 0x1FFF) as usize, value);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.bank & 0x10 == 0 {
+            Mirroring::SingleScreenLower
+        } else {
+            Mirroring::SingleScreenUpper
+        }
+    }
+
+    fn cartridge(&self) -> &CartridgeData {
+        &self.cartridge
+    }
+
+    fn cartridge_mut(&mut self) -> &mut CartridgeData {
+        &mut self.cartridge
+    }
+
+    fn reset(&mut self) {
+        self.bank = 0;
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        save_cartridge(&self.cartridge, state);
+        state.u8(self.bank);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        load_cartridge(&mut self.cartridge, state)?;
+        self.bank = state.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cartridge::CartridgeBuilder;
+
+    use super::super::tests::cartridge;
+    use super::*;
+
+    #[test]
+    fn bank_1_with_nametable_b() {
+        let mut axrom = Axrom::new(cartridge(7, 8, 0));
+        assert_eq!(axrom.cpu_read(0x8000), 0);
+        assert_eq!(axrom.mirroring(), Mirroring::SingleScreenLower);
+
+        axrom.cpu_write(0x8000, 0x11);
+        assert_eq!(axrom.cpu_read(0x8000), 4);
+        assert_eq!(axrom.cpu_read(0xFFFF), 7);
+        assert_eq!(axrom.mirroring(), Mirroring::SingleScreenUpper);
+    }
+
+    #[test]
+    fn mirroring_follows_bit_4() {
+        let mut axrom = Axrom::new(cartridge(7, 8, 0));
+        for value in [0x10, 0x00, 0x11, 0x01] {
+            axrom.cpu_write(0x8000, value);
+            let expected = if value & 0x10 != 0 {
+                Mirroring::SingleScreenUpper
+            } else {
+                Mirroring::SingleScreenLower
+            };
+            assert_eq!(axrom.mirroring(), expected, "{value:#04X}");
+        }
+    }
+
+    #[test]
+    fn only_amrom_has_bus_conflicts() {
+        // Four 32 KB banks, bank n filled with 3 - n
+        let prg_rom: Vec<u8> = (0..4).flat_map(|bank| [3 - bank; 0x8000]).collect();
+        for (submapper, bank, mirroring) in [
+            (1, 3, Mirroring::SingleScreenUpper),
+            (2, 3, Mirroring::SingleScreenLower),
+        ] {
+            let cartridge = CartridgeBuilder::new()
+                .mapper(7)
+                .submapper(submapper)
+                .prg_rom(prg_rom.clone())
+                .build()
+                .unwrap();
+            let mut axrom = Axrom::new(cartridge);
+            // $8000 reads 3, which keeps the bank bits but not bit 4
+            axrom.cpu_write(0x8000, 0x13);
+            assert_eq!(axrom.cpu_read(0x8000), 3 - bank, "submapper {submapper}");
+            assert_eq!(axrom.mirroring(), mirroring, "submapper {submapper}");
+        }
+    }
+}
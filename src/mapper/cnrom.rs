@@ -0,0 +1,123 @@
+use crate::cartridge::{CartridgeData, Mirroring};
+use crate::state::{StateError, StateReader, StateWriter};
+
+use super::{has_bus_conflicts, load_cartridge, read_chr, read_prg_rom, save_cartridge, Mapper};
+
+// Mapper 3: https://www.nesdev.org/wiki/CNROM
+// PRG ROM is fixed like NROM, and writes to $8000-$FFFF pick an 8 KB CHR bank.
+// Mapper 185 boards use the same register for copy protection, which isn't
+// handled here.
+pub struct Cnrom {
+    cartridge: CartridgeData,
+    chr_bank: u8,
+    bus_conflicts: bool,
+}
+
+impl Cnrom {
+    pub fn new(cartridge: CartridgeData) -> Cnrom {
+        let bus_conflicts = has_bus_conflicts(&cartridge);
+        Cnrom {
+            cartridge,
+            chr_bank: 0,
+            bus_conflicts,
+        }
+    }
+
+    // Boards wire up as many bank bits as the CHR ROM needs, so bigger bank
+    // numbers wrap around
+    fn chr_bank(&self) -> usize {
+        self.chr_bank as usize % self.cartridge.chr_rom_banks().max(1)
+    }
+}
+
+impl Mapper for Cnrom {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        match address {
+            0x8000..=0xFFFF => read_prg_rom(&self.cartridge, (address - 0x8000) as usize),
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        if address >= 0x8000 {
+            self.chr_bank = if self.bus_conflicts {
+                value & self.cpu_read(address)
+            } else {
+                value
+            };
+        }
+    }
+
+    fn ppu_read(&mut self, address: u16) -> u8 {
+        read_chr(
+            &self.cartridge,
+            self.chr_bank() * 0x2000 + (address & 0x1FFF) as usize,
+        )
+    }
+
+    // CHR ROM only
+    fn ppu_write(&mut self, _address: u16, _value: u8) {}
+
+    fn mirroring(&self) -> Mirroring {
+        self.cartridge.mirroring()
+    }
+
+    fn cartridge(&self) -> &CartridgeData {
+        &self.cartridge
+    }
+
+    fn cartridge_mut(&mut self) -> &mut CartridgeData {
+        &mut self.cartridge
+    }
+
+    fn reset(&mut self) {
+        self.chr_bank = 0;
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        save_cartridge(&self.cartridge, state);
+        state.u8(self.chr_bank);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        load_cartridge(&mut self.cartridge, state)?;
+        self.chr_bank = state.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::cartridge;
+    use super::*;
+
+    #[test]
+    fn selects_chr_banks() {
+        // $E000 reads $03, letting bank numbers up to 3 through the bus conflict
+        let mut cnrom = Cnrom::new(cartridge(3, 4, 32));
+        assert_eq!(cnrom.ppu_read(0x0000), 0);
+        cnrom.cpu_write(0xE000, 2);
+        assert_eq!(cnrom.ppu_read(0x0000), 16);
+        assert_eq!(cnrom.ppu_read(0x1FFF), 23);
+        cnrom.cpu_write(0xE000, 0);
+        assert_eq!(cnrom.ppu_read(0x0000), 0);
+    }
+
+    #[test]
+    fn bus_conflicts_and_wrapping() {
+        // $A000 reads $01, so writing 3 there only selects bank 1
+        let mut cnrom = Cnrom::new(cartridge(3, 4, 32));
+        cnrom.cpu_write(0xA000, 3);
+        assert_eq!(cnrom.ppu_read(0x0000), 8);
+        cnrom.cpu_write(0x8000, 3);
+        assert_eq!(cnrom.ppu_read(0x0000), 0);
+
+        // With two CHR banks, bank 3 wraps to 1
+        let mut cnrom = Cnrom::new(cartridge(3, 4, 16));
+        cnrom.cpu_write(0xE000, 3);
+        assert_eq!(cnrom.ppu_read(0x0400), 9);
+        // PRG ROM stays put
+        assert_eq!(cnrom.cpu_read(0x8000), 0);
+        assert_eq!(cnrom.cpu_read(0xFFFF), 3);
+    }
+}
@@ -0,0 +1,267 @@
+use crate::cartridge::{CartridgeData, Mirroring};
+use crate::state::{StateError, StateReader, StateWriter};
+
+use super::{
+    load_cartridge, read_chr, read_prg_ram, read_prg_rom, save_cartridge, write_chr, write_prg_ram,
+    Mapper,
+};
+
+// Mapper 69: https://www.nesdev.org/wiki/Sunsoft_FME-7
+// Sixteen registers written through a command/parameter pair at $8000 and
+// $A000. PRG is switched in 8 KB banks, including one at $6000 that can be
+// RAM or ROM, and CHR in 1 KB banks. The IRQ counts CPU cycles rather than
+// scanlines. The 5B's extra audio isn't emulated.
+pub struct Fme7 {
+    cartridge: CartridgeData,
+    // Register the next $A000 write goes to
+    command: u8,
+    chr_banks: [u8; 8],
+    // 7  bit  0
+    // ---- ----
+    // ERbB BBBB
+    // |||| ||||
+    // ||++-++++- Bank at $6000
+    // |+-------- 0: PRG ROM; 1: PRG RAM
+    // +--------- PRG RAM enable, ignored for ROM
+    prg_bank_6000: u8,
+    // $8000, $A000 and $C000. $E000 is fixed to the last bank.
+    prg_banks: [u8; 3],
+    // 0: vertical; 1: horizontal; 2: single screen lower; 3: single screen upper
+    mirroring: u8,
+    // Bit 0 lets the counter raise an IRQ, bit 7 makes it count
+    irq_control: u8,
+    irq_counter: u16,
+    irq_pending: bool,
+}
+
+impl Fme7 {
+    pub fn new(cartridge: CartridgeData) -> Fme7 {
+        Fme7 {
+            cartridge,
+            command: 0,
+            chr_banks: [0; 8],
+            prg_bank_6000: 0,
+            prg_banks: [0; 3],
+            mirroring: 0,
+            irq_control: 0,
+            irq_counter: 0,
+            irq_pending: false,
+        }
+    }
+
+    fn write_parameter(&mut self, value: u8) {
+        match self.command {
+            0x0..=0x7 => self.chr_banks[self.command as usize] = value,
+            0x8 => self.prg_bank_6000 = value,
+            0x9..=0xB => self.prg_banks[(self.command - 0x9) as usize] = value & 0x3F,
+            0xC => self.mirroring = value & 0b11,
+            // Any write acknowledges the IRQ
+            0xD => {
+                self.irq_control = value;
+                self.irq_pending = false;
+            }
+            0xE => self.irq_counter = (self.irq_counter & 0xFF00) | value as u16,
+            _ => self.irq_counter = (self.irq_counter & 0x00FF) | (value as u16) << 8,
+        }
+    }
+
+    fn prg_rom_offset(&self, address: u16) -> usize {
+        let last_bank = (self.cartridge.prg_rom().len() / 0x2000).saturating_sub(1);
+        let bank = match address {
+            0x6000..=0x7FFF => (self.prg_bank_6000 & 0x3F) as usize,
+            0x8000..=0x9FFF => self.prg_banks[0] as usize,
+            0xA000..=0xBFFF => self.prg_banks[1] as usize,
+            0xC000..=0xDFFF => self.prg_banks[2] as usize,
+            _ => last_bank,
+        };
+        bank * 0x2000 + (address & 0x1FFF) as usize
+    }
+
+    fn prg_ram_selected(&self) -> bool {
+        self.prg_bank_6000 & 0x40 != 0
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_bank_6000 & 0xC0 == 0xC0
+    }
+
+    fn prg_ram_offset(&self, address: u16) -> usize {
+        (self.prg_bank_6000 & 0x3F) as usize * 0x2000 + (address & 0x1FFF) as usize
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        let address = (address & 0x1FFF) as usize;
+        self.chr_banks[address >> 10] as usize * 0x400 + (address & 0x3FF)
+    }
+}
+
+impl Mapper for Fme7 {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        match address {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                read_prg_ram(&self.cartridge, self.prg_ram_offset(address))
+            }
+            // Disabled RAM leaves the bus floating, and the last thing on it
+            // is usually the high byte of the address
+            0x6000..=0x7FFF if self.prg_ram_selected() => (address >> 8) as u8,
+            0x6000..=0xFFFF => read_prg_rom(&self.cartridge, self.prg_rom_offset(address)),
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        match address {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                let offset = self.prg_ram_offset(address);
+                write_prg_ram(&mut self.cartridge, offset, value)
+            }
+            0x8000..=0x9FFF => self.command = value & 0x0F,
+            0xA000..=0xBFFF => self.write_parameter(value),
+            _ => (),
+        }
+    }
+
+    fn ppu_read(&mut self, address: u16) -> u8 {
+        read_chr(&self.cartridge, self.chr_offset(address))
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        let offset = self.chr_offset(address);
+        write_chr(&mut self.cartridge, offset, value);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.mirroring {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenLower,
+            _ => Mirroring::SingleScreenUpper,
+        }
+    }
+
+    fn cartridge(&self) -> &CartridgeData {
+        &self.cartridge
+    }
+
+    fn cartridge_mut(&mut self) -> &mut CartridgeData {
+        &mut self.cartridge
+    }
+
+    fn reset(&mut self) {
+        self.command = 0;
+        self.chr_banks = [0; 8];
+        self.prg_bank_6000 = 0;
+        self.prg_banks = [0; 3];
+        self.mirroring = 0;
+        self.irq_control = 0;
+        self.irq_counter = 0;
+        self.irq_pending = false;
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        save_cartridge(&self.cartridge, state);
+        state.u8(self.command);
+        state.bytes(&self.chr_banks);
+        state.u8(self.prg_bank_6000);
+        state.bytes(&self.prg_banks);
+        state.u8(self.mirroring);
+        state.u8(self.irq_control);
+        state.u16(self.irq_counter);
+        state.bool(self.irq_pending);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        load_cartridge(&mut self.cartridge, state)?;
+        self.command = state.u8()?;
+        state.fill(&mut self.chr_banks)?;
+        self.prg_bank_6000 = state.u8()?;
+        state.fill(&mut self.prg_banks)?;
+        self.mirroring = state.u8()?;
+        self.irq_control = state.u8()?;
+        self.irq_counter = state.u16()?;
+        self.irq_pending = state.bool()?;
+        Ok(())
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    // The counter goes down every cycle while counting is on, and wrapping
+    // from $0000 to $FFFF raises the IRQ if that's on too
+    fn cpu_clock(&mut self) {
+        if self.irq_control & 0x80 == 0 {
+            return;
+        }
+        if self.irq_counter == 0 && self.irq_control & 0x01 != 0 {
+            self.irq_pending = true;
+        }
+        self.irq_counter = self.irq_counter.wrapping_sub(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::cartridge;
+    use super::*;
+
+    fn write(fme7: &mut Fme7, command: u8, value: u8) {
+        fme7.cpu_write(0x8000, command);
+        fme7.cpu_write(0xA000, value);
+    }
+
+    #[test]
+    fn irq_on_underflow() {
+        let mut fme7 = Fme7::new(cartridge(69, 16, 64));
+        write(&mut fme7, 0xE, 3);
+        write(&mut fme7, 0xF, 0);
+        write(&mut fme7, 0xD, 0x81);
+        // Down to 0, then the wrap to $FFFF on the fourth cycle
+        for _ in 0..3 {
+            fme7.cpu_clock();
+            assert!(!fme7.irq_pending());
+        }
+        fme7.cpu_clock();
+        assert!(fme7.irq_pending());
+        assert_eq!(fme7.irq_counter, 0xFFFF);
+
+        // Acknowledged by any write to the control register
+        write(&mut fme7, 0xD, 0x81);
+        assert!(!fme7.irq_pending());
+    }
+
+    #[test]
+    fn counting_and_irqs_are_enabled_separately() {
+        let mut fme7 = Fme7::new(cartridge(69, 16, 64));
+        // Counting without the IRQ wraps silently
+        write(&mut fme7, 0xD, 0x80);
+        fme7.cpu_clock();
+        assert!(!fme7.irq_pending());
+        assert_eq!(fme7.irq_counter, 0xFFFF);
+        // The IRQ alone doesn't count
+        write(&mut fme7, 0xE, 0);
+        write(&mut fme7, 0xF, 0);
+        write(&mut fme7, 0xD, 0x01);
+        for _ in 0..10 {
+            fme7.cpu_clock();
+        }
+        assert!(!fme7.irq_pending());
+        assert_eq!(fme7.irq_counter, 0);
+    }
+
+    #[test]
+    fn prg_ram_at_6000() {
+        let mut fme7 = Fme7::new(cartridge(69, 16, 64));
+        // ROM bank 5
+        write(&mut fme7, 0x8, 0x05);
+        assert_eq!(fme7.cpu_read(0x6000), 5);
+        // RAM, but disabled, so open bus
+        write(&mut fme7, 0x8, 0x40);
+        assert_eq!(fme7.cpu_read(0x6123), 0x61);
+        fme7.cpu_write(0x6000, 0x42);
+        write(&mut fme7, 0x8, 0xC0);
+        assert_eq!(fme7.cpu_read(0x6000), 0);
+        fme7.cpu_write(0x6000, 0x42);
+        assert_eq!(fme7.cpu_read(0x6000), 0x42);
+    }
+}
@@ -0,0 +1,341 @@
+use crate::cartridge::{CartridgeData, Mirroring};
+use crate::state::{StateError, StateReader, StateWriter};
+
+use super::{
+    load_cartridge, read_chr, read_prg_ram, read_prg_rom, save_cartridge, write_chr, write_prg_ram,
+    Mapper,
+};
+
+// Mapper 1: https://www.nesdev.org/wiki/MMC1
+// Registers are loaded one bit at a time through a 5 bit shift register.
+// Writing a value with bit 7 set clears the shift register instead.
+pub struct Mmc1 {
+    cartridge: CartridgeData,
+    shift_register: u8,
+    shift_count: u8,
+    // 4bit0
+    // -----
+    // CPPMM
+    // |||||
+    // |||++- Mirroring (0: one-screen lower, 1: one-screen upper, 2: vertical, 3: horizontal)
+    // |++--- PRG ROM bank mode (0, 1: 32 KB at $8000; 2: first bank fixed at $8000;
+    // |                         3: last bank fixed at $C000)
+    // +----- CHR ROM bank mode (0: one 8 KB bank; 1: two 4 KB banks)
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    // Bits 0-3 select a 16 KB bank, bit 4 disables PRG RAM
+    prg_bank: u8,
+    // Whether the PPU last fetched from $1000-$1FFF, which picks the CHR bank
+    // register that drives the outer PRG bank in 4 KB mode
+    chr_upper_half: bool,
+}
+
+impl Mmc1 {
+    pub fn new(cartridge: CartridgeData) -> Mmc1 {
+        Mmc1 {
+            cartridge,
+            shift_register: 0,
+            shift_count: 0,
+            // Fixing the last bank at $C000 means the reset vector is always visible
+            control: 0x0C,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+            chr_upper_half: false,
+        }
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x8000..=0x9FFF => self.control = value,
+            0xA000..=0xBFFF => self.chr_bank_0 = value,
+            0xC000..=0xDFFF => self.chr_bank_1 = value,
+            _ => self.prg_bank = value,
+        }
+    }
+
+    fn prg_rom_offset(&self, address: u16) -> usize {
+        let bank = (self.prg_bank & 0x0F) as usize;
+        // Last bank of the current 256 KB, since the bank number is only 4 bits
+        let last_bank = 0x0F;
+        let bank = match ((self.control >> 2) & 0b11, address) {
+            // 32 KB mode ignores the low bit of the bank number
+            (0 | 1, 0x8000..=0xBFFF) => bank & !1,
+            (0 | 1, _) => bank | 1,
+            (2, 0x8000..=0xBFFF) => 0,
+            (2, _) => bank,
+            (_, 0x8000..=0xBFFF) => bank,
+            (_, _) => last_bank,
+        };
+        // SUROM and SXROM use bit 4 of the CHR bank to pick a 256 KB half of
+        // PRG ROM. In 4 KB mode that's the bank the PPU is currently using, so
+        // games keep the bit the same in both.
+        let outer_bank = if self.cartridge.prg_rom().len() > 0x40000 {
+            let chr_bank = if self.control & 0b10000 != 0 && self.chr_upper_half {
+                self.chr_bank_1
+            } else {
+                self.chr_bank_0
+            };
+            (chr_bank & 0x10) as usize
+        } else {
+            0
+        };
+        (outer_bank | bank) * 0x4000 + (address & 0x3FFF) as usize
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        let address = (address & 0x1FFF) as usize;
+        if self.control & 0b10000 == 0 {
+            (self.chr_bank_0 & !1) as usize * 0x1000 + address
+        } else if address < 0x1000 {
+            self.chr_bank_0 as usize * 0x1000 + address
+        } else {
+            self.chr_bank_1 as usize * 0x1000 + (address - 0x1000)
+        }
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        match address {
+            0x6000..=0x7FFF if self.prg_bank & 0x10 == 0 => {
+                read_prg_ram(&self.cartridge, (address - 0x6000) as usize)
+            }
+            0x8000..=0xFFFF => read_prg_rom(&self.cartridge, self.prg_rom_offset(address)),
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        match address {
+            0x6000..=0x7FFF if self.prg_bank & 0x10 == 0 => {
+                write_prg_ram(&mut self.cartridge, (address - 0x6000) as usize, value)
+            }
+            0x8000..=0xFFFF => {
+                if value & 0x80 != 0 {
+                    self.shift_register = 0;
+                    self.shift_count = 0;
+                    self.control |= 0x0C;
+                    return;
+                }
+                self.shift_register = (self.shift_register >> 1) | ((value & 1) << 4);
+                self.shift_count += 1;
+                // The fifth write copies the shift register into the register
+                // picked by bits 13 and 14 of its address
+                if self.shift_count == 5 {
+                    self.write_register(address, self.shift_register);
+                    self.shift_register = 0;
+                    self.shift_count = 0;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn ppu_read(&mut self, address: u16) -> u8 {
+        self.chr_upper_half = address & 0x1000 != 0;
+        read_chr(&self.cartridge, self.chr_offset(address))
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        self.chr_upper_half = address & 0x1000 != 0;
+        let offset = self.chr_offset(address);
+        write_chr(&mut self.cartridge, offset, value);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0b11 {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    fn cartridge(&self) -> &CartridgeData {
+        &self.cartridge
+    }
+
+    fn cartridge_mut(&mut self) -> &mut CartridgeData {
+        &mut self.cartridge
+    }
+
+    fn reset(&mut self) {
+        self.shift_register = 0;
+        self.shift_count = 0;
+        self.control = 0x0C;
+        self.chr_bank_0 = 0;
+        self.chr_bank_1 = 0;
+        self.prg_bank = 0;
+        self.chr_upper_half = false;
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        save_cartridge(&self.cartridge, state);
+        state.u8(self.shift_register);
+        state.u8(self.shift_count);
+        state.u8(self.control);
+        state.u8(self.chr_bank_0);
+        state.u8(self.chr_bank_1);
+        state.u8(self.prg_bank);
+        state.bool(self.chr_upper_half);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        load_cartridge(&mut self.cartridge, state)?;
+        self.shift_register = state.u8()?;
+        self.shift_count = state.u8_below(5, "MMC1 shift count")?;
+        self.control = state.u8()?;
+        self.chr_bank_0 = state.u8()?;
+        self.chr_bank_1 = state.u8()?;
+        self.prg_bank = state.u8()?;
+        self.chr_upper_half = state.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::cartridge;
+    use super::*;
+
+    // Five writes, low bit first
+    fn write_serial(mmc1: &mut Mmc1, address: u16, value: u8) {
+        for bit in 0..5 {
+            mmc1.cpu_write(address, (value >> bit) & 1);
+        }
+    }
+
+    // 8 KB bank numbers at $8000 and $C000
+    fn prg_banks(mmc1: &mut Mmc1) -> (u8, u8) {
+        (mmc1.cpu_read(0x8000), mmc1.cpu_read(0xC000))
+    }
+
+    #[test]
+    fn prg_bank_select() {
+        // 128 KB, eight 16 KB banks
+        let mut mmc1 = Mmc1::new(cartridge(1, 16, 8));
+        assert_eq!(prg_banks(&mut mmc1), (0, 14));
+        write_serial(&mut mmc1, 0xE000, 3);
+        assert_eq!(prg_banks(&mut mmc1), (6, 14));
+        assert_eq!(mmc1.cpu_read(0xBFFF), 7);
+        assert_eq!(mmc1.cpu_read(0xFFFF), 15);
+
+        // First bank fixed at $8000 instead
+        write_serial(&mut mmc1, 0x8000, 0b01000);
+        assert_eq!(prg_banks(&mut mmc1), (0, 6));
+
+        // 32 KB mode drops the low bit
+        write_serial(&mut mmc1, 0x8000, 0b00000);
+        assert_eq!(prg_banks(&mut mmc1), (4, 6));
+    }
+
+    #[test]
+    fn chr_bank_select_picks_the_512k_half() {
+        let mut mmc1 = Mmc1::new(cartridge(1, 64, 8));
+        assert_eq!(prg_banks(&mut mmc1), (0, 30));
+        write_serial(&mut mmc1, 0xA000, 0x10);
+        assert_eq!(prg_banks(&mut mmc1), (32, 62));
+        write_serial(&mut mmc1, 0xE000, 2);
+        assert_eq!(prg_banks(&mut mmc1), (36, 62));
+    }
+
+    #[test]
+    fn chr_banks() {
+        let mut mmc1 = Mmc1::new(cartridge(1, 2, 32));
+        // Two 4 KB banks
+        write_serial(&mut mmc1, 0x8000, 0b11100);
+        write_serial(&mut mmc1, 0xA000, 3);
+        write_serial(&mut mmc1, 0xC000, 5);
+        assert_eq!(mmc1.ppu_read(0x0000), 12);
+        assert_eq!(mmc1.ppu_read(0x1000), 20);
+        // One 8 KB bank, ignoring the low bit
+        write_serial(&mut mmc1, 0x8000, 0b01100);
+        assert_eq!(mmc1.ppu_read(0x0000), 8);
+        assert_eq!(mmc1.ppu_read(0x1000), 12);
+    }
+
+    #[test]
+    fn control_register_sets_mirroring() {
+        let mut mmc1 = Mmc1::new(cartridge(1, 16, 8));
+        write_serial(&mut mmc1, 0x8000, 0b01110);
+        assert_eq!(mmc1.mirroring(), Mirroring::Vertical);
+        write_serial(&mut mmc1, 0x8000, 0b01100);
+        assert_eq!(mmc1.mirroring(), Mirroring::SingleScreenLower);
+        write_serial(&mut mmc1, 0x8000, 0b01101);
+        assert_eq!(mmc1.mirroring(), Mirroring::SingleScreenUpper);
+        write_serial(&mut mmc1, 0x8000, 0b01111);
+        assert_eq!(mmc1.mirroring(), Mirroring::Horizontal);
+    }
+
+    #[test]
+    fn reset_restores_the_power_on_mapping() {
+        let mut mmc1 = Mmc1::new(cartridge(1, 16, 8));
+        write_serial(&mut mmc1, 0x8000, 0b10010);
+        write_serial(&mut mmc1, 0xE000, 0b10101);
+        // Halfway through a write, too
+        mmc1.cpu_write(0x8000, 1);
+        assert_eq!(prg_banks(&mut mmc1), (8, 10));
+        assert_eq!(mmc1.mirroring(), Mirroring::Vertical);
+
+        mmc1.reset();
+        assert_eq!(prg_banks(&mut mmc1), (0, 14));
+        assert_eq!(mmc1.mirroring(), Mirroring::SingleScreenLower);
+        write_serial(&mut mmc1, 0xE000, 1);
+        assert_eq!(prg_banks(&mut mmc1), (2, 14));
+    }
+
+    #[test]
+    fn only_the_fifth_write_switches_banks() {
+        let mut mmc1 = Mmc1::new(cartridge(1, 16, 8));
+        // 5 is 0b00101, low bit first
+        for (write, bit) in [1, 0, 1, 0].into_iter().enumerate() {
+            mmc1.cpu_write(0xE000, bit);
+            assert_eq!(prg_banks(&mut mmc1), (0, 14), "after write {}", write + 1);
+        }
+        mmc1.cpu_write(0xE000, 0);
+        assert_eq!(prg_banks(&mut mmc1), (10, 14));
+        // The shift register is empty again for the next value
+        write_serial(&mut mmc1, 0xE000, 2);
+        assert_eq!(prg_banks(&mut mmc1), (4, 14));
+    }
+
+    #[test]
+    fn bit_7_clears_the_shift_register() {
+        let mut mmc1 = Mmc1::new(cartridge(1, 16, 8));
+        write_serial(&mut mmc1, 0x8000, 0b00000);
+        mmc1.cpu_write(0xE000, 1);
+        mmc1.cpu_write(0xE000, 1);
+        mmc1.cpu_write(0xE000, 0x80);
+        // Back to the last bank fixed at $C000, and the next five writes
+        // make a whole new value
+        assert_eq!(prg_banks(&mut mmc1), (0, 14));
+        write_serial(&mut mmc1, 0xE000, 1);
+        assert_eq!(prg_banks(&mut mmc1), (2, 14));
+    }
+
+    #[test]
+    fn shift_count_is_checked_on_load() {
+        let mut mmc1 = Mmc1::new(cartridge(1, 16, 8));
+        let mut before = StateWriter::new();
+        mmc1.save_state(&mut before);
+        // Zeros leave the shift register as it was, so only the count moves
+        for _ in 0..4 {
+            mmc1.cpu_write(0x8000, 0);
+        }
+        let mut after = StateWriter::new();
+        mmc1.save_state(&mut after);
+        let (before, mut after) = (before.into_bytes(), after.into_bytes());
+        let changed: Vec<usize> = (0..after.len())
+            .filter(|&i| before[i] != after[i])
+            .collect();
+        assert_eq!(changed.len(), 1);
+
+        after[changed[0]] = 5;
+        assert_eq!(
+            mmc1.load_state(&mut StateReader::new(&after)),
+            Err(StateError::InvalidValue("MMC1 shift count"))
+        );
+    }
+}
@@ -0,0 +1,233 @@
+use crate::cartridge::{CartridgeData, Mirroring};
+use crate::state::{StateError, StateReader, StateWriter};
+
+use super::{load_cartridge, read_chr, read_prg_rom, save_cartridge, write_chr, Mapper};
+
+// Mapper 9: https://www.nesdev.org/wiki/MMC2
+// Each pattern table has two CHR banks and a latch picking between them,
+// flipped by the PPU fetching tile $FD or $FE. The game draws those tiles at
+// the edges of anything that needs different graphics, so a whole screen can
+// use more than 8 KB of CHR. PRG is one switchable 8 KB bank and three fixed.
+pub struct Mmc2 {
+    cartridge: CartridgeData,
+    prg_bank: u8,
+    latches: ChrLatches,
+}
+
+// The latch core MMC2 and MMC4 share. They differ only in which fetches trip
+// the latch for the lower pattern table.
+pub(super) struct ChrLatches {
+    // [pattern table][latch], where latch 0 is $FD and 1 is $FE
+    banks: [[u8; 2]; 2],
+    latches: [usize; 2],
+    // MMC2 only trips on $0FD8 and $0FE8 exactly, MMC4 on the whole 8 bytes
+    // like the upper table always does
+    exact_lower_trigger: bool,
+    // 0: vertical, 1: horizontal
+    mirroring: u8,
+}
+
+impl ChrLatches {
+    pub fn new(exact_lower_trigger: bool) -> ChrLatches {
+        ChrLatches {
+            banks: [[0; 2]; 2],
+            latches: [1, 1],
+            exact_lower_trigger,
+            mirroring: 0,
+        }
+    }
+
+    // $B000-$FFFF, the same on both boards
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0xB000..=0xBFFF => self.banks[0][0] = value & 0x1F,
+            0xC000..=0xCFFF => self.banks[0][1] = value & 0x1F,
+            0xD000..=0xDFFF => self.banks[1][0] = value & 0x1F,
+            0xE000..=0xEFFF => self.banks[1][1] = value & 0x1F,
+            _ => self.mirroring = value & 1,
+        }
+    }
+
+    pub fn chr_offset(&self, address: u16) -> usize {
+        let table = ((address >> 12) & 1) as usize;
+        let bank = self.banks[table][self.latches[table]] as usize;
+        bank * 0x1000 + (address & 0x0FFF) as usize
+    }
+
+    // Called after the fetch, so the byte that trips the latch still comes
+    // from the old bank
+    pub fn update(&mut self, address: u16) {
+        let table = ((address >> 12) & 1) as usize;
+        let row = address & 0x0FFF;
+        let (fd, fe) = if table == 0 && self.exact_lower_trigger {
+            (row == 0x0FD8, row == 0x0FE8)
+        } else {
+            (
+                (0x0FD8..=0x0FDF).contains(&row),
+                (0x0FE8..=0x0FEF).contains(&row),
+            )
+        };
+        if fd {
+            self.latches[table] = 0;
+        } else if fe {
+            self.latches[table] = 1;
+        }
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        if self.mirroring == 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = ChrLatches::new(self.exact_lower_trigger);
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        for table in 0..2 {
+            state.bytes(&self.banks[table]);
+            state.bool(self.latches[table] == 1);
+        }
+        state.u8(self.mirroring);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        for table in 0..2 {
+            state.fill(&mut self.banks[table])?;
+            self.latches[table] = state.bool()? as usize;
+        }
+        self.mirroring = state.u8()?;
+        Ok(())
+    }
+}
+
+impl Mmc2 {
+    pub fn new(cartridge: CartridgeData) -> Mmc2 {
+        Mmc2 {
+            cartridge,
+            prg_bank: 0,
+            latches: ChrLatches::new(true),
+        }
+    }
+
+    fn prg_rom_offset(&self, address: u16) -> usize {
+        let banks = self.cartridge.prg_rom().len() / 0x2000;
+        let bank = match address {
+            0x8000..=0x9FFF => (self.prg_bank & 0x0F) as usize,
+            // The last three banks
+            _ => banks.saturating_sub(4) + ((address - 0x8000) >> 13) as usize,
+        };
+        bank * 0x2000 + (address & 0x1FFF) as usize
+    }
+}
+
+impl Mapper for Mmc2 {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        match address {
+            0x8000..=0xFFFF => read_prg_rom(&self.cartridge, self.prg_rom_offset(address)),
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        match address {
+            0xA000..=0xAFFF => self.prg_bank = value,
+            0xB000..=0xFFFF => self.latches.write_register(address, value),
+            _ => (),
+        }
+    }
+
+    fn ppu_read(&mut self, address: u16) -> u8 {
+        let value = read_chr(&self.cartridge, self.latches.chr_offset(address));
+        self.latches.update(address);
+        value
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        let offset = self.latches.chr_offset(address);
+        write_chr(&mut self.cartridge, offset, value);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.latches.mirroring()
+    }
+
+    fn cartridge(&self) -> &CartridgeData {
+        &self.cartridge
+    }
+
+    fn cartridge_mut(&mut self) -> &mut CartridgeData {
+        &mut self.cartridge
+    }
+
+    fn reset(&mut self) {
+        self.prg_bank = 0;
+        self.latches.reset();
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        save_cartridge(&self.cartridge, state);
+        state.u8(self.prg_bank);
+        self.latches.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        load_cartridge(&mut self.cartridge, state)?;
+        self.prg_bank = state.u8()?;
+        self.latches.load_state(state)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::cartridge;
+    use super::*;
+
+    // FD and FE banks of 2 and 3 for $0000 and 4 and 5 for $1000. Each 4 KB
+    // bank starts with the 1 KB bank four times its number.
+    fn set_chr_banks(mapper: &mut Mmc2) {
+        for (address, bank) in [(0xB000, 2), (0xC000, 3), (0xD000, 4), (0xE000, 5)] {
+            mapper.cpu_write(address, bank);
+        }
+    }
+
+    #[test]
+    fn latches_switch_after_the_fetch() {
+        let mut mmc2 = Mmc2::new(cartridge(9, 16, 128));
+        set_chr_banks(&mut mmc2);
+        // Both latches start on FE
+        assert_eq!(mmc2.ppu_read(0x0000), 12);
+        assert_eq!(mmc2.ppu_read(0x1000), 20);
+
+        // The byte that trips the latch still comes from the old bank
+        assert_eq!(mmc2.ppu_read(0x0FD8), 15);
+        assert_eq!(mmc2.ppu_read(0x0000), 8);
+        assert_eq!(mmc2.ppu_read(0x0FE8), 11);
+        assert_eq!(mmc2.ppu_read(0x0000), 12);
+
+        // The upper table trips on the whole row, the lower only on the
+        // first byte of it
+        assert_eq!(mmc2.ppu_read(0x1FDB), 23);
+        assert_eq!(mmc2.ppu_read(0x1000), 16);
+        mmc2.ppu_read(0x0FDB);
+        assert_eq!(mmc2.ppu_read(0x0000), 12);
+    }
+
+    #[test]
+    fn prg_and_mirroring() {
+        let mut mmc2 = Mmc2::new(cartridge(9, 16, 128));
+        mmc2.cpu_write(0xA000, 5);
+        let prg: Vec<u8> = (0..4)
+            .map(|bank| mmc2.cpu_read(0x8000 + bank * 0x2000))
+            .collect();
+        assert_eq!(prg, [5, 13, 14, 15]);
+        mmc2.cpu_write(0xF000, 1);
+        assert_eq!(mmc2.mirroring(), Mirroring::Horizontal);
+        mmc2.cpu_write(0xF000, 0);
+        assert_eq!(mmc2.mirroring(), Mirroring::Vertical);
+    }
+}
@@ -0,0 +1,427 @@
+use crate::cartridge::{CartridgeData, Mirroring};
+use crate::state::{StateError, StateReader, StateWriter};
+
+use super::{
+    load_cartridge, read_chr, read_prg_ram, read_prg_rom, save_cartridge, write_chr, write_prg_ram,
+    Mapper,
+};
+
+// Mapper 4: https://www.nesdev.org/wiki/MMC3
+// Eight bank registers are written through a select/data pair at $8000 and
+// $8001. PRG is switched in 8 KB banks and CHR in 1 KB and 2 KB banks.
+// The scanline counter is clocked by PPU A12 rising, which with the usual
+// background at $0000 and sprites at $1000 happens once per scanline.
+// TxSROM and TQROM boards wrap this in txsrom.rs and tqrom.rs.
+pub struct Mmc3 {
+    cartridge: CartridgeData,
+    // 7  bit  0
+    // ---- ----
+    // CPxx xRRR
+    // ||     |||
+    // ||     +++- Bank register written by the next $8001 write
+    // |+-------- PRG ROM bank mode (0: $8000 swappable, $C000 fixed to the
+    // |                                second-last bank; 1: the other way round)
+    // +--------- CHR A12 inversion (0: 2 KB banks at $0000; 1: 2 KB banks at $1000)
+    bank_select: u8,
+    // R0-R1 pick 2 KB CHR banks, R2-R5 1 KB CHR banks, R6-R7 8 KB PRG banks
+    bank_registers: [u8; 8],
+    // 0: vertical, 1: horizontal
+    mirroring: u8,
+    // Bit 7 enables PRG RAM, bit 6 write protects it
+    prg_ram_protect: u8,
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+    // Older MMC3As only fire when the counter is decremented to 0 or reloaded
+    // to 0 through $C001, NES 2.0 submapper 4
+    old_style_irq: bool,
+    a12: A12Filter,
+}
+
+impl Mmc3 {
+    pub fn new(cartridge: CartridgeData) -> Mmc3 {
+        let old_style_irq = cartridge.submapper() == 4;
+        Mmc3 {
+            cartridge,
+            bank_select: 0,
+            bank_registers: [0, 2, 4, 5, 6, 7, 0, 1],
+            mirroring: 0,
+            prg_ram_protect: 0x80,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+            old_style_irq,
+            a12: A12Filter::default(),
+        }
+    }
+
+    // https://www.nesdev.org/wiki/MMC3#IRQ_Specifics
+    fn clock_irq_counter(&mut self) {
+        let was_reloaded = self.irq_reload;
+        let was_zero = self.irq_counter == 0;
+        if was_zero || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+        let fires = if self.old_style_irq {
+            !was_zero || was_reloaded
+        } else {
+            true
+        };
+        if self.irq_counter == 0 && self.irq_enabled && fires {
+            self.irq_pending = true;
+        }
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        // Registers are picked by the address range and whether it's even or odd
+        match (address, address & 1) {
+            (0x8000..=0x9FFF, 0) => self.bank_select = value,
+            (0x8000..=0x9FFF, _) => {
+                self.bank_registers[(self.bank_select & 0b111) as usize] = value
+            }
+            (0xA000..=0xBFFF, 0) => self.mirroring = value,
+            (0xA000..=0xBFFF, _) => self.prg_ram_protect = value,
+            (0xC000..=0xDFFF, 0) => self.irq_latch = value,
+            (0xC000..=0xDFFF, _) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            (_, 0) => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            }
+            (_, _) => self.irq_enabled = true,
+        }
+    }
+
+    fn prg_rom_offset(&self, address: u16) -> usize {
+        let second_last_bank = (self.cartridge.prg_rom().len() / 0x2000).saturating_sub(2);
+        let r6 = (self.bank_registers[6] & 0x3F) as usize;
+        let r7 = (self.bank_registers[7] & 0x3F) as usize;
+        let bank = match (self.bank_select & 0x40 != 0, address) {
+            (false, 0x8000..=0x9FFF) => r6,
+            (true, 0x8000..=0x9FFF) => second_last_bank,
+            (_, 0xA000..=0xBFFF) => r7,
+            (false, 0xC000..=0xDFFF) => second_last_bank,
+            (true, 0xC000..=0xDFFF) => r6,
+            (_, _) => second_last_bank + 1,
+        };
+        bank * 0x2000 + (address & 0x1FFF) as usize
+    }
+
+    // The 1 KB CHR bank a pattern table address is in, as the bank registers
+    // give it. TxSROM and TQROM use the top bits for other things.
+    pub(super) fn chr_bank(&self, address: u16) -> u8 {
+        let mut address = address & 0x1FFF;
+        // Inversion swaps the two pattern tables
+        if self.bank_select & 0x80 != 0 {
+            address ^= 0x1000;
+        }
+        let registers = &self.bank_registers;
+        let low_bit = (address >> 10 & 1) as u8;
+        match address {
+            // 2 KB banks ignore the low bit
+            0x0000..=0x07FF => registers[0] & !1 | low_bit,
+            0x0800..=0x0FFF => registers[1] & !1 | low_bit,
+            _ => registers[2 + ((address - 0x1000) >> 10) as usize],
+        }
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        self.chr_bank(address) as usize * 0x400 + (address & 0x3FF) as usize
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_ram_protect & 0x80 != 0
+    }
+}
+
+// Picks out the PPU A12 rises that clock the scanline counter, shared with
+// the boards that copied it. Rises are ignored unless A12 has been low for a
+// few CPU cycles, which filters out the toggling between sprite fetches.
+#[derive(Default)]
+pub(super) struct A12Filter {
+    a12: bool,
+    low_cycles: u8,
+}
+
+impl A12Filter {
+    // Every address the PPU puts out. Returns whether it made a rise that
+    // counts.
+    pub fn rose(&mut self, address: u16) -> bool {
+        let a12 = address & 0x1000 != 0;
+        let rose = a12 && !self.a12 && self.low_cycles >= 3;
+        if !a12 && self.a12 {
+            self.low_cycles = 0;
+        }
+        self.a12 = a12;
+        rose
+    }
+
+    // Once per CPU cycle
+    pub fn cpu_clock(&mut self) {
+        if !self.a12 {
+            self.low_cycles = self.low_cycles.saturating_add(1);
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = A12Filter::default();
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.a12);
+        state.u8(self.low_cycles);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.a12 = state.bool()?;
+        self.low_cycles = state.u8()?;
+        Ok(())
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        match address {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                read_prg_ram(&self.cartridge, (address - 0x6000) as usize)
+            }
+            0x8000..=0xFFFF => read_prg_rom(&self.cartridge, self.prg_rom_offset(address)),
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        match address {
+            0x6000..=0x7FFF if self.prg_ram_enabled() && self.prg_ram_protect & 0x40 == 0 => {
+                write_prg_ram(&mut self.cartridge, (address - 0x6000) as usize, value)
+            }
+            0x8000..=0xFFFF => self.write_register(address, value),
+            _ => (),
+        }
+    }
+
+    fn ppu_read(&mut self, address: u16) -> u8 {
+        read_chr(&self.cartridge, self.chr_offset(address))
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        let offset = self.chr_offset(address);
+        write_chr(&mut self.cartridge, offset, value);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        // Boards with extra VRAM ignore the mirroring register
+        if self.cartridge.mirroring() == Mirroring::FourScreen {
+            return Mirroring::FourScreen;
+        }
+        if self.mirroring & 1 == 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        }
+    }
+
+    fn cartridge(&self) -> &CartridgeData {
+        &self.cartridge
+    }
+
+    fn cartridge_mut(&mut self) -> &mut CartridgeData {
+        &mut self.cartridge
+    }
+
+    fn reset(&mut self) {
+        self.bank_select = 0;
+        self.bank_registers = [0, 2, 4, 5, 6, 7, 0, 1];
+        self.mirroring = 0;
+        self.prg_ram_protect = 0x80;
+        self.irq_latch = 0;
+        self.irq_counter = 0;
+        self.irq_reload = false;
+        self.irq_enabled = false;
+        self.irq_pending = false;
+        self.a12.reset();
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        save_cartridge(&self.cartridge, state);
+        state.u8(self.bank_select);
+        state.bytes(&self.bank_registers);
+        state.u8(self.mirroring);
+        state.u8(self.prg_ram_protect);
+        state.u8(self.irq_latch);
+        state.u8(self.irq_counter);
+        state.bool(self.irq_reload);
+        state.bool(self.irq_enabled);
+        state.bool(self.irq_pending);
+        self.a12.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        load_cartridge(&mut self.cartridge, state)?;
+        self.bank_select = state.u8()?;
+        state.fill(&mut self.bank_registers)?;
+        self.mirroring = state.u8()?;
+        self.prg_ram_protect = state.u8()?;
+        self.irq_latch = state.u8()?;
+        self.irq_counter = state.u8()?;
+        self.irq_reload = state.bool()?;
+        self.irq_enabled = state.bool()?;
+        self.irq_pending = state.bool()?;
+        self.a12.load_state(state)
+    }
+
+    // The IRQ line stays asserted until the CPU disables it at $E000
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn notify_ppu_address(&mut self, address: u16) {
+        if self.a12.rose(address) {
+            self.clock_irq_counter();
+        }
+    }
+
+    fn cpu_clock(&mut self) {
+        self.a12.cpu_clock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cartridge::CartridgeBuilder;
+
+    use super::super::tests::cartridge;
+    use super::*;
+
+    fn set_bank(mmc3: &mut Mmc3, register: u8, bank: u8) {
+        mmc3.cpu_write(0x8000, register);
+        mmc3.cpu_write(0x8001, bank);
+    }
+
+    // Background fetches from $0000 then sprite fetches from $1000, with
+    // A12 low for long enough in between to count
+    fn scanline(mmc3: &mut Mmc3) {
+        mmc3.notify_ppu_address(0x0000);
+        for _ in 0..20 {
+            mmc3.cpu_clock();
+        }
+        mmc3.notify_ppu_address(0x1000);
+    }
+
+    #[test]
+    fn bank_registers() {
+        // 128 KB PRG, 64 KB CHR
+        let mut mmc3 = Mmc3::new(cartridge(4, 16, 64));
+        for (register, bank) in [(0, 10), (1, 21), (2, 40), (3, 41), (4, 42), (5, 43)] {
+            set_bank(&mut mmc3, register, bank);
+        }
+        set_bank(&mut mmc3, 6, 3);
+        set_bank(&mut mmc3, 7, 5);
+
+        let chr: Vec<u8> = (0..8).map(|bank| mmc3.ppu_read(bank * 0x400)).collect();
+        assert_eq!(chr, [10, 11, 20, 21, 40, 41, 42, 43]);
+        let prg: Vec<u8> = (0..4)
+            .map(|bank| mmc3.cpu_read(0x8000 + bank * 0x2000))
+            .collect();
+        assert_eq!(prg, [3, 5, 14, 15]);
+
+        // Swapping the PRG mode moves R6 to $C000, and CHR inversion swaps
+        // the pattern tables
+        mmc3.cpu_write(0x8000, 0xC0);
+        let prg: Vec<u8> = (0..4)
+            .map(|bank| mmc3.cpu_read(0x8000 + bank * 0x2000))
+            .collect();
+        assert_eq!(prg, [14, 5, 3, 15]);
+        let chr: Vec<u8> = (0..8).map(|bank| mmc3.ppu_read(bank * 0x400)).collect();
+        assert_eq!(chr, [40, 41, 42, 43, 10, 11, 20, 21]);
+    }
+
+    #[test]
+    fn irq_reloads_then_fires() {
+        let mut mmc3 = Mmc3::new(cartridge(4, 16, 64));
+        mmc3.cpu_write(0xC000, 3);
+        mmc3.cpu_write(0xC001, 0);
+        mmc3.cpu_write(0xE001, 0);
+
+        // The first clock reloads the counter and the next three count it down
+        for _ in 0..3 {
+            scanline(&mut mmc3);
+            assert!(!mmc3.irq_pending());
+        }
+        scanline(&mut mmc3);
+        assert!(mmc3.irq_pending());
+
+        // Acknowledged and disabled by $E000, then reloaded from the latch
+        mmc3.cpu_write(0xE000, 0);
+        assert!(!mmc3.irq_pending());
+        mmc3.cpu_write(0xE001, 0);
+        for _ in 0..3 {
+            scanline(&mut mmc3);
+            assert!(!mmc3.irq_pending());
+        }
+        scanline(&mut mmc3);
+        assert!(mmc3.irq_pending());
+    }
+
+    #[test]
+    fn a12_filter_ignores_quick_toggles() {
+        let mut mmc3 = Mmc3::new(cartridge(4, 16, 64));
+        mmc3.cpu_write(0xC000, 1);
+        mmc3.cpu_write(0xC001, 0);
+        mmc3.cpu_write(0xE001, 0);
+        // Reloads to 1
+        scanline(&mut mmc3);
+
+        // Eight sprite fetches toggling A12 with hardly any time low in
+        // between only count as the one rise
+        for _ in 0..8 {
+            mmc3.notify_ppu_address(0x0000);
+            mmc3.cpu_clock();
+            mmc3.notify_ppu_address(0x1000);
+        }
+        assert!(!mmc3.irq_pending());
+        scanline(&mut mmc3);
+        assert!(mmc3.irq_pending());
+    }
+
+    #[test]
+    fn old_style_irq_with_a_latch_of_0() {
+        let prg_rom: Vec<u8> = vec![0; 0x20000];
+        let fire_counts: Vec<usize> = [0, 4]
+            .into_iter()
+            .map(|submapper| {
+                let cartridge = CartridgeBuilder::new()
+                    .mapper(4)
+                    .submapper(submapper)
+                    .prg_rom(prg_rom.clone())
+                    .build()
+                    .unwrap();
+                let mut mmc3 = Mmc3::new(cartridge);
+                mmc3.cpu_write(0xC000, 0);
+                mmc3.cpu_write(0xC001, 0);
+                mmc3.cpu_write(0xE001, 0);
+                (0..4)
+                    .filter(|_| {
+                        scanline(&mut mmc3);
+                        let fired = mmc3.irq_pending();
+                        mmc3.cpu_write(0xE000, 0);
+                        mmc3.cpu_write(0xE001, 0);
+                        fired
+                    })
+                    .count()
+            })
+            .collect();
+        // New style fires on every scanline, old style only on the reload
+        // through $C001
+        assert_eq!(fire_counts, [4, 1]);
+    }
+}
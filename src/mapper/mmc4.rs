@@ -0,0 +1,130 @@
+use crate::cartridge::{CartridgeData, Mirroring};
+use crate::state::{StateError, StateReader, StateWriter};
+
+use super::mmc2::ChrLatches;
+use super::{
+    load_cartridge, read_chr, read_prg_ram, read_prg_rom, save_cartridge, write_chr, write_prg_ram,
+    Mapper,
+};
+
+// Mapper 10: https://www.nesdev.org/wiki/MMC4
+// The MMC2's CHR latches with 16 KB PRG banking, the last bank fixed at
+// $C000, and 8 KB of PRG RAM at $6000
+pub struct Mmc4 {
+    cartridge: CartridgeData,
+    prg_bank: u8,
+    latches: ChrLatches,
+}
+
+impl Mmc4 {
+    pub fn new(cartridge: CartridgeData) -> Mmc4 {
+        Mmc4 {
+            cartridge,
+            prg_bank: 0,
+            latches: ChrLatches::new(false),
+        }
+    }
+
+    fn prg_rom_offset(&self, address: u16) -> usize {
+        let bank = match address {
+            0x8000..=0xBFFF => (self.prg_bank & 0x0F) as usize,
+            _ => self.cartridge.prg_rom_banks().saturating_sub(1),
+        };
+        bank * 0x4000 + (address & 0x3FFF) as usize
+    }
+}
+
+impl Mapper for Mmc4 {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        match address {
+            0x6000..=0x7FFF => read_prg_ram(&self.cartridge, (address - 0x6000) as usize),
+            0x8000..=0xFFFF => read_prg_rom(&self.cartridge, self.prg_rom_offset(address)),
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        match address {
+            0x6000..=0x7FFF => {
+                write_prg_ram(&mut self.cartridge, (address - 0x6000) as usize, value)
+            }
+            0xA000..=0xAFFF => self.prg_bank = value,
+            0xB000..=0xFFFF => self.latches.write_register(address, value),
+            _ => (),
+        }
+    }
+
+    fn ppu_read(&mut self, address: u16) -> u8 {
+        let value = read_chr(&self.cartridge, self.latches.chr_offset(address));
+        self.latches.update(address);
+        value
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        let offset = self.latches.chr_offset(address);
+        write_chr(&mut self.cartridge, offset, value);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.latches.mirroring()
+    }
+
+    fn cartridge(&self) -> &CartridgeData {
+        &self.cartridge
+    }
+
+    fn cartridge_mut(&mut self) -> &mut CartridgeData {
+        &mut self.cartridge
+    }
+
+    fn reset(&mut self) {
+        self.prg_bank = 0;
+        self.latches.reset();
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        save_cartridge(&self.cartridge, state);
+        state.u8(self.prg_bank);
+        self.latches.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        load_cartridge(&mut self.cartridge, state)?;
+        self.prg_bank = state.u8()?;
+        self.latches.load_state(state)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::cartridge;
+    use super::*;
+
+    #[test]
+    fn latches_trip_on_the_whole_row() {
+        let mut mmc4 = Mmc4::new(cartridge(10, 16, 128));
+        // FD and FE banks of 2 and 3 for $0000 and 4 and 5 for $1000
+        for (address, bank) in [(0xB000, 2), (0xC000, 3), (0xD000, 4), (0xE000, 5)] {
+            mmc4.cpu_write(address, bank);
+        }
+        // Unlike the MMC2, the lower table trips anywhere in $0FD8-$0FDF
+        assert_eq!(mmc4.ppu_read(0x0FDB), 15);
+        assert_eq!(mmc4.ppu_read(0x0000), 8);
+        assert_eq!(mmc4.ppu_read(0x0FEF), 11);
+        assert_eq!(mmc4.ppu_read(0x0000), 12);
+        assert_eq!(mmc4.ppu_read(0x1FD8), 23);
+        assert_eq!(mmc4.ppu_read(0x1000), 16);
+    }
+
+    #[test]
+    fn prg_banks_are_16k() {
+        let mut mmc4 = Mmc4::new(cartridge(10, 16, 128));
+        mmc4.cpu_write(0xA000, 3);
+        assert_eq!(mmc4.cpu_read(0x8000), 6);
+        assert_eq!(mmc4.cpu_read(0xBFFF), 7);
+        assert_eq!(mmc4.cpu_read(0xC000), 14);
+        mmc4.cpu_write(0x6000, 0x42);
+        assert_eq!(mmc4.cpu_read(0x6000), 0x42);
+    }
+}
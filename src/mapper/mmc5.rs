@@ -0,0 +1,591 @@
+use crate::cartridge::{CartridgeData, Mirroring};
+use crate::state::{StateError, StateReader, StateWriter};
+
+use super::{
+    load_cartridge, read_chr, read_prg_ram, read_prg_rom, save_cartridge, write_chr, write_prg_ram,
+    Mapper,
+};
+
+// Mapper 5: https://www.nesdev.org/wiki/MMC5
+// Four PRG and four CHR banking modes, PRG RAM that can be banked into ROM
+// space, 1 KB of extra RAM usable as a nametable, an attribute table per
+// tile or plain memory, and a nametable controller that can also fill a
+// screen with one tile. The board works out what the PPU is doing by
+// watching its bus, since it has no other connection to it.
+pub struct Mmc5 {
+    cartridge: CartridgeData,
+    // $5100: 0: 32 KB; 1: 16 KB x2; 2: 16 KB + 8 KB x2; 3: 8 KB x4
+    prg_mode: u8,
+    // $5101: 0: 8 KB; 1: 4 KB; 2: 2 KB; 3: 1 KB
+    chr_mode: u8,
+    // $5102 and $5103 have to be 2 and 1 for PRG RAM to take writes
+    prg_ram_protect: [u8; 2],
+    // $5104: 0: nametable; 1: extended attributes; 2: RAM; 3: read-only RAM
+    exram_mode: u8,
+    // $5105, two bits per nametable: 0, 1: console VRAM; 2: ExRAM; 3: fill
+    nametable_mapping: u8,
+    fill_tile: u8,
+    fill_attribute: u8,
+    // $5113-$5117, 8 KB bank numbers. Bit 7 picks ROM over RAM for all but
+    // $5113, which is always RAM, and $5117, which is always ROM.
+    prg_banks: [u8; 5],
+    // $5120-$5127 are used for sprites and $5128-$512B for the background
+    // when sprites are 8x16. Otherwise whichever set was written last is
+    // used for everything.
+    chr_banks: [u16; 12],
+    chr_upper_bits: u8,
+    background_set_last: bool,
+    exram: [u8; 1024],
+    // $5203-$5204
+    irq_compare: u8,
+    irq_enabled: bool,
+    irq_pending: bool,
+    multiplicand: u8,
+    multiplier: u8,
+    // Snooped from $2000 and $2001
+    tall_sprites: bool,
+    rendering: bool,
+    // Scanline detection. The PPU fetches the same nametable byte three
+    // times at the end of every rendered line, and stops fetching outside
+    // the picture.
+    in_frame: bool,
+    scanline: u8,
+    last_address: u16,
+    repeats: u8,
+    idle_cycles: u8,
+    // Fetches since the start of the line. 32 tiles of four fetches take the
+    // first 128, then come 16 sprite pattern fetches.
+    fetch_index: u16,
+    sprite_fetch: bool,
+    // ExRAM byte for the tile being fetched, in extended attribute mode
+    extended_attribute: u8,
+}
+
+impl Mmc5 {
+    pub fn new(cartridge: CartridgeData) -> Mmc5 {
+        Mmc5 {
+            cartridge,
+            prg_mode: 3,
+            chr_mode: 0,
+            prg_ram_protect: [0; 2],
+            exram_mode: 0,
+            nametable_mapping: 0,
+            fill_tile: 0,
+            fill_attribute: 0,
+            // The last bank comes up at $E000 so the reset vector is there
+            prg_banks: [0, 0, 0, 0, 0xFF],
+            chr_banks: [0; 12],
+            chr_upper_bits: 0,
+            background_set_last: false,
+            exram: [0; 1024],
+            irq_compare: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            multiplicand: 0xFF,
+            multiplier: 0xFF,
+            tall_sprites: false,
+            rendering: false,
+            in_frame: false,
+            scanline: 0,
+            last_address: 0,
+            repeats: 0,
+            idle_cycles: 0,
+            fetch_index: 0,
+            sprite_fetch: false,
+            extended_attribute: 0,
+        }
+    }
+
+    fn prg_ram_writable(&self) -> bool {
+        self.prg_ram_protect == [2, 1]
+    }
+
+    // Register and size of the bank behind a $8000-$FFFF address
+    fn prg_bank_register(&self, address: u16) -> (usize, usize) {
+        match (self.prg_mode, address) {
+            (0, _) => (4, 0x8000),
+            (1, 0x8000..=0xBFFF) | (2, 0x8000..=0xBFFF) => (2, 0x4000),
+            (1, _) => (4, 0x4000),
+            (2, 0xC000..=0xDFFF) => (3, 0x2000),
+            (2, _) => (4, 0x2000),
+            (_, _) => (1 + ((address - 0x8000) >> 13) as usize, 0x2000),
+        }
+    }
+
+    // Where a $6000-$FFFF address lands, and whether that's ROM
+    fn prg_offset(&self, address: u16) -> (usize, bool) {
+        if address < 0x8000 {
+            let bank = (self.prg_banks[0] & 0x0F) as usize;
+            return (bank * 0x2000 + (address & 0x1FFF) as usize, false);
+        }
+        let (register, size) = self.prg_bank_register(address);
+        let value = self.prg_banks[register];
+        let rom = register == 4 || value & 0x80 != 0;
+        // Bigger banks ignore the low bits of the 8 KB bank number
+        let mut bank = (value & 0x7F) as usize & !(size / 0x2000 - 1);
+        if !rom {
+            bank &= 0x0F;
+        }
+        (bank * 0x2000 + (address as usize & (size - 1)), rom)
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        let address = (address & 0x1FFF) as usize;
+        // Extended attributes give each background tile its own 4 KB bank
+        if self.exram_mode == 1 && self.in_frame && !self.sprite_fetch {
+            let bank =
+                (self.extended_attribute & 0x3F) as usize | (self.chr_upper_bits as usize) << 6;
+            return bank * 0x1000 + (address & 0x0FFF);
+        }
+        let background_set = if self.tall_sprites && self.in_frame {
+            !self.sprite_fetch
+        } else {
+            self.background_set_last
+        };
+        let mode = self.chr_mode as usize;
+        let size = 0x2000 >> mode;
+        let register = if background_set {
+            // Four registers cover $0000-$0FFF and repeat at $1000
+            let address = if mode == 0 { address } else { address & 0x0FFF };
+            8 + (address / size + 1) * (8 >> mode).min(4) - 1
+        } else {
+            (address / size + 1) * (8 >> mode) - 1
+        };
+        self.chr_banks[register] as usize * size + (address % size)
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x5100 => self.prg_mode = value & 0b11,
+            0x5101 => self.chr_mode = value & 0b11,
+            0x5102 => self.prg_ram_protect[0] = value & 0b11,
+            0x5103 => self.prg_ram_protect[1] = value & 0b11,
+            0x5104 => self.exram_mode = value & 0b11,
+            0x5105 => self.nametable_mapping = value,
+            0x5106 => self.fill_tile = value,
+            0x5107 => self.fill_attribute = value & 0b11,
+            0x5113..=0x5117 => self.prg_banks[(address - 0x5113) as usize] = value,
+            0x5120..=0x512B => {
+                self.chr_banks[(address - 0x5120) as usize] =
+                    value as u16 | (self.chr_upper_bits as u16) << 8;
+                self.background_set_last = address >= 0x5128;
+            }
+            0x5130 => self.chr_upper_bits = value & 0b11,
+            0x5203 => self.irq_compare = value,
+            0x5204 => self.irq_enabled = value & 0x80 != 0,
+            0x5205 => self.multiplicand = value,
+            0x5206 => self.multiplier = value,
+            _ => {}
+        }
+    }
+
+    // Three fetches in a row from one nametable address end a line
+    fn detect_scanline(&mut self, address: u16) -> bool {
+        if address == self.last_address && (0x2000..=0x2FFF).contains(&address) {
+            self.repeats += 1;
+        } else {
+            self.repeats = 0;
+        }
+        self.last_address = address;
+        if self.repeats != 2 {
+            return false;
+        }
+        if self.in_frame {
+            self.scanline = self.scanline.wrapping_add(1);
+            if self.scanline == self.irq_compare && self.irq_compare != 0 {
+                self.irq_pending = true;
+            }
+        } else {
+            self.in_frame = true;
+            self.scanline = 0;
+        }
+        true
+    }
+}
+
+impl Mapper for Mmc5 {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        match address {
+            // Reading the status acknowledges the IRQ
+            0x5204 => {
+                let status = (self.irq_pending as u8) << 7 | (self.in_frame as u8) << 6;
+                self.irq_pending = false;
+                status
+            }
+            0x5205 => (self.multiplicand as u16 * self.multiplier as u16) as u8,
+            0x5206 => ((self.multiplicand as u16 * self.multiplier as u16) >> 8) as u8,
+            0x5C00..=0x5FFF if self.exram_mode >= 2 => self.exram[(address - 0x5C00) as usize],
+            0x6000..=0xFFFF => match self.prg_offset(address) {
+                (offset, true) => read_prg_rom(&self.cartridge, offset),
+                (offset, false) => read_prg_ram(&self.cartridge, offset),
+            },
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        match address {
+            0x5000..=0x5BFF => self.write_register(address, value),
+            // Nametable and extended attribute modes only take writes while
+            // the PPU is drawing, and store 0 otherwise
+            0x5C00..=0x5FFF => match self.exram_mode {
+                0 | 1 => {
+                    self.exram[(address - 0x5C00) as usize] = if self.in_frame { value } else { 0 }
+                }
+                2 => self.exram[(address - 0x5C00) as usize] = value,
+                _ => {}
+            },
+            0x6000..=0xFFFF => {
+                if let (offset, false) = self.prg_offset(address) {
+                    if self.prg_ram_writable() {
+                        write_prg_ram(&mut self.cartridge, offset, value);
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn ppu_read(&mut self, address: u16) -> u8 {
+        read_chr(&self.cartridge, self.chr_offset(address))
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        let offset = self.chr_offset(address);
+        write_chr(&mut self.cartridge, offset, value);
+    }
+
+    // Only meaningful for the common arrangements, read_nametable has the rest
+    fn mirroring(&self) -> Mirroring {
+        match self.nametable_mapping {
+            0x44 => Mirroring::Vertical,
+            0x50 => Mirroring::Horizontal,
+            0x55 => Mirroring::SingleScreenUpper,
+            _ => Mirroring::SingleScreenLower,
+        }
+    }
+
+    fn read_nametable(&mut self, address: u16, vram: &[u8; 4096]) -> u8 {
+        let offset = (address & 0x3FF) as usize;
+        let attribute = offset >= 0x3C0;
+        if self.exram_mode == 1 && self.in_frame && !self.sprite_fetch {
+            if !attribute {
+                self.extended_attribute = self.exram[offset];
+            } else {
+                // Every quadrant gets the tile's palette
+                return (self.extended_attribute >> 6) * 0x55;
+            }
+        }
+        let quadrant = (address >> 10) & 0b11;
+        match (self.nametable_mapping >> (quadrant * 2)) & 0b11 {
+            0 => vram[offset],
+            1 => vram[0x400 + offset],
+            2 if self.exram_mode <= 1 => self.exram[offset],
+            2 => 0,
+            _ if attribute => self.fill_attribute * 0x55,
+            _ => self.fill_tile,
+        }
+    }
+
+    fn write_nametable(&mut self, address: u16, value: u8, vram: &mut [u8; 4096]) {
+        let offset = (address & 0x3FF) as usize;
+        let quadrant = (address >> 10) & 0b11;
+        match (self.nametable_mapping >> (quadrant * 2)) & 0b11 {
+            0 => vram[offset] = value,
+            1 => vram[0x400 + offset] = value,
+            2 if self.exram_mode <= 1 => self.exram[offset] = value,
+            _ => {}
+        }
+    }
+
+    fn notify_ppu_address(&mut self, address: u16) {
+        self.idle_cycles = 0;
+        if self.detect_scanline(address) {
+            self.fetch_index = 0;
+            return;
+        }
+        self.sprite_fetch = self.in_frame && (128..144).contains(&self.fetch_index);
+        self.fetch_index = self.fetch_index.saturating_add(1);
+    }
+
+    fn notify_ppu_register_write(&mut self, address: u16, value: u8) {
+        match address % 8 {
+            0 => self.tall_sprites = value & 0x20 != 0,
+            1 => {
+                self.rendering = value & 0x18 != 0;
+                if !self.rendering {
+                    self.in_frame = false;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // The PPU going quiet for a few cycles means the picture has ended
+    fn cpu_clock(&mut self) {
+        self.idle_cycles = self.idle_cycles.saturating_add(1);
+        if self.idle_cycles >= 3 {
+            self.in_frame = false;
+            self.sprite_fetch = false;
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending && self.irq_enabled
+    }
+
+    fn cartridge(&self) -> &CartridgeData {
+        &self.cartridge
+    }
+
+    fn cartridge_mut(&mut self) -> &mut CartridgeData {
+        &mut self.cartridge
+    }
+
+    // ExRAM is left alone along with the cartridge's RAM
+    fn reset(&mut self) {
+        self.prg_mode = 3;
+        self.chr_mode = 0;
+        self.prg_ram_protect = [0; 2];
+        self.exram_mode = 0;
+        self.nametable_mapping = 0;
+        self.fill_tile = 0;
+        self.fill_attribute = 0;
+        self.prg_banks = [0, 0, 0, 0, 0xFF];
+        self.chr_banks = [0; 12];
+        self.chr_upper_bits = 0;
+        self.background_set_last = false;
+        self.irq_compare = 0;
+        self.irq_enabled = false;
+        self.irq_pending = false;
+        self.tall_sprites = false;
+        self.rendering = false;
+        self.in_frame = false;
+        self.repeats = 0;
+        self.sprite_fetch = false;
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        save_cartridge(&self.cartridge, state);
+        state.u8(self.prg_mode);
+        state.u8(self.chr_mode);
+        state.bytes(&self.prg_ram_protect);
+        state.u8(self.exram_mode);
+        state.u8(self.nametable_mapping);
+        state.u8(self.fill_tile);
+        state.u8(self.fill_attribute);
+        state.bytes(&self.prg_banks);
+        for &bank in &self.chr_banks {
+            state.u16(bank);
+        }
+        state.u8(self.chr_upper_bits);
+        state.bool(self.background_set_last);
+        state.bytes(&self.exram);
+        state.u8(self.irq_compare);
+        state.bool(self.irq_enabled);
+        state.bool(self.irq_pending);
+        state.u8(self.multiplicand);
+        state.u8(self.multiplier);
+        state.bool(self.tall_sprites);
+        state.bool(self.rendering);
+        state.bool(self.in_frame);
+        state.u8(self.scanline);
+        state.u16(self.last_address);
+        state.u8(self.repeats);
+        state.u8(self.idle_cycles);
+        state.u16(self.fetch_index);
+        state.bool(self.sprite_fetch);
+        state.u8(self.extended_attribute);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        load_cartridge(&mut self.cartridge, state)?;
+        self.prg_mode = state.u8_below(4, "MMC5 PRG mode")?;
+        self.chr_mode = state.u8_below(4, "MMC5 CHR mode")?;
+        state.fill(&mut self.prg_ram_protect)?;
+        self.exram_mode = state.u8_below(4, "MMC5 ExRAM mode")?;
+        self.nametable_mapping = state.u8()?;
+        self.fill_tile = state.u8()?;
+        self.fill_attribute = state.u8_below(4, "MMC5 fill attribute")?;
+        state.fill(&mut self.prg_banks)?;
+        for bank in &mut self.chr_banks {
+            *bank = state.u16()?;
+        }
+        self.chr_upper_bits = state.u8()?;
+        self.background_set_last = state.bool()?;
+        state.fill(&mut self.exram)?;
+        self.irq_compare = state.u8()?;
+        self.irq_enabled = state.bool()?;
+        self.irq_pending = state.bool()?;
+        self.multiplicand = state.u8()?;
+        self.multiplier = state.u8()?;
+        self.tall_sprites = state.bool()?;
+        self.rendering = state.bool()?;
+        self.in_frame = state.bool()?;
+        self.scanline = state.u8()?;
+        self.last_address = state.u16()?;
+        self.repeats = state.u8()?;
+        self.idle_cycles = state.u8()?;
+        self.fetch_index = state.u16()?;
+        self.sprite_fetch = state.bool()?;
+        self.extended_attribute = state.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::cartridge;
+    use super::*;
+
+    // The bank number each 8 KB of $8000-$FFFF reads back
+    fn prg_banks(mmc5: &mut Mmc5) -> [u8; 4] {
+        [0x8000, 0xA000, 0xC000, 0xE000].map(|address| mmc5.cpu_read(address))
+    }
+
+    #[test]
+    fn each_prg_mode() {
+        // 256 KB of PRG ROM
+        let mut mmc5 = Mmc5::new(cartridge(5, 32, 8));
+        for (address, bank) in [(0x5114, 0x84), (0x5115, 0x89), (0x5116, 0x8D), (0x5117, 27)] {
+            mmc5.cpu_write(address, bank);
+        }
+        // Bigger banks drop the low bits of the bank number
+        for (mode, banks) in [
+            (0, [24, 25, 26, 27]),
+            (1, [8, 9, 26, 27]),
+            (2, [8, 9, 13, 27]),
+            (3, [4, 9, 13, 27]),
+        ] {
+            mmc5.cpu_write(0x5100, mode);
+            assert_eq!(prg_banks(&mut mmc5), banks, "mode {mode}");
+        }
+    }
+
+    #[test]
+    fn prg_ram_in_rom_space() {
+        let mut mmc5 = Mmc5::new(cartridge(5, 32, 8));
+        // Locked until $5102 and $5103 are 2 and 1
+        mmc5.cpu_write(0x5114, 0x00);
+        mmc5.cpu_write(0x8000, 0x55);
+        assert_eq!(mmc5.cpu_read(0x8000), 0);
+        mmc5.cpu_write(0x5102, 2);
+        mmc5.cpu_write(0x5103, 1);
+        mmc5.cpu_write(0x8000, 0x55);
+        assert_eq!(mmc5.cpu_read(0x8000), 0x55);
+        // The same RAM bank at $6000
+        assert_eq!(mmc5.cpu_read(0x6000), 0x55);
+        // $E000 is always ROM
+        mmc5.cpu_write(0x5117, 0x00);
+        assert_eq!(mmc5.cpu_read(0xE000), 0);
+        mmc5.cpu_write(0xE000, 0x66);
+        assert_eq!(mmc5.cpu_read(0xE000), 0);
+    }
+
+    #[test]
+    fn fill_mode_nametable() {
+        let mut mmc5 = Mmc5::new(cartridge(5, 32, 8));
+        let mut vram = [0; 4096];
+        vram[0x005] = 0x11;
+        vram[0x405] = 0x22;
+        // Console VRAM A, B, then ExRAM, then fill
+        mmc5.cpu_write(0x5105, 0b11_10_01_00);
+        mmc5.cpu_write(0x5106, 0x42);
+        mmc5.cpu_write(0x5107, 2);
+        mmc5.write_nametable(0x2805, 0x33, &mut vram);
+        assert_eq!(mmc5.read_nametable(0x2005, &vram), 0x11);
+        assert_eq!(mmc5.read_nametable(0x2405, &vram), 0x22);
+        assert_eq!(mmc5.read_nametable(0x2805, &vram), 0x33);
+        // Fill mode has one tile and one palette for the whole screen, and
+        // ignores writes
+        mmc5.write_nametable(0x2C05, 0x44, &mut vram);
+        assert_eq!(mmc5.read_nametable(0x2C05, &vram), 0x42);
+        assert_eq!(mmc5.read_nametable(0x2FBF, &vram), 0x42);
+        assert_eq!(mmc5.read_nametable(0x2FC0, &vram), 0xAA);
+
+        // What went to ExRAM can be read back once it's plain RAM
+        mmc5.cpu_write(0x5104, 2);
+        assert_eq!(mmc5.cpu_read(0x5C05), 0x33);
+    }
+
+    #[test]
+    fn multiplier() {
+        let mut mmc5 = Mmc5::new(cartridge(5, 32, 8));
+        mmc5.cpu_write(0x5205, 12);
+        mmc5.cpu_write(0x5206, 34);
+        assert_eq!(mmc5.cpu_read(0x5205), 0x98);
+        assert_eq!(mmc5.cpu_read(0x5206), 0x01);
+    }
+
+    // The 1 KB bank number showing in each 1 KB of $0000-$1FFF
+    fn chr_banks(mmc5: &mut Mmc5) -> [u8; 8] {
+        [0, 1, 2, 3, 4, 5, 6, 7].map(|slot| mmc5.ppu_read(slot * 0x400))
+    }
+
+    #[test]
+    fn chr_banks_in_each_mode() {
+        let mut mmc5 = Mmc5::new(cartridge(5, 16, 248));
+        // $5120-$5127 get 1 to 8 and $5128-$512B get 20 to 23
+        let sprite_set = |mmc5: &mut Mmc5| mmc5.cpu_write(0x5127, 8);
+        let background_set = |mmc5: &mut Mmc5| mmc5.cpu_write(0x512B, 23);
+        for register in 0..8 {
+            mmc5.cpu_write(0x5120 + register, register as u8 + 1);
+        }
+        for register in 0..4 {
+            mmc5.cpu_write(0x5128 + register, register as u8 + 20);
+        }
+        // Bigger banks count in their own size
+        for (mode, sprites, background) in [
+            (
+                0,
+                [64, 65, 66, 67, 68, 69, 70, 71],
+                [184, 185, 186, 187, 188, 189, 190, 191],
+            ),
+            (
+                1,
+                [16, 17, 18, 19, 32, 33, 34, 35],
+                [92, 93, 94, 95, 92, 93, 94, 95],
+            ),
+            (
+                2,
+                [4, 5, 8, 9, 12, 13, 16, 17],
+                [42, 43, 46, 47, 42, 43, 46, 47],
+            ),
+            (
+                3,
+                [1, 2, 3, 4, 5, 6, 7, 8],
+                [20, 21, 22, 23, 20, 21, 22, 23],
+            ),
+        ] {
+            mmc5.cpu_write(0x5101, mode);
+            sprite_set(&mut mmc5);
+            assert_eq!(chr_banks(&mut mmc5), sprites, "mode {mode}");
+            background_set(&mut mmc5);
+            assert_eq!(chr_banks(&mut mmc5), background, "mode {mode}");
+        }
+    }
+
+    #[test]
+    fn tall_sprites_split_the_sets_by_fetch() {
+        let mut mmc5 = Mmc5::new(cartridge(5, 16, 248));
+        mmc5.cpu_write(0x5101, 3);
+        mmc5.cpu_write(0x5128, 20);
+        mmc5.cpu_write(0x5120, 1);
+        mmc5.notify_ppu_register_write(0x2000, 0x20);
+        mmc5.notify_ppu_register_write(0x2001, 0x18);
+        // Outside the frame the last set written wins
+        assert_eq!(mmc5.ppu_read(0x0000), 1);
+
+        // The start of a line, then a background fetch
+        for _ in 0..3 {
+            mmc5.notify_ppu_address(0x2000);
+        }
+        mmc5.notify_ppu_address(0x0000);
+        assert_eq!(mmc5.ppu_read(0x0000), 20);
+        // Fetches 128 to 143 are the sprites
+        for fetch in 1..129 {
+            mmc5.notify_ppu_address(fetch);
+        }
+        assert_eq!(mmc5.ppu_read(0x0000), 1);
+    }
+}
@@ -0,0 +1,344 @@
+// Cartridge boards, which decide what the CPU sees at $4020-$FFFF and what
+// the PPU sees at $0000-$1FFF
+// https://www.nesdev.org/wiki/Mapper
+
+mod axrom;
+mod cnrom;
+mod fme7;
+mod mmc1;
+mod mmc2;
+mod mmc3;
+mod mmc4;
+mod mmc5;
+mod namco163;
+mod nrom;
+mod rambo1;
+mod tqrom;
+mod txsrom;
+mod uxrom;
+mod vrc2;
+mod vrc4;
+mod vrc6;
+
+pub use axrom::Axrom;
+pub use cnrom::Cnrom;
+pub use fme7::Fme7;
+pub use mmc1::Mmc1;
+pub use mmc2::Mmc2;
+pub use mmc3::Mmc3;
+pub use mmc4::Mmc4;
+pub use mmc5::Mmc5;
+pub use namco163::Namco163;
+pub use nrom::Nrom;
+pub use rambo1::Rambo1;
+pub use tqrom::Tqrom;
+pub use txsrom::Txsrom;
+pub use uxrom::Uxrom;
+pub use vrc2::Vrc2;
+pub use vrc4::Vrc4;
+pub use vrc6::Vrc6;
+
+use crate::cartridge::{CartridgeData, CartridgeSnapshot, Mirroring};
+use crate::state::{StateError, StateReader, StateWriter};
+
+// Reads take &mut self since some boards react to them, like the MMC2 and
+// MMC4 latches switching on pattern fetches
+pub trait Mapper {
+    fn cpu_read(&mut self, address: u16) -> u8;
+    fn cpu_write(&mut self, address: u16, value: u8);
+    fn ppu_read(&mut self, address: u16) -> u8;
+    fn ppu_write(&mut self, address: u16, value: u8);
+    // Current nametable arrangement, which boards with a mirroring register
+    // can change at any time. Fixed boards report the header's.
+    fn mirroring(&self) -> Mirroring;
+    // Puts the registers back to their power-on state. Cartridge RAM is left
+    // alone, the same as pressing reset on the console.
+    fn reset(&mut self);
+    fn cartridge(&self) -> &CartridgeData;
+    fn cartridge_mut(&mut self) -> &mut CartridgeData;
+    // The RAM a battery keeps between sessions, for frontends to write out
+    // to a .sav file. None if the cartridge has no battery.
+    fn battery_ram(&self) -> Option<&[u8]> {
+        let cartridge = self.cartridge();
+        let has_battery = cartridge.is_battery_backed() && !cartridge.prg_nvram().is_empty();
+        has_battery.then(|| cartridge.prg_nvram())
+    }
+    fn battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        self.battery_ram()?;
+        Some(self.cartridge_mut().prg_nvram_mut())
+    }
+    // Registers and cartridge RAM for save states. load_state reads back
+    // exactly what save_state wrote, and fails on a state from another ROM.
+    fn save_state(&self, state: &mut StateWriter);
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError>;
+    // Whether the board is holding the CPU's IRQ line low
+    fn irq_pending(&self) -> bool {
+        false
+    }
+    // Every address the PPU puts on its bus, including nametable fetches and
+    // the ones that never reach ppu_read, for boards that watch A12
+    fn notify_ppu_address(&mut self, _address: u16) {}
+    // Once per CPU cycle, for boards that count M2
+    fn cpu_clock(&mut self) {}
+    // Pattern table accesses at $0000-$1FFF, given the console's VRAM too
+    // for boards that can bank it in there
+    fn read_pattern(&mut self, address: u16, _vram: &[u8; 4096]) -> u8 {
+        self.ppu_read(address)
+    }
+    fn write_pattern(&mut self, address: u16, value: u8, _vram: &mut [u8; 4096]) {
+        self.ppu_write(address, value);
+    }
+    // Nametable accesses at $2000-$3EFF. The cartridge connector carries
+    // the console's VRAM enable, so boards can put their own memory in place
+    // of it or arrange it however they like instead of by mirroring().
+    fn read_nametable(&mut self, address: u16, vram: &[u8; 4096]) -> u8 {
+        vram[nametable_offset(address, self.mirroring())]
+    }
+    fn write_nametable(&mut self, address: u16, value: u8, vram: &mut [u8; 4096]) {
+        vram[nametable_offset(address, self.mirroring())] = value;
+    }
+    // Writes to the PPU registers at $2000-$3FFF, which every board sees on
+    // the CPU bus and some snoop to follow what the PPU is doing
+    fn notify_ppu_register_write(&mut self, _address: u16, _value: u8) {}
+}
+
+// A mapper number with no board behind it yet
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnsupportedMapper {
+    pub number: u16,
+    pub submapper: u8,
+    pub name: Option<&'static str>,
+}
+
+// Builds the board for the cartridge's mapper number, with the cartridge in
+// its power-on state and the trainer loaded
+pub fn create_mapper(mut cartridge: CartridgeData) -> Result<Box<dyn Mapper>, UnsupportedMapper> {
+    // Without room for it the game runs as if it had no trainer
+    let _ = cartridge.load_trainer();
+    Ok(match cartridge.mapper_number() {
+        0 => Box::new(Nrom::new(cartridge)),
+        1 => Box::new(Mmc1::new(cartridge)),
+        2 => Box::new(Uxrom::new(cartridge)),
+        3 => Box::new(Cnrom::new(cartridge)),
+        4 => Box::new(Mmc3::new(cartridge)),
+        5 => Box::new(Mmc5::new(cartridge)),
+        7 => Box::new(Axrom::new(cartridge)),
+        9 => Box::new(Mmc2::new(cartridge)),
+        10 => Box::new(Mmc4::new(cartridge)),
+        19 => Box::new(Namco163::new(cartridge)),
+        21 | 22 | 23 | 25 => {
+            match vrc2::vrc_variant(cartridge.mapper_number(), cartridge.submapper()) {
+                (true, _) => Box::new(Vrc4::new(cartridge)),
+                (false, _) => Box::new(Vrc2::new(cartridge)),
+            }
+        }
+        24 | 26 => Box::new(Vrc6::new(cartridge)),
+        64 => Box::new(Rambo1::new(cartridge)),
+        69 => Box::new(Fme7::new(cartridge)),
+        118 => Box::new(Txsrom::new(cartridge)),
+        119 => Box::new(Tqrom::new(cartridge)),
+        number => {
+            return Err(UnsupportedMapper {
+                number,
+                submapper: cartridge.submapper(),
+                name: cartridge.mapper_name(),
+            })
+        }
+    })
+}
+
+// The old name, which only gave None for an unsupported mapper
+#[deprecated(note = "use create_mapper, which says which mapper is missing")]
+pub fn from_cartridge(cartridge: &CartridgeData) -> Option<Box<dyn Mapper>> {
+    create_mapper(cartridge.clone()).ok()
+}
+
+// Folds the four logical nametables onto the physical ones
+fn nametable_offset(address: u16, mirroring: Mirroring) -> usize {
+    let table = (address as usize >> 10) & 0b11;
+    let physical = match mirroring {
+        Mirroring::Horizontal => table >> 1,
+        Mirroring::Vertical => table & 1,
+        Mirroring::SingleScreenLower => 0,
+        Mirroring::SingleScreenUpper => 1,
+        Mirroring::FourScreen => table,
+    };
+    physical * 0x400 + (address as usize & 0x3FF)
+}
+
+// The cartridge's RAM, for the boards' save states
+fn save_cartridge(cartridge: &CartridgeData, state: &mut StateWriter) {
+    cartridge.snapshot().save_state(state);
+}
+
+fn load_cartridge(
+    cartridge: &mut CartridgeData,
+    state: &mut StateReader,
+) -> Result<(), StateError> {
+    let snapshot = CartridgeSnapshot::load_state(state)?;
+    cartridge.restore_snapshot(&snapshot)?;
+    Ok(())
+}
+
+// Helpers shared by the boards. Offsets past the end of a ROM wrap around,
+// the same way unconnected address lines mirror it on real hardware.
+
+// On discrete logic boards the ROM drives the data bus during a register
+// write as well, so the value that lands is ANDed with the ROM byte. NES 2.0
+// submapper 1 marks boards without the conflict, 2 boards with it, and 0
+// leaves it unspecified, where assuming conflicts is the safe choice.
+fn has_bus_conflicts(cartridge: &CartridgeData) -> bool {
+    cartridge.submapper() != 1
+}
+
+fn read_prg_rom(cartridge: &CartridgeData, offset: usize) -> u8 {
+    let prg_rom = cartridge.prg_rom();
+    if prg_rom.is_empty() {
+        return 0;
+    }
+    prg_rom[offset % prg_rom.len()]
+}
+
+// Boards without CHR ROM have CHR RAM in its place
+fn read_chr(cartridge: &CartridgeData, offset: usize) -> u8 {
+    let chr = if cartridge.chr_rom().is_empty() {
+        cartridge.chr_ram()
+    } else {
+        cartridge.chr_rom()
+    };
+    if chr.is_empty() {
+        return 0;
+    }
+    chr[offset % chr.len()]
+}
+
+fn write_chr(cartridge: &mut CartridgeData, offset: usize, value: u8) {
+    if !cartridge.chr_rom().is_empty() {
+        return;
+    }
+    let chr_ram = cartridge.chr_ram_mut();
+    if !chr_ram.is_empty() {
+        let len = chr_ram.len();
+        chr_ram[offset % len] = value;
+    }
+}
+
+// PRG RAM at $6000-$7FFF, battery-backed if the cartridge has any
+fn read_prg_ram(cartridge: &CartridgeData, offset: usize) -> u8 {
+    let prg_ram = if cartridge.prg_nvram().is_empty() {
+        cartridge.prg_ram()
+    } else {
+        cartridge.prg_nvram()
+    };
+    if prg_ram.is_empty() {
+        return 0;
+    }
+    prg_ram[offset % prg_ram.len()]
+}
+
+fn write_prg_ram(cartridge: &mut CartridgeData, offset: usize, value: u8) {
+    let prg_ram = if cartridge.prg_nvram().is_empty() {
+        cartridge.prg_ram_mut()
+    } else {
+        cartridge.prg_nvram_mut()
+    };
+    if !prg_ram.is_empty() {
+        let len = prg_ram.len();
+        prg_ram[offset % len] = value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cartridge::CartridgeBuilder;
+
+    use super::*;
+
+    // PRG ROM with each 8 KB filled with its bank number, and CHR ROM with
+    // each 1 KB filled with its bank number
+    pub(super) fn cartridge(mapper: u16, prg_8k_banks: u8, chr_1k_banks: u8) -> CartridgeData {
+        let prg_rom: Vec<u8> = (0..prg_8k_banks).flat_map(|bank| [bank; 0x2000]).collect();
+        let chr_rom: Vec<u8> = (0..chr_1k_banks).flat_map(|bank| [bank; 0x400]).collect();
+        CartridgeBuilder::new()
+            .mapper(mapper)
+            .prg_rom(prg_rom)
+            .chr_rom(chr_rom)
+            .build()
+            .unwrap()
+    }
+
+    const SUPPORTED: [u16; 20] = [
+        0, 1, 2, 3, 4, 5, 7, 9, 10, 19, 21, 22, 23, 24, 25, 26, 64, 69, 118, 119,
+    ];
+
+    fn save(mapper: &dyn Mapper) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        mapper.save_state(&mut state);
+        state.into_bytes()
+    }
+
+    // What the CPU and PPU see from every 1 KB of the cartridge
+    fn contents(mapper: &mut dyn Mapper) -> Vec<u8> {
+        let cpu = (0x6000..=0xFFFF)
+            .step_by(0x400)
+            .map(|address| mapper.cpu_read(address));
+        let cpu: Vec<u8> = cpu.collect();
+        let ppu = (0..0x2000)
+            .step_by(0x400)
+            .map(|address| mapper.ppu_read(address));
+        cpu.into_iter().chain(ppu).collect()
+    }
+
+    // The same battery of register writes for every board. Whatever they
+    // mean to it, none of them should panic, and a save state taken
+    // afterwards has to put a fresh board in the same place.
+    #[test]
+    fn every_mapper_conforms() {
+        for number in SUPPORTED {
+            let mut mapper = create_mapper(cartridge(number, 32, 128)).unwrap();
+            assert_eq!(mapper.cartridge().mapper_number(), number);
+            let power_on = contents(mapper.as_mut());
+            assert_eq!(contents(mapper.as_mut()), power_on, "mapper {number}");
+
+            for (i, address) in (0x4020..=0xFFFF).step_by(0x7F).enumerate() {
+                mapper.cpu_write(address, (i * 37) as u8);
+                mapper.cpu_clock();
+            }
+            for address in (0..0x3000).step_by(0x11) {
+                mapper.notify_ppu_address(address);
+                mapper.ppu_write(address & 0x1FFF, address as u8);
+            }
+            let _ = mapper.mirroring();
+            let _ = mapper.irq_pending();
+
+            let state = save(mapper.as_ref());
+            let written = contents(mapper.as_mut());
+            let mut restored = create_mapper(cartridge(number, 32, 128)).unwrap();
+            restored.load_state(&mut StateReader::new(&state)).unwrap();
+            assert_eq!(save(restored.as_ref()), state, "mapper {number}");
+            assert_eq!(contents(restored.as_mut()), written, "mapper {number}");
+            assert_eq!(restored.mirroring(), mapper.mirroring(), "mapper {number}");
+
+            mapper.reset();
+            let _ = contents(mapper.as_mut());
+        }
+    }
+
+    #[test]
+    fn unsupported_mappers_are_errors() {
+        let Err(error) = create_mapper(cartridge(6, 2, 8)) else {
+            panic!("mapper 6 has no board");
+        };
+        assert_eq!(error.number, 6);
+        assert_eq!(error.submapper, 0);
+        assert_eq!(error.name, crate::cartridge::mapper_name(6, 0));
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn from_cartridge_wraps_create_mapper() {
+        assert!(from_cartridge(&cartridge(6, 2, 8)).is_none());
+        let mapper = from_cartridge(&cartridge(4, 2, 8)).unwrap();
+        assert_eq!(mapper.cartridge().mapper_number(), 4);
+    }
+}
@@ -0,0 +1,366 @@
+use crate::cartridge::{CartridgeData, Mirroring};
+use crate::state::{StateError, StateReader, StateWriter};
+
+use super::{
+    load_cartridge, read_chr, read_prg_ram, read_prg_rom, save_cartridge, write_chr, write_prg_ram,
+    Mapper,
+};
+
+// Mapper 19: https://www.nesdev.org/wiki/Namco_163
+// Three switchable 8 KB PRG banks, 1 KB banks for each pattern table slot
+// and each nametable that can hold CHR ROM or the console's VRAM, a 15 bit
+// IRQ counter clocked by the CPU, and 128 bytes of RAM inside the chip
+// reached through an address and data port. The expansion audio plays from
+// that RAM but isn't emulated. The Namco 129 is the same chip without it.
+pub struct Namco163 {
+    cartridge: CartridgeData,
+    // $8000-$BFFF, one per 1 KB of $0000-$1FFF. $E0-$FF put VRAM page
+    // (bank & 1) there instead unless chr_vram_disabled says otherwise.
+    chr_banks: [u8; 8],
+    // $C000-$DFFF, one per nametable. $E0-$FF are VRAM pages too, and
+    // always are here.
+    nametable_banks: [u8; 4],
+    // $8000, $A000 and $C000. $E000 is fixed to the last bank.
+    prg_banks: [u8; 3],
+    // $E000 bit 6
+    sound_disabled: bool,
+    // $E800 bits 6 and 7, for $0000-$0FFF and $1000-$1FFF. Set means banks
+    // $E0-$FF are CHR ROM like the rest.
+    chr_vram_disabled: [bool; 2],
+    // $F800
+    // 7  bit  0
+    // ---- ----
+    // IAAA AAAA
+    // |||| ||||
+    // |+++-++++- Internal RAM address
+    // +--------- Step the address after each data port access
+    // The same write is also the PRG RAM protect, see prg_ram_writable.
+    ram_port: u8,
+    internal_ram: [u8; 128],
+    irq_counter: u16,
+    irq_enabled: bool,
+    irq_pending: bool,
+}
+
+impl Namco163 {
+    pub fn new(cartridge: CartridgeData) -> Namco163 {
+        Namco163 {
+            cartridge,
+            chr_banks: [0; 8],
+            nametable_banks: [0; 4],
+            prg_banks: [0; 3],
+            sound_disabled: false,
+            chr_vram_disabled: [false; 2],
+            ram_port: 0,
+            internal_ram: [0; 128],
+            irq_counter: 0,
+            irq_enabled: false,
+            irq_pending: false,
+        }
+    }
+
+    fn ram_address(&self) -> usize {
+        (self.ram_port & 0x7F) as usize
+    }
+
+    fn step_ram_address(&mut self) {
+        if self.ram_port & 0x80 != 0 {
+            self.ram_port = 0x80 | self.ram_port.wrapping_add(1) & 0x7F;
+        }
+    }
+
+    // Writes need the high nibble of $F800 to be 0100, and then each of the
+    // low four bits protects 2 KB of $6000-$7FFF
+    fn prg_ram_writable(&self, address: u16) -> bool {
+        let window = (address - 0x6000) >> 11;
+        self.ram_port & 0xF0 == 0x40 && self.ram_port >> window & 1 == 0
+    }
+
+    fn prg_rom_offset(&self, address: u16) -> usize {
+        let last_bank = (self.cartridge.prg_rom().len() / 0x2000).saturating_sub(1);
+        let bank = match address {
+            0x8000..=0x9FFF => self.prg_banks[0] as usize,
+            0xA000..=0xBFFF => self.prg_banks[1] as usize,
+            0xC000..=0xDFFF => self.prg_banks[2] as usize,
+            _ => last_bank,
+        };
+        bank * 0x2000 + (address & 0x1FFF) as usize
+    }
+
+    // The VRAM page a pattern table slot holds, if it holds one
+    fn pattern_vram_page(&self, address: u16) -> Option<usize> {
+        let bank = self.chr_banks[(address as usize >> 10) & 0b111];
+        let disabled = self.chr_vram_disabled[(address as usize >> 12) & 1];
+        (bank >= 0xE0 && !disabled).then_some((bank & 1) as usize)
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        let bank = self.chr_banks[(address as usize >> 10) & 0b111] as usize;
+        bank * 0x400 + (address & 0x3FF) as usize
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x8000..=0xBFFF => self.chr_banks[((address - 0x8000) >> 11) as usize] = value,
+            0xC000..=0xDFFF => self.nametable_banks[((address - 0xC000) >> 11) as usize] = value,
+            0xE000..=0xE7FF => {
+                self.prg_banks[0] = value & 0x3F;
+                self.sound_disabled = value & 0x40 != 0;
+            }
+            0xE800..=0xEFFF => {
+                self.prg_banks[1] = value & 0x3F;
+                self.chr_vram_disabled = [value & 0x40 != 0, value & 0x80 != 0];
+            }
+            0xF000..=0xF7FF => self.prg_banks[2] = value & 0x3F,
+            _ => self.ram_port = value,
+        }
+    }
+}
+
+impl Mapper for Namco163 {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        match address {
+            0x4800..=0x4FFF => {
+                let value = self.internal_ram[self.ram_address()];
+                self.step_ram_address();
+                value
+            }
+            0x5000..=0x57FF => self.irq_counter as u8,
+            0x5800..=0x5FFF => (self.irq_counter >> 8) as u8 | (self.irq_enabled as u8) << 7,
+            0x6000..=0x7FFF => read_prg_ram(&self.cartridge, (address - 0x6000) as usize),
+            0x8000..=0xFFFF => read_prg_rom(&self.cartridge, self.prg_rom_offset(address)),
+            _ => (address >> 8) as u8,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        match address {
+            0x4800..=0x4FFF => {
+                self.internal_ram[self.ram_address()] = value;
+                self.step_ram_address();
+            }
+            // Writing either half of the counter acknowledges the IRQ
+            0x5000..=0x57FF => {
+                self.irq_counter = (self.irq_counter & 0x7F00) | value as u16;
+                self.irq_pending = false;
+            }
+            0x5800..=0x5FFF => {
+                self.irq_counter = (self.irq_counter & 0x00FF) | ((value & 0x7F) as u16) << 8;
+                self.irq_enabled = value & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            0x6000..=0x7FFF if self.prg_ram_writable(address) => {
+                write_prg_ram(&mut self.cartridge, (address - 0x6000) as usize, value)
+            }
+            0x8000..=0xFFFF => self.write_register(address, value),
+            _ => (),
+        }
+    }
+
+    fn ppu_read(&mut self, address: u16) -> u8 {
+        read_chr(&self.cartridge, self.chr_offset(address))
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        let offset = self.chr_offset(address);
+        write_chr(&mut self.cartridge, offset, value);
+    }
+
+    // The VRAM pages the nametables use. Ones in CHR ROM or in arrangements
+    // that aren't any of these are left to read_nametable.
+    fn mirroring(&self) -> Mirroring {
+        match self.nametable_banks.map(|bank| bank & 1) {
+            [0, 0, 1, 1] => Mirroring::Horizontal,
+            [0, 0, 0, 0] => Mirroring::SingleScreenLower,
+            [1, 1, 1, 1] => Mirroring::SingleScreenUpper,
+            _ => Mirroring::Vertical,
+        }
+    }
+
+    fn cartridge(&self) -> &CartridgeData {
+        &self.cartridge
+    }
+
+    fn cartridge_mut(&mut self) -> &mut CartridgeData {
+        &mut self.cartridge
+    }
+
+    // The internal RAM is left alone along with the cartridge's
+    fn reset(&mut self) {
+        self.chr_banks = [0; 8];
+        self.nametable_banks = [0; 4];
+        self.prg_banks = [0; 3];
+        self.sound_disabled = false;
+        self.chr_vram_disabled = [false; 2];
+        self.ram_port = 0;
+        self.irq_counter = 0;
+        self.irq_enabled = false;
+        self.irq_pending = false;
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        save_cartridge(&self.cartridge, state);
+        state.bytes(&self.chr_banks);
+        state.bytes(&self.nametable_banks);
+        state.bytes(&self.prg_banks);
+        state.bool(self.sound_disabled);
+        state.bool(self.chr_vram_disabled[0]);
+        state.bool(self.chr_vram_disabled[1]);
+        state.u8(self.ram_port);
+        state.bytes(&self.internal_ram);
+        state.u16(self.irq_counter);
+        state.bool(self.irq_enabled);
+        state.bool(self.irq_pending);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        load_cartridge(&mut self.cartridge, state)?;
+        state.fill(&mut self.chr_banks)?;
+        state.fill(&mut self.nametable_banks)?;
+        state.fill(&mut self.prg_banks)?;
+        self.sound_disabled = state.bool()?;
+        self.chr_vram_disabled = [state.bool()?, state.bool()?];
+        self.ram_port = state.u8()?;
+        state.fill(&mut self.internal_ram)?;
+        self.irq_counter = state.u16_below(0x8000, "Namco 163 IRQ counter")?;
+        self.irq_enabled = state.bool()?;
+        self.irq_pending = state.bool()?;
+        Ok(())
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    // Counts up while enabled and stops at $7FFF, raising the IRQ there
+    fn cpu_clock(&mut self) {
+        if !self.irq_enabled || self.irq_counter == 0x7FFF {
+            return;
+        }
+        self.irq_counter += 1;
+        if self.irq_counter == 0x7FFF {
+            self.irq_pending = true;
+        }
+    }
+
+    fn read_pattern(&mut self, address: u16, vram: &[u8; 4096]) -> u8 {
+        match self.pattern_vram_page(address) {
+            Some(page) => vram[page * 0x400 + (address & 0x3FF) as usize],
+            None => self.ppu_read(address),
+        }
+    }
+
+    fn write_pattern(&mut self, address: u16, value: u8, vram: &mut [u8; 4096]) {
+        match self.pattern_vram_page(address) {
+            Some(page) => vram[page * 0x400 + (address & 0x3FF) as usize] = value,
+            None => self.ppu_write(address, value),
+        }
+    }
+
+    fn read_nametable(&mut self, address: u16, vram: &[u8; 4096]) -> u8 {
+        let bank = self.nametable_banks[(address as usize >> 10) & 0b11] as usize;
+        let offset = (address & 0x3FF) as usize;
+        if bank >= 0xE0 {
+            vram[(bank & 1) * 0x400 + offset]
+        } else {
+            read_chr(&self.cartridge, bank * 0x400 + offset)
+        }
+    }
+
+    fn write_nametable(&mut self, address: u16, value: u8, vram: &mut [u8; 4096]) {
+        let bank = self.nametable_banks[(address as usize >> 10) & 0b11] as usize;
+        let offset = (address & 0x3FF) as usize;
+        if bank >= 0xE0 {
+            vram[(bank & 1) * 0x400 + offset] = value;
+        } else {
+            write_chr(&mut self.cartridge, bank * 0x400 + offset, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::cartridge;
+    use super::*;
+
+    #[test]
+    fn data_port_auto_increments() {
+        let mut namco = Namco163::new(cartridge(19, 16, 248));
+        // Wrapping from $7F to $00
+        namco.cpu_write(0xF800, 0x80 | 0x7E);
+        for value in [0x11, 0x22, 0x33] {
+            namco.cpu_write(0x4800, value);
+        }
+        assert_eq!(namco.internal_ram[0x7E..], [0x11, 0x22]);
+        assert_eq!(namco.internal_ram[0], 0x33);
+        namco.cpu_write(0xF800, 0x80 | 0x7E);
+        let read: Vec<u8> = (0..3).map(|_| namco.cpu_read(0x4800)).collect();
+        assert_eq!(read, [0x11, 0x22, 0x33]);
+
+        // Without bit 7 the address stays put
+        namco.cpu_write(0xF800, 0x05);
+        namco.cpu_write(0x4800, 0x44);
+        namco.cpu_write(0x4800, 0x55);
+        assert_eq!(namco.cpu_read(0x4800), 0x55);
+        assert_eq!(namco.cpu_read(0x4800), 0x55);
+        assert_eq!(namco.internal_ram[4..7], [0, 0x55, 0]);
+    }
+
+    #[test]
+    fn irq_counter_reads_back() {
+        let mut namco = Namco163::new(cartridge(19, 16, 248));
+        namco.cpu_write(0x5000, 0xFD);
+        namco.cpu_write(0x5800, 0xFF);
+        assert_eq!(
+            (namco.cpu_read(0x5000), namco.cpu_read(0x5800)),
+            (0xFD, 0xFF)
+        );
+        namco.cpu_clock();
+        assert!(!namco.irq_pending());
+        assert_eq!(namco.cpu_read(0x5000), 0xFE);
+        namco.cpu_clock();
+        assert!(namco.irq_pending());
+        // It stops at $7FFF
+        namco.cpu_clock();
+        assert_eq!(
+            (namco.cpu_read(0x5000), namco.cpu_read(0x5800)),
+            (0xFF, 0xFF)
+        );
+
+        // Writing the counter acknowledges the IRQ, and bit 7 turns it off
+        namco.cpu_write(0x5800, 0x12);
+        assert!(!namco.irq_pending());
+        namco.cpu_clock();
+        assert_eq!(
+            (namco.cpu_read(0x5000), namco.cpu_read(0x5800)),
+            (0xFF, 0x12)
+        );
+    }
+
+    #[test]
+    fn chr_banks_from_e0_are_nametable_ram() {
+        let mut namco = Namco163::new(cartridge(19, 16, 248));
+        let mut vram = [0; 4096];
+        vram[0x400] = 0x77;
+        // $0000-$03FF on VRAM page 1, $0400-$07FF on CHR ROM bank 5
+        namco.cpu_write(0x8000, 0xE1);
+        namco.cpu_write(0x8800, 0x05);
+        assert_eq!(namco.read_pattern(0x0000, &vram), 0x77);
+        assert_eq!(namco.read_pattern(0x0400, &vram), 5);
+        namco.write_pattern(0x0001, 0x88, &mut vram);
+        assert_eq!(vram[0x401], 0x88);
+
+        // $E800 bit 6 makes $E0-$FF CHR ROM for the lower pattern table
+        namco.cpu_write(0xE800, 0x40);
+        assert_eq!(namco.read_pattern(0x0000, &vram), 0xE1);
+        namco.cpu_write(0xA000, 0xE0);
+        vram[0] = 0x99;
+        assert_eq!(namco.read_pattern(0x1000, &vram), 0x99);
+
+        // Nametables always take $E0-$FF as VRAM
+        namco.cpu_write(0xC000, 0xE1);
+        namco.cpu_write(0xD000, 0x05);
+        assert_eq!(namco.read_nametable(0x2000, &vram), 0x77);
+        assert_eq!(namco.read_nametable(0x2800, &vram), 5);
+    }
+}
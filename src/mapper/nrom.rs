@@ -0,0 +1,126 @@
+use crate::cartridge::{CartridgeData, Mirroring};
+use crate::state::{StateError, StateReader, StateWriter};
+
+use super::{
+    load_cartridge, read_chr, read_prg_ram, read_prg_rom, save_cartridge, write_chr, write_prg_ram,
+    Mapper,
+};
+
+// Mapper 0: no bank switching. NROM-128 boards have a single 16 KB bank,
+// which shows up at both $8000 and $C000. Family BASIC carts add PRG RAM at
+// $6000-$7FFF, and nothing else decodes there, so it's always mapped.
+pub struct Nrom {
+    cartridge: CartridgeData,
+}
+
+impl Nrom {
+    pub fn new(cartridge: CartridgeData) -> Nrom {
+        Nrom { cartridge }
+    }
+}
+
+impl Mapper for Nrom {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        match address {
+            0x6000..=0x7FFF => read_prg_ram(&self.cartridge, (address - 0x6000) as usize),
+            0x8000..=0xFFFF => read_prg_rom(&self.cartridge, (address - 0x8000) as usize),
+            _ => 0,
+        }
+    }
+
+    // Writes to ROM go nowhere
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        if let 0x6000..=0x7FFF = address {
+            write_prg_ram(&mut self.cartridge, (address - 0x6000) as usize, value);
+        }
+    }
+
+    fn ppu_read(&mut self, address: u16) -> u8 {
+        read_chr(&self.cartridge, (address & 0x1FFF) as usize)
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        write_chr(&mut self.cartridge, (address & 0x1FFF) as usize, value);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.cartridge.mirroring()
+    }
+
+    // No registers
+    fn cartridge(&self) -> &CartridgeData {
+        &self.cartridge
+    }
+
+    fn cartridge_mut(&mut self) -> &mut CartridgeData {
+        &mut self.cartridge
+    }
+
+    fn reset(&mut self) {}
+
+    fn save_state(&self, state: &mut StateWriter) {
+        save_cartridge(&self.cartridge, state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        load_cartridge(&mut self.cartridge, state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::cartridge;
+    use super::*;
+    use crate::cartridge::CartridgeBuilder;
+
+    #[test]
+    fn nrom_128_is_mirrored() {
+        let mut nrom = Nrom::new(cartridge(0, 2, 8));
+        assert_eq!(nrom.cpu_read(0x8000), 0);
+        assert_eq!(nrom.cpu_read(0xA000), 1);
+        assert_eq!(nrom.cpu_read(0xC000), 0);
+        assert_eq!(nrom.cpu_read(0xFFFF), 1);
+    }
+
+    #[test]
+    fn nrom_256_is_not() {
+        let mut nrom = Nrom::new(cartridge(0, 4, 8));
+        for (address, bank) in [(0x8000, 0), (0xA000, 1), (0xC000, 2), (0xE000, 3)] {
+            assert_eq!(nrom.cpu_read(address), bank);
+        }
+        // Writes don't change the ROM
+        nrom.cpu_write(0x8000, 0xFF);
+        assert_eq!(nrom.cpu_read(0x8000), 0);
+    }
+
+    #[test]
+    fn chr_ram_is_writable() {
+        let mut nrom = Nrom::new(cartridge(0, 2, 0));
+        nrom.ppu_write(0x0000, 0x12);
+        nrom.ppu_write(0x1FFF, 0x34);
+        assert_eq!(nrom.ppu_read(0x0000), 0x12);
+        assert_eq!(nrom.ppu_read(0x1FFF), 0x34);
+
+        // CHR ROM isn't
+        let mut nrom = Nrom::new(cartridge(0, 2, 8));
+        nrom.ppu_write(0x0400, 0xFF);
+        assert_eq!(nrom.ppu_read(0x0400), 1);
+    }
+
+    #[test]
+    fn prg_ram_and_header_mirroring() {
+        let mut nrom = Nrom::new(cartridge(0, 2, 8));
+        nrom.cpu_write(0x6000, 0x42);
+        nrom.cpu_write(0x7FFF, 0x43);
+        assert_eq!(nrom.cpu_read(0x6000), 0x42);
+        assert_eq!(nrom.cpu_read(0x7FFF), 0x43);
+        assert_eq!(nrom.mirroring(), Mirroring::Horizontal);
+
+        let cartridge = CartridgeBuilder::new()
+            .prg_rom(vec![0; 0x4000])
+            .mirroring(Mirroring::Vertical)
+            .build()
+            .unwrap();
+        assert_eq!(Nrom::new(cartridge).mirroring(), Mirroring::Vertical);
+    }
+}
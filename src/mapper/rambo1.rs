@@ -0,0 +1,339 @@
+use crate::cartridge::{CartridgeData, Mirroring};
+use crate::state::{StateError, StateReader, StateWriter};
+
+use super::mmc3::A12Filter;
+use super::{load_cartridge, read_chr, read_prg_rom, save_cartridge, write_chr, Mapper};
+
+// Mapper 64: https://www.nesdev.org/wiki/RAMBO-1
+// Tengen's take on the MMC3. It adds a third switchable PRG bank, a mode
+// with all eight CHR banks 1 KB, and an IRQ counter that can count CPU
+// cycles instead of A12 rises. There's no PRG RAM.
+pub struct Rambo1 {
+    cartridge: CartridgeData,
+    // 7  bit  0
+    // ---- ----
+    // CPK. RRRR
+    // |||  ||||
+    // |||  ++++- Bank register written by the next $8001 write
+    // ||+------- 1: R0 and R1 are 1 KB banks, followed by R8 and R9
+    // |+-------- PRG ROM bank mode (0: R6, R7, RF; 1: RF, R6, R7)
+    // +--------- CHR A12 inversion, as on the MMC3
+    bank_select: u8,
+    // R0-R5 and R8-R9 are CHR banks, R6, R7 and RF are 8 KB PRG banks, and
+    // RA-RE aren't connected
+    bank_registers: [u8; 16],
+    // 0: vertical, 1: horizontal
+    mirroring: u8,
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    // $C001 bit 0. Counts every fourth CPU cycle instead of A12 rises.
+    irq_cycle_mode: bool,
+    prescaler: u8,
+    irq_enabled: bool,
+    // The IRQ line goes low a cycle after the counter reaches 0
+    irq_delay: u8,
+    irq_pending: bool,
+    a12: A12Filter,
+}
+
+impl Rambo1 {
+    pub fn new(cartridge: CartridgeData) -> Rambo1 {
+        Rambo1 {
+            cartridge,
+            bank_select: 0,
+            bank_registers: [0; 16],
+            mirroring: 0,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_cycle_mode: false,
+            prescaler: 0,
+            irq_enabled: false,
+            irq_delay: 0,
+            irq_pending: false,
+            a12: A12Filter::default(),
+        }
+    }
+
+    // Unlike the MMC3 a reload gives one more clock before the IRQ, except
+    // with latches of 0 and 1, which Hard Drivin' depends on
+    fn clock_irq_counter(&mut self) {
+        if self.irq_reload {
+            let extra = if self.irq_latch <= 1 { 1 } else { 2 };
+            self.irq_counter = self.irq_latch.wrapping_add(extra);
+            self.irq_reload = false;
+        } else if self.irq_counter == 0 {
+            self.irq_counter = self.irq_latch.wrapping_add(1);
+        }
+        self.irq_counter = self.irq_counter.wrapping_sub(1);
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_delay = 1;
+        }
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match (address, address & 1) {
+            (0x8000..=0x9FFF, 0) => self.bank_select = value,
+            (0x8000..=0x9FFF, _) => self.bank_registers[(self.bank_select & 0x0F) as usize] = value,
+            (0xA000..=0xBFFF, 0) => self.mirroring = value,
+            (0xA000..=0xBFFF, _) => {}
+            (0xC000..=0xDFFF, 0) => self.irq_latch = value,
+            (0xC000..=0xDFFF, _) => {
+                self.irq_cycle_mode = value & 1 != 0;
+                self.prescaler = 0;
+                self.irq_reload = true;
+            }
+            (_, 0) => {
+                self.irq_enabled = false;
+                self.irq_delay = 0;
+                self.irq_pending = false;
+            }
+            (_, _) => self.irq_enabled = true,
+        }
+    }
+
+    fn prg_rom_offset(&self, address: u16) -> usize {
+        let last_bank = (self.cartridge.prg_rom().len() / 0x2000).saturating_sub(1);
+        let r = |register: usize| self.bank_registers[register] as usize;
+        let bank = match (self.bank_select & 0x40 != 0, address) {
+            (false, 0x8000..=0x9FFF) => r(6),
+            (false, 0xA000..=0xBFFF) => r(7),
+            (false, 0xC000..=0xDFFF) => r(15),
+            (true, 0x8000..=0x9FFF) => r(15),
+            (true, 0xA000..=0xBFFF) => r(6),
+            (true, 0xC000..=0xDFFF) => r(7),
+            (_, _) => last_bank,
+        };
+        bank * 0x2000 + (address & 0x1FFF) as usize
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        let mut address = (address & 0x1FFF) as usize;
+        if self.bank_select & 0x80 != 0 {
+            address ^= 0x1000;
+        }
+        let r = |register: usize| self.bank_registers[register] as usize;
+        let one_kb = self.bank_select & 0x20 != 0;
+        let bank = match address >> 10 {
+            0 if one_kb => r(0),
+            1 if one_kb => r(8),
+            2 if one_kb => r(1),
+            3 if one_kb => r(9),
+            // 2 KB banks ignore the low bit
+            slot @ 0..=3 => (r(slot >> 1) & !1) + (slot & 1),
+            slot => r(slot - 2),
+        };
+        bank * 0x400 + (address & 0x3FF)
+    }
+}
+
+impl Mapper for Rambo1 {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        match address {
+            0x8000..=0xFFFF => read_prg_rom(&self.cartridge, self.prg_rom_offset(address)),
+            _ => (address >> 8) as u8,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        if address >= 0x8000 {
+            self.write_register(address, value);
+        }
+    }
+
+    fn ppu_read(&mut self, address: u16) -> u8 {
+        read_chr(&self.cartridge, self.chr_offset(address))
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        let offset = self.chr_offset(address);
+        write_chr(&mut self.cartridge, offset, value);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.mirroring & 1 == 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        }
+    }
+
+    fn cartridge(&self) -> &CartridgeData {
+        &self.cartridge
+    }
+
+    fn cartridge_mut(&mut self) -> &mut CartridgeData {
+        &mut self.cartridge
+    }
+
+    fn reset(&mut self) {
+        self.bank_select = 0;
+        self.bank_registers = [0; 16];
+        self.mirroring = 0;
+        self.irq_latch = 0;
+        self.irq_counter = 0;
+        self.irq_reload = false;
+        self.irq_cycle_mode = false;
+        self.prescaler = 0;
+        self.irq_enabled = false;
+        self.irq_delay = 0;
+        self.irq_pending = false;
+        self.a12.reset();
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        save_cartridge(&self.cartridge, state);
+        state.u8(self.bank_select);
+        state.bytes(&self.bank_registers);
+        state.u8(self.mirroring);
+        state.u8(self.irq_latch);
+        state.u8(self.irq_counter);
+        state.bool(self.irq_reload);
+        state.bool(self.irq_cycle_mode);
+        state.u8(self.prescaler);
+        state.bool(self.irq_enabled);
+        state.u8(self.irq_delay);
+        state.bool(self.irq_pending);
+        self.a12.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        load_cartridge(&mut self.cartridge, state)?;
+        self.bank_select = state.u8()?;
+        state.fill(&mut self.bank_registers)?;
+        self.mirroring = state.u8()?;
+        self.irq_latch = state.u8()?;
+        self.irq_counter = state.u8()?;
+        self.irq_reload = state.bool()?;
+        self.irq_cycle_mode = state.bool()?;
+        self.prescaler = state.u8_below(4, "RAMBO-1 prescaler")?;
+        self.irq_enabled = state.bool()?;
+        self.irq_delay = state.u8()?;
+        self.irq_pending = state.bool()?;
+        self.a12.load_state(state)
+    }
+
+    // Held until the CPU disables it at $E000
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn notify_ppu_address(&mut self, address: u16) {
+        if self.a12.rose(address) && !self.irq_cycle_mode {
+            self.clock_irq_counter();
+        }
+    }
+
+    fn cpu_clock(&mut self) {
+        if self.irq_delay > 0 {
+            self.irq_delay -= 1;
+            if self.irq_delay == 0 {
+                self.irq_pending = true;
+            }
+        }
+        self.a12.cpu_clock();
+        if self.irq_cycle_mode {
+            self.prescaler = (self.prescaler + 1) & 0b11;
+            if self.prescaler == 0 {
+                self.clock_irq_counter();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::cartridge;
+    use super::*;
+
+    fn set_bank(rambo1: &mut Rambo1, register: u8, bank: u8) {
+        rambo1.cpu_write(0x8000, register);
+        rambo1.cpu_write(0x8001, bank);
+    }
+
+    // The same PPU fetch pattern as the MMC3 tests
+    fn scanline(rambo1: &mut Rambo1) {
+        rambo1.notify_ppu_address(0x0000);
+        for _ in 0..20 {
+            rambo1.cpu_clock();
+        }
+        rambo1.notify_ppu_address(0x1000);
+    }
+
+    #[test]
+    fn three_prg_banks_and_1k_chr_mode() {
+        let mut rambo1 = Rambo1::new(cartridge(64, 16, 64));
+        for (register, bank) in [(0, 10), (1, 20), (2, 40), (3, 41), (4, 42), (5, 43)] {
+            set_bank(&mut rambo1, register, bank);
+        }
+        for (register, bank) in [(6, 3), (7, 5), (15, 7), (8, 30), (9, 31)] {
+            set_bank(&mut rambo1, register, bank);
+        }
+        let prg = |rambo1: &mut Rambo1| -> Vec<u8> {
+            (0..4)
+                .map(|bank| rambo1.cpu_read(0x8000 + bank * 0x2000))
+                .collect()
+        };
+        let chr = |rambo1: &mut Rambo1| -> Vec<u8> {
+            (0..8).map(|bank| rambo1.ppu_read(bank * 0x400)).collect()
+        };
+        assert_eq!(prg(&mut rambo1), [3, 5, 7, 15]);
+        assert_eq!(chr(&mut rambo1), [10, 11, 20, 21, 40, 41, 42, 43]);
+
+        rambo1.cpu_write(0x8000, 0x60);
+        assert_eq!(prg(&mut rambo1), [7, 3, 5, 15]);
+        assert_eq!(chr(&mut rambo1), [10, 30, 20, 31, 40, 41, 42, 43]);
+    }
+
+    #[test]
+    fn a12_clocked_irq() {
+        let mut rambo1 = Rambo1::new(cartridge(64, 16, 64));
+        rambo1.cpu_write(0xC000, 3);
+        rambo1.cpu_write(0xC001, 0);
+        rambo1.cpu_write(0xE001, 0);
+
+        // A reload takes one clock more than on the MMC3
+        for _ in 0..5 {
+            assert!(!rambo1.irq_pending());
+            scanline(&mut rambo1);
+        }
+        // and the line goes low a cycle later
+        assert!(!rambo1.irq_pending());
+        rambo1.cpu_clock();
+        assert!(rambo1.irq_pending());
+
+        rambo1.cpu_write(0xE000, 0);
+        assert!(!rambo1.irq_pending());
+    }
+
+    #[test]
+    fn cpu_cycle_clocked_irq() {
+        let mut rambo1 = Rambo1::new(cartridge(64, 16, 64));
+        rambo1.cpu_write(0xC000, 3);
+        rambo1.cpu_write(0xC001, 1);
+        rambo1.cpu_write(0xE001, 0);
+
+        // Five counter clocks of four cycles each, then the one-cycle delay.
+        // A12 rises don't count in this mode.
+        for _ in 0..20 {
+            rambo1.notify_ppu_address(0x0000);
+            rambo1.notify_ppu_address(0x1000);
+            rambo1.cpu_clock();
+            assert!(!rambo1.irq_pending());
+        }
+        rambo1.cpu_clock();
+        assert!(rambo1.irq_pending());
+
+        // Without a reload it takes four counter clocks to come round again,
+        // and the prescaler is already a cycle in
+        rambo1.cpu_write(0xE000, 0);
+        rambo1.cpu_write(0xE001, 0);
+        for _ in 0..15 {
+            rambo1.cpu_clock();
+            assert!(!rambo1.irq_pending());
+        }
+        rambo1.cpu_clock();
+        assert!(rambo1.irq_pending());
+    }
+}
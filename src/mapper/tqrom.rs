@@ -0,0 +1,129 @@
+use crate::cartridge::{CartridgeData, Mirroring};
+use crate::state::{StateError, StateReader, StateWriter};
+
+use super::{read_chr, Mapper, Mmc3};
+
+// Mapper 119: https://www.nesdev.org/wiki/INES_Mapper_119
+// TQROM, an MMC3 with 8 KB of CHR RAM on the board next to the CHR ROM.
+// Bit 6 of each CHR bank picks which one its 1 KB comes from, with the low
+// three bits picking the bank within the RAM.
+pub struct Tqrom {
+    mmc3: Mmc3,
+    chr_ram: Vec<u8>,
+}
+
+impl Tqrom {
+    pub fn new(cartridge: CartridgeData) -> Tqrom {
+        Tqrom {
+            mmc3: Mmc3::new(cartridge),
+            chr_ram: vec![0; 0x2000],
+        }
+    }
+
+    // Offset into the CHR RAM, if the address is banked there
+    fn chr_ram_offset(&self, address: u16) -> Option<usize> {
+        let bank = self.mmc3.chr_bank(address);
+        (bank & 0x40 != 0).then(|| (bank & 0b111) as usize * 0x400 + (address & 0x3FF) as usize)
+    }
+}
+
+impl Mapper for Tqrom {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        self.mmc3.cpu_read(address)
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        self.mmc3.cpu_write(address, value);
+    }
+
+    fn ppu_read(&mut self, address: u16) -> u8 {
+        match self.chr_ram_offset(address) {
+            Some(offset) => self.chr_ram[offset],
+            None => {
+                let bank = self.mmc3.chr_bank(address) & 0x3F;
+                read_chr(
+                    self.mmc3.cartridge(),
+                    bank as usize * 0x400 + (address & 0x3FF) as usize,
+                )
+            }
+        }
+    }
+
+    // CHR ROM ignores writes
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        if let Some(offset) = self.chr_ram_offset(address) {
+            self.chr_ram[offset] = value;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mmc3.mirroring()
+    }
+
+    fn cartridge(&self) -> &CartridgeData {
+        self.mmc3.cartridge()
+    }
+
+    fn cartridge_mut(&mut self) -> &mut CartridgeData {
+        self.mmc3.cartridge_mut()
+    }
+
+    // The CHR RAM is left alone along with the cartridge's
+    fn reset(&mut self) {
+        self.mmc3.reset();
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.mmc3.save_state(state);
+        state.bytes(&self.chr_ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.mmc3.load_state(state)?;
+        state.fill(&mut self.chr_ram)
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.mmc3.irq_pending()
+    }
+
+    fn notify_ppu_address(&mut self, address: u16) {
+        self.mmc3.notify_ppu_address(address);
+    }
+
+    fn cpu_clock(&mut self) {
+        self.mmc3.cpu_clock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::cartridge;
+    use super::*;
+
+    fn set_bank(tqrom: &mut Tqrom, register: u8, bank: u8) {
+        tqrom.cpu_write(0x8000, register);
+        tqrom.cpu_write(0x8001, bank);
+    }
+
+    #[test]
+    fn bit_6_picks_chr_ram() {
+        let mut tqrom = Tqrom::new(cartridge(119, 16, 64));
+        for (register, bank) in [(0, 0x40), (2, 0x05), (3, 0x47), (4, 0x41)] {
+            set_bank(&mut tqrom, register, bank);
+        }
+
+        // RAM takes writes and ROM ignores them
+        tqrom.ppu_write(0x0000, 0xAA);
+        tqrom.ppu_write(0x1000, 0xBB);
+        tqrom.ppu_write(0x1400, 0xCC);
+        assert_eq!(tqrom.ppu_read(0x0000), 0xAA);
+        assert_eq!(tqrom.ppu_read(0x1000), 5);
+        assert_eq!(tqrom.ppu_read(0x1400), 0xCC);
+        assert_eq!(tqrom.chr_ram[0x1C00], 0xCC);
+
+        // R0's second 1 KB is RAM page 1, which R4 also points at
+        tqrom.ppu_write(0x0400, 0xDD);
+        assert_eq!(tqrom.ppu_read(0x1800), 0xDD);
+    }
+}
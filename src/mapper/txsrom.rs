@@ -0,0 +1,148 @@
+use crate::cartridge::{CartridgeData, Mirroring};
+use crate::state::{StateError, StateReader, StateWriter};
+
+use super::{Mapper, Mmc3};
+
+// Mapper 118: https://www.nesdev.org/wiki/INES_Mapper_118
+// TKSROM and TLSROM, an MMC3 with CHR A17 wired to the console's VRAM A10
+// instead of the $A000 mirroring. Each nametable takes its VRAM page from
+// bit 7 of the CHR bank for the same 1 KB of $0000-$0FFF, so with
+// inversion off R0 covers $2000-$27FF and R1 $2800-$2FFF, and with it on
+// R2-R5 cover one nametable each.
+pub struct Txsrom {
+    mmc3: Mmc3,
+}
+
+impl Txsrom {
+    pub fn new(cartridge: CartridgeData) -> Txsrom {
+        Txsrom {
+            mmc3: Mmc3::new(cartridge),
+        }
+    }
+
+    fn vram_page(&self, address: u16) -> usize {
+        let quadrant = (address >> 10) & 0b11;
+        (self.mmc3.chr_bank(quadrant * 0x400) >> 7) as usize
+    }
+}
+
+impl Mapper for Txsrom {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        self.mmc3.cpu_read(address)
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        self.mmc3.cpu_write(address, value);
+    }
+
+    fn ppu_read(&mut self, address: u16) -> u8 {
+        self.mmc3.ppu_read(address)
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        self.mmc3.ppu_write(address, value);
+    }
+
+    // The closest arrangement to the pages in use; read_nametable follows
+    // the banks exactly
+    fn mirroring(&self) -> Mirroring {
+        match [0x2000, 0x2400, 0x2800, 0x2C00].map(|address| self.vram_page(address)) {
+            [0, 0, 1, 1] => Mirroring::Horizontal,
+            [0, 0, 0, 0] => Mirroring::SingleScreenLower,
+            [1, 1, 1, 1] => Mirroring::SingleScreenUpper,
+            _ => Mirroring::Vertical,
+        }
+    }
+
+    fn cartridge(&self) -> &CartridgeData {
+        self.mmc3.cartridge()
+    }
+
+    fn cartridge_mut(&mut self) -> &mut CartridgeData {
+        self.mmc3.cartridge_mut()
+    }
+
+    fn reset(&mut self) {
+        self.mmc3.reset();
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.mmc3.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.mmc3.load_state(state)
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.mmc3.irq_pending()
+    }
+
+    fn notify_ppu_address(&mut self, address: u16) {
+        self.mmc3.notify_ppu_address(address);
+    }
+
+    fn cpu_clock(&mut self) {
+        self.mmc3.cpu_clock();
+    }
+
+    fn read_nametable(&mut self, address: u16, vram: &[u8; 4096]) -> u8 {
+        vram[self.vram_page(address) * 0x400 + (address & 0x3FF) as usize]
+    }
+
+    fn write_nametable(&mut self, address: u16, value: u8, vram: &mut [u8; 4096]) {
+        vram[self.vram_page(address) * 0x400 + (address & 0x3FF) as usize] = value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::cartridge;
+    use super::*;
+
+    fn set_bank(txsrom: &mut Txsrom, register: u8, bank: u8) {
+        txsrom.cpu_write(0x8000, register);
+        txsrom.cpu_write(0x8001, bank);
+    }
+
+    #[test]
+    fn nametables_follow_bit_7_of_the_chr_banks() {
+        let mut txsrom = Txsrom::new(cartridge(118, 16, 128));
+        set_bank(&mut txsrom, 0, 0x00);
+        set_bank(&mut txsrom, 1, 0x80);
+        assert_eq!(txsrom.mirroring(), Mirroring::Horizontal);
+        // $A000 does nothing
+        txsrom.cpu_write(0xA000, 0);
+        assert_eq!(txsrom.mirroring(), Mirroring::Horizontal);
+
+        let mut vram = [0; 4096];
+        txsrom.write_nametable(0x2C05, 0x11, &mut vram);
+        txsrom.write_nametable(0x2405, 0x22, &mut vram);
+        assert_eq!((vram[0x405], vram[0x005]), (0x11, 0x22));
+        assert_eq!(txsrom.read_nametable(0x2805, &vram), 0x11);
+
+        set_bank(&mut txsrom, 0, 0x80);
+        assert_eq!(txsrom.mirroring(), Mirroring::SingleScreenUpper);
+        assert_eq!(txsrom.read_nametable(0x2005, &vram), 0x11);
+    }
+
+    #[test]
+    fn chr_inversion_uses_the_1k_banks() {
+        let mut txsrom = Txsrom::new(cartridge(118, 16, 128));
+        for (register, bank) in [(2, 0x80), (3, 0x01), (4, 0x82), (5, 0x03)] {
+            set_bank(&mut txsrom, register, bank);
+        }
+        txsrom.cpu_write(0x8000, 0x80);
+        assert_eq!(txsrom.mirroring(), Mirroring::Vertical);
+
+        let mut vram = [0; 4096];
+        vram[0x400] = 0x33;
+        let pages: Vec<u8> = [0x2000, 0x2400, 0x2800, 0x2C00]
+            .map(|address| txsrom.read_nametable(address, &vram))
+            .to_vec();
+        assert_eq!(pages, [0x33, 0, 0x33, 0]);
+        // The CHR itself still comes from the low seven bits
+        assert_eq!(txsrom.ppu_read(0x0000), 0);
+        assert_eq!(txsrom.ppu_read(0x0400), 1);
+    }
+}
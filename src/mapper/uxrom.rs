@@ -0,0 +1,148 @@
+use crate::cartridge::{CartridgeData, Mirroring};
+use crate::state::{StateError, StateReader, StateWriter};
+
+use super::{
+    has_bus_conflicts, load_cartridge, read_chr, read_prg_rom, save_cartridge, write_chr, Mapper,
+};
+
+// Mapper 2: https://www.nesdev.org/wiki/UxROM
+// Any write to $8000-$FFFF picks the 16 KB bank at $8000, while $C000 is
+// always the last bank. CHR is 8 KB of RAM.
+pub struct Uxrom {
+    cartridge: CartridgeData,
+    prg_bank: u8,
+    bus_conflicts: bool,
+}
+
+impl Uxrom {
+    pub fn new(cartridge: CartridgeData) -> Uxrom {
+        let bus_conflicts = has_bus_conflicts(&cartridge);
+        Uxrom {
+            cartridge,
+            prg_bank: 0,
+            bus_conflicts,
+        }
+    }
+
+    // Bank numbers past the end of PRG ROM wrap around, since the board only
+    // wires up as many bank bits as the ROM needs
+    fn prg_bank(&self) -> usize {
+        self.prg_bank as usize % self.cartridge.prg_rom_banks().max(1)
+    }
+}
+
+impl Mapper for Uxrom {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        match address {
+            0x8000..=0xBFFF => read_prg_rom(
+                &self.cartridge,
+                self.prg_bank() * 0x4000 + (address & 0x3FFF) as usize,
+            ),
+            0xC000..=0xFFFF => {
+                let last_bank = self.cartridge.prg_rom_banks().saturating_sub(1);
+                read_prg_rom(
+                    &self.cartridge,
+                    last_bank * 0x4000 + (address & 0x3FFF) as usize,
+                )
+            }
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        if address >= 0x8000 {
+            self.prg_bank = if self.bus_conflicts {
+                value & self.cpu_read(address)
+            } else {
+                value
+            };
+        }
+    }
+
+    fn ppu_read(&mut self, address: u16) -> u8 {
+        read_chr(&self.cartridge, (address & 0x1FFF) as usize)
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        write_chr(&mut self.cartridge, (address & 0x1FFF) as usize, value);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.cartridge.mirroring()
+    }
+
+    fn cartridge(&self) -> &CartridgeData {
+        &self.cartridge
+    }
+
+    fn cartridge_mut(&mut self) -> &mut CartridgeData {
+        &mut self.cartridge
+    }
+
+    fn reset(&mut self) {
+        self.prg_bank = 0;
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        save_cartridge(&self.cartridge, state);
+        state.u8(self.prg_bank);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        load_cartridge(&mut self.cartridge, state)?;
+        self.prg_bank = state.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cartridge::CartridgeBuilder;
+
+    use super::super::tests::cartridge;
+    use super::*;
+
+    #[test]
+    fn switches_the_bank_at_8000() {
+        // 128 KB, the 16 KB banks reading 0, 2, 4 and so on. The last bank
+        // reads $0F at $E000, so the written value gets through a bus conflict.
+        let mut uxrom = Uxrom::new(cartridge(2, 16, 0));
+        uxrom.cpu_write(0xE000, 3);
+        assert_eq!(uxrom.cpu_read(0x8000), 6);
+        assert_eq!(uxrom.cpu_read(0xC000), 14);
+        assert_eq!(uxrom.cpu_read(0xFFFF), 15);
+    }
+
+    #[test]
+    fn bus_conflicts_and_the_written_value() {
+        // $8000 reads 0 under bank 0 and $A000 reads 1, so writing 3 there
+        // only gets 1 through
+        let mut uxrom = Uxrom::new(cartridge(2, 16, 0));
+        uxrom.cpu_write(0xA000, 3);
+        assert_eq!(uxrom.cpu_read(0x8000), 2);
+        assert_eq!(uxrom.cpu_read(0xC000), 14);
+
+        // Submapper 1 boards don't conflict
+        let prg_rom: Vec<u8> = (0..16).flat_map(|bank| [bank; 0x2000]).collect();
+        let cartridge = CartridgeBuilder::new()
+            .mapper(2)
+            .submapper(1)
+            .prg_rom(prg_rom)
+            .build()
+            .unwrap();
+        let mut uxrom = Uxrom::new(cartridge);
+        uxrom.cpu_write(0xA000, 3);
+        assert_eq!(uxrom.cpu_read(0x8000), 6);
+        assert_eq!(uxrom.cpu_read(0xC000), 14);
+    }
+
+    #[test]
+    fn bank_numbers_wrap_and_chr_ram_is_writable() {
+        let mut uxrom = Uxrom::new(cartridge(2, 16, 0));
+        // 8 banks, so 9 wraps to 1, written where the last bank reads $0F
+        uxrom.cpu_write(0xE000, 9);
+        assert_eq!(uxrom.cpu_read(0x8000), 2);
+        uxrom.ppu_write(0x1234, 0x5A);
+        assert_eq!(uxrom.ppu_read(0x1234), 0x5A);
+    }
+}
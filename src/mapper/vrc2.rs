@@ -0,0 +1,320 @@
+use crate::cartridge::{CartridgeData, Mirroring};
+use crate::state::{StateError, StateReader, StateWriter};
+
+use super::{
+    load_cartridge, read_chr, read_prg_ram, read_prg_rom, save_cartridge, write_chr, write_prg_ram,
+    Mapper,
+};
+
+// Mappers 22, 23 and 25: https://www.nesdev.org/wiki/VRC2_and_VRC4
+// Two switchable 8 KB PRG banks and eight 1 KB CHR banks, each CHR bank
+// number written a nibble at a time. Boards connect the chip's two register
+// select pins to different CPU address lines, so the same register turns up
+// at different addresses depending on the board, which is what the NES 2.0
+// submapper says. VRC4 is a superset with an IRQ, in vrc4.rs.
+
+// Which CPU address lines drive the chip's A0 and A1. The combined wirings
+// for submapper 0 OR two boards' lines together, which works because the
+// games for either never set the other's lines.
+#[derive(Clone, Copy)]
+pub(super) struct VrcPins {
+    a0: u16,
+    a1: u16,
+}
+
+impl VrcPins {
+    // The register's address as the chip sees it, $x000-$x003
+    fn translate(self, address: u16) -> u16 {
+        let a0 = (address & self.a0 != 0) as u16;
+        let a1 = (address & self.a1 != 0) as u16;
+        (address & 0xF000) | a1 << 1 | a0
+    }
+}
+
+// The chip on a board, and how it's wired
+pub(super) fn vrc_variant(mapper: u16, submapper: u8) -> (bool, VrcPins) {
+    let pins = |a0, a1| VrcPins { a0, a1 };
+    // (is VRC4, pins)
+    match (mapper, submapper) {
+        // VRC4a, VRC4c, or either
+        (21, 1) => (true, pins(0x02, 0x04)),
+        (21, 2) => (true, pins(0x40, 0x80)),
+        (21, _) => (true, pins(0x42, 0x84)),
+        // VRC2a
+        (22, _) => (false, pins(0x02, 0x01)),
+        // VRC4f, VRC4e, VRC2b, or VRC4e and VRC2b
+        (23, 1) => (true, pins(0x01, 0x02)),
+        (23, 2) => (true, pins(0x04, 0x08)),
+        (23, 3) => (false, pins(0x01, 0x02)),
+        (23, _) => (true, pins(0x05, 0x0A)),
+        // VRC4b, VRC4d, VRC2c, or VRC4b and VRC4d
+        (25, 1) => (true, pins(0x02, 0x01)),
+        (25, 2) => (true, pins(0x08, 0x04)),
+        (25, 3) => (false, pins(0x02, 0x01)),
+        (_, _) => (true, pins(0x0A, 0x05)),
+    }
+}
+
+// The banking both chips share
+pub(super) struct VrcBanks {
+    pins: VrcPins,
+    prg_banks: [u8; 2],
+    // VRC4 only. 0: $8000 switchable and $C000 fixed to the second-last
+    // bank; 1: the other way round
+    prg_swap: bool,
+    chr_banks: [u16; 8],
+    // VRC2 only has the low bit, for vertical or horizontal
+    mirroring: u8,
+    // VRC2a leaves the low bit of each CHR bank number unconnected, so its
+    // banks are numbered in 2 KB steps
+    chr_shift: u8,
+}
+
+impl VrcBanks {
+    pub fn new(pins: VrcPins, chr_shift: u8) -> VrcBanks {
+        VrcBanks {
+            pins,
+            prg_banks: [0; 2],
+            prg_swap: false,
+            chr_banks: [0; 8],
+            mirroring: 0,
+            chr_shift,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = VrcBanks::new(self.pins, self.chr_shift);
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.prg_banks);
+        state.bool(self.prg_swap);
+        for &bank in &self.chr_banks {
+            state.u16(bank);
+        }
+        state.u8(self.mirroring);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.fill(&mut self.prg_banks)?;
+        self.prg_swap = state.bool()?;
+        for bank in &mut self.chr_banks {
+            *bank = state.u16()?;
+        }
+        self.mirroring = state.u8()?;
+        Ok(())
+    }
+
+    pub fn translate(&self, address: u16) -> u16 {
+        self.pins.translate(address)
+    }
+
+    // address has been through translate. Returns false for the registers
+    // this doesn't handle, $9002-$9003 and $F000-$F003.
+    pub fn write_register(&mut self, address: u16, value: u8, vrc4: bool) -> bool {
+        match address {
+            0x8000..=0x8003 => self.prg_banks[0] = value & 0x1F,
+            0x9000..=0x9003 if !vrc4 => self.mirroring = value & 0b1,
+            0x9000 | 0x9001 => self.mirroring = value & 0b11,
+            0xA000..=0xA003 => self.prg_banks[1] = value & 0x1F,
+            // Each register pair holds the low nibble and the high bits of
+            // one bank. VRC4 has 9 bit bank numbers, VRC2 8 bit.
+            0xB000..=0xE003 => {
+                let register =
+                    ((address - 0xB000) >> 12) as usize * 2 + (address as usize >> 1 & 1);
+                let bank = &mut self.chr_banks[register];
+                if address & 1 == 0 {
+                    *bank = (*bank & !0x0F) | (value & 0x0F) as u16;
+                } else {
+                    let high = if vrc4 { value & 0x1F } else { value & 0x0F };
+                    *bank = (*bank & 0x0F) | (high as u16) << 4;
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    pub fn set_prg_swap(&mut self, swap: bool) {
+        self.prg_swap = swap;
+    }
+
+    pub fn prg_rom_offset(&self, cartridge: &CartridgeData, address: u16) -> usize {
+        let second_last_bank = (cartridge.prg_rom().len() / 0x2000).saturating_sub(2);
+        let bank = match (self.prg_swap, address) {
+            (false, 0x8000..=0x9FFF) => self.prg_banks[0] as usize,
+            (true, 0x8000..=0x9FFF) => second_last_bank,
+            (_, 0xA000..=0xBFFF) => self.prg_banks[1] as usize,
+            (false, 0xC000..=0xDFFF) => second_last_bank,
+            (true, 0xC000..=0xDFFF) => self.prg_banks[0] as usize,
+            (_, _) => second_last_bank + 1,
+        };
+        bank * 0x2000 + (address & 0x1FFF) as usize
+    }
+
+    pub fn chr_offset(&self, address: u16) -> usize {
+        let address = (address & 0x1FFF) as usize;
+        let bank = (self.chr_banks[address >> 10] >> self.chr_shift) as usize;
+        bank * 0x400 + (address & 0x3FF)
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        match self.mirroring {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenLower,
+            _ => Mirroring::SingleScreenUpper,
+        }
+    }
+}
+
+pub struct Vrc2 {
+    cartridge: CartridgeData,
+    banks: VrcBanks,
+    // Boards without PRG RAM have a 1 bit latch at $6000-$6FFF instead,
+    // meant for a serial EEPROM that never shipped. Some games check for it.
+    microwire_latch: u8,
+}
+
+impl Vrc2 {
+    pub fn new(cartridge: CartridgeData) -> Vrc2 {
+        let (_, pins) = vrc_variant(cartridge.mapper_number(), cartridge.submapper());
+        let chr_shift = (cartridge.mapper_number() == 22) as u8;
+        Vrc2 {
+            cartridge,
+            banks: VrcBanks::new(pins, chr_shift),
+            microwire_latch: 0,
+        }
+    }
+
+    fn has_prg_ram(&self) -> bool {
+        self.cartridge.prg_ram_size() + self.cartridge.prg_nvram_size() > 0
+    }
+}
+
+impl Mapper for Vrc2 {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        match address {
+            0x6000..=0x7FFF if self.has_prg_ram() => {
+                read_prg_ram(&self.cartridge, (address - 0x6000) as usize)
+            }
+            // The other bits float, and usually hold the address's high byte
+            0x6000..=0x6FFF => (address >> 8) as u8 & 0xFE | self.microwire_latch,
+            0x8000..=0xFFFF => read_prg_rom(
+                &self.cartridge,
+                self.banks.prg_rom_offset(&self.cartridge, address),
+            ),
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        match address {
+            0x6000..=0x7FFF if self.has_prg_ram() => {
+                write_prg_ram(&mut self.cartridge, (address - 0x6000) as usize, value)
+            }
+            0x6000..=0x6FFF => self.microwire_latch = value & 1,
+            0x8000..=0xFFFF => {
+                let address = self.banks.translate(address);
+                self.banks.write_register(address, value, false);
+            }
+            _ => (),
+        }
+    }
+
+    fn ppu_read(&mut self, address: u16) -> u8 {
+        read_chr(&self.cartridge, self.banks.chr_offset(address))
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        let offset = self.banks.chr_offset(address);
+        write_chr(&mut self.cartridge, offset, value);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.banks.mirroring()
+    }
+
+    fn cartridge(&self) -> &CartridgeData {
+        &self.cartridge
+    }
+
+    fn cartridge_mut(&mut self) -> &mut CartridgeData {
+        &mut self.cartridge
+    }
+
+    fn reset(&mut self) {
+        self.banks.reset();
+        self.microwire_latch = 0;
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        save_cartridge(&self.cartridge, state);
+        self.banks.save_state(state);
+        state.u8(self.microwire_latch);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        load_cartridge(&mut self.cartridge, state)?;
+        self.banks.load_state(state)?;
+        self.microwire_latch = state.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::cartridge;
+    use super::*;
+
+    #[test]
+    fn pins_translate_to_chip_registers() {
+        for (mapper, submapper, address) in [
+            (22, 0, 0xB003),
+            (23, 3, 0xB003),
+            (23, 0, 0xB00F),
+            (25, 3, 0xB003),
+        ] {
+            let (vrc4, pins) = vrc_variant(mapper, submapper);
+            assert_eq!(pins.translate(address), 0xB003, "{mapper}.{submapper}");
+            assert_eq!(vrc4, submapper == 0 && mapper != 22, "{mapper}.{submapper}");
+        }
+        // VRC2a's pins are swapped from VRC2b's
+        let (_, vrc2a) = vrc_variant(22, 0);
+        let (_, vrc2b) = vrc_variant(23, 3);
+        assert_eq!(vrc2a.translate(0xB001), 0xB002);
+        assert_eq!(vrc2b.translate(0xB001), 0xB001);
+    }
+
+    #[test]
+    fn vrc2a_chr_banks_skip_the_low_bit() {
+        // VRC2a: A0 is CPU A1 and A1 is CPU A0, and bank 6 is 1 KB bank 3
+        let mut vrc2 = Vrc2::new(cartridge(22, 16, 128));
+        vrc2.cpu_write(0xB000, 6);
+        vrc2.cpu_write(0xB002, 0);
+        assert_eq!(vrc2.ppu_read(0x0000), 3);
+
+        // VRC2b: bank 0x25 from its two nibbles
+        let mut vrc2 = Vrc2::new(cartridge(23, 16, 128));
+        vrc2.cpu_write(0xB002, 5);
+        vrc2.cpu_write(0xB003, 2);
+        assert_eq!(vrc2.ppu_read(0x0400), 0x25);
+    }
+
+    #[test]
+    fn microwire_latch_without_prg_ram() {
+        // NES 2.0, mapper 22 with no PRG RAM
+        let mut file = b"NES\x1A\x01\x01\x60\x18".to_vec();
+        file.resize(16 + 0x4000 + 0x2000, 0);
+        let mut vrc2 = Vrc2::new(CartridgeData::new(file).unwrap());
+        vrc2.cpu_write(0x6000, 0x43);
+        assert_eq!(vrc2.cpu_read(0x6000), 0x61);
+        vrc2.cpu_write(0x6000, 0x00);
+        assert_eq!(vrc2.cpu_read(0x6000), 0x60);
+
+        // Boards with RAM have it there instead
+        let mut vrc2 = Vrc2::new(cartridge(22, 16, 128));
+        vrc2.cpu_write(0x6000, 0x42);
+        assert_eq!(vrc2.cpu_read(0x6000), 0x42);
+    }
+}
@@ -0,0 +1,324 @@
+use crate::cartridge::{CartridgeData, Mirroring};
+use crate::state::{StateError, StateReader, StateWriter};
+
+use super::vrc2::{vrc_variant, VrcBanks};
+use super::{
+    load_cartridge, read_chr, read_prg_ram, read_prg_rom, save_cartridge, write_chr, write_prg_ram,
+    Mapper,
+};
+
+// Mappers 21, 23 and 25: https://www.nesdev.org/wiki/VRC2_and_VRC4
+// VRC2's banking plus a PRG swap mode, PRG RAM, single screen mirroring and
+// an IRQ counter. Most boards are wired differently from one another, see
+// vrc_variant.
+pub struct Vrc4 {
+    cartridge: CartridgeData,
+    banks: VrcBanks,
+    irq: VrcIrq,
+}
+
+impl Vrc4 {
+    pub fn new(cartridge: CartridgeData) -> Vrc4 {
+        let (_, pins) = vrc_variant(cartridge.mapper_number(), cartridge.submapper());
+        Vrc4 {
+            cartridge,
+            banks: VrcBanks::new(pins, 0),
+            irq: VrcIrq::default(),
+        }
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        if self.banks.write_register(address, value, true) {
+            return;
+        }
+        match address {
+            // Bit 0 is meant to enable PRG RAM, but not every game sets it
+            // and boards don't all connect it, so RAM is always on
+            0x9002 => self.banks.set_prg_swap(value & 0b10 != 0),
+            0xF000 => self.irq.write_latch_low(value),
+            0xF001 => self.irq.write_latch_high(value),
+            0xF002 => self.irq.write_control(value),
+            0xF003 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+}
+
+// The IRQ counter shared by VRC4, VRC6 and VRC7. It counts up from the latch
+// and fires when it wraps, either every CPU cycle or once per scanline, where
+// a prescaler takes 341 PPU dots' worth of CPU cycles, three dots at a time.
+// https://www.nesdev.org/wiki/VRC_IRQ
+#[derive(Default)]
+pub(super) struct VrcIrq {
+    latch: u8,
+    counter: u8,
+    prescaler: i16,
+    enable_after_ack: bool,
+    enabled: bool,
+    cycle_mode: bool,
+    pending: bool,
+}
+
+impl VrcIrq {
+    // VRC6 and VRC7 take the latch in one write
+    pub fn write_latch(&mut self, value: u8) {
+        self.latch = value;
+    }
+
+    pub fn write_latch_low(&mut self, value: u8) {
+        self.latch = (self.latch & 0xF0) | (value & 0x0F);
+    }
+
+    pub fn write_latch_high(&mut self, value: u8) {
+        self.latch = (self.latch & 0x0F) | (value & 0x0F) << 4;
+    }
+
+    // ---- -MEA: cycle mode, enable, enable after acknowledgement. Enabling
+    // reloads the counter and restarts the prescaler.
+    pub fn write_control(&mut self, value: u8) {
+        self.enable_after_ack = value & 0b001 != 0;
+        self.enabled = value & 0b010 != 0;
+        self.cycle_mode = value & 0b100 != 0;
+        self.pending = false;
+        if self.enabled {
+            self.counter = self.latch;
+            self.prescaler = 341;
+        }
+    }
+
+    pub fn acknowledge(&mut self) {
+        self.pending = false;
+        self.enabled = self.enable_after_ack;
+    }
+
+    pub fn pending(&self) -> bool {
+        self.pending
+    }
+
+    pub fn reset(&mut self) {
+        *self = VrcIrq::default();
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.latch);
+        state.u8(self.counter);
+        state.i16(self.prescaler);
+        state.bool(self.enable_after_ack);
+        state.bool(self.enabled);
+        state.bool(self.cycle_mode);
+        state.bool(self.pending);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.latch = state.u8()?;
+        self.counter = state.u8()?;
+        self.prescaler = match state.i16()? {
+            prescaler @ 0..=341 => prescaler,
+            _ => return Err(StateError::InvalidValue("VRC IRQ prescaler")),
+        };
+        self.enable_after_ack = state.bool()?;
+        self.enabled = state.bool()?;
+        self.cycle_mode = state.bool()?;
+        self.pending = state.bool()?;
+        Ok(())
+    }
+
+    // Once every CPU cycle
+    pub fn clock(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        if !self.cycle_mode {
+            self.prescaler -= 3;
+            if self.prescaler > 0 {
+                return;
+            }
+            self.prescaler += 341;
+        }
+        if self.counter == 0xFF {
+            self.counter = self.latch;
+            self.pending = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+}
+
+impl Mapper for Vrc4 {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        match address {
+            0x6000..=0x7FFF => read_prg_ram(&self.cartridge, (address - 0x6000) as usize),
+            0x8000..=0xFFFF => read_prg_rom(
+                &self.cartridge,
+                self.banks.prg_rom_offset(&self.cartridge, address),
+            ),
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        match address {
+            0x6000..=0x7FFF => {
+                write_prg_ram(&mut self.cartridge, (address - 0x6000) as usize, value)
+            }
+            0x8000..=0xFFFF => {
+                let address = self.banks.translate(address);
+                self.write_register(address, value);
+            }
+            _ => (),
+        }
+    }
+
+    fn ppu_read(&mut self, address: u16) -> u8 {
+        read_chr(&self.cartridge, self.banks.chr_offset(address))
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        let offset = self.banks.chr_offset(address);
+        write_chr(&mut self.cartridge, offset, value);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.banks.mirroring()
+    }
+
+    fn cartridge(&self) -> &CartridgeData {
+        &self.cartridge
+    }
+
+    fn cartridge_mut(&mut self) -> &mut CartridgeData {
+        &mut self.cartridge
+    }
+
+    fn reset(&mut self) {
+        self.banks.reset();
+        self.irq.reset();
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        save_cartridge(&self.cartridge, state);
+        self.banks.save_state(state);
+        self.irq.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        load_cartridge(&mut self.cartridge, state)?;
+        self.banks.load_state(state)?;
+        self.irq.load_state(state)?;
+        Ok(())
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq.pending()
+    }
+
+    fn cpu_clock(&mut self) {
+        self.irq.clock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cartridge::CartridgeBuilder;
+
+    use super::super::tests::cartridge;
+    use super::*;
+
+    fn vrc4(mapper: u16, submapper: u8) -> Vrc4 {
+        let prg_rom: Vec<u8> = (0..16).flat_map(|bank| [bank; 0x2000]).collect();
+        let chr_rom: Vec<u8> = (0..=255).flat_map(|bank| [bank; 0x400]).collect();
+        let cartridge = CartridgeBuilder::new()
+            .mapper(mapper)
+            .submapper(submapper)
+            .prg_rom(prg_rom)
+            .chr_rom(chr_rom)
+            .build()
+            .unwrap();
+        Vrc4::new(cartridge)
+    }
+
+    #[test]
+    fn different_pins_reach_the_same_register() {
+        // CHR bank 1's two nibbles, chip registers $B002 and $B003
+        for (mapper, submapper, low, high) in [
+            (21, 1, 0xB004, 0xB006),
+            (21, 2, 0xB080, 0xB0C0),
+            (23, 2, 0xB008, 0xB00C),
+            (25, 1, 0xB001, 0xB003),
+        ] {
+            let mut vrc4 = vrc4(mapper, submapper);
+            vrc4.cpu_write(low, 0x5);
+            vrc4.cpu_write(high, 0x2);
+            assert_eq!(vrc4.ppu_read(0x0400), 0x25, "{mapper}.{submapper}");
+            assert_eq!(vrc4.ppu_read(0x0000), 0, "{mapper}.{submapper}");
+        }
+        // The combined wiring for submapper 0 takes either board's lines
+        let mut vrc4 = vrc4(21, 0);
+        vrc4.cpu_write(0xB004, 0x5);
+        vrc4.cpu_write(0xB0C0, 0x2);
+        assert_eq!(vrc4.ppu_read(0x0400), 0x25);
+    }
+
+    #[test]
+    fn prg_swap_mode() {
+        let mut vrc4 = Vrc4::new(cartridge(21, 16, 128));
+        vrc4.cpu_write(0x8000, 3);
+        vrc4.cpu_write(0xA000, 5);
+        let prg = |vrc4: &mut Vrc4| [0x8000, 0xA000, 0xC000, 0xE000].map(|a| vrc4.cpu_read(a));
+        assert_eq!(prg(&mut vrc4), [3, 5, 14, 15]);
+        vrc4.cpu_write(0x9004, 0b10);
+        assert_eq!(prg(&mut vrc4), [14, 5, 3, 15]);
+    }
+
+    #[test]
+    fn irq_cycle_and_scanline_modes() {
+        let mut vrc4 = Vrc4::new(cartridge(21, 16, 128));
+        // Latch $FD, then enabled in cycle mode
+        vrc4.cpu_write(0xF000, 0xD);
+        vrc4.cpu_write(0xF002, 0xF);
+        vrc4.cpu_write(0xF004, 0b110);
+        for _ in 0..2 {
+            vrc4.cpu_clock();
+        }
+        assert!(!vrc4.irq_pending());
+        vrc4.cpu_clock();
+        assert!(vrc4.irq_pending());
+
+        // Latch $FF in scanline mode counts once per 341 dots, so the first
+        // IRQ takes 114 cycles
+        vrc4.cpu_write(0xF000, 0xF);
+        vrc4.cpu_write(0xF004, 0b010);
+        assert!(!vrc4.irq_pending());
+        for _ in 0..113 {
+            vrc4.cpu_clock();
+        }
+        assert!(!vrc4.irq_pending());
+        vrc4.cpu_clock();
+        assert!(vrc4.irq_pending());
+        vrc4.cpu_write(0xF006, 0);
+        assert!(!vrc4.irq_pending());
+    }
+
+    #[test]
+    fn irq_prescaler_is_checked_on_load() {
+        let irq_state = |prescaler: i16| {
+            let mut state = StateWriter::new();
+            state.u8(0);
+            state.u8(0);
+            state.i16(prescaler);
+            for _ in 0..4 {
+                state.bool(true);
+            }
+            state.into_bytes()
+        };
+        let mut irq = VrcIrq::default();
+        irq.load_state(&mut StateReader::new(&irq_state(341)))
+            .unwrap();
+        for prescaler in [-32768, -1, 342] {
+            assert_eq!(
+                irq.load_state(&mut StateReader::new(&irq_state(prescaler))),
+                Err(StateError::InvalidValue("VRC IRQ prescaler"))
+            );
+        }
+    }
+}
@@ -0,0 +1,308 @@
+use crate::cartridge::{CartridgeData, Mirroring};
+use crate::state::{StateError, StateReader, StateWriter};
+
+use super::vrc4::VrcIrq;
+use super::{
+    load_cartridge, read_chr, read_prg_ram, read_prg_rom, save_cartridge, write_chr, write_prg_ram,
+    Mapper,
+};
+
+// Mappers 24 and 26: https://www.nesdev.org/wiki/VRC6
+// A 16 KB and an 8 KB PRG bank, eight CHR bank registers that $B003 can
+// arrange in 1 KB or 2 KB banks and also use for nametables, either picking
+// the console VRAM page or putting CHR ROM in its place, and the VRC4 IRQ.
+// Mapper 26 boards swap the A0 and A1 lines. The expansion audio at
+// $9000-$B002 isn't emulated.
+pub struct Vrc6 {
+    cartridge: CartridgeData,
+    swap_pins: bool,
+    // $8000-$BFFF in 16 KB units. $C000-$DFFF is prg_bank_8k and $E000 is
+    // fixed to the last 8 KB bank.
+    prg_bank_16k: u8,
+    prg_bank_8k: u8,
+    chr_banks: [u8; 8],
+    // $B003
+    // 7  bit  0
+    // ---- ----
+    // W.PN MMDD
+    // | || ||||
+    // | || ||++- Pattern table mode, see chr_bank
+    // | || ++--- Nametable arrangement, see nametable_bank
+    // | |+------ 0: nametables in console VRAM; 1: in CHR ROM
+    // | +------- 2 KB banks take their low bit from PPU A10 instead
+    // +--------- PRG RAM enable
+    ppu_mode: u8,
+    irq: VrcIrq,
+}
+
+impl Vrc6 {
+    pub fn new(cartridge: CartridgeData) -> Vrc6 {
+        let swap_pins = cartridge.mapper_number() == 26;
+        Vrc6 {
+            cartridge,
+            swap_pins,
+            prg_bank_16k: 0,
+            prg_bank_8k: 0,
+            chr_banks: [0; 8],
+            ppu_mode: 0,
+            irq: VrcIrq::default(),
+        }
+    }
+
+    // The register's address as the chip sees it, $x000-$x003
+    fn translate(&self, address: u16) -> u16 {
+        if self.swap_pins {
+            (address & 0xF000) | (address & 1) << 1 | (address >> 1 & 1)
+        } else {
+            address & 0xF003
+        }
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x8000..=0x8003 => self.prg_bank_16k = value & 0x0F,
+            0xB003 => self.ppu_mode = value,
+            0xC000..=0xC003 => self.prg_bank_8k = value & 0x1F,
+            0xD000..=0xE003 => {
+                let register = ((address - 0xD000) >> 12) as usize * 4 + (address & 0b11) as usize;
+                self.chr_banks[register] = value;
+            }
+            0xF000 => self.irq.write_latch(value),
+            0xF001 => self.irq.write_control(value),
+            0xF002 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.ppu_mode & 0x80 != 0
+    }
+
+    fn prg_rom_offset(&self, address: u16) -> usize {
+        match address {
+            0x8000..=0xBFFF => self.prg_bank_16k as usize * 0x4000 + (address & 0x3FFF) as usize,
+            0xC000..=0xDFFF => self.prg_bank_8k as usize * 0x2000 + (address & 0x1FFF) as usize,
+            _ => {
+                let last_bank = (self.cartridge.prg_rom().len() / 0x2000).saturating_sub(1);
+                last_bank * 0x2000 + (address & 0x1FFF) as usize
+            }
+        }
+    }
+
+    // A 2 KB bank from one register, covering two 1 KB slots
+    fn half_of_pair(&self, register: usize, slot: usize) -> usize {
+        let bank = self.chr_banks[register] as usize;
+        if self.ppu_mode & 0x20 != 0 {
+            bank & !1 | slot & 1
+        } else {
+            bank
+        }
+    }
+
+    // 1 KB bank for a pattern table slot. Mode 0: eight 1 KB banks; 1: four
+    // 2 KB banks; 2 and 3: four 1 KB banks then two 2 KB banks.
+    fn chr_bank(&self, slot: usize) -> usize {
+        match self.ppu_mode & 0b11 {
+            0 => self.chr_banks[slot] as usize,
+            1 => self.half_of_pair(slot >> 1, slot),
+            _ if slot < 4 => self.chr_banks[slot] as usize,
+            _ => self.half_of_pair(4 + ((slot - 4) >> 1), slot),
+        }
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        let slot = (address as usize >> 10) & 0b111;
+        self.chr_bank(slot) * 0x400 + (address & 0x3FF) as usize
+    }
+
+    // 1 KB bank for nametable $2000, $2400, $2800 or $2C00. In console VRAM
+    // only its low bit matters, picking the page. Games only use the four
+    // arrangements where MM picks ordinary mirroring, with the low bit
+    // following PPU A10 or A11 or fixed. The rest use the registers as they
+    // are, which is as close as the chip's behaviour for them is known.
+    fn nametable_bank(&self, quadrant: usize) -> u8 {
+        let r = |register: usize| self.chr_banks[register];
+        let low = |register: usize, bit: usize| r(register) & !1 | bit as u8;
+        match self.ppu_mode & 0x2F {
+            // Vertical
+            0x20 | 0x27 => low(6 + (quadrant >> 1), quadrant & 1),
+            // Horizontal
+            0x23 | 0x24 => low(6 + (quadrant & 1), quadrant >> 1),
+            // Single screen, lower then upper
+            0x28 | 0x2F => low(6 + (quadrant >> 1), 0),
+            0x2B | 0x2C => low(6 + (quadrant & 1), 1),
+            mode => match mode & 0b111 {
+                1 | 5 => r(4 + quadrant),
+                2..=4 => r(6 + (quadrant & 1)),
+                _ => r(6 + (quadrant >> 1)),
+            },
+        }
+    }
+
+    fn nametables_in_chr(&self) -> bool {
+        self.ppu_mode & 0x10 != 0
+    }
+}
+
+impl Mapper for Vrc6 {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        match address {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                read_prg_ram(&self.cartridge, (address - 0x6000) as usize)
+            }
+            // Disabled RAM leaves the bus floating
+            0x6000..=0x7FFF => (address >> 8) as u8,
+            0x8000..=0xFFFF => read_prg_rom(&self.cartridge, self.prg_rom_offset(address)),
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        match address {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                write_prg_ram(&mut self.cartridge, (address - 0x6000) as usize, value)
+            }
+            0x8000..=0xFFFF => self.write_register(self.translate(address), value),
+            _ => (),
+        }
+    }
+
+    fn ppu_read(&mut self, address: u16) -> u8 {
+        read_chr(&self.cartridge, self.chr_offset(address))
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        let offset = self.chr_offset(address);
+        write_chr(&mut self.cartridge, offset, value);
+    }
+
+    // The console VRAM pages in use. Arrangements that aren't any of these
+    // are left to read_nametable.
+    fn mirroring(&self) -> Mirroring {
+        match [0, 1, 2, 3].map(|quadrant| self.nametable_bank(quadrant) & 1) {
+            [0, 0, 1, 1] => Mirroring::Horizontal,
+            [0, 0, 0, 0] => Mirroring::SingleScreenLower,
+            [1, 1, 1, 1] => Mirroring::SingleScreenUpper,
+            _ => Mirroring::Vertical,
+        }
+    }
+
+    fn cartridge(&self) -> &CartridgeData {
+        &self.cartridge
+    }
+
+    fn cartridge_mut(&mut self) -> &mut CartridgeData {
+        &mut self.cartridge
+    }
+
+    fn reset(&mut self) {
+        self.prg_bank_16k = 0;
+        self.prg_bank_8k = 0;
+        self.chr_banks = [0; 8];
+        self.ppu_mode = 0;
+        self.irq.reset();
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        save_cartridge(&self.cartridge, state);
+        state.u8(self.prg_bank_16k);
+        state.u8(self.prg_bank_8k);
+        state.bytes(&self.chr_banks);
+        state.u8(self.ppu_mode);
+        self.irq.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        load_cartridge(&mut self.cartridge, state)?;
+        self.prg_bank_16k = state.u8()?;
+        self.prg_bank_8k = state.u8()?;
+        state.fill(&mut self.chr_banks)?;
+        self.ppu_mode = state.u8()?;
+        self.irq.load_state(state)
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq.pending()
+    }
+
+    fn cpu_clock(&mut self) {
+        self.irq.clock();
+    }
+
+    fn read_nametable(&mut self, address: u16, vram: &[u8; 4096]) -> u8 {
+        let bank = self.nametable_bank((address as usize >> 10) & 0b11) as usize;
+        let offset = (address & 0x3FF) as usize;
+        if self.nametables_in_chr() {
+            read_chr(&self.cartridge, bank * 0x400 + offset)
+        } else {
+            vram[(bank & 1) * 0x400 + offset]
+        }
+    }
+
+    fn write_nametable(&mut self, address: u16, value: u8, vram: &mut [u8; 4096]) {
+        let bank = self.nametable_bank((address as usize >> 10) & 0b11) as usize;
+        let offset = (address & 0x3FF) as usize;
+        if self.nametables_in_chr() {
+            write_chr(&mut self.cartridge, bank * 0x400 + offset, value);
+        } else {
+            vram[(bank & 1) * 0x400 + offset] = value;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::cartridge;
+    use super::*;
+
+    #[test]
+    fn mapper_26_swaps_a0_and_a1() {
+        for (mapper, r1, r2) in [(24, 0xD001, 0xD002), (26, 0xD002, 0xD001)] {
+            let mut vrc6 = Vrc6::new(cartridge(mapper, 16, 128));
+            vrc6.cpu_write(r1, 9);
+            vrc6.cpu_write(r2, 17);
+            assert_eq!(vrc6.ppu_read(0x0400), 9, "mapper {mapper}");
+            assert_eq!(vrc6.ppu_read(0x0800), 17, "mapper {mapper}");
+            // $B003 is the same either way round
+            vrc6.cpu_write(0xB003, 0x01);
+            assert_eq!(vrc6.ppu_read(0x0400), 0, "mapper {mapper}");
+            assert_eq!(vrc6.ppu_read(0x0800), 9, "mapper {mapper}");
+        }
+    }
+
+    #[test]
+    fn prg_banks() {
+        let mut vrc6 = Vrc6::new(cartridge(24, 16, 128));
+        vrc6.cpu_write(0x8000, 2);
+        vrc6.cpu_write(0xC000, 9);
+        let prg = [0x8000, 0xA000, 0xC000, 0xE000].map(|address| vrc6.cpu_read(address));
+        assert_eq!(prg, [4, 5, 9, 15]);
+    }
+
+    #[test]
+    fn nametables_from_chr_rom() {
+        let mut vrc6 = Vrc6::new(cartridge(24, 16, 128));
+        let mut vram = [0; 4096];
+        vram[0x400] = 0x55;
+        vrc6.cpu_write(0xE002, 20);
+        vrc6.cpu_write(0xE003, 33);
+        // R6 and R7 banked in vertically in console VRAM, which only takes
+        // the low bit
+        vrc6.cpu_write(0xB003, 0x20);
+        assert_eq!(vrc6.mirroring(), Mirroring::Vertical);
+        assert_eq!(vrc6.read_nametable(0x2400, &vram), 0x55);
+        vrc6.cpu_write(0xB003, 0x24);
+        assert_eq!(vrc6.mirroring(), Mirroring::Horizontal);
+
+        // The same registers as whole CHR ROM banks
+        vrc6.cpu_write(0xB003, 0x10);
+        assert_eq!(vrc6.read_nametable(0x2000, &vram), 20);
+        assert_eq!(vrc6.read_nametable(0x27FF, &vram), 20);
+        assert_eq!(vrc6.read_nametable(0x2800, &vram), 33);
+        assert_eq!(vrc6.read_nametable(0x2FFF, &vram), 33);
+        // ROM can't be written
+        vrc6.write_nametable(0x2000, 0x66, &mut vram);
+        assert_eq!(vrc6.read_nametable(0x2000, &vram), 20);
+        assert_eq!(vram[0], 0);
+    }
+}
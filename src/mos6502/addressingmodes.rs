@@ -25,6 +25,7 @@ pub enum AddressingMode {
     AbsoluteX, // Second and third bytes of instruction form an address to which the X register is added. The sum is the address of the operand
     AbsoluteY, // Second and third bytes of instruction form an address to which the Y register is added. The sum is the address of the operand
     AbsoluteIndirect, // Second and third bytes of instruction form an address to a pointer. Program Counter is set to this pointer
+    Accumulator,      // A single byte instruction operating on the accumulator
     Immediate,        // Operand is the second byte
     Implied,          // A single bye instruction, no operands
     Relative, // An offset in the second byte of the instruction is added to the program counter if the branch statement is true.
@@ -71,7 +72,14 @@ const fn group_two(opcode: u8) -> AddressingMode {
     match (opcode & 0b00011100) >> 2 {
         0b000 => AddressingMode::Immediate,
         0b001 => AddressingMode::ZeroPage,
-        0b010 => AddressingMode::Implied,
+        // ASL, ROL, LSR and ROR on the accumulator, then TXA, TAX, DEX and NOP
+        0b010 => {
+            if opcode < 0x80 {
+                AddressingMode::Accumulator
+            } else {
+                AddressingMode::Implied
+            }
+        }
         0b011 => AddressingMode::Absolute,
         0b100 => AddressingMode::Implied, // Every instruction here is JAM
         0b101 => {
@@ -0,0 +1,272 @@
+// Static disassembly of 6502 code, for debuggers and listings. Operands
+// are shown the way an assembler would take them, so unlike the tracer
+// nothing depends on the registers or what's in memory.
+
+use std::fmt;
+
+use super::addressingmodes::{AddressingMode, ADDRESSING_MODES};
+// The instruction table's own Instruction, which only names the operation
+use super::instruction_table::{Instruction as Operation, INSTRUCTIONS};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub address: u16,
+    // The opcode followed by any operand bytes
+    pub bytes: Vec<u8>,
+    pub mnemonic: String,
+    // Empty for implied instructions, "A" for the accumulator, and the
+    // target address for branches
+    pub operand: String,
+    // False for the unofficial opcodes and for .byte
+    pub official: bool,
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.operand.is_empty() {
+            write!(f, "{}", self.mnemonic)
+        } else {
+            write!(f, "{} {}", self.mnemonic, self.operand)
+        }
+    }
+}
+
+/// Decodes the code in bytes as if it were loaded at base. An instruction
+/// cut off by the end of bytes comes out as a `.byte` for each byte left.
+///
+/// ```
+/// use zephyrnes::mos6502::disasm::disassemble;
+///
+/// let code = [
+///     0xA2, 0x05, // LDX #$05
+///     0xCA, // DEX
+///     0xD0, 0xFD, // BNE back to the DEX
+///     0xA7, 0x10, // LAX $10, which is unofficial
+///     0x6C, 0xFC, 0xFF, // JMP ($FFFC)
+///     0x20, // A JSR with its address missing
+/// ];
+/// let lines: Vec<_> = disassemble(&code, 0xC000)
+///     .iter()
+///     .map(|instruction| format!("{:04X} {instruction}", instruction.address))
+///     .collect();
+/// assert_eq!(
+///     lines,
+///     [
+///         "C000 LDX #$05",
+///         "C002 DEX",
+///         "C003 BNE $C002",
+///         "C005 LAX $10",
+///         "C007 JMP ($FFFC)",
+///         "C00A .byte $20",
+///     ]
+/// );
+///
+/// let listing = disassemble(&code, 0xC000);
+/// assert_eq!(listing[2].bytes, [0xD0, 0xFD]);
+/// assert!(listing[2].official && !listing[3].official);
+/// ```
+pub fn disassemble(bytes: &[u8], base: u16) -> Vec<Instruction> {
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let address = base.wrapping_add(offset as u16);
+        let opcode = bytes[offset];
+        let mode = ADDRESSING_MODES[opcode as usize];
+        let len = instruction_len(mode) as usize;
+        match bytes.get(offset..offset + len) {
+            Some(encoded) => {
+                let instruction = INSTRUCTIONS[opcode as usize];
+                instructions.push(Instruction {
+                    address,
+                    bytes: encoded.to_vec(),
+                    mnemonic: mnemonic(instruction),
+                    operand: operand(mode, address, encoded),
+                    official: is_official(opcode, instruction),
+                });
+                offset += len;
+            }
+            None => {
+                for (index, &byte) in bytes[offset..].iter().enumerate() {
+                    instructions.push(Instruction {
+                        address: address.wrapping_add(index as u16),
+                        bytes: vec![byte],
+                        mnemonic: ".byte".to_string(),
+                        operand: format!("${byte:02X}"),
+                        official: false,
+                    });
+                }
+                break;
+            }
+        }
+    }
+    instructions
+}
+
+fn operand(mode: AddressingMode, address: u16, encoded: &[u8]) -> String {
+    let byte = encoded.get(1).copied().unwrap_or(0);
+    let word = u16::from_le_bytes([byte, encoded.get(2).copied().unwrap_or(0)]);
+    match mode {
+        AddressingMode::Implied => String::new(),
+        AddressingMode::Accumulator => "A".to_string(),
+        AddressingMode::Immediate => format!("#${byte:02X}"),
+        AddressingMode::Relative => format!("${:04X}", branch_target(address, byte)),
+        AddressingMode::Absolute => format!("${word:04X}"),
+        AddressingMode::AbsoluteIndirect => format!("(${word:04X})"),
+        AddressingMode::AbsoluteX => format!("${word:04X},X"),
+        AddressingMode::AbsoluteY => format!("${word:04X},Y"),
+        AddressingMode::ZeroPage => format!("${byte:02X}"),
+        AddressingMode::ZeroPageX => format!("${byte:02X},X"),
+        AddressingMode::ZeroPageY => format!("${byte:02X},Y"),
+        AddressingMode::ZeroPageIndexedIndirectX => format!("(${byte:02X},X)"),
+        AddressingMode::ZeroPageIndirectIndexedY => format!("(${byte:02X}),Y"),
+    }
+}
+
+// Offsets count from the instruction after the branch
+pub(super) fn branch_target(address: u16, offset: u8) -> u16 {
+    address.wrapping_add(2).wrapping_add(offset as i8 as u16)
+}
+
+pub(super) fn instruction_len(mode: AddressingMode) -> u16 {
+    match mode {
+        AddressingMode::Absolute
+        | AddressingMode::AbsoluteIndirect
+        | AddressingMode::AbsoluteX
+        | AddressingMode::AbsoluteY => 3,
+        AddressingMode::Accumulator | AddressingMode::Implied => 1,
+        _ => 2,
+    }
+}
+
+// The unofficial NOPs and the SBC at $EB share a name with official ones
+pub(super) fn is_official(opcode: u8, instruction: Operation) -> bool {
+    match instruction {
+        Operation::NOP => opcode == 0xEA,
+        Operation::SBC => opcode != 0xEB,
+        Operation::SLO
+        | Operation::RLA
+        | Operation::SRE
+        | Operation::RRA
+        | Operation::DCP
+        | Operation::ISC
+        | Operation::SAX
+        | Operation::LAX
+        | Operation::ANC
+        | Operation::ALR
+        | Operation::ARR
+        | Operation::XAA
+        | Operation::LAS
+        | Operation::AXS
+        | Operation::AHX
+        | Operation::SHX
+        | Operation::SHY
+        | Operation::TAS
+        | Operation::STP => false,
+        _ => true,
+    }
+}
+
+// ISC goes by ISB in nestest.log and most other listings
+pub(super) fn mnemonic(instruction: Operation) -> String {
+    match instruction {
+        Operation::ISC => "ISB".to_string(),
+        instruction => format!("{instruction:?}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listing(bytes: &[u8], base: u16) -> Vec<String> {
+        disassemble(bytes, base)
+            .iter()
+            .map(|instruction| format!("{:04X} {instruction}", instruction.address))
+            .collect()
+    }
+
+    #[test]
+    fn branches_and_indirect_jump() {
+        let code = [
+            0xA9, 0x00, // LDA #$00
+            0xF0, 0x03, // BEQ forward past the JMP
+            0x6C, 0x34, 0x12, // JMP ($1234)
+            0x10, 0xF7, // BPL back to the LDA
+            0x0A, // ASL A
+            0x60, // RTS
+        ];
+        assert_eq!(
+            listing(&code, 0x8000),
+            [
+                "8000 LDA #$00",
+                "8002 BEQ $8007",
+                "8004 JMP ($1234)",
+                "8007 BPL $8000",
+                "8009 ASL A",
+                "800A RTS",
+            ]
+        );
+        // Targets wrap around the address space
+        assert_eq!(listing(&[0xD0, 0x7F], 0xFFF0), ["FFF0 BNE $0071"]);
+        assert_eq!(listing(&[0x90, 0x80], 0x0010), ["0010 BCC $FF92"]);
+    }
+
+    #[test]
+    fn indexed_and_indirect_operands() {
+        let code = [
+            0xB5, 0x10, // LDA $10,X
+            0xB6, 0x20, // LDX $20,Y
+            0xA1, 0x30, // LDA ($30,X)
+            0xB1, 0x40, // LDA ($40),Y
+            0xBD, 0x00, 0x02, // LDA $0200,X
+            0xB9, 0x00, 0x03, // LDA $0300,Y
+        ];
+        let instructions = disassemble(&code, 0);
+        let operands: Vec<&str> = instructions.iter().map(|i| i.operand.as_str()).collect();
+        assert_eq!(
+            operands,
+            ["$10,X", "$20,Y", "($30,X)", "($40),Y", "$0200,X", "$0300,Y"]
+        );
+        assert_eq!(instructions[4].bytes, [0xBD, 0x00, 0x02]);
+        assert_eq!(instructions[5].address, 11);
+    }
+
+    #[test]
+    fn cut_off_instructions_become_bytes() {
+        let code = [0xEA, 0xAD, 0x00];
+        assert_eq!(
+            listing(&code, 0x4000),
+            ["4000 NOP", "4001 .byte $AD", "4002 .byte $00"]
+        );
+        let instructions = disassemble(&code, 0x4000);
+        assert_eq!(instructions[2].bytes, [0x00]);
+        assert!(!instructions[1].official && !instructions[2].official);
+        assert_eq!(listing(&[0x4C], 0xFFFF), ["FFFF .byte $4C"]);
+    }
+
+    #[test]
+    fn unofficial_opcodes() {
+        // Every opcode decodes to an instruction of its own
+        for opcode in 0..=255 {
+            let instructions = disassemble(&[opcode, 0, 0], 0);
+            assert_ne!(instructions[0].mnemonic, ".byte", "{opcode:02X}");
+        }
+
+        let code = [0xEA, 0x1A, 0xE9, 0x01, 0xEB, 0x01, 0xE7, 0x10, 0x12];
+        let decoded: Vec<(String, bool)> = disassemble(&code, 0)
+            .into_iter()
+            .map(|i| (i.to_string(), i.official))
+            .collect();
+        assert_eq!(
+            decoded,
+            [
+                ("NOP".to_string(), true),
+                ("NOP".to_string(), false),
+                ("SBC #$01".to_string(), true),
+                ("SBC #$01".to_string(), false),
+                ("ISB $10".to_string(), false),
+                ("STP".to_string(), false),
+            ]
+        );
+    }
+}
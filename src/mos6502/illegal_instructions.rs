@@ -0,0 +1,304 @@
+// The unofficial opcodes, which fall out of how the instruction decoder
+// combines the official ones. Most run an official read-modify-write
+// instruction and then an ALU instruction on its result.
+// https://www.nesdev.org/wiki/CPU_unofficial_opcodes
+
+// What the unstable instructions OR into A, which varies between chips
+const MAGIC_CONSTANT: u8 = 0xEE;
+
+impl super::Mos6502 {
+    // ASL then ORA
+    pub(super) fn slo(&mut self, value: u8) -> u8 {
+        let value = self.asl(value);
+        self.ora(value);
+        value
+    }
+
+    // ROL then AND
+    pub(super) fn rla(&mut self, value: u8) -> u8 {
+        let value = self.rol(value);
+        self.and(value);
+        value
+    }
+
+    // LSR then EOR
+    pub(super) fn sre(&mut self, value: u8) -> u8 {
+        let value = self.lsr(value);
+        self.eor(value);
+        value
+    }
+
+    // ROR then ADC
+    pub(super) fn rra(&mut self, value: u8) -> u8 {
+        let value = self.ror(value);
+        self.add_with_carry(value);
+        value
+    }
+
+    // DEC then CMP
+    pub(super) fn dcp(&mut self, value: u8) -> u8 {
+        let value = value.wrapping_sub(1);
+        self.compare(self.accumulator, value);
+        value
+    }
+
+    // INC then SBC
+    pub(super) fn isc(&mut self, value: u8) -> u8 {
+        let value = value.wrapping_add(1);
+        self.add_with_carry(!value);
+        value
+    }
+
+    // STA and STX at once, storing A AND X
+    pub(super) fn sax(&self) -> u8 {
+        self.accumulator & self.index_x
+    }
+
+    // LDA and LDX at once
+    pub(super) fn lax(&mut self, value: u8) {
+        self.accumulator = value;
+        self.index_x = value;
+        self.set_zero_sign(value);
+    }
+
+    // The immediate form of LAX, which is unstable
+    pub(super) fn lxa(&mut self, value: u8) {
+        self.lax((self.accumulator | MAGIC_CONSTANT) & value);
+    }
+
+    // AND, then copy N into C
+    pub(super) fn anc(&mut self, value: u8) {
+        self.and(value);
+        self.carry = self.sign;
+    }
+
+    // AND then LSR A
+    pub(super) fn alr(&mut self, value: u8) {
+        let value = self.accumulator & value;
+        self.accumulator = self.lsr(value);
+    }
+
+    // AND then ROR A, except C and V come from bits 6 and 5 of the result
+    pub(super) fn arr(&mut self, value: u8) {
+        let value = self.accumulator & value;
+        self.accumulator = (value >> 1) | ((self.carry as u8) << 7);
+        self.set_zero_sign(self.accumulator);
+        self.carry = self.accumulator & 0b01000000 != 0;
+        self.overflow = ((self.accumulator >> 6) ^ (self.accumulator >> 5)) & 1 != 0;
+    }
+
+    // Also known as ANE. Unstable.
+    pub(super) fn xaa(&mut self, value: u8) {
+        self.accumulator = (self.accumulator | MAGIC_CONSTANT) & self.index_x & value;
+        self.set_zero_sign(self.accumulator);
+    }
+
+    // Memory AND SP, into A, X and SP
+    pub(super) fn las(&mut self, value: u8) {
+        let value = value & self.stack_pointer;
+        self.stack_pointer = value;
+        self.lax(value);
+    }
+
+    // Also known as SBX. (A AND X) minus the operand into X, setting flags
+    // like CMP and ignoring the carry going in.
+    pub(super) fn axs(&mut self, value: u8) {
+        let register = self.accumulator & self.index_x;
+        self.compare(register, value);
+        self.index_x = register.wrapping_sub(value);
+    }
+
+    // Also known as SHA
+    pub(super) fn ahx(&self) -> (u16, u8) {
+        self.store_and_high(self.accumulator & self.index_x)
+    }
+
+    pub(super) fn shx(&self) -> (u16, u8) {
+        self.store_and_high(self.index_x)
+    }
+
+    pub(super) fn shy(&self) -> (u16, u8) {
+        self.store_and_high(self.index_y)
+    }
+
+    // Also known as SHS. Puts A AND X in SP, then stores like SHA.
+    pub(super) fn tas(&mut self) -> (u16, u8) {
+        self.stack_pointer = self.accumulator & self.index_x;
+        self.store_and_high(self.stack_pointer)
+    }
+
+    // Where these store and what. The value is ANDed with the high byte of
+    // the base address plus one. When indexing crosses a page, that same
+    // value replaces the high byte of the address written to.
+    fn store_and_high(&self, value: u8) -> (u16, u8) {
+        // Before the carry out of the low byte is added back in
+        let base_high = ((self.address >> 8) as u8).wrapping_sub(self.page_crossed as u8);
+        let value = value & base_high.wrapping_add(1);
+        let address = if self.page_crossed {
+            ((value as u16) << 8) | (self.address & 0x00FF)
+        } else {
+            self.address
+        };
+        (address, value)
+    }
+
+    // Also known as JAM or KIL. Locks the CPU up until it's reset.
+    pub(super) fn stp(&mut self) {
+        self.jammed = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::Ram;
+    use super::super::Mos6502;
+
+    // Runs the program through to its end, returning the cycles it took
+    fn run(cpu: &mut Mos6502, bus: &mut Ram, program_len: u16) -> u32 {
+        let mut cycles = 0;
+        while cpu.program_counter() < 0x0600 + program_len {
+            cycles += cpu.step(bus) as u32;
+        }
+        cycles
+    }
+
+    // A CPU about to run the program with zero page and A and X set up
+    fn setup(program: &[u8], zero_page: &[(u16, u8)], a: u8, x: u8) -> (Mos6502, Ram) {
+        let mut bus = Ram::with_program(program);
+        for &(address, value) in zero_page {
+            bus.memory[address as usize] = value;
+        }
+        // Reset leaves A and X alone
+        let mut cpu = Mos6502::new();
+        cpu.accumulator = a;
+        cpu.index_x = x;
+        cpu.reset();
+        (cpu, bus)
+    }
+
+    #[test]
+    fn lax_and_sax() {
+        // LAX $10, SAX $11
+        let (mut cpu, mut bus) = setup(&[0xA7, 0x10, 0x87, 0x11], &[(0x10, 0x85)], 0, 0);
+        assert_eq!(run(&mut cpu, &mut bus, 4), 3 + 3);
+        assert_eq!((cpu.accumulator(), cpu.index_x()), (0x85, 0x85));
+        assert!(cpu.negative());
+        assert_eq!(bus.writes, [(0x11, 0x85)]);
+
+        let (mut cpu, mut bus) = setup(&[0x87, 0x11], &[], 0xF0, 0x3C);
+        run(&mut cpu, &mut bus, 2);
+        assert_eq!(bus.memory[0x11], 0x30);
+    }
+
+    #[test]
+    fn dcp_and_isc() {
+        // DCP $10 decrements then compares
+        let (mut cpu, mut bus) = setup(&[0xC7, 0x10], &[(0x10, 0x11)], 0x10, 0);
+        assert_eq!(run(&mut cpu, &mut bus, 2), 5);
+        assert_eq!(bus.memory[0x10], 0x10);
+        assert!(cpu.zero() && cpu.carry());
+
+        // SEC, ISC $10 increments then subtracts
+        let (mut cpu, mut bus) = setup(&[0x38, 0xE7, 0x10], &[(0x10, 0x0F)], 0x20, 0);
+        run(&mut cpu, &mut bus, 3);
+        assert_eq!(bus.memory[0x10], 0x10);
+        assert_eq!(cpu.accumulator(), 0x10);
+        assert!(cpu.carry());
+    }
+
+    #[test]
+    fn shift_then_alu() {
+        // SLO $10
+        let (mut cpu, mut bus) = setup(&[0x07, 0x10], &[(0x10, 0x81)], 0x40, 0);
+        run(&mut cpu, &mut bus, 2);
+        assert_eq!((bus.memory[0x10], cpu.accumulator()), (0x02, 0x42));
+        assert!(cpu.carry());
+
+        // SEC, RLA $10
+        let (mut cpu, mut bus) = setup(&[0x38, 0x27, 0x10], &[(0x10, 0x80)], 0xFF, 0);
+        run(&mut cpu, &mut bus, 3);
+        assert_eq!((bus.memory[0x10], cpu.accumulator()), (0x01, 0x01));
+        assert!(cpu.carry());
+
+        // SRE $10
+        let (mut cpu, mut bus) = setup(&[0x47, 0x10], &[(0x10, 0x03)], 0xFF, 0);
+        run(&mut cpu, &mut bus, 2);
+        assert_eq!((bus.memory[0x10], cpu.accumulator()), (0x01, 0xFE));
+        assert!(cpu.carry());
+
+        // CLC, RRA $10, where the ROR's carry goes into the ADC
+        let (mut cpu, mut bus) = setup(&[0x18, 0x67, 0x10], &[(0x10, 0x03)], 0x10, 0);
+        run(&mut cpu, &mut bus, 3);
+        assert_eq!((bus.memory[0x10], cpu.accumulator()), (0x01, 0x12));
+        assert!(!cpu.carry());
+    }
+
+    #[test]
+    fn nops_take_their_addressing_mode_cycles() {
+        // NOP, NOP $10, NOP $0300, NOP $03FF,X crossing a page
+        let program = [0x1A, 0x04, 0x10, 0x0C, 0x00, 0x03, 0x1C, 0xFF, 0x03];
+        let (mut cpu, mut bus) = setup(&program, &[], 0x55, 0x01);
+        assert_eq!(run(&mut cpu, &mut bus, 9), 2 + 3 + 4 + 5);
+        assert_eq!((cpu.accumulator(), cpu.index_x()), (0x55, 0x01));
+        assert!(bus.writes.is_empty());
+    }
+
+    #[test]
+    fn immediate_and_combinations() {
+        // ANC #$80 copies N into C
+        let (mut cpu, mut bus) = setup(&[0x0B, 0x80], &[], 0xC0, 0);
+        assert_eq!(run(&mut cpu, &mut bus, 2), 2);
+        assert_eq!(cpu.accumulator(), 0x80);
+        assert!(cpu.carry() && cpu.negative());
+
+        // ALR #$03
+        let (mut cpu, mut bus) = setup(&[0x4B, 0x03], &[], 0xFF, 0);
+        run(&mut cpu, &mut bus, 2);
+        assert_eq!(cpu.accumulator(), 0x01);
+        assert!(cpu.carry());
+
+        // SEC, ARR #$FF takes C and V from bits 6 and 5
+        let (mut cpu, mut bus) = setup(&[0x38, 0x6B, 0xFF], &[], 0x80, 0);
+        run(&mut cpu, &mut bus, 3);
+        assert_eq!(cpu.accumulator(), 0xC0);
+        assert!(cpu.carry() && cpu.overflow());
+
+        // AXS #$01 ignores the carry going in
+        let (mut cpu, mut bus) = setup(&[0xCB, 0x01], &[], 0x0F, 0xFC);
+        run(&mut cpu, &mut bus, 2);
+        assert_eq!(cpu.index_x(), 0x0B);
+        assert!(cpu.carry());
+    }
+
+    #[test]
+    fn unstable_instructions_use_the_magic_constant() {
+        // XAA #$FF
+        let (mut cpu, mut bus) = setup(&[0x8B, 0xFF], &[], 0x00, 0x0F);
+        run(&mut cpu, &mut bus, 2);
+        assert_eq!(cpu.accumulator(), 0xEE & 0x0F);
+        // LAX #$FF
+        let (mut cpu, mut bus) = setup(&[0xAB, 0xFF], &[], 0x01, 0);
+        run(&mut cpu, &mut bus, 2);
+        assert_eq!((cpu.accumulator(), cpu.index_x()), (0xEF, 0xEF));
+    }
+
+    #[test]
+    fn disabled_illegal_ops_do_nothing() {
+        let (mut cpu, mut bus) = setup(&[0xA7, 0x10, 0x87, 0x11], &[(0x10, 0x85)], 0, 0);
+        cpu.enable_illegal_ops = false;
+        assert_eq!(run(&mut cpu, &mut bus, 4), 3 + 3);
+        assert_eq!((cpu.accumulator(), cpu.index_x()), (0, 0));
+        assert!(bus.writes.is_empty());
+    }
+
+    #[test]
+    fn stp_jams() {
+        let (mut cpu, mut bus) = setup(&[0x02, 0xEA], &[], 0, 0);
+        cpu.step(&mut bus);
+        let jammed_at = cpu.program_counter();
+        for _ in 0..10 {
+            assert_eq!(cpu.step(&mut bus), 1);
+        }
+        assert_eq!(cpu.program_counter(), jammed_at);
+    }
+}
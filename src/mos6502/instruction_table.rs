@@ -1,9 +1,9 @@
-#[derive(Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Instruction {
     ADC,
     AHX,
     ALR,
-    ALS,
     ANC,
     AND,
     ARR,
@@ -26,7 +26,6 @@ pub enum Instruction {
     CMP,
     CPX,
     CPY,
-    DCD,
     DCP,
     DEC,
     DEX,
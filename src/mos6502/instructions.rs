@@ -1,41 +1,208 @@
-use std::ops::Add;
+// https://www.nesdev.org/obelisk-6502-guide/reference.html
+//
+// What each instruction does with its operand. Fetching the operand and
+// writing back the result happen a cycle at a time in micro_ops.
 
-use crate::cpu_memory::CpuMemory;
-
-use super::Operand;
+use super::instruction_table::Instruction;
 
 impl super::Mos6502 {
-    pub fn adc(&mut self, memory: &mut CpuMemory, operand: Operand) {
-        let operand = operand.read(memory);
-        if !self.decimal_mode {
-            let res = operand.overflowing_add(self.accumulator);
-            let carry_in_res = res.0.overflowing_add(self.carry as u8);
-            self.carry = res.1 | carry_in_res.1;
-            self.accumulator = carry_in_res.0;
-        } else {
-            let a_low = self.accumulator & 0b00001111;
-            let a_high = (self.accumulator & 0b11110000) >> 4;
-            let op_low = operand & 0b00001111;
-            let op_high = (operand & 0b11110000) >> 4;
-
-            let res_lower = a_low + op_low + self.carry as u8;
-            let carry = res_lower > 9;
-            let res_lower = res_lower % 10;
-
-            let res_upper = a_high + op_high + carry as u8;
-            self.carry = res_upper > 9;
-            let res_upper = res_upper % 10;
-
-            self.accumulator = (res_upper << 4) | res_lower;
-        }
+    pub(super) fn set_zero_sign(&mut self, value: u8) {
+        self.zero = value == 0;
+        self.sign = value & 0x80 != 0;
+    }
+
+    pub(super) fn lda(&mut self, value: u8) {
+        self.accumulator = value;
+        self.set_zero_sign(self.accumulator);
+    }
+
+    pub(super) fn ldx(&mut self, value: u8) {
+        self.index_x = value;
+        self.set_zero_sign(self.index_x);
+    }
+
+    pub(super) fn ldy(&mut self, value: u8) {
+        self.index_y = value;
+        self.set_zero_sign(self.index_y);
+    }
+
+    pub(super) fn tax(&mut self) {
+        self.index_x = self.accumulator;
+        self.set_zero_sign(self.index_x);
+    }
+
+    pub(super) fn tay(&mut self) {
+        self.index_y = self.accumulator;
+        self.set_zero_sign(self.index_y);
+    }
+
+    pub(super) fn txa(&mut self) {
+        self.accumulator = self.index_x;
+        self.set_zero_sign(self.accumulator);
+    }
+
+    pub(super) fn tya(&mut self) {
+        self.accumulator = self.index_y;
+        self.set_zero_sign(self.accumulator);
+    }
+
+    pub(super) fn tsx(&mut self) {
+        self.index_x = self.stack_pointer;
+        self.set_zero_sign(self.index_x);
+    }
+
+    // The only transfer that leaves the flags alone
+    pub(super) fn txs(&mut self) {
+        self.stack_pointer = self.index_x;
+    }
+
+    // The pushed copy always has the B flag set
+    pub(super) fn php(&self) -> u8 {
+        self.status() | 0b00010000
+    }
+
+    pub(super) fn pla(&mut self, value: u8) {
+        self.accumulator = value;
+        self.set_zero_sign(self.accumulator);
+    }
+
+    // RTI pulls the status the same way
+    pub(super) fn plp(&mut self, value: u8) {
+        self.set_status(value & !0b00010000);
+    }
+
+    pub(super) fn and(&mut self, value: u8) {
+        self.accumulator &= value;
+        self.set_zero_sign(self.accumulator);
+    }
+
+    pub(super) fn eor(&mut self, value: u8) {
+        self.accumulator ^= value;
+        self.set_zero_sign(self.accumulator);
+    }
+
+    pub(super) fn ora(&mut self, value: u8) {
+        self.accumulator |= value;
+        self.set_zero_sign(self.accumulator);
+    }
+
+    // Z from A AND the operand, N and V straight from bits 7 and 6 of the operand
+    pub(super) fn bit(&mut self, value: u8) {
+        self.zero = self.accumulator & value == 0;
+        self.overflow = value & 0b01000000 != 0;
+        self.sign = value & 0b10000000 != 0;
     }
 
-    pub fn and(&mut self, memory: &mut CpuMemory, operand: Operand) {
-        let operand = operand.read(memory);
-        self.accumulator = self.accumulator & operand;
+    // The 2A03 has the decimal mode circuitry cut, so the D flag is ignored
+    pub(super) fn adc(&mut self, value: u8) {
+        self.add_with_carry(value);
     }
 
-    pub fn asl(&mut self) {
-        self.accumulator <<= 1;
+    // Subtracting is adding the one's complement, with carry as "not borrow"
+    pub(super) fn sbc(&mut self, value: u8) {
+        self.add_with_carry(!value);
+    }
+
+    pub(super) fn add_with_carry(&mut self, operand: u8) {
+        let sum = self.accumulator as u16 + operand as u16 + self.carry as u16;
+        let result = sum as u8;
+        self.carry = sum > 0xFF;
+        // Signed overflow happens when both inputs share a sign the result doesn't
+        self.overflow = (self.accumulator ^ result) & (operand ^ result) & 0x80 != 0;
+        self.accumulator = result;
+        self.set_zero_sign(result);
+    }
+
+    pub(super) fn cmp(&mut self, value: u8) {
+        self.compare(self.accumulator, value);
+    }
+
+    pub(super) fn cpx(&mut self, value: u8) {
+        self.compare(self.index_x, value);
+    }
+
+    pub(super) fn cpy(&mut self, value: u8) {
+        self.compare(self.index_y, value);
+    }
+
+    pub(super) fn compare(&mut self, register: u8, operand: u8) {
+        self.carry = register >= operand;
+        self.set_zero_sign(register.wrapping_sub(operand));
+    }
+
+    pub(super) fn inc(&mut self, value: u8) -> u8 {
+        let value = value.wrapping_add(1);
+        self.set_zero_sign(value);
+        value
+    }
+
+    pub(super) fn inx(&mut self) {
+        self.index_x = self.index_x.wrapping_add(1);
+        self.set_zero_sign(self.index_x);
+    }
+
+    pub(super) fn iny(&mut self) {
+        self.index_y = self.index_y.wrapping_add(1);
+        self.set_zero_sign(self.index_y);
+    }
+
+    pub(super) fn dec(&mut self, value: u8) -> u8 {
+        let value = value.wrapping_sub(1);
+        self.set_zero_sign(value);
+        value
+    }
+
+    pub(super) fn dex(&mut self) {
+        self.index_x = self.index_x.wrapping_sub(1);
+        self.set_zero_sign(self.index_x);
+    }
+
+    pub(super) fn dey(&mut self) {
+        self.index_y = self.index_y.wrapping_sub(1);
+        self.set_zero_sign(self.index_y);
+    }
+
+    pub(super) fn asl(&mut self, value: u8) -> u8 {
+        self.carry = value & 0x80 != 0;
+        let value = value << 1;
+        self.set_zero_sign(value);
+        value
+    }
+
+    pub(super) fn lsr(&mut self, value: u8) -> u8 {
+        self.carry = value & 0x01 != 0;
+        let value = value >> 1;
+        self.set_zero_sign(value);
+        value
+    }
+
+    pub(super) fn rol(&mut self, value: u8) -> u8 {
+        let carry_in = self.carry as u8;
+        self.carry = value & 0x80 != 0;
+        let value = (value << 1) | carry_in;
+        self.set_zero_sign(value);
+        value
+    }
+
+    pub(super) fn ror(&mut self, value: u8) -> u8 {
+        let carry_in = self.carry as u8;
+        self.carry = value & 0x01 != 0;
+        let value = (value >> 1) | (carry_in << 7);
+        self.set_zero_sign(value);
+        value
+    }
+
+    pub(super) fn branch_taken(&self, instruction: Instruction) -> bool {
+        match instruction {
+            Instruction::BCC => !self.carry,
+            Instruction::BCS => self.carry,
+            Instruction::BEQ => self.zero,
+            Instruction::BMI => self.sign,
+            Instruction::BNE => !self.zero,
+            Instruction::BPL => !self.sign,
+            Instruction::BVC => !self.overflow,
+            Instruction::BVS => self.overflow,
+            _ => false,
+        }
     }
 }
@@ -0,0 +1,606 @@
+// Instructions and interrupts broken into the cycles they take on the chip.
+// Every cycle reads or writes the bus exactly once, in the order the 6502
+// does, including the dummy reads it makes while it works out an address
+// and the unmodified value read-modify-write instructions write back first.
+// https://www.nesdev.org/6502_cpu.txt
+
+use crate::bus::Bus;
+
+use super::addressingmodes::{AddressingMode, ADDRESSING_MODES};
+use super::instruction_table::{Instruction, INSTRUCTIONS};
+use super::{Sequence, IRQ_VECTOR, NMI_VECTOR, RESET_VECTOR};
+
+// What an instruction does with the memory its addressing mode points at
+#[derive(Clone, Copy, PartialEq)]
+enum Access {
+    Read,
+    Write,
+    ReadModifyWrite,
+}
+
+impl Instruction {
+    fn access(self) -> Access {
+        if self.rwr() {
+            Access::ReadModifyWrite
+        } else if self.stores() {
+            Access::Write
+        } else {
+            Access::Read
+        }
+    }
+}
+
+impl super::Mos6502 {
+    // Runs the next cycle of whatever is in progress, or starts something
+    // new between instructions. None if DMA took the cycle instead, which
+    // leaves everything as it was so the same cycle runs again next time.
+    pub(super) fn run_cycle(&mut self, bus: &mut dyn Bus) -> Option<()> {
+        // The chip polls for interrupts at the end of each cycle, and what
+        // it found at the end of the second-to-last one decides what comes
+        // after. Polling going into each cycle leaves that in place once the
+        // last one has run.
+        if self.sequence.is_some() {
+            self.polled_interrupt = self.poll_interrupts(bus);
+        }
+        let done = match self.sequence {
+            None => return self.begin(bus),
+            Some(Sequence::Instruction) => self.instruction_cycle(bus)?,
+            Some(Sequence::Nmi) => self.interrupt_cycle(bus, NMI_VECTOR, false)?,
+            Some(Sequence::Irq) => self.interrupt_cycle(bus, IRQ_VECTOR, false)?,
+            Some(Sequence::Reset) => self.reset_cycle(bus)?,
+        };
+        if done {
+            self.sequence = None;
+        } else {
+            self.cycle += 1;
+        }
+        Some(())
+    }
+
+    // The opcode fetch. An interrupt the last instruction polled turns it
+    // into a dummy read.
+    fn begin(&mut self, bus: &mut dyn Bus) -> Option<()> {
+        let opcode = self.read(bus, self.program_counter)?;
+        // Jammed, it spins on the same read
+        if self.jammed {
+            return Some(());
+        }
+        self.cycle = 1;
+        match self.polled_interrupt.take() {
+            Some(Sequence::Nmi) => {
+                self.nmi_pending = false;
+                self.sequence = Some(Sequence::Nmi);
+            }
+            Some(Sequence::Irq) => self.sequence = Some(Sequence::Irq),
+            _ => {
+                self.opcode = opcode;
+                self.page_crossed = false;
+                self.program_counter = self.program_counter.wrapping_add(1);
+                self.sequence = Some(Sequence::Instruction);
+            }
+        }
+        Some(())
+    }
+
+    // NMI takes priority
+    fn poll_interrupts(&self, bus: &dyn Bus) -> Option<Sequence> {
+        if self.nmi_pending {
+            Some(Sequence::Nmi)
+        } else if self.irq_asserted(bus) {
+            Some(Sequence::Irq)
+        } else {
+            None
+        }
+    }
+
+    // The rest of NMI, IRQ and BRK once the opcode is fetched. Hardware
+    // interrupts push the status with B clear, which is how a handler shared
+    // with BRK tells them apart.
+    fn interrupt_cycle(&mut self, bus: &mut dyn Bus, vector: u16, brk: bool) -> Option<bool> {
+        match self.cycle {
+            // BRK is two bytes long, the second being padding that RTI
+            // returns past
+            1 if brk => {
+                self.fetch(bus)?;
+            }
+            1 => self.dummy_read(bus)?,
+            2 => self.push(bus, (self.program_counter >> 8) as u8),
+            3 => self.push(bus, self.program_counter as u8),
+            4 if brk => self.push(bus, self.php()),
+            4 => self.push(bus, self.status() & !0b00010000),
+            5 => {
+                self.address = self.read(bus, vector)? as u16;
+                self.interrupt_disable = true;
+            }
+            _ => {
+                let high = self.read(bus, vector.wrapping_add(1))?;
+                self.program_counter = self.address | (high as u16) << 8;
+                return Some(true);
+            }
+        }
+        Some(false)
+    }
+
+    // reset has already moved the stack pointer and set I. These are the
+    // sequence's bus cycles, where the three pushes are reads because writes
+    // are held off.
+    fn reset_cycle(&mut self, bus: &mut dyn Bus) -> Option<bool> {
+        match self.cycle {
+            0 | 1 => self.dummy_read(bus)?,
+            2..=4 => {
+                let pushed_at = self.stack_pointer.wrapping_add(5 - self.cycle);
+                self.read(bus, 0x0100 | pushed_at as u16)?;
+            }
+            5 => self.address = self.read(bus, RESET_VECTOR)? as u16,
+            _ => {
+                let high = self.read(bus, RESET_VECTOR + 1)?;
+                self.program_counter = self.address | (high as u16) << 8;
+                return Some(true);
+            }
+        }
+        Some(false)
+    }
+
+    fn instruction_cycle(&mut self, bus: &mut dyn Bus) -> Option<bool> {
+        let instruction = INSTRUCTIONS[self.opcode as usize];
+        let mode = ADDRESSING_MODES[self.opcode as usize];
+        match instruction {
+            Instruction::BRK => self.interrupt_cycle(bus, IRQ_VECTOR, true),
+            Instruction::RTI => self.rti_cycle(bus),
+            Instruction::RTS => self.rts_cycle(bus),
+            Instruction::JSR => self.jsr_cycle(bus),
+            Instruction::PHA | Instruction::PHP => self.push_cycle(bus, instruction),
+            Instruction::PLA | Instruction::PLP => self.pull_cycle(bus, instruction),
+            Instruction::JMP => self.jump_cycle(bus, mode),
+            Instruction::BCC
+            | Instruction::BCS
+            | Instruction::BEQ
+            | Instruction::BMI
+            | Instruction::BNE
+            | Instruction::BPL
+            | Instruction::BVC
+            | Instruction::BVS => self.branch_cycle(bus, instruction),
+            Instruction::STP if !self.skipped(instruction) => {
+                self.dummy_read(bus)?;
+                self.stp();
+                Some(true)
+            }
+            _ => self.operand_cycle(bus, mode, instruction),
+        }
+    }
+
+    fn rti_cycle(&mut self, bus: &mut dyn Bus) -> Option<bool> {
+        match self.cycle {
+            1 => self.dummy_read(bus)?,
+            2 => self.stack_read(bus)?,
+            3 => {
+                let status = self.pull(bus)?;
+                self.plp(status);
+            }
+            4 => self.address = self.pull(bus)? as u16,
+            _ => {
+                let high = self.pull(bus)?;
+                self.program_counter = self.address | (high as u16) << 8;
+                return Some(true);
+            }
+        }
+        Some(false)
+    }
+
+    // The address pulled is the last byte of the JSR, so it's stepped past
+    fn rts_cycle(&mut self, bus: &mut dyn Bus) -> Option<bool> {
+        match self.cycle {
+            1 => self.dummy_read(bus)?,
+            2 => self.stack_read(bus)?,
+            3 => self.address = self.pull(bus)? as u16,
+            4 => {
+                let high = self.pull(bus)?;
+                self.program_counter = self.address | (high as u16) << 8;
+            }
+            _ => {
+                self.fetch(bus)?;
+                return Some(true);
+            }
+        }
+        Some(false)
+    }
+
+    // Pushes the address of its own last byte, since the high byte of the
+    // target is only fetched after the pushes
+    fn jsr_cycle(&mut self, bus: &mut dyn Bus) -> Option<bool> {
+        match self.cycle {
+            1 => self.address = self.fetch(bus)? as u16,
+            2 => self.stack_read(bus)?,
+            3 => self.push(bus, (self.program_counter >> 8) as u8),
+            4 => self.push(bus, self.program_counter as u8),
+            _ => {
+                let high = self.read(bus, self.program_counter)?;
+                self.program_counter = self.address | (high as u16) << 8;
+                return Some(true);
+            }
+        }
+        Some(false)
+    }
+
+    fn push_cycle(&mut self, bus: &mut dyn Bus, instruction: Instruction) -> Option<bool> {
+        if self.cycle == 1 {
+            self.dummy_read(bus)?;
+            return Some(false);
+        }
+        let value = match instruction {
+            Instruction::PHP => self.php(),
+            _ => self.accumulator,
+        };
+        self.push(bus, value);
+        Some(true)
+    }
+
+    fn pull_cycle(&mut self, bus: &mut dyn Bus, instruction: Instruction) -> Option<bool> {
+        match self.cycle {
+            1 => self.dummy_read(bus)?,
+            2 => self.stack_read(bus)?,
+            _ => {
+                let value = self.pull(bus)?;
+                match instruction {
+                    Instruction::PLP => self.plp(value),
+                    _ => self.pla(value),
+                }
+                return Some(true);
+            }
+        }
+        Some(false)
+    }
+
+    // The indirect pointer's high byte is fetched without carrying into the
+    // page, so JMP ($10FF) reads from $10FF and $1000
+    fn jump_cycle(&mut self, bus: &mut dyn Bus, mode: AddressingMode) -> Option<bool> {
+        let indirect = matches!(mode, AddressingMode::AbsoluteIndirect);
+        match self.cycle {
+            1 => self.address = self.fetch(bus)? as u16,
+            2 if indirect => {
+                let high = self.fetch(bus)?;
+                self.address |= (high as u16) << 8;
+            }
+            2 => {
+                let high = self.read(bus, self.program_counter)?;
+                self.program_counter = self.address | (high as u16) << 8;
+                return Some(true);
+            }
+            3 => self.data = self.read(bus, self.address)?,
+            _ => {
+                let high_at = (self.address & 0xFF00) | (self.address.wrapping_add(1) & 0x00FF);
+                let high = self.read(bus, high_at)?;
+                self.program_counter = u16::from_le_bytes([self.data, high]);
+                return Some(true);
+            }
+        }
+        Some(false)
+    }
+
+    // Taken branches take one more cycle, and another to fix up the high
+    // byte if they land on a different page
+    fn branch_cycle(&mut self, bus: &mut dyn Bus, instruction: Instruction) -> Option<bool> {
+        match self.cycle {
+            1 => {
+                self.data = self.fetch(bus)?;
+                Some(!self.branch_taken(instruction))
+            }
+            2 => {
+                self.dummy_read(bus)?;
+                let pc = self.program_counter;
+                self.address = pc.wrapping_add(self.data as i8 as u16);
+                self.page_crossed = self.address & 0xFF00 != pc & 0xFF00;
+                self.program_counter = (pc & 0xFF00) | (self.address & 0x00FF);
+                Some(!self.page_crossed)
+            }
+            _ => {
+                self.dummy_read(bus)?;
+                self.program_counter = self.address;
+                Some(true)
+            }
+        }
+    }
+
+    // Everything else. The addressing mode works out an address over the
+    // first few cycles, then the instruction reads it, writes it, or reads
+    // it and writes it back changed.
+    fn operand_cycle(
+        &mut self,
+        bus: &mut dyn Bus,
+        mode: AddressingMode,
+        instruction: Instruction,
+    ) -> Option<bool> {
+        // Indexed reads only take the extra cycle when the high byte of the
+        // address has to be fixed up; writes always take it
+        let fix_up = self.page_crossed || instruction.access() != Access::Read;
+        let first_access = match mode {
+            AddressingMode::Accumulator | AddressingMode::Implied => {
+                self.dummy_read(bus)?;
+                if !self.skipped(instruction) {
+                    self.execute_implied(instruction);
+                }
+                return Some(true);
+            }
+            AddressingMode::Immediate => {
+                let value = self.fetch(bus)?;
+                if !self.skipped(instruction) {
+                    self.execute_read(instruction, mode, value);
+                }
+                return Some(true);
+            }
+            AddressingMode::ZeroPage => 2,
+            AddressingMode::ZeroPageX | AddressingMode::ZeroPageY | AddressingMode::Absolute => 3,
+            AddressingMode::AbsoluteX | AddressingMode::AbsoluteY => 3 + fix_up as u8,
+            AddressingMode::ZeroPageIndirectIndexedY => 4 + fix_up as u8,
+            // JMP and the branches have their own cycles, so of these only
+            // (zp,X) gets here
+            AddressingMode::ZeroPageIndexedIndirectX
+            | AddressingMode::AbsoluteIndirect
+            | AddressingMode::Relative => 5,
+        };
+        if self.cycle < first_access {
+            self.address_cycle(bus, mode)?;
+            return Some(false);
+        }
+        self.access_cycle(bus, instruction, mode, self.cycle - first_access)
+    }
+
+    fn address_cycle(&mut self, bus: &mut dyn Bus, mode: AddressingMode) -> Option<()> {
+        match (mode, self.cycle) {
+            (
+                AddressingMode::ZeroPageIndexedIndirectX | AddressingMode::ZeroPageIndirectIndexedY,
+                1,
+            ) => {
+                self.pointer = self.fetch(bus)?;
+            }
+            (_, 1) => self.address = self.fetch(bus)? as u16,
+            (
+                AddressingMode::Absolute | AddressingMode::AbsoluteX | AddressingMode::AbsoluteY,
+                2,
+            ) => {
+                let high = self.fetch(bus)?;
+                self.address |= (high as u16) << 8;
+                match mode {
+                    AddressingMode::AbsoluteX => self.add_index(self.index_x),
+                    AddressingMode::AbsoluteY => self.add_index(self.index_y),
+                    _ => (),
+                }
+            }
+            // Zero page indexing reads from the address before adding to it,
+            // and stays in the zero page
+            (AddressingMode::ZeroPageX | AddressingMode::ZeroPageY, _) => {
+                self.read(bus, self.address)?;
+                let index = match mode {
+                    AddressingMode::ZeroPageX => self.index_x,
+                    _ => self.index_y,
+                };
+                self.address = (self.address as u8).wrapping_add(index) as u16;
+            }
+            (AddressingMode::ZeroPageIndexedIndirectX, 2) => {
+                self.read(bus, self.pointer as u16)?;
+                self.pointer = self.pointer.wrapping_add(self.index_x);
+            }
+            (AddressingMode::ZeroPageIndexedIndirectX, 3)
+            | (AddressingMode::ZeroPageIndirectIndexedY, 2) => {
+                self.address = self.read(bus, self.pointer as u16)? as u16;
+            }
+            // Pointers wrap around within the zero page
+            (AddressingMode::ZeroPageIndexedIndirectX, _)
+            | (AddressingMode::ZeroPageIndirectIndexedY, 3) => {
+                let high = self.read(bus, self.pointer.wrapping_add(1) as u16)?;
+                self.address |= (high as u16) << 8;
+                if matches!(mode, AddressingMode::ZeroPageIndirectIndexedY) {
+                    self.add_index(self.index_y);
+                }
+            }
+            // The high byte of an indexed address is fixed up a cycle late,
+            // after reading from where it points with the high byte wrong
+            _ => {
+                self.read(bus, self.address)?;
+                if self.page_crossed {
+                    self.address = self.address.wrapping_add(0x0100);
+                }
+            }
+        }
+        Some(())
+    }
+
+    // Indexing adds to the low byte first and carries into the high byte on
+    // a later cycle, if it needs to
+    fn add_index(&mut self, index: u8) {
+        let indexed = self.address.wrapping_add(index as u16);
+        self.page_crossed = indexed & 0xFF00 != self.address & 0xFF00;
+        self.address = (self.address & 0xFF00) | (indexed & 0x00FF);
+    }
+
+    // step counts the cycles since the address was worked out
+    fn access_cycle(
+        &mut self,
+        bus: &mut dyn Bus,
+        instruction: Instruction,
+        mode: AddressingMode,
+        step: u8,
+    ) -> Option<bool> {
+        let access = instruction.access();
+        let last_step = match access {
+            Access::ReadModifyWrite => 2,
+            _ => 0,
+        };
+        // A NOP in this mode reads where the instruction would have written
+        if self.skipped(instruction) {
+            self.read(bus, self.address)?;
+            return Some(step >= last_step);
+        }
+        match (access, step) {
+            (Access::Read, _) => {
+                let value = self.read(bus, self.address)?;
+                self.execute_read(instruction, mode, value);
+            }
+            (Access::Write, _) => {
+                let (address, value) = self.execute_store(instruction);
+                bus.write(address, value);
+            }
+            (Access::ReadModifyWrite, 0) => self.data = self.read(bus, self.address)?,
+            // The value read goes back unchanged while it's being modified
+            (Access::ReadModifyWrite, 1) => bus.write(self.address, self.data),
+            (Access::ReadModifyWrite, _) => {
+                let value = self.execute_modify(instruction, self.data);
+                bus.write(self.address, value);
+            }
+        }
+        Some(step >= last_step)
+    }
+
+    // With illegal opcodes turned off, the unofficial ones run as NOPs of
+    // the same length. The unofficial NOPs and the SBC at $EB share their
+    // names with official instructions and run as them either way.
+    fn skipped(&self, instruction: Instruction) -> bool {
+        !self.enable_illegal_ops
+            && matches!(
+                instruction,
+                Instruction::SLO
+                    | Instruction::RLA
+                    | Instruction::SRE
+                    | Instruction::RRA
+                    | Instruction::DCP
+                    | Instruction::ISC
+                    | Instruction::SAX
+                    | Instruction::LAX
+                    | Instruction::ANC
+                    | Instruction::ALR
+                    | Instruction::ARR
+                    | Instruction::XAA
+                    | Instruction::LAS
+                    | Instruction::AXS
+                    | Instruction::AHX
+                    | Instruction::SHX
+                    | Instruction::SHY
+                    | Instruction::TAS
+                    | Instruction::STP
+            )
+    }
+
+    fn execute_implied(&mut self, instruction: Instruction) {
+        match instruction {
+            // Register transfers
+            Instruction::TAX => self.tax(),
+            Instruction::TAY => self.tay(),
+            Instruction::TXA => self.txa(),
+            Instruction::TYA => self.tya(),
+            Instruction::TSX => self.tsx(),
+            Instruction::TXS => self.txs(),
+            // Increments and decrements
+            Instruction::INX => self.inx(),
+            Instruction::INY => self.iny(),
+            Instruction::DEX => self.dex(),
+            Instruction::DEY => self.dey(),
+            // Shifts of the accumulator
+            Instruction::ASL => self.accumulator = self.asl(self.accumulator),
+            Instruction::LSR => self.accumulator = self.lsr(self.accumulator),
+            Instruction::ROL => self.accumulator = self.rol(self.accumulator),
+            Instruction::ROR => self.accumulator = self.ror(self.accumulator),
+            // Status flags
+            Instruction::CLC => self.carry = false,
+            Instruction::CLD => self.decimal_mode = false,
+            Instruction::CLI => self.interrupt_disable = false,
+            Instruction::CLV => self.overflow = false,
+            Instruction::SEC => self.carry = true,
+            Instruction::SED => self.decimal_mode = true,
+            Instruction::SEI => self.interrupt_disable = true,
+            // NOP, official or not
+            _ => (),
+        }
+    }
+
+    fn execute_read(&mut self, instruction: Instruction, mode: AddressingMode, value: u8) {
+        match instruction {
+            Instruction::LDA => self.lda(value),
+            Instruction::LDX => self.ldx(value),
+            Instruction::LDY => self.ldy(value),
+            Instruction::AND => self.and(value),
+            Instruction::EOR => self.eor(value),
+            Instruction::ORA => self.ora(value),
+            Instruction::BIT => self.bit(value),
+            Instruction::ADC => self.adc(value),
+            Instruction::SBC => self.sbc(value),
+            Instruction::CMP => self.cmp(value),
+            Instruction::CPX => self.cpx(value),
+            Instruction::CPY => self.cpy(value),
+            // Unofficial
+            Instruction::LAX if matches!(mode, AddressingMode::Immediate) => self.lxa(value),
+            Instruction::LAX => self.lax(value),
+            Instruction::ANC => self.anc(value),
+            Instruction::ALR => self.alr(value),
+            Instruction::ARR => self.arr(value),
+            Instruction::XAA => self.xaa(value),
+            Instruction::LAS => self.las(value),
+            Instruction::AXS => self.axs(value),
+            // The NOPs that read, which are all unofficial
+            _ => (),
+        }
+    }
+
+    // Where the store goes, which only the unofficial stores change, and
+    // what's stored
+    fn execute_store(&mut self, instruction: Instruction) -> (u16, u8) {
+        match instruction {
+            Instruction::STX => (self.address, self.index_x),
+            Instruction::STY => (self.address, self.index_y),
+            Instruction::SAX => (self.address, self.sax()),
+            Instruction::AHX => self.ahx(),
+            Instruction::SHX => self.shx(),
+            Instruction::SHY => self.shy(),
+            Instruction::TAS => self.tas(),
+            // STA, the only store left
+            _ => (self.address, self.accumulator),
+        }
+    }
+
+    fn execute_modify(&mut self, instruction: Instruction, value: u8) -> u8 {
+        match instruction {
+            Instruction::ASL => self.asl(value),
+            Instruction::LSR => self.lsr(value),
+            Instruction::ROL => self.rol(value),
+            Instruction::ROR => self.ror(value),
+            Instruction::INC => self.inc(value),
+            Instruction::DEC => self.dec(value),
+            // Unofficial
+            Instruction::SLO => self.slo(value),
+            Instruction::RLA => self.rla(value),
+            Instruction::SRE => self.sre(value),
+            Instruction::RRA => self.rra(value),
+            Instruction::DCP => self.dcp(value),
+            // ISC, the only read-modify-write instruction left
+            _ => self.isc(value),
+        }
+    }
+
+    // The next byte of code, stepping past it
+    fn fetch(&mut self, bus: &mut dyn Bus) -> Option<u8> {
+        let value = self.read(bus, self.program_counter)?;
+        self.program_counter = self.program_counter.wrapping_add(1);
+        Some(value)
+    }
+
+    // What cycles with nothing to fetch read, without stepping past it
+    fn dummy_read(&mut self, bus: &mut dyn Bus) -> Option<()> {
+        self.read(bus, self.program_counter).map(drop)
+    }
+
+    // The stack lives in page 1 and grows downwards
+    fn push(&mut self, bus: &mut dyn Bus, value: u8) {
+        bus.write(0x0100 | self.stack_pointer as u16, value);
+        self.stack_pointer = self.stack_pointer.wrapping_sub(1);
+    }
+
+    fn pull(&mut self, bus: &mut dyn Bus) -> Option<u8> {
+        let value = self.read(bus, 0x0100 | self.stack_pointer.wrapping_add(1) as u16)?;
+        self.stack_pointer = self.stack_pointer.wrapping_add(1);
+        Some(value)
+    }
+
+    // Pulls start with a read from the top of the stack before moving up
+    fn stack_read(&mut self, bus: &mut dyn Bus) -> Option<()> {
+        self.read(bus, 0x0100 | self.stack_pointer as u16).map(drop)
+    }
+}
@@ -1,16 +1,24 @@
 mod addressingmodes;
+pub mod disasm;
+mod illegal_instructions;
 mod instruction_table;
 mod instructions;
+mod micro_ops;
 mod timing;
+mod trace;
 
-use core::panic;
+use std::io;
 
-use addressingmodes::{AddressingMode, ADDRESSING_MODES};
-use instruction_table::{Instruction, INSTRUCTIONS};
+use crate::bus::Bus;
+use crate::state::{StateError, StateReader, StateWriter};
 
-use crate::cpu_memory::CpuMemory;
+// Vectors at the top of memory, each a little-endian address
+const NMI_VECTOR: u16 = 0xFFFA;
+const RESET_VECTOR: u16 = 0xFFFC;
+// Shared by IRQs and BRK
+const IRQ_VECTOR: u16 = 0xFFFE;
 
-struct Mos6502 {
+pub struct Mos6502 {
     program_counter: u16,
     accumulator: u8,
     index_x: u8,
@@ -18,129 +26,789 @@ struct Mos6502 {
     stack_pointer: u8,
     carry: bool,
     zero: bool,
-    interrupt_enable: bool,
+    interrupt_disable: bool,
     decimal_mode: bool,
-    interrupt: bool,
+    // Only exists in the copy of the status register pushed by BRK and PHP
+    break_command: bool,
     overflow: bool,
     sign: bool,
+    // NMI is edge triggered, so an edge is remembered until it's serviced.
+    // IRQ is level triggered and is serviced for as long as the line is held.
+    nmi_pending: bool,
+    irq_line: bool,
+    // Level the bus last showed on the NMI line, to catch it going high
+    nmi_line: bool,
+    // The interrupt to run in place of the next instruction. It's polled
+    // before the last cycle of each instruction, so one that changes I only
+    // has an effect after the instruction that follows it.
+    polled_interrupt: Option<Sequence>,
+    // Runs the unofficial opcodes as NOPs of the same length when off
+    pub enable_illegal_ops: bool,
+    // Set by STP, which only a reset gets out of
+    jammed: bool,
+    // The instruction or interrupt in progress and how many of its cycles
+    // have run, or None between instructions
+    sequence: Option<Sequence>,
+    cycle: u8,
+    // Held between the cycles of an instruction: its opcode, the address it
+    // has worked out so far, a zero page pointer, a value read on one cycle
+    // for use on a later one, and whether indexing still has to carry into
+    // the high byte of the address
+    opcode: u8,
+    address: u16,
+    pointer: u8,
+    data: u8,
+    page_crossed: bool,
+    // DMA waiting for a read cycle to halt the CPU on, or under way
+    oam_dma: Option<OamDma>,
+    stall_cycles: u16,
+    // Since power-on
+    total_cycles: u64,
+}
+
+impl Default for Mos6502 {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Mos6502 {
-    // Returns the number of cycles this instruction took
-    fn run_instruction(&mut self, memory: &mut CpuMemory) -> usize {
-        let opcode = memory.read(self.program_counter);
-        let addressing_mode: addressingmodes::AddressingMode = ADDRESSING_MODES[opcode as usize];
-        let instruction = INSTRUCTIONS[opcode as usize];
-        let (operand, crossed_page) = self.get_operand(memory, addressing_mode);
-        match instruction {
-            Instruction::ADC => self.adc(memory, operand),
-            _ => todo!(),
-        }
-        0
-    }
-
-    fn get_operand(&self, memory: &CpuMemory, mode: AddressingMode) -> (Operand, bool) {
-        match mode {
-            AddressingMode::Absolute | AddressingMode::AbsoluteIndirect => {
-                let low = memory.read(self.program_counter + 1);
-                let high = memory.read(self.program_counter + 2);
-                let address = u16::from_be_bytes([high, low]);
-                (Operand::Address(address), false)
-            }
-            AddressingMode::AbsoluteX => {
-                let low = memory.read(self.program_counter + 1);
-                let high = memory.read(self.program_counter + 2);
-                let address = u16::from_be_bytes([high, low]) + self.index_x as u16;
-                let page_crossed = self.program_counter & 0xFF00 != address & 0xFF00;
-                (Operand::Address(address), page_crossed)
-            }
-            AddressingMode::AbsoluteY => {
-                let low = memory.read(self.program_counter + 1);
-                let high = memory.read(self.program_counter + 2);
-                let address = u16::from_be_bytes([high, low]) + self.index_y as u16;
-                let page_crossed = self.program_counter & 0xFF00 != address & 0xFF00;
-                (Operand::Address(address), page_crossed)
-            }
-            AddressingMode::ZeroPage => {
-                let address = memory.read(self.program_counter + 1);
-                (Operand::Address(address as u16), false)
-            }
-            AddressingMode::ZeroPageIndexedIndirectX => {
-                let address: u8 = memory
-                    .read(self.program_counter + 1)
-                    .wrapping_add(self.index_x);
-                let low = memory.read(address as u16);
-                let high = memory.read(address.wrapping_add(1) as u16);
-                let address = u16::from_be_bytes([high, low]);
-                (Operand::Address(address), false)
-            }
-            AddressingMode::ZeroPageX => {
-                let address: u8 = memory
-                    .read(self.program_counter + 1)
-                    .wrapping_add(self.index_x);
-                (Operand::Address(address as u16), false)
-            }
-            AddressingMode::ZeroPageY => {
-                let address: u8 = memory
-                    .read(self.program_counter + 1)
-                    .wrapping_add(self.index_y);
-                (Operand::Address(address as u16), false)
-            }
-            AddressingMode::ZeroPageIndirectIndexedY => {
-                let address: u8 = memory.read(self.program_counter);
-                let low = memory.read(address as u16);
-                let high = memory.read(address.wrapping_add(1) as u16);
-                let address = u16::from_be_bytes([high, low]).wrapping_add(self.index_y as u16);
-                (Operand::Address(address), false)
-            }
-            AddressingMode::Immediate => {
-                let immediate: u8 = memory.read(self.program_counter + 1);
-                (Operand::Immediate(immediate), false)
+    // Power-on state, before the reset sequence has run.
+    // https://www.nesdev.org/wiki/CPU_power_up_state
+    pub fn new() -> Mos6502 {
+        Mos6502 {
+            program_counter: 0,
+            accumulator: 0,
+            index_x: 0,
+            index_y: 0,
+            stack_pointer: 0,
+            carry: false,
+            zero: false,
+            interrupt_disable: true,
+            decimal_mode: false,
+            break_command: false,
+            overflow: false,
+            sign: false,
+            nmi_pending: false,
+            irq_line: false,
+            nmi_line: false,
+            polled_interrupt: None,
+            enable_illegal_ops: true,
+            jammed: false,
+            sequence: None,
+            cycle: 0,
+            opcode: 0,
+            address: 0,
+            pointer: 0,
+            data: 0,
+            page_crossed: false,
+            oam_dma: None,
+            stall_cycles: 0,
+            total_cycles: 0,
+        }
+    }
+
+    // The reset sequence goes through the motions of an interrupt with writes
+    // suppressed, so the stack pointer drops by 3 without touching memory.
+    // Registers other than I are left alone. It runs on the 7 ticks that
+    // follow, the last two reading the vector into the program counter.
+    pub fn reset(&mut self) {
+        self.stack_pointer = self.stack_pointer.wrapping_sub(3);
+        self.interrupt_disable = true;
+        self.jammed = false;
+        self.polled_interrupt = None;
+        self.sequence = Some(Sequence::Reset);
+        self.cycle = 0;
+    }
+
+    // Called on the falling edge of the PPU's NMI output
+    pub fn trigger_nmi(&mut self) {
+        self.nmi_pending = true;
+    }
+
+    // For IRQ sources off the bus. Bus::irq_line is polled as well.
+    pub fn set_irq(&mut self, asserted: bool) {
+        self.irq_line = asserted;
+    }
+
+    pub fn program_counter(&self) -> u16 {
+        self.program_counter
+    }
+
+    pub fn accumulator(&self) -> u8 {
+        self.accumulator
+    }
+
+    pub fn index_x(&self) -> u8 {
+        self.index_x
+    }
+
+    pub fn index_y(&self) -> u8 {
+        self.index_y
+    }
+
+    pub fn stack_pointer(&self) -> u8 {
+        self.stack_pointer
+    }
+
+    pub fn carry(&self) -> bool {
+        self.carry
+    }
+
+    pub fn zero(&self) -> bool {
+        self.zero
+    }
+
+    pub fn interrupt_disable(&self) -> bool {
+        self.interrupt_disable
+    }
+
+    pub fn decimal_mode(&self) -> bool {
+        self.decimal_mode
+    }
+
+    pub fn break_command(&self) -> bool {
+        self.break_command
+    }
+
+    pub fn overflow(&self) -> bool {
+        self.overflow
+    }
+
+    pub fn negative(&self) -> bool {
+        self.sign
+    }
+
+    // 7  bit  0
+    // ---- ----
+    // NV1B DIZC
+    // Bit 5 isn't stored anywhere and always reads back as 1
+    pub fn status(&self) -> u8 {
+        (self.sign as u8) << 7
+            | (self.overflow as u8) << 6
+            | 1 << 5
+            | (self.break_command as u8) << 4
+            | (self.decimal_mode as u8) << 3
+            | (self.interrupt_disable as u8) << 2
+            | (self.zero as u8) << 1
+            | self.carry as u8
+    }
+
+    pub fn set_status(&mut self, status: u8) {
+        self.sign = status & 0b10000000 != 0;
+        self.overflow = status & 0b01000000 != 0;
+        self.break_command = status & 0b00010000 != 0;
+        self.decimal_mode = status & 0b00001000 != 0;
+        self.interrupt_disable = status & 0b00000100 != 0;
+        self.zero = status & 0b00000010 != 0;
+        self.carry = status & 0b00000001 != 0;
+    }
+
+    // Advances by a single clock cycle, which reads or writes the bus once.
+    // Each instruction runs as the cycles it takes on the chip, dummy reads
+    // and the extra write of read-modify-write instructions included, so a
+    // register access lands on the cycle it would on hardware.
+    pub fn tick(&mut self, bus: &mut dyn Bus) {
+        // Without a trace there's nothing to fail
+        let _ = self.clock(bus, None);
+    }
+
+    // The same as tick, first writing a line of trace to out if an
+    // instruction starts on this cycle
+    pub fn tick_traced(&mut self, bus: &mut dyn Bus, out: &mut dyn io::Write) -> io::Result<()> {
+        self.clock(bus, Some(out))
+    }
+
+    fn clock(&mut self, bus: &mut dyn Bus, trace: Option<&mut dyn io::Write>) -> io::Result<()> {
+        let nmi_line = bus.nmi_line();
+        if nmi_line && !self.nmi_line {
+            self.nmi_pending = true;
+        }
+        self.nmi_line = nmi_line;
+        self.stall_cycles += bus.take_stall_cycles();
+        let mut traced = Ok(());
+        if let Some(out) = trace {
+            if self.at_instruction_boundary() && !self.dma_waiting() && self.starts_instruction() {
+                traced = writeln!(out, "{}", self.trace(bus));
             }
-            AddressingMode::Relative => {
-                let low = memory.read(self.program_counter + 1);
-                let high = memory.read(self.program_counter + 2);
-                let offset = u16::from_be_bytes([high, low]);
-                (Operand::Offset(offset), false)
+        }
+        // A cycle DMA takes is run again once it's done
+        let _ = self.run_cycle(bus);
+        // Only a write on this cycle can have started one
+        if let Some(page) = bus.take_oam_dma() {
+            self.oam_dma = Some(OamDma::new(page));
+        }
+        self.total_cycles += 1;
+        traced
+    }
+
+    // Whether begin would run an instruction now rather than an interrupt
+    fn starts_instruction(&self) -> bool {
+        !self.jammed && self.polled_interrupt.is_none()
+    }
+
+    fn irq_asserted(&self, bus: &dyn Bus) -> bool {
+        (self.irq_line || bus.irq_line()) && !self.interrupt_disable
+    }
+
+    // Every bus read the CPU makes. DMA can only halt the CPU on a read, so
+    // one that's waiting takes the cycle here, and None tells the cycle to
+    // leave everything as it was.
+    fn read(&mut self, bus: &mut dyn Bus, address: u16) -> Option<u8> {
+        if self.dma_cycle(bus, address) {
+            return None;
+        }
+        Some(bus.read(address))
+    }
+
+    fn dma_waiting(&self) -> bool {
+        self.stall_cycles > 0 || self.oam_dma.is_some()
+    }
+
+    // Runs a cycle of DMA in place of a CPU read of address, if there's any
+    // waiting. The halted CPU keeps its read on the bus through the cycles
+    // DMA doesn't use the bus itself, so those repeat the read, which is how
+    // DMC fetches come to read the controller ports twice.
+    // https://www.nesdev.org/wiki/DMA
+    fn dma_cycle(&mut self, bus: &mut dyn Bus, address: u16) -> bool {
+        // DMC fetches win over OAM DMA. The bus has already made the fetch.
+        if self.stall_cycles > 0 {
+            self.stall_cycles -= 1;
+            bus.read(address);
+            return true;
+        }
+        let Some(dma) = &mut self.oam_dma else {
+            return false;
+        };
+        // Bytes are read on odd cycles and written on even ones. DMA takes
+        // 513 cycles from the halt, plus one to line up with a read cycle if
+        // it halted on an odd one.
+        let get_cycle = self.total_cycles % 2 == 1;
+        if !dma.halted {
+            dma.halted = true;
+            bus.read(address);
+        } else if let Some(value) = dma.value.take() {
+            bus.write(0x2004, value);
+            dma.offset += 1;
+            if dma.offset == 0x100 {
+                self.oam_dma = None;
             }
-            AddressingMode::Implied => (Operand::Implied, false),
-        }
-    }
-
-    fn move_program_counter(&mut self, mode: AddressingMode) -> usize {
-        let step = match mode {
-            AddressingMode::Absolute
-            | AddressingMode::AbsoluteIndirect
-            | AddressingMode::AbsoluteX
-            | AddressingMode::AbsoluteY => 3,
-            AddressingMode::ZeroPage
-            | AddressingMode::ZeroPageX
-            | AddressingMode::ZeroPageY
-            | AddressingMode::ZeroPageIndirectIndexedY
-            | AddressingMode::ZeroPageIndexedIndirectX
-            | AddressingMode::Immediate
-            | AddressingMode::Relative => 2,
-            AddressingMode::Implied => 1,
+        } else if get_cycle {
+            dma.value = Some(bus.read((dma.page as u16) << 8 | dma.offset));
+        } else {
+            bus.read(address);
+        }
+        true
+    }
+
+    // Runs to the end of the next instruction and returns the number of
+    // cycles it took. DMA waiting to halt the CPU ahead of it is run through
+    // first and isn't counted, though DMA that halts it partway through is.
+    // total_cycles covers everything.
+    pub fn step(&mut self, bus: &mut dyn Bus) -> u8 {
+        while !self.at_instruction_boundary() || self.dma_waiting() {
+            self.tick(bus);
+        }
+        let start = self.total_cycles;
+        self.tick(bus);
+        while !self.at_instruction_boundary() {
+            self.tick(bus);
+        }
+        (self.total_cycles - start) as u8
+    }
+
+    // Whether the last instruction has finished, so the next tick starts a
+    // new one unless DMA halts the CPU first
+    pub fn at_instruction_boundary(&self) -> bool {
+        self.sequence.is_none()
+    }
+
+    // Halts the CPU from its next read cycle, which is what DMA does while
+    // it has the bus
+    pub fn stall(&mut self, cycles: u16) {
+        self.stall_cycles += cycles;
+    }
+
+    pub fn total_cycles(&self) -> u64 {
+        self.total_cycles
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.program_counter);
+        state.u8(self.accumulator);
+        state.u8(self.index_x);
+        state.u8(self.index_y);
+        state.u8(self.stack_pointer);
+        state.bool(self.carry);
+        state.bool(self.zero);
+        state.bool(self.interrupt_disable);
+        state.bool(self.decimal_mode);
+        state.bool(self.break_command);
+        state.bool(self.overflow);
+        state.bool(self.sign);
+        state.bool(self.nmi_pending);
+        state.bool(self.irq_line);
+        state.bool(self.nmi_line);
+        state.bool(self.jammed);
+        state.u8(self.sequence.map_or(0, |sequence| sequence as u8 + 1));
+        state.u8(self
+            .polled_interrupt
+            .map_or(0, |sequence| sequence as u8 + 1));
+        state.u8(self.cycle);
+        state.u8(self.opcode);
+        state.u16(self.address);
+        state.u8(self.pointer);
+        state.u8(self.data);
+        state.bool(self.page_crossed);
+        state.bool(self.oam_dma.is_some());
+        if let Some(dma) = &self.oam_dma {
+            state.u8(dma.page);
+            state.u16(dma.offset);
+            state.option_u8(dma.value);
+            state.bool(dma.halted);
+        }
+        state.u16(self.stall_cycles);
+        state.u64(self.total_cycles);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.program_counter = state.u16()?;
+        self.accumulator = state.u8()?;
+        self.index_x = state.u8()?;
+        self.index_y = state.u8()?;
+        self.stack_pointer = state.u8()?;
+        self.carry = state.bool()?;
+        self.zero = state.bool()?;
+        self.interrupt_disable = state.bool()?;
+        self.decimal_mode = state.bool()?;
+        self.break_command = state.bool()?;
+        self.overflow = state.bool()?;
+        self.sign = state.bool()?;
+        self.nmi_pending = state.bool()?;
+        self.irq_line = state.bool()?;
+        self.nmi_line = state.bool()?;
+        self.jammed = state.bool()?;
+        self.sequence = match state.u8()? {
+            0 => None,
+            1 => Some(Sequence::Instruction),
+            2 => Some(Sequence::Nmi),
+            3 => Some(Sequence::Irq),
+            4 => Some(Sequence::Reset),
+            _ => return Err(StateError::InvalidValue("CPU sequence")),
         };
-        let prev_counter = self.program_counter;
-        self.program_counter = self.program_counter.wrapping_add(step);
-        (self.program_counter >> 8 != prev_counter >> 8) as usize
+        self.polled_interrupt = match state.u8()? {
+            0 => None,
+            2 => Some(Sequence::Nmi),
+            3 => Some(Sequence::Irq),
+            _ => return Err(StateError::InvalidValue("CPU polled interrupt")),
+        };
+        self.cycle = state.u8()?;
+        self.opcode = state.u8()?;
+        self.address = state.u16()?;
+        self.pointer = state.u8()?;
+        self.data = state.u8()?;
+        self.page_crossed = state.bool()?;
+        self.oam_dma = match state.bool()? {
+            true => Some(OamDma {
+                page: state.u8()?,
+                offset: state.u16_below(0x100, "OAM DMA offset")?,
+                value: state.option_u8()?,
+                halted: state.bool()?,
+            }),
+            false => None,
+        };
+        self.stall_cycles = state.u16()?;
+        self.total_cycles = state.u64()?;
+        Ok(())
     }
 }
 
-enum Operand {
-    Address(u16),
-    Immediate(u8),
-    Offset(u16),
-    Implied,
+// What the CPU is in the middle of. Every instruction, interrupt and the
+// reset starts with a cycle spent fetching an opcode, even if it's thrown
+// away.
+#[derive(Clone, Copy)]
+enum Sequence {
+    Instruction,
+    Nmi,
+    Irq,
+    Reset,
 }
 
-impl Operand {
-    pub fn read(&self, memory: &CpuMemory) -> u8 {
-        match self {
-            Operand::Address(v) => memory.read(*v),
-            Operand::Immediate(v) => *v,
-            _ => panic!("Tried to read the value of a non-operand value"),
+// A page written to $4014 on its way into OAM through $2004
+// https://www.nesdev.org/wiki/DMA#OAM_DMA
+struct OamDma {
+    page: u8,
+    // Bytes copied so far
+    offset: u16,
+    // Read and waiting for the next cycle to write it
+    value: Option<u8>,
+    // Whether the CPU has stopped for it yet
+    halted: bool,
+}
+
+impl OamDma {
+    fn new(page: u8) -> OamDma {
+        OamDma {
+            page,
+            offset: 0,
+            value: None,
+            halted: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 64 KB of RAM with the reset vector pointing at $0600
+    pub(super) struct Ram {
+        pub(super) memory: Vec<u8>,
+        pub(super) reads: Vec<u16>,
+        pub(super) writes: Vec<(u16, u8)>,
+    }
+
+    impl Ram {
+        pub(super) fn with_program(program: &[u8]) -> Ram {
+            let mut memory = vec![0; 0x10000];
+            memory[0x0600..0x0600 + program.len()].copy_from_slice(program);
+            memory[RESET_VECTOR as usize..][..2].copy_from_slice(&[0x00, 0x06]);
+            Ram {
+                memory,
+                reads: Vec::new(),
+                writes: Vec::new(),
+            }
+        }
+    }
+
+    impl Bus for Ram {
+        fn read(&mut self, address: u16) -> u8 {
+            self.reads.push(address);
+            self.memory[address as usize]
+        }
+
+        fn write(&mut self, address: u16, value: u8) {
+            self.memory[address as usize] = value;
+            self.writes.push((address, value));
+        }
+
+        // Only the CPU's own reads are logged
+        fn peek(&mut self, address: u16) -> u8 {
+            self.memory[address as usize]
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Access {
+        Read(u16),
+        Write(u16, u8),
+    }
+
+    // The bus access on each cycle up to the end of the next instruction,
+    // checking there's exactly one every cycle
+    fn instruction_accesses(cpu: &mut Mos6502, bus: &mut Ram) -> Vec<Access> {
+        let mut accesses = Vec::new();
+        let mut started = false;
+        while !(started && cpu.at_instruction_boundary()) {
+            let (reads, writes) = (bus.reads.len(), bus.writes.len());
+            cpu.tick(bus);
+            started |= !cpu.at_instruction_boundary();
+            let cycle: Vec<Access> = bus.reads[reads..]
+                .iter()
+                .map(|&address| Access::Read(address))
+                .chain(
+                    bus.writes[writes..]
+                        .iter()
+                        .map(|&(address, value)| Access::Write(address, value)),
+                )
+                .collect();
+            assert_eq!(cycle.len(), 1, "{cycle:?} on cycle {}", accesses.len());
+            accesses.extend(cycle);
+        }
+        accesses
+    }
+
+    #[test]
+    fn power_on_state() {
+        let cpu = Mos6502::new();
+        assert!(cpu.interrupt_disable());
+        assert!(!cpu.carry() && !cpu.zero() && !cpu.decimal_mode());
+        assert!(!cpu.break_command() && !cpu.overflow() && !cpu.negative());
+        assert_eq!(cpu.status(), 0x24);
+        assert_eq!((cpu.accumulator(), cpu.index_x(), cpu.index_y()), (0, 0, 0));
+    }
+
+    #[test]
+    fn reset_reads_the_vector() {
+        let mut bus = Ram::with_program(&[]);
+        bus.memory[0xFFFC] = 0x34;
+        bus.memory[0xFFFD] = 0x12;
+        let mut cpu = Mos6502::new();
+        cpu.set_status(0);
+        cpu.reset();
+        assert_eq!(cpu.stack_pointer(), 0xFD);
+        assert!(cpu.interrupt_disable());
+
+        // The vector is read on the last two of the sequence's cycles
+        for _ in 0..7 {
+            cpu.tick(&mut bus);
         }
+        assert!(cpu.at_instruction_boundary());
+        assert_eq!(cpu.program_counter(), 0x1234);
+        assert_eq!(bus.reads[5..], [0xFFFC, 0xFFFD]);
+        // One read a cycle, and nothing was pushed
+        assert_eq!(bus.reads.len(), 7);
+        assert!(bus.writes.is_empty());
+    }
+
+    #[test]
+    fn nmi_mid_program() {
+        // SEC, LDA #$80, NOP with the handler at $0700
+        let mut bus = Ram::with_program(&[0x38, 0xA9, 0x80, 0xEA]);
+        bus.memory[NMI_VECTOR as usize..][..2].copy_from_slice(&[0x00, 0x07]);
+        let mut cpu = Mos6502::new();
+        cpu.reset();
+        cpu.step(&mut bus);
+
+        // The LDA runs first, since it's polled for going into its last cycle
+        cpu.trigger_nmi();
+        assert_eq!(cpu.step(&mut bus), 2);
+        assert_eq!(cpu.accumulator(), 0x80);
+        assert_eq!(cpu.step(&mut bus), 7);
+        assert_eq!(cpu.program_counter(), 0x0700);
+        assert!(cpu.interrupt_disable());
+        assert_eq!(cpu.stack_pointer(), 0xFA);
+        // The return address high byte first, then the status with B clear
+        assert_eq!(bus.writes, [(0x01FD, 0x06), (0x01FC, 0x03), (0x01FB, 0xA5)]);
+    }
+
+    #[test]
+    fn irq_waits_for_interrupt_disable() {
+        // CLI, NOP
+        let mut bus = Ram::with_program(&[0x58, 0xEA]);
+        bus.memory[IRQ_VECTOR as usize..][..2].copy_from_slice(&[0x00, 0x08]);
+        let mut cpu = Mos6502::new();
+        cpu.reset();
+        cpu.set_irq(true);
+        cpu.step(&mut bus);
+        assert_eq!(cpu.program_counter(), 0x0601);
+        // I was still set when the CLI polled, so the NOP runs first
+        assert_eq!(cpu.step(&mut bus), 2);
+        assert_eq!(cpu.program_counter(), 0x0602);
+        assert_eq!(cpu.step(&mut bus), 7);
+        assert_eq!(cpu.program_counter(), 0x0800);
+        assert_eq!(bus.writes, [(0x01FD, 0x06), (0x01FC, 0x02), (0x01FB, 0x20)]);
+    }
+
+    #[test]
+    fn irq_gets_in_after_sei() {
+        // CLI, SEI, NOP
+        let mut bus = Ram::with_program(&[0x58, 0x78, 0xEA]);
+        bus.memory[IRQ_VECTOR as usize..][..2].copy_from_slice(&[0x00, 0x08]);
+        let mut cpu = Mos6502::new();
+        cpu.reset();
+        cpu.step(&mut bus);
+
+        // I was still clear when the SEI polled
+        cpu.set_irq(true);
+        assert_eq!(cpu.step(&mut bus), 2);
+        assert!(cpu.interrupt_disable());
+        assert_eq!(cpu.step(&mut bus), 7);
+        assert_eq!(cpu.program_counter(), 0x0800);
+        // Returning from it goes back to the NOP with I set
+        assert_eq!(bus.writes, [(0x01FD, 0x06), (0x01FC, 0x02), (0x01FB, 0x24)]);
+    }
+
+    #[test]
+    fn brk_pushes_the_b_flag() {
+        let mut bus = Ram::with_program(&[0x00, 0xFF]);
+        bus.memory[IRQ_VECTOR as usize..][..2].copy_from_slice(&[0x00, 0x08]);
+        let mut cpu = Mos6502::new();
+        cpu.reset();
+        assert_eq!(cpu.step(&mut bus), 7);
+        assert_eq!(cpu.program_counter(), 0x0800);
+        assert_eq!(bus.writes, [(0x01FD, 0x06), (0x01FC, 0x02), (0x01FB, 0x34)]);
+    }
+
+    #[test]
+    fn ticks_add_up_to_instruction_cycles() {
+        let program = [
+            0xA2, 0xFF, // LDX #$FF, 2
+            0xBD, 0xF0, 0x02, // LDA $02F0,X crossing a page, 5
+            0x8D, 0x00, 0x03, // STA $0300, 4
+            0xE6, 0x10, // INC $10, 5
+            0xA0, 0x03, // LDY #$03, 2
+            0x88, // DEY, 2 three times
+            0xD0, 0xFD, // BNE to the DEY, 3 twice then 2
+            0x48, // PHA, 3
+            0x68, // PLA, 4
+        ];
+        let mut bus = Ram::with_program(&program);
+        let mut cpu = Mos6502::new();
+        cpu.reset();
+
+        let mut ticks = 0;
+        while !(cpu.program_counter() == 0x0611 && cpu.at_instruction_boundary()) {
+            cpu.tick(&mut bus);
+            ticks += 1;
+        }
+        assert_eq!(ticks, 7 + 39);
+        assert_eq!(cpu.total_cycles(), ticks);
+
+        let mut bus = Ram::with_program(&program);
+        let mut cpu = Mos6502::new();
+        cpu.reset();
+        let cycles: Vec<u8> = (0..13).map(|_| cpu.step(&mut bus)).collect();
+        assert_eq!(cycles, [2, 5, 4, 5, 2, 2, 3, 2, 3, 2, 2, 3, 4]);
+        assert_eq!(cpu.total_cycles(), 7 + 39);
+    }
+
+    #[test]
+    fn stores_happen_on_the_last_cycle() {
+        // LDA #$42, STA $0300
+        let mut bus = Ram::with_program(&[0xA9, 0x42, 0x8D, 0x00, 0x03]);
+        let mut cpu = Mos6502::new();
+        cpu.reset();
+        for _ in 0..7 + 2 + 3 {
+            cpu.tick(&mut bus);
+        }
+        assert!(bus.writes.is_empty());
+        cpu.tick(&mut bus);
+        assert_eq!(bus.writes, [(0x0300, 0x42)]);
+    }
+
+    #[test]
+    fn every_opcode_takes_its_listed_cycles() {
+        use super::addressingmodes::{AddressingMode, ADDRESSING_MODES};
+        use super::instruction_table::{Instruction, INSTRUCTIONS};
+        use super::timing::get_timing;
+
+        for opcode in 0..=0xFF {
+            let instruction = INSTRUCTIONS[opcode as usize];
+            let mode = ADDRESSING_MODES[opcode as usize];
+            // Branches are covered below, and STP never finishes
+            if matches!(mode, AddressingMode::Relative) || instruction == Instruction::STP {
+                continue;
+            }
+            // Indexing $0280 by $10 stays on the page and by $90 leaves it.
+            // Every zero page pointer the index could pick points there too.
+            for index in [0x10, 0x90] {
+                let mut bus = Ram::with_program(&[opcode, 0x80, 0x02]);
+                for pointer in [0x10, 0x80, 0x90] {
+                    bus.memory[pointer..pointer + 2].copy_from_slice(&[0x80, 0x02]);
+                }
+                let mut cpu = Mos6502::new();
+                cpu.index_x = index;
+                cpu.index_y = index;
+                cpu.reset();
+                let crossed_page = index == 0x90
+                    && matches!(
+                        mode,
+                        AddressingMode::AbsoluteX
+                            | AddressingMode::AbsoluteY
+                            | AddressingMode::ZeroPageIndirectIndexedY
+                    );
+                assert_eq!(
+                    cpu.step(&mut bus),
+                    get_timing(mode, instruction, crossed_page),
+                    "opcode {opcode:02X} indexed by {index:02X}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn register_accesses_land_on_their_cycle() {
+        let program = [
+            0xA2, 0x03, // LDX #$03
+            0xAD, 0x02, 0x20, // LDA $2002
+            0xBD, 0xFF, 0x20, // LDA $20FF,X
+            0x8D, 0x02, 0x20, // STA $2002
+            0xEE, 0x02, 0x20, // INC $2002
+        ];
+        let mut bus = Ram::with_program(&program);
+        bus.memory[0x2102] = 0x41;
+        let mut cpu = Mos6502::new();
+        cpu.reset();
+        cpu.step(&mut bus);
+
+        // The read is the last of 4 cycles
+        assert_eq!(
+            instruction_accesses(&mut cpu, &mut bus),
+            [
+                Access::Read(0x0602),
+                Access::Read(0x0603),
+                Access::Read(0x0604),
+                Access::Read(0x2002),
+            ]
+        );
+        // Crossing a page reads from the address before the high byte is
+        // fixed up, which is $2002 again
+        assert_eq!(
+            instruction_accesses(&mut cpu, &mut bus),
+            [
+                Access::Read(0x0605),
+                Access::Read(0x0606),
+                Access::Read(0x0607),
+                Access::Read(0x2002),
+                Access::Read(0x2102),
+            ]
+        );
+        assert_eq!(
+            instruction_accesses(&mut cpu, &mut bus),
+            [
+                Access::Read(0x0608),
+                Access::Read(0x0609),
+                Access::Read(0x060A),
+                Access::Write(0x2002, 0x41),
+            ]
+        );
+        // Read-modify-write writes back what it read before the result
+        assert_eq!(
+            instruction_accesses(&mut cpu, &mut bus),
+            [
+                Access::Read(0x060B),
+                Access::Read(0x060C),
+                Access::Read(0x060D),
+                Access::Read(0x2002),
+                Access::Write(0x2002, 0x41),
+                Access::Write(0x2002, 0x42),
+            ]
+        );
+    }
+
+    #[test]
+    fn dma_only_halts_on_reads() {
+        // INC $10, NOP
+        let mut bus = Ram::with_program(&[0xE6, 0x10, 0xEA]);
+        let mut cpu = Mos6502::new();
+        cpu.reset();
+        while !cpu.at_instruction_boundary() {
+            cpu.tick(&mut bus);
+        }
+
+        // Up to the read of $10, then a stall with only writes left
+        for _ in 0..3 {
+            cpu.tick(&mut bus);
+        }
+        cpu.stall(2);
+        let mut accesses = Vec::new();
+        while !cpu.at_instruction_boundary() {
+            let writes = bus.writes.len();
+            cpu.tick(&mut bus);
+            accesses.extend_from_slice(&bus.writes[writes..]);
+        }
+        assert_eq!(accesses, [(0x0010, 0x00), (0x0010, 0x01)]);
+
+        // The NOP's opcode fetch is held up and read again each cycle
+        assert_eq!(
+            instruction_accesses(&mut cpu, &mut bus),
+            [
+                Access::Read(0x0602),
+                Access::Read(0x0602),
+                Access::Read(0x0602),
+                Access::Read(0x0603),
+            ]
+        );
     }
 }
@@ -1,13 +1,23 @@
-use super::{addressingmodes::AddressingMode, instruction_table::Instruction};
+// https://www.nesdev.org/wiki/6502_cycle_times
 
-fn get_timing(mode: AddressingMode, instruction: Instruction, crossed_page: bool) -> usize {
+#[cfg(test)]
+use super::addressingmodes::AddressingMode;
+use super::instruction_table::Instruction;
+
+// Cycles for everything but taken branches, which take one or two more. The
+// micro-ops come out at these lengths on their own; this is what they're
+// checked against.
+#[cfg(test)]
+pub(super) fn get_timing(mode: AddressingMode, instruction: Instruction, crossed_page: bool) -> u8 {
     match mode {
-        AddressingMode::Implied => match instruction {
+        AddressingMode::Accumulator | AddressingMode::Implied => match instruction {
             Instruction::BRK => 7,
             Instruction::RTI | Instruction::RTS => 6,
+            Instruction::PLA | Instruction::PLP => 4,
+            Instruction::PHA | Instruction::PHP => 3,
             _ => 2,
         },
-        AddressingMode::Immediate => 2,
+        AddressingMode::Immediate | AddressingMode::Relative => 2,
         AddressingMode::ZeroPage => {
             if instruction.rwr() {
                 5
@@ -15,14 +25,13 @@ fn get_timing(mode: AddressingMode, instruction: Instruction, crossed_page: bool
                 3
             }
         }
-        AddressingMode::ZeroPageX => {
+        AddressingMode::ZeroPageX | AddressingMode::ZeroPageY => {
             if instruction.rwr() {
                 6
             } else {
                 4
             }
         }
-        AddressingMode::ZeroPageY => 4,
         AddressingMode::Absolute => match instruction {
             Instruction::JMP => 3,
             Instruction::JSR => 6,
@@ -30,58 +39,72 @@ fn get_timing(mode: AddressingMode, instruction: Instruction, crossed_page: bool
                 if instruction.rwr() {
                     6
                 } else {
-                    {
-                        4
-                    }
+                    4
                 }
             }
         },
-        AddressingMode::AbsoluteX => {
-            if instruction == Instruction::STA {
-                5
-            } else if instruction.rwr() {
+        // Indexed reads only take the extra cycle when the high byte of the
+        // address has to be fixed up; writes always take it
+        AddressingMode::AbsoluteX | AddressingMode::AbsoluteY => {
+            if instruction.rwr() {
                 7
-            } else if crossed_page {
+            } else if instruction.stores() {
                 5
             } else {
-                4
+                4 + crossed_page as u8
             }
         }
-        AddressingMode::AbsoluteY => {
-            if instruction == Instruction::STA {
-                5
-            } else if crossed_page {
-                5
+        AddressingMode::AbsoluteIndirect => 5,
+        AddressingMode::ZeroPageIndexedIndirectX => {
+            if instruction.rwr() {
+                8
             } else {
-                4
+                6
             }
         }
-        AddressingMode::AbsoluteIndirect => 5,
-        AddressingMode::ZeroPageIndexedIndirectX => 6,
         AddressingMode::ZeroPageIndirectIndexedY => {
-            if instruction == Instruction::STA {
-                6
-            } else if crossed_page {
+            if instruction.rwr() {
+                8
+            } else if instruction.stores() {
                 6
             } else {
-                5
-            }
-        }
-        AddressingMode::Relative => {
-            if crossed_page {
-                3
-            } else {
-                2
+                5 + crossed_page as u8
             }
         }
     }
 }
 
 impl Instruction {
-    fn rwr(&self) -> bool {
-        match self {
-            Self::ASL | Self::DEC | Self::INC | Self::LSR | Self::ROL | Self::ROR => true,
-            _ => false,
-        }
+    // Read-modify-write
+    pub(super) fn rwr(&self) -> bool {
+        matches!(
+            self,
+            Self::ASL
+                | Self::DEC
+                | Self::INC
+                | Self::LSR
+                | Self::ROL
+                | Self::ROR
+                | Self::SLO
+                | Self::RLA
+                | Self::SRE
+                | Self::RRA
+                | Self::DCP
+                | Self::ISC
+        )
+    }
+
+    pub(super) fn stores(&self) -> bool {
+        matches!(
+            self,
+            Self::STA
+                | Self::STX
+                | Self::STY
+                | Self::SAX
+                | Self::AHX
+                | Self::SHX
+                | Self::SHY
+                | Self::TAS
+        )
     }
 }
@@ -0,0 +1,213 @@
+// Instruction traces in the format of nestest.log, which came out of
+// Nintendulator and which most emulators can produce for comparison:
+//
+// C72F  B0 04     BCS $C735                       A:00 X:00 Y:00 P:27 SP:FB PPU:  0, 93 CYC:31
+//
+// Unofficial opcodes get a * before the mnemonic. Memory operands show the
+// address worked out and the value there before the instruction runs.
+// https://www.qmtpro.com/~nes/misc/nestest.log
+
+use std::fmt::Write;
+
+use crate::bus::Bus;
+
+use super::addressingmodes::{AddressingMode, ADDRESSING_MODES};
+use super::disasm::{branch_target, instruction_len, is_official, mnemonic};
+use super::instruction_table::{Instruction, INSTRUCTIONS};
+
+impl super::Mos6502 {
+    // The line for the instruction at the program counter, as it stands
+    // before that instruction runs. Reads go through Bus::peek.
+    pub fn trace(&self, bus: &mut dyn Bus) -> String {
+        let pc = self.program_counter;
+        let opcode = bus.peek(pc);
+        let mode = ADDRESSING_MODES[opcode as usize];
+        let instruction = INSTRUCTIONS[opcode as usize];
+
+        let mut bytes = String::new();
+        for offset in 0..instruction_len(mode) {
+            if offset > 0 {
+                bytes.push(' ');
+            }
+            let _ = write!(bytes, "{:02X}", bus.peek(pc.wrapping_add(offset)));
+        }
+        let unofficial = if is_official(opcode, instruction) {
+            ' '
+        } else {
+            '*'
+        };
+        let disassembly = format!(
+            "{} {}",
+            mnemonic(instruction),
+            self.trace_operand(bus, mode, instruction)
+        );
+        let (scanline, dot) = bus.ppu_position();
+        format!(
+            "{pc:04X}  {bytes:<9}{unofficial}{:<32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{scanline:>3},{dot:>3} CYC:{}",
+            disassembly.trim_end(),
+            self.accumulator,
+            self.index_x,
+            self.index_y,
+            self.status(),
+            self.stack_pointer,
+            self.total_cycles,
+        )
+    }
+
+    fn trace_operand(
+        &self,
+        bus: &mut dyn Bus,
+        mode: AddressingMode,
+        instruction: Instruction,
+    ) -> String {
+        let pc = self.program_counter;
+        let byte = bus.peek(pc.wrapping_add(1));
+        let word = peek_word(bus, pc.wrapping_add(1));
+        match mode {
+            AddressingMode::Implied => String::new(),
+            AddressingMode::Accumulator => "A".to_string(),
+            AddressingMode::Immediate => format!("#${byte:02X}"),
+            AddressingMode::Relative => format!("${:04X}", branch_target(pc, byte)),
+            // Jumps only use the address
+            AddressingMode::Absolute
+                if matches!(instruction, Instruction::JMP | Instruction::JSR) =>
+            {
+                format!("${word:04X}")
+            }
+            AddressingMode::Absolute => format!("${word:04X} = {:02X}", bus.peek(word)),
+            AddressingMode::AbsoluteIndirect => {
+                // With the same page wrap as the real thing
+                let high = (word & 0xFF00) | (word.wrapping_add(1) & 0x00FF);
+                let target = u16::from_le_bytes([bus.peek(word), bus.peek(high)]);
+                format!("(${word:04X}) = {target:04X}")
+            }
+            AddressingMode::AbsoluteX | AddressingMode::AbsoluteY => {
+                let (index, name) = self.trace_index(mode);
+                let address = word.wrapping_add(index as u16);
+                format!(
+                    "${word:04X},{name} @ {address:04X} = {:02X}",
+                    bus.peek(address)
+                )
+            }
+            AddressingMode::ZeroPage => format!("${byte:02X} = {:02X}", bus.peek(byte as u16)),
+            AddressingMode::ZeroPageX | AddressingMode::ZeroPageY => {
+                let (index, name) = self.trace_index(mode);
+                let address = byte.wrapping_add(index);
+                format!(
+                    "${byte:02X},{name} @ {address:02X} = {:02X}",
+                    bus.peek(address as u16)
+                )
+            }
+            AddressingMode::ZeroPageIndexedIndirectX => {
+                let pointer = byte.wrapping_add(self.index_x);
+                let address = peek_zero_page_word(bus, pointer);
+                format!(
+                    "(${byte:02X},X) @ {pointer:02X} = {address:04X} = {:02X}",
+                    bus.peek(address)
+                )
+            }
+            AddressingMode::ZeroPageIndirectIndexedY => {
+                let base = peek_zero_page_word(bus, byte);
+                let address = base.wrapping_add(self.index_y as u16);
+                format!(
+                    "(${byte:02X}),Y = {base:04X} @ {address:04X} = {:02X}",
+                    bus.peek(address)
+                )
+            }
+        }
+    }
+
+    fn trace_index(&self, mode: AddressingMode) -> (u8, char) {
+        match mode {
+            AddressingMode::AbsoluteX | AddressingMode::ZeroPageX => (self.index_x, 'X'),
+            _ => (self.index_y, 'Y'),
+        }
+    }
+}
+
+fn peek_word(bus: &mut dyn Bus, address: u16) -> u16 {
+    u16::from_le_bytes([bus.peek(address), bus.peek(address.wrapping_add(1))])
+}
+
+// Pointers in the zero page wrap around within it
+fn peek_zero_page_word(bus: &mut dyn Bus, pointer: u8) -> u16 {
+    u16::from_le_bytes([
+        bus.peek(pointer as u16),
+        bus.peek(pointer.wrapping_add(1) as u16),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::Ram;
+    use super::super::Mos6502;
+
+    // The operand text for each instruction of program, traced as it runs
+    fn operands(program: &[u8], setup: impl Fn(&mut Ram)) -> Vec<String> {
+        let mut bus = Ram::with_program(program);
+        setup(&mut bus);
+        let mut cpu = Mos6502::new();
+        cpu.reset();
+        while !cpu.at_instruction_boundary() {
+            cpu.tick(&mut bus);
+        }
+        let mut lines = Vec::new();
+        while (cpu.program_counter() as usize) < 0x0600 + program.len() {
+            // Mnemonic and operand, without the registers
+            lines.push(cpu.trace(&mut bus)[15..48].trim().to_string());
+            cpu.step(&mut bus);
+        }
+        lines
+    }
+
+    #[test]
+    fn addressing_modes() {
+        let program = [
+            0xA2, 0x02, // LDX #$02
+            0xA0, 0x01, // LDY #$01
+            0xA5, 0x10, // LDA $10
+            0xB5, 0xFF, // LDA $FF,X wrapping to $01
+            0xB6, 0x10, // LDX $10,Y
+            0xA1, 0x20, // LDA ($20,X)
+            0xB1, 0x20, // LDA ($20),Y
+            0xAD, 0x00, 0x03, // LDA $0300
+            0xB9, 0x00, 0x03, // LDA $0300,Y
+            0x0A, // ASL A
+            0xAF, 0x00, 0x03, // LAX $0300
+            0x4C, 0x1B, 0x06, // JMP $061B
+            0x6C, 0xFF, 0x02, // JMP ($02FF)
+        ];
+        let setup = |bus: &mut Ram| {
+            bus.memory[0x01] = 0x11;
+            bus.memory[0x10] = 0x05;
+            bus.memory[0x11] = 0x07;
+            bus.memory[0x20] = 0x00;
+            bus.memory[0x21] = 0x03;
+            bus.memory[0x27] = 0x01;
+            bus.memory[0x28] = 0x03;
+            bus.memory[0x0300] = 0x33;
+            bus.memory[0x0301] = 0x44;
+            // The high byte comes from $0200, not $0300
+            bus.memory[0x02FF] = 0x1E;
+            bus.memory[0x0200] = 0x06;
+        };
+        assert_eq!(
+            operands(&program, setup),
+            [
+                "LDX #$02",
+                "LDY #$01",
+                "LDA $10 = 05",
+                "LDA $FF,X @ 01 = 11",
+                "LDX $10,Y @ 11 = 07",
+                "LDA ($20,X) @ 27 = 0301 = 44",
+                "LDA ($20),Y = 0300 @ 0301 = 44",
+                "LDA $0300 = 33",
+                "LDA $0300,Y @ 0301 = 44",
+                "ASL A",
+                "*LAX $0300 = 33",
+                "JMP $061B",
+                "JMP ($02FF) = 061E",
+            ]
+        );
+    }
+}
@@ -0,0 +1,525 @@
+// The whole console, for frontends that just want frames and audio out of a
+// cartridge. The CPU and the bus are driven in lockstep, with the PPU taking
+// three dots for every CPU cycle as on NTSC.
+// https://www.nesdev.org/wiki/Cycle_reference_chart
+
+use std::io::Write;
+
+use crate::bus::NesBus;
+use crate::cartridge::CartridgeData;
+use crate::input::Buttons;
+use crate::mapper::{create_mapper, UnsupportedMapper};
+use crate::mos6502::Mos6502;
+use crate::state::{StateError, StateReader, StateWriter};
+
+const PPU_DOTS_PER_CPU_CYCLE: u8 = 3;
+const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+// How much audio_samples asks the APU for at a time
+const AUDIO_CHUNK: usize = 1024;
+// 64 bit FNV-1a, for frame_hash
+const FNV_OFFSET_BASIS: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01B3;
+
+/// A console with a cartridge plugged in.
+///
+/// ```
+/// use zephyrnes::cartridge::CartridgeBuilder;
+/// use zephyrnes::nes::Nes;
+///
+/// // A program that spins on JMP $8000, with the reset vector pointing at it
+/// let mut prg_rom = vec![0xEA; 0x4000];
+/// prg_rom[..3].copy_from_slice(&[0x4C, 0x00, 0x80]);
+/// prg_rom[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0x80]);
+/// let cartridge = CartridgeBuilder::new()
+///     .prg_rom(prg_rom)
+///     .chr_rom(vec![0; 0x2000])
+///     .build()
+///     .unwrap();
+///
+/// let mut nes = Nes::load(cartridge).unwrap();
+/// nes.step_frame();
+/// assert_eq!(nes.frame(), 1);
+/// assert_eq!(nes.framebuffer().len(), 256 * 240 * 3);
+/// assert!(!nes.audio_samples().is_empty());
+/// ```
+pub struct Nes {
+    cpu: Mos6502,
+    bus: NesBus,
+    sample_rate: u32,
+    audio: Vec<f32>,
+    trace: Option<Box<dyn Write>>,
+}
+
+impl Nes {
+    // Powers on with the cartridge in, which runs the CPU's reset sequence
+    pub fn load(cartridge: CartridgeData) -> Result<Nes, UnsupportedMapper> {
+        let mut nes = Nes {
+            cpu: Mos6502::new(),
+            bus: NesBus::new(create_mapper(cartridge)?),
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            audio: Vec::new(),
+            trace: None,
+        };
+        nes.cpu.reset();
+        Ok(nes)
+    }
+
+    // The reset button. Memory survives it, registers mostly don't.
+    pub fn reset(&mut self) {
+        self.bus.reset();
+        self.cpu.reset();
+    }
+
+    // Runs until the PPU finishes the frame it's on, which is also when the
+    // framebuffer holds a complete picture
+    pub fn step_frame(&mut self) {
+        let frame = self.bus.ppu().frame();
+        while self.bus.ppu().frame() == frame {
+            self.step_cycle();
+        }
+    }
+
+    // One CPU cycle and everything that happens alongside it
+    pub fn step_cycle(&mut self) {
+        match &mut self.trace {
+            Some(out) => {
+                // A trace that can't be written any more is dropped
+                if self.cpu.tick_traced(&mut self.bus, out.as_mut()).is_err() {
+                    self.trace = None;
+                }
+            }
+            None => self.cpu.tick(&mut self.bus),
+        }
+        self.bus.tick_apu();
+        self.bus.tick_mapper();
+        for _ in 0..PPU_DOTS_PER_CPU_CYCLE {
+            self.bus.tick_ppu();
+        }
+    }
+
+    // Writes a line to out for every instruction the CPU runs from here on,
+    // in the format of nestest.log
+    pub fn enable_trace(&mut self, out: impl Write + 'static) {
+        self.trace = Some(Box::new(out));
+    }
+
+    pub fn disable_trace(&mut self) {
+        self.trace = None;
+    }
+
+    // RGB, 3 bytes per pixel, 256x240
+    pub fn framebuffer(&self) -> &[u8] {
+        self.bus.ppu().framebuffer()
+    }
+
+    pub fn frame(&self) -> u64 {
+        self.bus.ppu().frame()
+    }
+
+    // A hash of the current framebuffer, the same on every platform and build,
+    // for checking a ROM still draws what it used to
+    pub fn frame_hash(&self) -> u64 {
+        self.framebuffer()
+            .iter()
+            .fold(FNV_OFFSET_BASIS, |hash, &byte| {
+                (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+            })
+    }
+
+    // Runs frames until each entry's frame number comes up, as counted by
+    // frame(), and then holds player 1's buttons as given. Entries have to
+    // be in order, and ones for frames already gone take effect straight
+    // away. Returns once the last entry is applied, leaving the rest of the
+    // run to the caller.
+    pub fn run_input_script(&mut self, script: &[(u32, Buttons)]) {
+        for &(frame, buttons) in script {
+            while self.frame() < frame as u64 {
+                self.step_frame();
+            }
+            if let Some(controller) = self.bus.controller_mut(0) {
+                controller.set_buttons(buttons.0);
+            }
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Audio at sample_rate made since the last call, mono, centred on 0
+    pub fn audio_samples(&mut self) -> &[f32] {
+        self.audio.clear();
+        loop {
+            let start = self.audio.len();
+            self.audio.resize(start + AUDIO_CHUNK, 0.0);
+            let written = self
+                .bus
+                .apu_mut()
+                .drain_samples(&mut self.audio[start..], self.sample_rate);
+            self.audio.truncate(start + written);
+            if written < AUDIO_CHUNK {
+                break;
+            }
+        }
+        &self.audio
+    }
+
+    // Everything the console would need to carry on from this point,
+    // including cartridge RAM. The picture and any audio not yet drained
+    // aren't part of it, so the frame after a load is the first complete one.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::with_header();
+        self.cpu.save_state(&mut state);
+        self.bus.save_state(&mut state);
+        state.into_bytes()
+    }
+
+    // Leaves the console as it was if the state doesn't load, which it won't
+    // if it came from another ROM. The backup comes from this same console,
+    // so it fits; should it somehow not, its error is the one returned.
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), StateError> {
+        let backup = self.save_state();
+        match self.read_state(bytes) {
+            Ok(()) => Ok(()),
+            Err(error) => self.read_state(&backup).and(Err(error)),
+        }
+    }
+
+    fn read_state(&mut self, bytes: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::with_header(bytes)?;
+        self.cpu.load_state(&mut state)?;
+        self.bus.load_state(&mut state)?;
+        state.finish()
+    }
+
+    // The cartridge's battery-backed PRG RAM, for keeping in a .sav file. None
+    // unless the header says there's a battery.
+    pub fn save_ram(&self) -> Option<&[u8]> {
+        self.bus.mapper().battery_ram()
+    }
+
+    // Puts a .sav file's contents back, normally straight after load. A file
+    // of the wrong size fills as much as it covers, and cartridges without a
+    // battery ignore it.
+    pub fn load_save_ram(&mut self, save: &[u8]) {
+        if let Some(ram) = self.bus.mapper_mut().battery_ram_mut() {
+            let len = ram.len().min(save.len());
+            ram[..len].copy_from_slice(&save[..len]);
+        }
+    }
+
+    pub fn cpu(&self) -> &Mos6502 {
+        &self.cpu
+    }
+
+    pub fn bus(&self) -> &NesBus {
+        &self.bus
+    }
+
+    pub fn bus_mut(&mut self) -> &mut NesBus {
+        &mut self.bus
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::{self, Write};
+    use std::rc::Rc;
+
+    use crate::bus::Bus;
+    use crate::cartridge::CartridgeBuilder;
+    use crate::input::Button;
+
+    use super::*;
+
+    // 16 KB with the program at $8000 and the reset vector pointing to it
+    fn prg_rom(program: &[u8]) -> Vec<u8> {
+        let mut prg_rom = vec![0xEA; 0x4000];
+        prg_rom[..program.len()].copy_from_slice(program);
+        prg_rom[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0x80]);
+        prg_rom
+    }
+
+    fn cartridge(program: &[u8]) -> CartridgeData {
+        CartridgeBuilder::new()
+            .prg_rom(prg_rom(program))
+            .chr_rom(vec![0; 0x2000])
+            .build()
+            .unwrap()
+    }
+
+    // INC $00, JMP $8000
+    const COUNT_FOREVER: [u8; 5] = [0xE6, 0x00, 0x4C, 0x00, 0x80];
+
+    #[test]
+    fn runs_one_frame() {
+        let mut nes = Nes::load(cartridge(&COUNT_FOREVER)).unwrap();
+        nes.step_frame();
+        assert_eq!(nes.frame(), 1);
+        // 89342 dots take 29781 CPU cycles, the last of which runs a dot over
+        assert_eq!((nes.bus().ppu().scanline(), nes.bus().ppu().dot()), (0, 1));
+        // 8 cycles round the loop
+        let count = nes.bus_mut().peek(0x0000);
+        assert_eq!(count, (29781 / 8) as u8);
+    }
+
+    #[test]
+    fn three_dots_per_cpu_cycle() {
+        let mut nes = Nes::load(cartridge(&COUNT_FOREVER)).unwrap();
+        // With rendering off no dot is skipped, so three frames of 262 lines
+        // of 341 dots come out to a whole number of CPU cycles
+        let mut cycles = 0;
+        while nes.frame() < 3 {
+            nes.step_cycle();
+            cycles += 1;
+        }
+        assert_eq!(cycles, 262 * 341);
+        assert_eq!((nes.bus().ppu().scanline(), nes.bus().ppu().dot()), (0, 0));
+    }
+
+    // Sets up a background palette, turns on rendering and NMIs, and counts
+    // in $00 forever while the NMI handler counts frames in $01 and scrolls
+    // by them
+    fn rendering_cartridge() -> CartridgeData {
+        let mut prg_rom = vec![0xEA; 0x4000];
+        prg_rom[..0x28].copy_from_slice(&[
+            0xA9, 0x3F, 0x8D, 0x06, 0x20, // LDA #$3F, STA $2006
+            0xA9, 0x01, 0x8D, 0x06, 0x20, // LDA #$01, STA $2006
+            0xA9, 0x16, 0x8D, 0x07, 0x20, // LDA #$16, STA $2007
+            0xA9, 0x2A, 0x8D, 0x07, 0x20, // LDA #$2A, STA $2007
+            0xA9, 0x30, 0x8D, 0x07, 0x20, // LDA #$30, STA $2007
+            0xA9, 0x1E, 0x8D, 0x01, 0x20, // LDA #$1E, STA $2001
+            0xA9, 0x80, 0x8D, 0x00, 0x20, // LDA #$80, STA $2000
+            0xE6, 0x00, 0x4C, 0x23, 0x80, // INC $00, JMP $8023
+        ]);
+        prg_rom[0x30..0x3B].copy_from_slice(&[
+            0xE6, 0x01, // INC $01
+            0xA5, 0x01, 0x8D, 0x05, 0x20, 0x8D, 0x05, 0x20, // LDA $01, STA $2005 twice
+            0x40, // RTI
+        ]);
+        prg_rom[0x3FFA..0x3FFE].copy_from_slice(&[0x30, 0x80, 0x00, 0x80]);
+        let chr_rom: Vec<u8> = (0..0x2000).map(|i| (i * 7) as u8).collect();
+        CartridgeBuilder::new()
+            .prg_rom(prg_rom)
+            .chr_rom(chr_rom)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn save_state_round_trip() {
+        let mut nes = Nes::load(rendering_cartridge()).unwrap();
+        for _ in 0..5 {
+            nes.step_frame();
+        }
+        // Mid-frame, so the CPU and PPU are partway through things
+        for _ in 0..12345 {
+            nes.step_cycle();
+        }
+        let state = nes.save_state();
+        let mut reloaded = Nes::load(rendering_cartridge()).unwrap();
+        reloaded.load_state(&state).unwrap();
+        assert_eq!(reloaded.save_state(), state);
+
+        for _ in 0..100 {
+            nes.step_frame();
+            reloaded.step_frame();
+        }
+        assert_eq!(reloaded.save_state(), nes.save_state());
+        assert_eq!(reloaded.frame_hash(), nes.frame_hash());
+        assert_eq!(nes.bus_mut().peek(0x0001), 105);
+    }
+
+    #[test]
+    fn bad_states_leave_the_console_alone() {
+        let mut nes = Nes::load(rendering_cartridge()).unwrap();
+        nes.step_frame();
+        let state = nes.save_state();
+        nes.step_frame();
+        let before = nes.save_state();
+
+        assert_eq!(nes.load_state(b"nope"), Err(StateError::BadMagic));
+        assert_eq!(
+            nes.load_state(&state[..state.len() - 1]),
+            Err(StateError::UnexpectedEnd)
+        );
+        let mut longer = state.clone();
+        longer.push(0);
+        assert_eq!(nes.load_state(&longer), Err(StateError::TrailingData(1)));
+        // A state for a different ROM doesn't fit
+        let other = Nes::load(cartridge(&COUNT_FOREVER)).unwrap().save_state();
+        assert!(nes.load_state(&other).is_err());
+        assert_eq!(nes.save_state(), before);
+    }
+
+    // Copies $6000 to $00, then writes $42 to $6000 and $99 to $7FFF
+    const SAVE_PROGRAM: [u8; 19] = [
+        0xAD, 0x00, 0x60, 0x85, 0x00, // LDA $6000, STA $00
+        0xA9, 0x42, 0x8D, 0x00, 0x60, // LDA #$42, STA $6000
+        0xA9, 0x99, 0x8D, 0xFF, 0x7F, // LDA #$99, STA $7FFF
+        0x4C, 0x0F, 0x80, 0xEA, // JMP $800F
+    ];
+
+    fn battery_cartridge(battery: bool) -> CartridgeData {
+        CartridgeBuilder::new()
+            .prg_rom(prg_rom(&SAVE_PROGRAM))
+            .chr_rom(vec![0; 0x2000])
+            .battery(battery)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn save_ram_carries_over() {
+        let mut nes = Nes::load(battery_cartridge(true)).unwrap();
+        nes.step_frame();
+        assert_eq!(nes.bus_mut().peek(0x0000), 0);
+        let save = nes.save_ram().unwrap().to_vec();
+        assert_eq!(save.len(), 0x2000);
+        assert_eq!((save[0], save[0x1FFF]), (0x42, 0x99));
+
+        // The program sees the saved value before it writes its own
+        let mut fresh = Nes::load(battery_cartridge(true)).unwrap();
+        fresh.load_save_ram(&save);
+        fresh.step_frame();
+        assert_eq!(fresh.bus_mut().peek(0x0000), 0x42);
+    }
+
+    #[test]
+    fn save_ram_needs_a_battery() {
+        let mut nes = Nes::load(battery_cartridge(false)).unwrap();
+        nes.step_frame();
+        assert!(nes.save_ram().is_none());
+        let mut fresh = Nes::load(battery_cartridge(false)).unwrap();
+        fresh.load_save_ram(&[0x42; 0x2000]);
+        fresh.step_frame();
+        assert_eq!(fresh.bus_mut().peek(0x0000), 0);
+
+        // A short file only fills the start
+        let mut fresh = Nes::load(battery_cartridge(true)).unwrap();
+        fresh.load_save_ram(&[0x17]);
+        assert_eq!(fresh.save_ram().unwrap()[..2], [0x17, 0x00]);
+    }
+
+    #[test]
+    fn frame_hash_is_deterministic() {
+        let hashes = |frames: usize| {
+            let mut nes = Nes::load(rendering_cartridge()).unwrap();
+            (0..frames)
+                .map(|_| {
+                    nes.step_frame();
+                    nes.frame_hash()
+                })
+                .collect::<Vec<_>>()
+        };
+        let first = hashes(10);
+        assert_eq!(hashes(10), first);
+        // It scrolls by a pixel a frame, so every frame looks different
+        for pair in first[2..].windows(2) {
+            assert_ne!(pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn input_script_applies_on_its_frames() {
+        let a = Buttons::new(&[Button::A]);
+        let start = Buttons::new(&[Button::Start]);
+        let mut nes = Nes::load(cartridge(&COUNT_FOREVER)).unwrap();
+        nes.run_input_script(&[(2, a), (5, start)]);
+        assert_eq!(nes.frame(), 5);
+        assert_eq!(nes.bus_mut().controller_mut(0).unwrap().buttons(), start.0);
+
+        // Entries for frames already gone apply straight away
+        nes.run_input_script(&[(1, a)]);
+        assert_eq!(nes.frame(), 5);
+        assert_eq!(nes.bus_mut().controller_mut(0).unwrap().buttons(), a.0);
+    }
+
+    #[derive(Clone, Default)]
+    struct Log(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Log {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(bytes)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Log {
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8(self.0.borrow().clone())
+                .unwrap()
+                .lines()
+                .map(str::to_string)
+                .collect()
+        }
+    }
+
+    #[test]
+    fn trace_of_jumps_stores_and_branches() {
+        // A format check only. The program is a few instructions laid out
+        // at the addresses nestest starts from, not the nestest ROM, and the
+        // expected lines are written out by hand rather than taken from
+        // nestest.log, so matching them doesn't show the log would match.
+        // It covers a jump, stores, a subroutine call and both kinds of
+        // branch.
+        let mut prg_rom = vec![0xEA; 0x4000];
+        let mut put = |address: usize, code: &[u8]| {
+            prg_rom[address - 0xC000..][..code.len()].copy_from_slice(code);
+        };
+        put(0xC000, &[0x4C, 0xF5, 0xC5]);
+        put(
+            0xC5F5,
+            &[
+                0xA2, 0x00, 0x86, 0x00, 0x86, 0x10, 0x86, 0x11, 0x20, 0x2D, 0xC7,
+            ],
+        );
+        put(0xC72D, &[0xEA, 0x38, 0xB0, 0x04]);
+        put(0xC735, &[0xEA, 0x18, 0x90, 0x03]);
+        put(0xFFFC, &[0x00, 0xC0]);
+        let cartridge = CartridgeBuilder::new()
+            .prg_rom(prg_rom)
+            .chr_rom(vec![0; 0x2000])
+            .build()
+            .unwrap();
+
+        let log = Log::default();
+        let mut nes = Nes::load(cartridge).unwrap();
+        nes.enable_trace(log.clone());
+        while log.lines().len() < 12 {
+            nes.step_cycle();
+        }
+        assert_eq!(
+            log.lines(),
+            [
+                "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7",
+                "C5F5  A2 00     LDX #$00                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 30 CYC:10",
+                "C5F7  86 00     STX $00 = 00                    A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 36 CYC:12",
+                "C5F9  86 10     STX $10 = 00                    A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 45 CYC:15",
+                "C5FB  86 11     STX $11 = 00                    A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 54 CYC:18",
+                "C5FD  20 2D C7  JSR $C72D                       A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 63 CYC:21",
+                "C72D  EA        NOP                             A:00 X:00 Y:00 P:26 SP:FB PPU:  0, 81 CYC:27",
+                "C72E  38        SEC                             A:00 X:00 Y:00 P:26 SP:FB PPU:  0, 87 CYC:29",
+                "C72F  B0 04     BCS $C735                       A:00 X:00 Y:00 P:27 SP:FB PPU:  0, 93 CYC:31",
+                "C735  EA        NOP                             A:00 X:00 Y:00 P:27 SP:FB PPU:  0,102 CYC:34",
+                "C736  18        CLC                             A:00 X:00 Y:00 P:27 SP:FB PPU:  0,108 CYC:36",
+                "C737  90 03     BCC $C73C                       A:00 X:00 Y:00 P:26 SP:FB PPU:  0,114 CYC:38",
+            ]
+        );
+
+        // Nothing more once it's off
+        nes.disable_trace();
+        for _ in 0..100 {
+            nes.step_cycle();
+        }
+        assert_eq!(log.lines().len(), 12);
+    }
+}
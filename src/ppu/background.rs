@@ -0,0 +1,279 @@
+// Background tile fetches and the shift registers that feed them to the
+// pixel output, eight dots ahead of where they're drawn
+// https://www.nesdev.org/wiki/PPU_rendering
+
+use crate::mapper::Mapper;
+use crate::state::{StateError, StateReader, StateWriter};
+
+// PPUCTRL
+const CTRL_BACKGROUND_TABLE: u8 = 0b00010000;
+
+#[derive(Default)]
+pub(super) struct BackgroundFetcher {
+    nametable_byte: u8,
+    attribute_bits: u8,
+    pattern_low: u8,
+    pattern_high: u8,
+    // The high byte is the tile being drawn, the low byte the next one
+    pattern_shift_low: u16,
+    pattern_shift_high: u16,
+    // The attribute bits are spread over 8 bits per tile to match
+    attribute_shift_low: u16,
+    attribute_shift_high: u16,
+}
+
+impl BackgroundFetcher {
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.nametable_byte);
+        state.u8(self.attribute_bits);
+        state.u8(self.pattern_low);
+        state.u8(self.pattern_high);
+        state.u16(self.pattern_shift_low);
+        state.u16(self.pattern_shift_high);
+        state.u16(self.attribute_shift_low);
+        state.u16(self.attribute_shift_high);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.nametable_byte = state.u8()?;
+        self.attribute_bits = state.u8()?;
+        self.pattern_low = state.u8()?;
+        self.pattern_high = state.u8()?;
+        self.pattern_shift_low = state.u16()?;
+        self.pattern_shift_high = state.u16()?;
+        self.attribute_shift_low = state.u16()?;
+        self.attribute_shift_high = state.u16()?;
+        Ok(())
+    }
+}
+
+impl super::Ppu {
+    // Fetches for the dots of a visible or pre-render scanline. Tiles are
+    // fetched two at a time at the end of the previous line, then one every
+    // eight dots across the line itself.
+    pub(super) fn background_dot(&mut self, mapper: &mut dyn Mapper) {
+        let dot = self.dot;
+        if matches!(dot, 2..=257 | 322..=337) {
+            self.shift_background();
+        }
+        // The shifters are reloaded every eight dots, just before the next
+        // tile starts shifting into place, so the last reload of each
+        // stretch lands on dots 257 and 337
+        if matches!(dot, 2..=257 | 321..=337) {
+            match (dot - 1) % 8 {
+                0 => {
+                    self.reload_background_shifters();
+                    self.background.nametable_byte = self.read(0x2000 | (self.v & 0x0FFF), mapper);
+                }
+                2 => {
+                    // Each attribute byte covers 4x4 tiles, two bits per 2x2 quadrant
+                    let address = 0x23C0
+                        | (self.v & 0x0C00)
+                        | ((self.v >> 4) & 0x38)
+                        | ((self.v >> 2) & 0x07);
+                    let shift = ((self.v >> 4) & 0b100) | (self.v & 0b10);
+                    self.background.attribute_bits = (self.read(address, mapper) >> shift) & 0b11;
+                }
+                4 => self.background.pattern_low = self.read(self.pattern_address(), mapper),
+                6 => self.background.pattern_high = self.read(self.pattern_address() + 8, mapper),
+                7 => self.increment_coarse_x(),
+                _ => {}
+            }
+        }
+        match dot {
+            256 => self.increment_y(),
+            257 => self.copy_horizontal_bits(),
+            // Unused nametable fetches, which some mappers watch for
+            338 | 340 => {
+                self.read(0x2000 | (self.v & 0x0FFF), mapper);
+            }
+            _ => {}
+        }
+    }
+
+    // Palette index of the background at the current dot, 0 if transparent.
+    // fine_x picks the bit within the tile.
+    pub(super) fn background_pixel(&self) -> u8 {
+        let bit = 0x8000 >> self.x;
+        let pixel = ((self.background.pattern_shift_high & bit != 0) as u8) << 1
+            | (self.background.pattern_shift_low & bit != 0) as u8;
+        if pixel == 0 {
+            return 0;
+        }
+        let palette = ((self.background.attribute_shift_high & bit != 0) as u8) << 1
+            | (self.background.attribute_shift_low & bit != 0) as u8;
+        (palette << 2) | pixel
+    }
+
+    fn pattern_address(&self) -> u16 {
+        let table = if self.ctrl & CTRL_BACKGROUND_TABLE != 0 {
+            0x1000
+        } else {
+            0
+        };
+        let fine_y = (self.v >> 12) & 0b111;
+        table | ((self.background.nametable_byte as u16) << 4) | fine_y
+    }
+
+    fn shift_background(&mut self) {
+        let background = &mut self.background;
+        background.pattern_shift_low <<= 1;
+        background.pattern_shift_high <<= 1;
+        background.attribute_shift_low <<= 1;
+        background.attribute_shift_high <<= 1;
+    }
+
+    fn reload_background_shifters(&mut self) {
+        let background = &mut self.background;
+        background.pattern_shift_low =
+            (background.pattern_shift_low & 0xFF00) | background.pattern_low as u16;
+        background.pattern_shift_high =
+            (background.pattern_shift_high & 0xFF00) | background.pattern_high as u16;
+        let spread = |bit: u8| if bit != 0 { 0x00FF } else { 0 };
+        background.attribute_shift_low =
+            (background.attribute_shift_low & 0xFF00) | spread(background.attribute_bits & 0b01);
+        background.attribute_shift_high =
+            (background.attribute_shift_high & 0xFF00) | spread(background.attribute_bits & 0b10);
+    }
+
+    // Coarse X wraps into the horizontally adjacent nametable
+    fn increment_coarse_x(&mut self) {
+        if self.v & 0x001F == 31 {
+            self.v &= !0x001F;
+            self.v ^= 0x0400;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    // Fine Y carries into coarse Y, which wraps into the vertically adjacent
+    // nametable after row 29. Rows 30 and 31 are the attribute table and wrap
+    // without switching.
+    fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !0x7000;
+        let mut coarse_y = (self.v & 0x03E0) >> 5;
+        if coarse_y == 29 {
+            coarse_y = 0;
+            self.v ^= 0x0800;
+        } else if coarse_y == 31 {
+            coarse_y = 0;
+        } else {
+            coarse_y += 1;
+        }
+        self.v = (self.v & !0x03E0) | (coarse_y << 5);
+    }
+
+    // Coarse X and the horizontal nametable bit, at dot 257 of every line
+    fn copy_horizontal_bits(&mut self) {
+        self.v = (self.v & !0x041F) | (self.t & 0x041F);
+    }
+
+    // Fine Y, coarse Y and the vertical nametable bit, over dots 280-304 of
+    // the pre-render line
+    pub(super) fn copy_vertical_bits(&mut self) {
+        self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::palette::NTSC_PALETTE;
+    use super::super::tests::nrom;
+    use super::super::{Ppu, SCREEN_HEIGHT, SCREEN_WIDTH};
+    use crate::mapper::Nrom;
+
+    const SCROLL_X: usize = 3;
+    const SCROLL_Y: usize = 4;
+
+    // Tile 1's low plane changes halfway down, tile 2 is solid colour 1
+    fn pattern(tile: usize, row: usize) -> (u8, u8) {
+        match tile {
+            1 if row < 4 => (0xF0, 0xCC),
+            1 => (0x0F, 0xCC),
+            2 => (0xFF, 0x00),
+            _ => (0, 0),
+        }
+    }
+
+    // Nametable A at $2000 has a diagonal of tiles 0-2 with each quadrant of
+    // the attribute table using a different palette. B at $2800, which is
+    // scrolled into at the bottom, is all tile 2 in palette 0.
+    fn tile_and_palette(world_x: usize, world_y: usize) -> (usize, usize) {
+        if world_y >= 240 {
+            return (2, 0);
+        }
+        let (column, row) = ((world_x / 8) % 32, world_y / 8);
+        let quadrant = ((row % 4) / 2) * 2 + (column % 4) / 2;
+        ((column + row) % 3, quadrant)
+    }
+
+    fn write(ppu: &mut Ppu, mapper: &mut Nrom, address: u16, bytes: impl IntoIterator<Item = u8>) {
+        ppu.write_register(0x2006, (address >> 8) as u8, mapper);
+        ppu.write_register(0x2006, address as u8, mapper);
+        for byte in bytes {
+            ppu.write_register(0x2007, byte, mapper);
+        }
+    }
+
+    fn palette_entry(palette: usize, colour: usize) -> u8 {
+        (palette * 0x10 + colour) as u8
+    }
+
+    #[test]
+    fn renders_the_background() {
+        let (mut ppu, mut mapper) = (Ppu::new(), nrom());
+        for tile in 1..3 {
+            let planes: Vec<_> = (0..8).map(|row| pattern(tile, row)).collect();
+            let bytes = planes.iter().map(|planes| planes.0);
+            let bytes = bytes.chain(planes.iter().map(|planes| planes.1));
+            write(&mut ppu, &mut mapper, tile as u16 * 16, bytes);
+        }
+        let tiles = (0..30 * 32).map(|i| ((i % 32 + i / 32) % 3) as u8);
+        write(&mut ppu, &mut mapper, 0x2000, tiles);
+        write(&mut ppu, &mut mapper, 0x23C0, [0b11100100; 64]);
+        write(&mut ppu, &mut mapper, 0x2800, [2; 30 * 32]);
+        let palettes = (0..16).map(|i| palette_entry(i / 4, i % 4));
+        write(&mut ppu, &mut mapper, 0x3F00, palettes);
+        ppu.write_register(0x2006, 0x3F, &mut mapper);
+        ppu.write_register(0x2006, 0x00, &mut mapper);
+        ppu.write_register(0x2007, 0x0F, &mut mapper);
+
+        ppu.read_register(0x2002, &mut mapper);
+        ppu.write_register(0x2005, SCROLL_X as u8, &mut mapper);
+        ppu.write_register(0x2005, SCROLL_Y as u8, &mut mapper);
+        ppu.write_register(0x2000, 0, &mut mapper);
+        ppu.write_register(0x2001, 0b00001010, &mut mapper);
+        // The first frame starts without a pre-render line to set up the scroll
+        while ppu.frame() < 2 {
+            ppu.tick(&mut mapper);
+        }
+
+        let mut expected = Vec::with_capacity(SCREEN_WIDTH * SCREEN_HEIGHT * 3);
+        for y in 0..SCREEN_HEIGHT {
+            for x in 0..SCREEN_WIDTH {
+                let (world_x, world_y) = (x + SCROLL_X, y + SCROLL_Y);
+                let (tile, palette) = tile_and_palette(world_x, world_y);
+                let (low, high) = pattern(tile, world_y % 8);
+                let bit = 7 - world_x % 8;
+                let colour = ((low >> bit) & 1) | (((high >> bit) & 1) << 1);
+                let index = if colour == 0 {
+                    0x0F
+                } else {
+                    palette_entry(palette, colour as usize)
+                };
+                let (r, g, b) = NTSC_PALETTE[index as usize];
+                expected.extend([r, g, b]);
+            }
+        }
+        let mismatch = (0..SCREEN_WIDTH * SCREEN_HEIGHT)
+            .find(|&pixel| ppu.framebuffer()[pixel * 3..][..3] != expected[pixel * 3..][..3]);
+        assert_eq!(
+            mismatch.map(|pixel| (pixel % SCREEN_WIDTH, pixel / SCREEN_WIDTH)),
+            None
+        );
+    }
+}
@@ -0,0 +1,587 @@
+// The 2C02 picture processing unit, as the CPU sees it through $2000-$2007
+// https://www.nesdev.org/wiki/PPU_registers
+// https://www.nesdev.org/wiki/PPU_scrolling
+
+mod background;
+mod palette;
+mod sprites;
+
+pub use palette::{load_pal_bytes, PaletteError, NTSC_PALETTE};
+
+use background::BackgroundFetcher;
+use sprites::{SpriteUnit, STATUS_SPRITE_OVERFLOW};
+
+use crate::mapper::Mapper;
+use crate::state::{StateError, StateReader, StateWriter};
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+// The last scanline of a frame is the pre-render line, which fetches the
+// first two tiles of the next frame
+const DOTS_PER_SCANLINE: u16 = 341;
+const VBLANK_SCANLINE: u16 = 241;
+const PRE_RENDER_SCANLINE: u16 = 261;
+
+// PPUCTRL
+const CTRL_ADDRESS_INCREMENT: u8 = 0b00000100;
+const CTRL_NMI_ENABLE: u8 = 0b10000000;
+// PPUMASK
+const MASK_GREYSCALE: u8 = 0b00000001;
+const MASK_BACKGROUND_LEFT: u8 = 0b00000010;
+const MASK_SPRITES_LEFT: u8 = 0b00000100;
+const MASK_BACKGROUND: u8 = 0b00001000;
+const MASK_SPRITES: u8 = 0b00010000;
+// PPUSTATUS
+const STATUS_VBLANK: u8 = 0b10000000;
+const STATUS_SPRITE_ZERO_HIT: u8 = 0b01000000;
+
+pub struct Ppu {
+    ctrl: u8,
+    mask: u8,
+    // Only the top three bits exist, the rest read back as open bus
+    status: u8,
+    oam_address: u8,
+    oam: [u8; 256],
+    // Current and temporary VRAM address, fine X scroll and the write toggle
+    // shared by PPUSCROLL and PPUADDR. v and t are 15 bits:
+    // yyy NN YYYYY XXXXX, fine Y, nametable, coarse Y and coarse X.
+    v: u16,
+    t: u16,
+    x: u8,
+    w: bool,
+    // What the last PPUDATA read fetched, handed out by the next one
+    read_buffer: u8,
+    // Last value written to any register, which write-only registers read back as
+    io_latch: u8,
+    // Two nametables on the console. Four-screen boards supply the other two,
+    // which live in the upper half. The mapper decides which one each
+    // address lands on.
+    nametables: [u8; 4096],
+    palette: [u8; 32],
+    // RGB for each colour index, NTSC_PALETTE unless one has been loaded
+    rgb_palette: [(u8, u8, u8); 64],
+    // Position of the next dot. Scanlines 0-239 are drawn, then comes a
+    // post-render line, vblank from 241, and the pre-render line 261.
+    dot: u16,
+    scanline: u16,
+    frame: u64,
+    // PPUSTATUS was read just as vblank was about to start, so it doesn't
+    suppress_vblank: bool,
+    background: BackgroundFetcher,
+    sprites: SpriteUnit,
+    // 256x240 RGB, three bytes per pixel
+    framebuffer: Vec<u8>,
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ppu {
+    pub fn new() -> Ppu {
+        Ppu {
+            ctrl: 0,
+            mask: 0,
+            status: 0,
+            oam_address: 0,
+            oam: [0; 256],
+            v: 0,
+            t: 0,
+            x: 0,
+            w: false,
+            read_buffer: 0,
+            io_latch: 0,
+            nametables: [0; 4096],
+            palette: [0; 32],
+            rgb_palette: NTSC_PALETTE,
+            dot: 0,
+            scanline: 0,
+            frame: 0,
+            suppress_vblank: false,
+            background: BackgroundFetcher::default(),
+            sprites: SpriteUnit::default(),
+            framebuffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * 3],
+        }
+    }
+
+    // Reset clears the control registers, the scroll latch and the read
+    // buffer but leaves memory alone
+    pub fn reset(&mut self) {
+        self.ctrl = 0;
+        self.mask = 0;
+        self.w = false;
+        self.t = 0;
+        self.x = 0;
+        self.read_buffer = 0;
+    }
+
+    pub fn ctrl(&self) -> u8 {
+        self.ctrl
+    }
+
+    pub fn mask(&self) -> u8 {
+        self.mask
+    }
+
+    pub fn status(&self) -> u8 {
+        self.status
+    }
+
+    pub fn set_palette(&mut self, palette: [(u8, u8, u8); 64]) {
+        self.rgb_palette = palette;
+    }
+
+    pub fn oam(&self) -> &[u8; 256] {
+        &self.oam
+    }
+
+    pub fn vram_address(&self) -> u16 {
+        self.v
+    }
+
+    pub fn temp_vram_address(&self) -> u16 {
+        self.t
+    }
+
+    pub fn fine_x_scroll(&self) -> u8 {
+        self.x
+    }
+
+    // Whether the next PPUSCROLL or PPUADDR write is the second of a pair
+    pub fn write_toggle(&self) -> bool {
+        self.w
+    }
+
+    pub fn dot(&self) -> u16 {
+        self.dot
+    }
+
+    pub fn scanline(&self) -> u16 {
+        self.scanline
+    }
+
+    // Frames completed since power-on
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer
+    }
+
+    // Whether the PPU is asserting NMI, which lasts until vblank ends or
+    // PPUSTATUS is read. Turning NMIs on during vblank asserts it straight away.
+    pub fn nmi_line(&self) -> bool {
+        self.status & STATUS_VBLANK != 0 && self.ctrl & CTRL_NMI_ENABLE != 0
+    }
+
+    fn rendering_enabled(&self) -> bool {
+        self.mask & (MASK_BACKGROUND | MASK_SPRITES) != 0
+    }
+
+    // Advances by one dot. The PPU runs three dots per CPU cycle on NTSC.
+    pub fn tick(&mut self, mapper: &mut dyn Mapper) {
+        let rendering_line = self.scanline < 240 || self.scanline == PRE_RENDER_SCANLINE;
+        if self.scanline == VBLANK_SCANLINE && self.dot == 1 {
+            if !self.suppress_vblank {
+                self.status |= STATUS_VBLANK;
+            }
+            self.suppress_vblank = false;
+        }
+        // The flags last until the pre-render line
+        if self.scanline == PRE_RENDER_SCANLINE && self.dot == 1 {
+            self.status &= !(STATUS_VBLANK | STATUS_SPRITE_ZERO_HIT | STATUS_SPRITE_OVERFLOW);
+        }
+        if self.rendering_enabled() && rendering_line {
+            self.background_dot(mapper);
+            self.sprite_dot(mapper);
+            if self.scanline == PRE_RENDER_SCANLINE && matches!(self.dot, 280..=304) {
+                self.copy_vertical_bits();
+            }
+        }
+        // After the shifters have moved on to this dot's pixel
+        if self.scanline < 240 && matches!(self.dot, 1..=256) {
+            self.output_pixel();
+        }
+
+        // With rendering on, odd frames skip the last dot of the pre-render line
+        let last_dot = if self.scanline == PRE_RENDER_SCANLINE
+            && self.frame % 2 == 1
+            && self.rendering_enabled()
+        {
+            DOTS_PER_SCANLINE - 2
+        } else {
+            DOTS_PER_SCANLINE - 1
+        };
+        if self.dot < last_dot {
+            self.dot += 1;
+            return;
+        }
+        self.dot = 0;
+        if self.scanline < PRE_RENDER_SCANLINE {
+            self.scanline += 1;
+        } else {
+            self.scanline = 0;
+            self.frame += 1;
+        }
+    }
+
+    fn output_pixel(&mut self) {
+        let x = self.dot as usize - 1;
+        let show_background =
+            self.mask & MASK_BACKGROUND != 0 && (x >= 8 || self.mask & MASK_BACKGROUND_LEFT != 0);
+        let background = if show_background {
+            self.background_pixel()
+        } else {
+            0
+        };
+        let show_sprites =
+            self.mask & MASK_SPRITES != 0 && (x >= 8 || self.mask & MASK_SPRITES_LEFT != 0);
+        let sprite = if show_sprites {
+            self.sprite_pixel(x)
+        } else {
+            None
+        };
+
+        let pixel = match sprite {
+            Some(sprite) if background != 0 => {
+                // Sprite 0 hit needs both opaque, and never happens at x=255
+                if sprite.sprite_zero && x != 255 {
+                    self.status |= STATUS_SPRITE_ZERO_HIT;
+                }
+                if sprite.behind_background {
+                    background
+                } else {
+                    sprite.colour
+                }
+            }
+            Some(sprite) => sprite.colour,
+            None => background,
+        };
+        let mut colour = self.palette[palette_offset(pixel as u16)] & 0x3F;
+        if self.mask & MASK_GREYSCALE != 0 {
+            colour &= 0x30;
+        }
+        let (r, g, b) = palette::emphasise(self.rgb_palette[colour as usize], colour, self.mask);
+        let offset = (self.scanline as usize * SCREEN_WIDTH + x) * 3;
+        self.framebuffer[offset..offset + 3].copy_from_slice(&[r, g, b]);
+    }
+
+    // address is anywhere in $2000-$3FFF, the registers repeat every 8 bytes
+    pub fn read_register(&mut self, address: u16, mapper: &mut dyn Mapper) -> u8 {
+        match address % 8 {
+            // PPUSTATUS, which acknowledges vblank and resets the write toggle.
+            // Reading it on the dot vblank starts reads the flag as clear and
+            // keeps it from being set at all, so no NMI happens that frame.
+            // https://www.nesdev.org/wiki/PPU_frame_timing#VBL_Flag_Timing
+            2 => {
+                if self.scanline == VBLANK_SCANLINE && self.dot == 1 {
+                    self.suppress_vblank = true;
+                }
+                self.io_latch = (self.status & 0b11100000) | (self.io_latch & 0b00011111);
+                self.status &= !STATUS_VBLANK;
+                self.w = false;
+            }
+            // OAMDATA
+            4 => self.io_latch = self.oam[self.oam_address as usize],
+            // PPUDATA. Everything below the palettes comes through the read
+            // buffer, one read late. Palette reads are immediate but still
+            // refill the buffer from the nametable underneath them.
+            7 => {
+                let address = self.v & 0x3FFF;
+                if address < 0x3F00 {
+                    self.io_latch = self.read_buffer;
+                    self.read_buffer = self.read(address, mapper);
+                } else {
+                    self.io_latch =
+                        (self.read(address, mapper) & 0b00111111) | (self.io_latch & 0b11000000);
+                    self.read_buffer = self.read(address - 0x1000, mapper);
+                }
+                self.increment_vram_address();
+            }
+            // Write-only
+            _ => {}
+        }
+        self.io_latch
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8, mapper: &mut dyn Mapper) {
+        self.io_latch = value;
+        match address % 8 {
+            // PPUCTRL, whose low bits pick the base nametable
+            0 => {
+                self.ctrl = value;
+                self.t = (self.t & !0x0C00) | ((value as u16 & 0b11) << 10);
+            }
+            // PPUMASK
+            1 => self.mask = value,
+            // OAMADDR
+            3 => self.oam_address = value,
+            // OAMDATA
+            4 => {
+                self.oam[self.oam_address as usize] = value;
+                self.oam_address = self.oam_address.wrapping_add(1);
+            }
+            // PPUSCROLL, X then Y
+            5 => {
+                if !self.w {
+                    self.t = (self.t & !0x001F) | (value as u16 >> 3);
+                    self.x = value & 0b111;
+                } else {
+                    self.t = (self.t & !0x73E0)
+                        | ((value as u16 & 0b111) << 12)
+                        | ((value as u16 & 0b11111000) << 2);
+                }
+                self.w = !self.w;
+            }
+            // PPUADDR, high byte then low byte. The second write copies t into v.
+            6 => {
+                if !self.w {
+                    self.t = (self.t & 0x00FF) | ((value as u16 & 0b00111111) << 8);
+                } else {
+                    self.t = (self.t & 0xFF00) | value as u16;
+                    self.v = self.t;
+                    // v drives the address bus while the PPU is idle
+                    mapper.notify_ppu_address(self.v & 0x3FFF);
+                }
+                self.w = !self.w;
+            }
+            // PPUDATA
+            7 => {
+                self.write(self.v & 0x3FFF, value, mapper);
+                self.increment_vram_address();
+            }
+            // PPUSTATUS is read-only
+            _ => {}
+        }
+    }
+
+    // PPUCTRL bit 2 picks between going across and going down a nametable
+    fn increment_vram_address(&mut self) {
+        let increment = if self.ctrl & CTRL_ADDRESS_INCREMENT != 0 {
+            32
+        } else {
+            1
+        };
+        self.v = self.v.wrapping_add(increment) & 0x7FFF;
+    }
+
+    // The PPU's own address space: pattern tables on the cartridge at
+    // $0000-$1FFF, nametables at $2000-$2FFF mirrored up to $3EFF, and
+    // palettes at $3F00-$3F1F mirrored up to $3FFF
+    // https://www.nesdev.org/wiki/PPU_memory_map
+    fn read(&self, address: u16, mapper: &mut dyn Mapper) -> u8 {
+        mapper.notify_ppu_address(address & 0x3FFF);
+        match address & 0x3FFF {
+            0x0000..=0x1FFF => mapper.read_pattern(address, &self.nametables),
+            0x2000..=0x3EFF => mapper.read_nametable(address, &self.nametables),
+            _ => self.palette[palette_offset(address)],
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8, mapper: &mut dyn Mapper) {
+        mapper.notify_ppu_address(address & 0x3FFF);
+        match address & 0x3FFF {
+            0x0000..=0x1FFF => mapper.write_pattern(address, value, &mut self.nametables),
+            0x2000..=0x3EFF => mapper.write_nametable(address, value, &mut self.nametables),
+            _ => self.palette[palette_offset(address)] = value,
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.ctrl);
+        state.u8(self.mask);
+        state.u8(self.status);
+        state.u8(self.oam_address);
+        state.bytes(&self.oam);
+        state.u16(self.v);
+        state.u16(self.t);
+        state.u8(self.x);
+        state.bool(self.w);
+        state.u8(self.read_buffer);
+        state.u8(self.io_latch);
+        state.bytes(&self.nametables);
+        state.bytes(&self.palette);
+        state.u16(self.dot);
+        state.u16(self.scanline);
+        state.u64(self.frame);
+        state.bool(self.suppress_vblank);
+        self.background.save_state(state);
+        self.sprites.save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.ctrl = state.u8()?;
+        self.mask = state.u8()?;
+        self.status = state.u8()?;
+        self.oam_address = state.u8()?;
+        state.fill(&mut self.oam)?;
+        self.v = state.u16()?;
+        self.t = state.u16()?;
+        self.x = state.u8()?;
+        self.w = state.bool()?;
+        self.read_buffer = state.u8()?;
+        self.io_latch = state.u8()?;
+        state.fill(&mut self.nametables)?;
+        state.fill(&mut self.palette)?;
+        self.dot = state.u16_below(DOTS_PER_SCANLINE, "PPU dot")?;
+        self.scanline = state.u16_below(PRE_RENDER_SCANLINE + 1, "PPU scanline")?;
+        self.frame = state.u64()?;
+        self.suppress_vblank = state.bool()?;
+        self.background.load_state(state)?;
+        self.sprites.load_state(state)?;
+        Ok(())
+    }
+}
+
+// Entry 0 of each sprite palette is the same byte as the background's
+fn palette_offset(address: u16) -> usize {
+    let offset = address as usize & 0x1F;
+    if offset & 0b10011 == 0b10000 {
+        offset & 0x0F
+    } else {
+        offset
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cartridge::CartridgeBuilder;
+    use crate::mapper::Nrom;
+
+    use super::*;
+
+    // NROM with CHR RAM, so pattern tables can be written too
+    pub(super) fn nrom() -> Nrom {
+        let cartridge = CartridgeBuilder::new()
+            .prg_rom(vec![0; 0x4000])
+            .build()
+            .unwrap();
+        Nrom::new(cartridge)
+    }
+
+    fn set_address(ppu: &mut Ppu, mapper: &mut Nrom, address: u16) {
+        ppu.write_register(0x2006, (address >> 8) as u8, mapper);
+        ppu.write_register(0x2006, address as u8, mapper);
+    }
+
+    #[test]
+    fn ppuaddr_takes_two_writes() {
+        let (mut ppu, mut mapper) = (Ppu::new(), nrom());
+        ppu.write_register(0x2006, 0x21, &mut mapper);
+        assert!(ppu.write_toggle());
+        assert_eq!(ppu.vram_address(), 0);
+        ppu.write_register(0x2006, 0x08, &mut mapper);
+        assert!(!ppu.write_toggle());
+        assert_eq!(ppu.vram_address(), 0x2108);
+
+        // Only 14 bits are kept, and through the $3FFF mirrors as well
+        ppu.write_register(0x3FFE, 0xFF, &mut mapper);
+        ppu.write_register(0x200E, 0xFF, &mut mapper);
+        assert_eq!(ppu.vram_address(), 0x3FFF);
+
+        // Reading PPUSTATUS starts the pair over
+        ppu.write_register(0x2006, 0x23, &mut mapper);
+        ppu.read_register(0x2002, &mut mapper);
+        set_address(&mut ppu, &mut mapper, 0x2400);
+        assert_eq!(ppu.vram_address(), 0x2400);
+    }
+
+    #[test]
+    fn ppudata_reads_come_through_the_buffer() {
+        let (mut ppu, mut mapper) = (Ppu::new(), nrom());
+        set_address(&mut ppu, &mut mapper, 0x2000);
+        for value in [0x11, 0x22, 0x33] {
+            ppu.write_register(0x2007, value, &mut mapper);
+        }
+
+        set_address(&mut ppu, &mut mapper, 0x2000);
+        assert_eq!(ppu.read_register(0x2007, &mut mapper), 0x00);
+        assert_eq!(ppu.read_register(0x2007, &mut mapper), 0x11);
+        assert_eq!(ppu.read_register(0x2007, &mut mapper), 0x22);
+        assert_eq!(ppu.vram_address(), 0x2003);
+
+        // Palette reads skip the buffer
+        set_address(&mut ppu, &mut mapper, 0x3F01);
+        ppu.write_register(0x2007, 0x2A, &mut mapper);
+        set_address(&mut ppu, &mut mapper, 0x3F01);
+        assert_eq!(ppu.read_register(0x2007, &mut mapper), 0x2A);
+    }
+
+    #[test]
+    fn ppudata_increment() {
+        let (mut ppu, mut mapper) = (Ppu::new(), nrom());
+        ppu.write_register(0x2000, CTRL_ADDRESS_INCREMENT, &mut mapper);
+        set_address(&mut ppu, &mut mapper, 0x2000);
+        ppu.write_register(0x2007, 0x01, &mut mapper);
+        ppu.write_register(0x2007, 0x02, &mut mapper);
+        assert_eq!(ppu.vram_address(), 0x2040);
+
+        ppu.write_register(0x2000, 0, &mut mapper);
+        set_address(&mut ppu, &mut mapper, 0x2020);
+        ppu.read_register(0x2007, &mut mapper);
+        assert_eq!(ppu.read_register(0x2007, &mut mapper), 0x02);
+    }
+
+    fn run_to(ppu: &mut Ppu, mapper: &mut Nrom, scanline: u16, dot: u16) {
+        while (ppu.scanline(), ppu.dot()) != (scanline, dot) {
+            ppu.tick(mapper);
+        }
+    }
+
+    #[test]
+    fn nmi_at_vblank() {
+        let (mut ppu, mut mapper) = (Ppu::new(), nrom());
+        ppu.write_register(0x2000, CTRL_NMI_ENABLE, &mut mapper);
+        let mut ticks = 0;
+        while (ppu.scanline(), ppu.dot()) != (241, 1) {
+            assert!(!ppu.nmi_line());
+            ppu.tick(&mut mapper);
+            ticks += 1;
+        }
+        assert_eq!(ticks, 241 * 341 + 1);
+        // Still clear right up to dot 1 of line 241, which sets it
+        assert_eq!(ppu.status() & STATUS_VBLANK, 0);
+        assert!(!ppu.nmi_line());
+        ppu.tick(&mut mapper);
+        assert_ne!(ppu.status() & STATUS_VBLANK, 0);
+        assert!(ppu.nmi_line());
+
+        // Reading PPUSTATUS clears the flag and with it the NMI
+        assert_ne!(ppu.read_register(0x2002, &mut mapper) & STATUS_VBLANK, 0);
+        assert!(!ppu.nmi_line());
+        assert_eq!(ppu.read_register(0x2002, &mut mapper) & STATUS_VBLANK, 0);
+
+        // Turning NMIs off and on during vblank raises another
+        run_to(&mut ppu, &mut mapper, 241, 1);
+        ppu.tick(&mut mapper);
+        ppu.write_register(0x2000, 0, &mut mapper);
+        assert!(!ppu.nmi_line());
+        ppu.write_register(0x2000, CTRL_NMI_ENABLE, &mut mapper);
+        assert!(ppu.nmi_line());
+    }
+
+    #[test]
+    fn vblank_ends_on_the_pre_render_line() {
+        let (mut ppu, mut mapper) = (Ppu::new(), nrom());
+        run_to(&mut ppu, &mut mapper, 261, 1);
+        assert_ne!(ppu.status() & STATUS_VBLANK, 0);
+        ppu.tick(&mut mapper);
+        assert_eq!(ppu.status() & STATUS_VBLANK, 0);
+    }
+
+    #[test]
+    fn reading_status_as_vblank_starts_suppresses_it() {
+        let (mut ppu, mut mapper) = (Ppu::new(), nrom());
+        ppu.write_register(0x2000, CTRL_NMI_ENABLE, &mut mapper);
+        run_to(&mut ppu, &mut mapper, 241, 1);
+        assert_eq!(ppu.read_register(0x2002, &mut mapper) & STATUS_VBLANK, 0);
+        run_to(&mut ppu, &mut mapper, 260, 0);
+        assert_eq!(ppu.status() & STATUS_VBLANK, 0);
+        // The next frame is back to normal
+        run_to(&mut ppu, &mut mapper, 241, 2);
+        assert!(ppu.nmi_line());
+    }
+}
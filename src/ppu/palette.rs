@@ -0,0 +1,166 @@
+// RGB for each of the 64 colours the PPU can output
+// https://www.nesdev.org/wiki/PPU_palettes
+
+// A .pal file is 64 RGB triples with no header
+const PAL_FILE_LEN: usize = 64 * 3;
+// How far each emphasis bit dims the other two channels
+const EMPHASIS_ATTENUATION: f32 = 0.816;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PaletteError {
+    // Anything but the 192 bytes of a plain 64 colour palette
+    WrongLength(usize),
+}
+
+// For the palette files FCEUX and most other emulators use
+pub fn load_pal_bytes(bytes: &[u8]) -> Result<[(u8, u8, u8); 64], PaletteError> {
+    if bytes.len() != PAL_FILE_LEN {
+        return Err(PaletteError::WrongLength(bytes.len()));
+    }
+    let mut palette = [(0, 0, 0); 64];
+    for (colour, rgb) in palette.iter_mut().zip(bytes.chunks_exact(3)) {
+        *colour = (rgb[0], rgb[1], rgb[2]);
+    }
+    Ok(palette)
+}
+
+// The top three bits of PPUMASK emphasise red, green and blue on NTSC by
+// darkening the other channels. $xE and $xF are black whatever they say.
+pub(super) fn emphasise((r, g, b): (u8, u8, u8), colour: u8, mask: u8) -> (u8, u8, u8) {
+    let emphasis = mask >> 5;
+    if emphasis == 0 || colour & 0x0E == 0x0E {
+        return (r, g, b);
+    }
+    let mut channels = [r as f32, g as f32, b as f32];
+    for (channel, value) in channels.iter_mut().enumerate() {
+        // Every emphasis bit that isn't this channel's dims it
+        let dimmed_by = (emphasis & !(1 << channel)).count_ones();
+        *value *= EMPHASIS_ATTENUATION.powi(dimmed_by as i32);
+    }
+    (channels[0] as u8, channels[1] as u8, channels[2] as u8)
+}
+
+pub const NTSC_PALETTE: [(u8, u8, u8); 64] = [
+    (84, 84, 84),
+    (0, 30, 116),
+    (8, 16, 144),
+    (48, 0, 136),
+    (68, 0, 100),
+    (92, 0, 48),
+    (84, 4, 0),
+    (60, 24, 0),
+    (32, 42, 0),
+    (8, 58, 0),
+    (0, 64, 0),
+    (0, 60, 0),
+    (0, 50, 60),
+    (0, 0, 0),
+    (0, 0, 0),
+    (0, 0, 0),
+    (152, 150, 152),
+    (8, 76, 196),
+    (48, 50, 236),
+    (92, 30, 228),
+    (136, 20, 176),
+    (160, 20, 100),
+    (152, 34, 32),
+    (120, 60, 0),
+    (84, 90, 0),
+    (40, 114, 0),
+    (8, 124, 0),
+    (0, 118, 40),
+    (0, 102, 120),
+    (0, 0, 0),
+    (0, 0, 0),
+    (0, 0, 0),
+    (236, 238, 236),
+    (76, 154, 236),
+    (120, 124, 236),
+    (176, 98, 236),
+    (228, 84, 236),
+    (236, 88, 180),
+    (236, 106, 100),
+    (212, 136, 32),
+    (160, 170, 0),
+    (116, 196, 0),
+    (76, 208, 32),
+    (56, 204, 108),
+    (56, 180, 204),
+    (60, 60, 60),
+    (0, 0, 0),
+    (0, 0, 0),
+    (236, 238, 236),
+    (168, 204, 236),
+    (188, 188, 236),
+    (212, 178, 236),
+    (236, 174, 236),
+    (236, 174, 212),
+    (236, 180, 176),
+    (228, 196, 144),
+    (204, 210, 120),
+    (180, 222, 120),
+    (168, 226, 144),
+    (152, 226, 180),
+    (160, 214, 228),
+    (160, 162, 160),
+    (0, 0, 0),
+    (0, 0, 0),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::nrom;
+    use super::super::Ppu;
+    use super::*;
+
+    // The colour of the first pixel, after a line of backdrop
+    fn first_pixel(ppu: &mut Ppu) -> (u8, u8, u8) {
+        let mut mapper = nrom();
+        while ppu.scanline() == 0 {
+            ppu.tick(&mut mapper);
+        }
+        let pixel = &ppu.framebuffer()[..3];
+        (pixel[0], pixel[1], pixel[2])
+    }
+
+    #[test]
+    fn default_palette() {
+        assert_eq!(NTSC_PALETTE[0x00], (84, 84, 84));
+        assert_eq!(NTSC_PALETTE[0x0F], (0, 0, 0));
+        assert_eq!(NTSC_PALETTE[0x20], (236, 238, 236));
+        // Palette RAM starts out as colour $00
+        assert_eq!(first_pixel(&mut Ppu::new()), (84, 84, 84));
+    }
+
+    #[test]
+    fn custom_palette() {
+        let bytes: Vec<u8> = (0..PAL_FILE_LEN).map(|i| i as u8).collect();
+        let palette = load_pal_bytes(&bytes).unwrap();
+        assert_eq!(palette[0], (0, 1, 2));
+        assert_eq!(palette[63], (189, 190, 191));
+        assert_eq!(
+            load_pal_bytes(&bytes[..191]),
+            Err(PaletteError::WrongLength(191))
+        );
+        // Some .pal files carry all eight emphasis combinations
+        assert_eq!(
+            load_pal_bytes(&[0; 8 * PAL_FILE_LEN]),
+            Err(PaletteError::WrongLength(8 * PAL_FILE_LEN))
+        );
+
+        let mut ppu = Ppu::new();
+        ppu.set_palette(palette);
+        assert_eq!(first_pixel(&mut ppu), (0, 1, 2));
+    }
+
+    #[test]
+    fn emphasis_dims_the_other_channels() {
+        assert_eq!(emphasise((100, 100, 100), 0x00, 0), (100, 100, 100));
+        // Red
+        assert_eq!(emphasise((100, 100, 100), 0x00, 0b00100000), (100, 81, 81));
+        // Green and blue
+        assert_eq!(emphasise((100, 100, 100), 0x00, 0b11000000), (66, 81, 81));
+        // The blacks in column $E aren't touched
+        assert_eq!(emphasise((10, 10, 10), 0x1E, 0b11100000), (10, 10, 10));
+    }
+}
@@ -0,0 +1,356 @@
+// Sprite evaluation, pattern fetches and the per-pixel sprite output
+// https://www.nesdev.org/wiki/PPU_sprite_evaluation
+// https://www.nesdev.org/wiki/PPU_OAM
+
+use crate::mapper::Mapper;
+use crate::state::{StateError, StateReader, StateWriter};
+
+// PPUCTRL
+const CTRL_SPRITE_TABLE: u8 = 0b00001000;
+const CTRL_TALL_SPRITES: u8 = 0b00100000;
+// PPUSTATUS
+pub(super) const STATUS_SPRITE_OVERFLOW: u8 = 0b00100000;
+// Sprite attributes
+const ATTRIBUTE_PALETTE: u8 = 0b00000011;
+const ATTRIBUTE_BEHIND_BACKGROUND: u8 = 0b00100000;
+const ATTRIBUTE_FLIP_HORIZONTAL: u8 = 0b01000000;
+const ATTRIBUTE_FLIP_VERTICAL: u8 = 0b10000000;
+
+const SPRITES_PER_LINE: usize = 8;
+
+#[derive(Clone, Copy, Default)]
+struct SpriteSlot {
+    x: u8,
+    attributes: u8,
+    pattern_low: u8,
+    pattern_high: u8,
+}
+
+#[derive(Default)]
+pub(super) struct SpriteUnit {
+    // Up to 8 sprites found for the next line, four OAM bytes each
+    secondary_oam: [u8; SPRITES_PER_LINE * 4],
+    found: usize,
+    // Whether sprite 0 was among them
+    sprite_zero_found: bool,
+    // The sprites being drawn on the current line
+    slots: [SpriteSlot; SPRITES_PER_LINE],
+    count: usize,
+    sprite_zero_on_line: bool,
+}
+
+impl SpriteUnit {
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.secondary_oam);
+        state.u8(self.found as u8);
+        state.bool(self.sprite_zero_found);
+        for slot in &self.slots {
+            state.bytes(&[slot.x, slot.attributes, slot.pattern_low, slot.pattern_high]);
+        }
+        state.u8(self.count as u8);
+        state.bool(self.sprite_zero_on_line);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.fill(&mut self.secondary_oam)?;
+        self.found = state.u8_below(SPRITES_PER_LINE as u8 + 1, "sprites found")? as usize;
+        self.sprite_zero_found = state.bool()?;
+        for slot in &mut self.slots {
+            let mut bytes = [0; 4];
+            state.fill(&mut bytes)?;
+            let [x, attributes, pattern_low, pattern_high] = bytes;
+            *slot = SpriteSlot {
+                x,
+                attributes,
+                pattern_low,
+                pattern_high,
+            };
+        }
+        self.count = state.u8_below(SPRITES_PER_LINE as u8 + 1, "sprites on line")? as usize;
+        self.sprite_zero_on_line = state.bool()?;
+        Ok(())
+    }
+}
+
+// What the sprites have at one pixel
+pub(super) struct SpritePixel {
+    // Palette index, 0 if transparent
+    pub colour: u8,
+    pub behind_background: bool,
+    pub sprite_zero: bool,
+}
+
+impl super::Ppu {
+    // Evaluation for the next line happens over dots 65-256 of each visible
+    // line and the patterns are fetched over dots 257-320, one sprite every
+    // eight dots. Evaluation is done all at once at the end of its window.
+    pub(super) fn sprite_dot(&mut self, mapper: &mut dyn Mapper) {
+        let dot = self.dot;
+        if dot == 256 && self.scanline < 240 {
+            self.evaluate_sprites();
+        }
+        if matches!(dot, 257..=320) {
+            // OAMADDR is cleared throughout the fetches
+            self.oam_address = 0;
+            let slot = (dot as usize - 257) / 8;
+            match (dot - 257) % 8 {
+                4 => {
+                    let address = self.sprite_pattern_address(slot);
+                    self.sprites.slots[slot].pattern_low = self.read(address, mapper);
+                }
+                6 => {
+                    let address = self.sprite_pattern_address(slot) + 8;
+                    self.sprites.slots[slot].pattern_high = self.read(address, mapper);
+                }
+                7 => self.load_sprite_slot(slot),
+                _ => {}
+            }
+        }
+        if dot == 320 {
+            let sprites = &mut self.sprites;
+            sprites.count = sprites.found;
+            sprites.sprite_zero_on_line = sprites.sprite_zero_found;
+            // The pre-render line fetches sprites but has none to draw, so
+            // nothing shows up on line 0
+            if self.scanline == super::PRE_RENDER_SCANLINE {
+                self.sprites.count = 0;
+                self.sprites.sprite_zero_on_line = false;
+            }
+        }
+    }
+
+    fn sprite_height(&self) -> u16 {
+        if self.ctrl & CTRL_TALL_SPRITES != 0 {
+            16
+        } else {
+            8
+        }
+    }
+
+    // Copies the first 8 sprites in range of the next line into secondary
+    // OAM. Past the eighth, the hardware keeps looking to set the overflow
+    // flag, but steps through the bytes of each sprite as well as through the
+    // sprites, so it compares tile numbers and attributes as Y coordinates.
+    fn evaluate_sprites(&mut self) {
+        let height = self.sprite_height();
+        let scanline = self.scanline;
+        let in_range = |y: u8| scanline.wrapping_sub(y as u16) < height;
+
+        self.sprites.secondary_oam = [0xFF; SPRITES_PER_LINE * 4];
+        self.sprites.found = 0;
+        self.sprites.sprite_zero_found = false;
+        let mut n = 0;
+        while n < 64 && self.sprites.found < SPRITES_PER_LINE {
+            if in_range(self.oam[n * 4]) {
+                let slot = self.sprites.found * 4;
+                self.sprites.secondary_oam[slot..slot + 4]
+                    .copy_from_slice(&self.oam[n * 4..n * 4 + 4]);
+                if n == 0 {
+                    self.sprites.sprite_zero_found = true;
+                }
+                self.sprites.found += 1;
+            }
+            n += 1;
+        }
+
+        let mut m = 0;
+        while n < 64 {
+            if in_range(self.oam[n * 4 + m]) {
+                self.status |= STATUS_SPRITE_OVERFLOW;
+                break;
+            }
+            n += 1;
+            m = (m + 1) % 4;
+        }
+    }
+
+    // Empty slots fetch tile $FF, which mappers watching the address bus see
+    fn sprite_pattern_address(&self, slot: usize) -> u16 {
+        let sprite = &self.sprites.secondary_oam[slot * 4..slot * 4 + 4];
+        let (y, tile, attributes) = (sprite[0], sprite[1], sprite[2]);
+        let height = self.sprite_height();
+        let mut row = if slot < self.sprites.found {
+            self.scanline.wrapping_sub(y as u16) % height
+        } else {
+            0
+        };
+        if attributes & ATTRIBUTE_FLIP_VERTICAL != 0 && slot < self.sprites.found {
+            row = height - 1 - row;
+        }
+
+        if height == 16 {
+            // Bit 0 of the tile number picks the pattern table, and the
+            // bottom half is the next tile
+            let table = (tile as u16 & 1) << 12;
+            let tile = (tile & 0xFE) as u16 + (row >> 3);
+            table | (tile << 4) | (row & 0b111)
+        } else {
+            let table = if self.ctrl & CTRL_SPRITE_TABLE != 0 {
+                0x1000
+            } else {
+                0
+            };
+            table | ((tile as u16) << 4) | row
+        }
+    }
+
+    fn load_sprite_slot(&mut self, slot: usize) {
+        let sprite = &mut self.sprites.slots[slot];
+        if slot >= self.sprites.found {
+            *sprite = SpriteSlot::default();
+            return;
+        }
+        let oam = &self.sprites.secondary_oam[slot * 4..slot * 4 + 4];
+        sprite.attributes = oam[2];
+        sprite.x = oam[3];
+        if sprite.attributes & ATTRIBUTE_FLIP_HORIZONTAL != 0 {
+            sprite.pattern_low = sprite.pattern_low.reverse_bits();
+            sprite.pattern_high = sprite.pattern_high.reverse_bits();
+        }
+    }
+
+    // The first opaque sprite at x wins, whatever its priority bit says
+    pub(super) fn sprite_pixel(&self, x: usize) -> Option<SpritePixel> {
+        let sprites = &self.sprites;
+        for (slot, sprite) in sprites.slots[..sprites.count].iter().enumerate() {
+            let column = x.wrapping_sub(sprite.x as usize);
+            if column >= 8 {
+                continue;
+            }
+            let bit = 0x80 >> column;
+            let pixel = ((sprite.pattern_high & bit != 0) as u8) << 1
+                | (sprite.pattern_low & bit != 0) as u8;
+            if pixel == 0 {
+                continue;
+            }
+            return Some(SpritePixel {
+                colour: 0x10 | ((sprite.attributes & ATTRIBUTE_PALETTE) << 2) | pixel,
+                behind_background: sprite.attributes & ATTRIBUTE_BEHIND_BACKGROUND != 0,
+                sprite_zero: slot == 0 && sprites.sprite_zero_on_line,
+            });
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::nrom;
+    use super::super::{Ppu, STATUS_SPRITE_ZERO_HIT, STATUS_VBLANK};
+    use super::*;
+    use crate::mapper::Nrom;
+
+    // Tile 1 is solid colour 1 and fills the background. OAM starts with
+    // every sprite hidden below the screen.
+    fn setup(sprites: &[[u8; 4]], mask: u8) -> (Ppu, Nrom) {
+        let (mut ppu, mut mapper) = (Ppu::new(), nrom());
+        ppu.write_register(0x2006, 0x00, &mut mapper);
+        ppu.write_register(0x2006, 0x10, &mut mapper);
+        for byte in [0xFF; 8].into_iter().chain([0; 8]) {
+            ppu.write_register(0x2007, byte, &mut mapper);
+        }
+        ppu.write_register(0x2006, 0x20, &mut mapper);
+        ppu.write_register(0x2006, 0x00, &mut mapper);
+        for _ in 0..0x3C0 {
+            ppu.write_register(0x2007, 1, &mut mapper);
+        }
+        ppu.write_register(0x2003, 0, &mut mapper);
+        for n in 0..64 {
+            let sprite = sprites.get(n).unwrap_or(&[0xFF; 4]);
+            for &byte in sprite {
+                ppu.write_register(0x2004, byte, &mut mapper);
+            }
+        }
+        ppu.write_register(0x2006, 0x00, &mut mapper);
+        ppu.write_register(0x2006, 0x00, &mut mapper);
+        ppu.write_register(0x2001, mask, &mut mapper);
+        (ppu, mapper)
+    }
+
+    // Runs to the start of vblank, returning the pixel where sprite 0 hit
+    // was first seen
+    fn run_frame(ppu: &mut Ppu, mapper: &mut Nrom) -> Option<(u16, u16)> {
+        let mut hit = None;
+        while ppu.status() & STATUS_VBLANK == 0 {
+            let (scanline, dot) = (ppu.scanline(), ppu.dot());
+            ppu.tick(mapper);
+            if hit.is_none() && ppu.status() & STATUS_SPRITE_ZERO_HIT != 0 {
+                hit = Some((scanline, dot - 1));
+            }
+        }
+        hit
+    }
+
+    const SHOW_ALL: u8 = 0b00011110;
+
+    #[test]
+    fn sprite_zero_hit_timing() {
+        // Drawn from line 50, since Y is one line early
+        let (mut ppu, mut mapper) = setup(&[[49, 1, 0, 100]], SHOW_ALL);
+        assert_eq!(run_frame(&mut ppu, &mut mapper), Some((50, 100)));
+
+        // A transparent sprite doesn't hit
+        let (mut ppu, mut mapper) = setup(&[[49, 0, 0, 100]], SHOW_ALL);
+        assert_eq!(run_frame(&mut ppu, &mut mapper), None);
+
+        // Nor does sprite 1
+        let (mut ppu, mut mapper) = setup(&[[0xFF; 4], [49, 1, 0, 100]], SHOW_ALL);
+        assert_eq!(run_frame(&mut ppu, &mut mapper), None);
+    }
+
+    #[test]
+    fn sprite_zero_hit_edges() {
+        // With the left 8 pixels clipped, only the part from x=8 can hit
+        let (mut ppu, mut mapper) = setup(&[[49, 1, 0, 4]], 0b00011000);
+        assert_eq!(run_frame(&mut ppu, &mut mapper), Some((50, 8)));
+        let (mut ppu, mut mapper) = setup(&[[49, 1, 0, 4]], SHOW_ALL);
+        assert_eq!(run_frame(&mut ppu, &mut mapper), Some((50, 4)));
+        // Never at x=255
+        let (mut ppu, mut mapper) = setup(&[[49, 1, 0, 255]], SHOW_ALL);
+        assert_eq!(run_frame(&mut ppu, &mut mapper), None);
+    }
+
+    #[test]
+    fn ninth_sprite_sets_overflow() {
+        let mut sprites = vec![[100, 1, 0, 0]; 8];
+        let (mut ppu, mut mapper) = setup(&sprites, SHOW_ALL);
+        run_frame(&mut ppu, &mut mapper);
+        assert_eq!(ppu.status() & STATUS_SPRITE_OVERFLOW, 0);
+
+        sprites.push([100, 1, 0, 0]);
+        let (mut ppu, mut mapper) = setup(&sprites, SHOW_ALL);
+        run_frame(&mut ppu, &mut mapper);
+        assert_ne!(ppu.status() & STATUS_SPRITE_OVERFLOW, 0);
+
+        // Only with rendering on
+        let (mut ppu, mut mapper) = setup(&sprites, 0);
+        run_frame(&mut ppu, &mut mapper);
+        assert_eq!(ppu.status() & STATUS_SPRITE_OVERFLOW, 0);
+    }
+
+    #[test]
+    fn overflow_check_is_buggy() {
+        // After sprite 8 misses, sprite 9's tile number is taken as its Y
+        let mut sprites = vec![[100, 1, 0, 0]; 8];
+        sprites.push([0xFF; 4]);
+        sprites.push([0xFF, 100, 0, 0]);
+        let (mut ppu, mut mapper) = setup(&sprites, SHOW_ALL);
+        run_frame(&mut ppu, &mut mapper);
+        assert_ne!(ppu.status() & STATUS_SPRITE_OVERFLOW, 0);
+
+        // So a real ninth sprite there goes unnoticed
+        sprites[9] = [100, 0, 0, 0];
+        let (mut ppu, mut mapper) = setup(&sprites, SHOW_ALL);
+        run_frame(&mut ppu, &mut mapper);
+        assert_eq!(ppu.status() & STATUS_SPRITE_OVERFLOW, 0);
+    }
+
+    #[test]
+    fn tall_sprites() {
+        // Tile 0/1 pair: the top half is tile 0, transparent, so the hit is
+        // 8 lines further down
+        let (mut ppu, mut mapper) = setup(&[[49, 0, 0, 100]], SHOW_ALL);
+        ppu.write_register(0x2000, CTRL_TALL_SPRITES, &mut mapper);
+        assert_eq!(run_frame(&mut ppu, &mut mapper), Some((58, 100)));
+    }
+}
@@ -0,0 +1,272 @@
+// The binary format save states are written in. Every part of the console
+// writes its fields in a fixed order through StateWriter and reads them back
+// in the same order through StateReader, so there are no field names or
+// lengths in the data apart from where sizes can vary. Anything that changes
+// that order has to bump STATE_VERSION.
+
+use crate::cartridge::SnapshotError;
+
+pub const STATE_MAGIC: [u8; 4] = *b"ZNST";
+pub const STATE_VERSION: u16 = 2;
+
+#[derive(Debug, PartialEq)]
+pub enum StateError {
+    // Not a save state at all
+    BadMagic,
+    // Written by a different version of the format
+    UnsupportedVersion(u16),
+    // Cut short, or written for a different board with less state
+    UnexpectedEnd,
+    // Bytes left over after everything was read
+    TrailingData(usize),
+    // A field holds something it never could on hardware
+    InvalidValue(&'static str),
+    // The cartridge RAM is for a different ROM or a different size
+    Cartridge(SnapshotError),
+}
+
+impl From<SnapshotError> for StateError {
+    fn from(error: SnapshotError) -> Self {
+        StateError::Cartridge(error)
+    }
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter::default()
+    }
+
+    // Starts a state with the magic number and version
+    pub fn with_header() -> StateWriter {
+        let mut writer = StateWriter::new();
+        writer.bytes(&STATE_MAGIC);
+        writer.u16(STATE_VERSION);
+        writer
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    // Multi-byte values are little-endian, like everything else on the NES
+    pub fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn i16(&mut self, value: i16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // A fixed length block, read back with StateReader::fill
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    // A block whose length is written ahead of it, read back with
+    // StateReader::vec
+    pub fn vec(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.bytes(bytes);
+    }
+
+    pub fn option_u8(&mut self, value: Option<u8>) {
+        self.bool(value.is_some());
+        self.u8(value.unwrap_or(0));
+    }
+}
+
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> StateReader<'a> {
+        StateReader { bytes, position: 0 }
+    }
+
+    // Checks the magic number and version written by StateWriter::with_header
+    pub fn with_header(bytes: &'a [u8]) -> Result<StateReader<'a>, StateError> {
+        let mut reader = StateReader::new(bytes);
+        let mut magic = [0; 4];
+        reader.fill(&mut magic).map_err(|_| StateError::BadMagic)?;
+        if magic != STATE_MAGIC {
+            return Err(StateError::BadMagic);
+        }
+        let version = reader.u16()?;
+        if version != STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        Ok(reader)
+    }
+
+    // Fails if anything is left unread
+    pub fn finish(self) -> Result<(), StateError> {
+        match self.bytes.len() - self.position {
+            0 => Ok(()),
+            left => Err(StateError::TrailingData(left)),
+        }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        let end = self.position + len;
+        let bytes = self
+            .bytes
+            .get(self.position..end)
+            .ok_or(StateError::UnexpectedEnd)?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        let mut bytes = [0; N];
+        bytes.copy_from_slice(self.take(N)?);
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::InvalidValue("bool")),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        self.array().map(u16::from_le_bytes)
+    }
+
+    pub fn i16(&mut self) -> Result<i16, StateError> {
+        self.array().map(i16::from_le_bytes)
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        self.array().map(u32::from_le_bytes)
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        self.array().map(u64::from_le_bytes)
+    }
+
+    // For values used as indices, which have to stay in range
+    pub fn u8_below(&mut self, limit: u8, field: &'static str) -> Result<u8, StateError> {
+        match self.u8()? {
+            value if value < limit => Ok(value),
+            _ => Err(StateError::InvalidValue(field)),
+        }
+    }
+
+    pub fn u16_below(&mut self, limit: u16, field: &'static str) -> Result<u16, StateError> {
+        match self.u16()? {
+            value if value < limit => Ok(value),
+            _ => Err(StateError::InvalidValue(field)),
+        }
+    }
+
+    pub fn fill(&mut self, out: &mut [u8]) -> Result<(), StateError> {
+        out.copy_from_slice(self.take(out.len())?);
+        Ok(())
+    }
+
+    pub fn vec(&mut self) -> Result<Vec<u8>, StateError> {
+        let len = self.u32()? as usize;
+        self.take(len).map(<[u8]>::to_vec)
+    }
+
+    pub fn option_u8(&mut self) -> Result<Option<u8>, StateError> {
+        let some = self.bool()?;
+        let value = self.u8()?;
+        Ok(some.then_some(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields_read_back_in_order() {
+        let mut writer = StateWriter::with_header();
+        writer.u8(0x12);
+        writer.bool(true);
+        writer.u16(0x3456);
+        writer.i16(-2);
+        writer.u32(0x789A_BCDE);
+        writer.u64(u64::MAX - 1);
+        writer.bytes(&[1, 2, 3]);
+        writer.vec(&[4, 5]);
+        writer.option_u8(None);
+        writer.option_u8(Some(6));
+        let bytes = writer.into_bytes();
+
+        let mut reader = StateReader::with_header(&bytes).unwrap();
+        assert_eq!(reader.u8(), Ok(0x12));
+        assert_eq!(reader.bool(), Ok(true));
+        assert_eq!(reader.u16(), Ok(0x3456));
+        assert_eq!(reader.i16(), Ok(-2));
+        assert_eq!(reader.u32(), Ok(0x789A_BCDE));
+        assert_eq!(reader.u64(), Ok(u64::MAX - 1));
+        let mut three = [0; 3];
+        reader.fill(&mut three).unwrap();
+        assert_eq!(three, [1, 2, 3]);
+        assert_eq!(reader.vec(), Ok(vec![4, 5]));
+        assert_eq!(reader.option_u8(), Ok(None));
+        assert_eq!(reader.option_u8(), Ok(Some(6)));
+        assert_eq!(reader.finish(), Ok(()));
+    }
+
+    #[test]
+    fn header_and_length_are_checked() {
+        let mut writer = StateWriter::with_header();
+        writer.u16(1);
+        let bytes = writer.into_bytes();
+        assert_eq!(&bytes[..4], b"ZNST");
+
+        assert!(matches!(
+            StateReader::with_header(b"ZNS"),
+            Err(StateError::BadMagic)
+        ));
+        assert!(matches!(
+            StateReader::with_header(b"NES\x1A\x02\x00"),
+            Err(StateError::BadMagic)
+        ));
+        let mut newer = bytes.clone();
+        newer[4..6].copy_from_slice(&(STATE_VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            StateReader::with_header(&newer),
+            Err(StateError::UnsupportedVersion(version)) if version == STATE_VERSION + 1
+        ));
+
+        let mut reader = StateReader::with_header(&bytes).unwrap();
+        assert_eq!(reader.u32(), Err(StateError::UnexpectedEnd));
+        let reader = StateReader::with_header(&bytes).unwrap();
+        assert_eq!(reader.finish(), Err(StateError::TrailingData(2)));
+        let mut reader = StateReader::new(&[2]);
+        assert_eq!(reader.bool(), Err(StateError::InvalidValue("bool")));
+    }
+}